@@ -10,12 +10,17 @@ use {
             command_queue::{Cmd, CommandQueue},
             ui::{
                 Tab,
-                file_ops::{FILT_MIDI, FILT_ORGANYA, FILT_PIYOPIYO, FILT_PTCOP, FILT_SF2, FileOp},
+                file_ops::{
+                    FILT_MIDI, FILT_MML, FILT_MUSICXML, FILT_ORGANYA, FILT_PIYOPIYO, FILT_PTCOP,
+                    FILT_PTTUNE, FILT_SF2, FILT_TRACKER, FileOp,
+                },
                 modal::Modal,
             },
         },
         audio_out::{OutParams, SongState, SongStateHandle, spawn_ptcow_audio_thread},
+        autosave::{self, Autosave},
         evilscript,
+        pttune::ProjFormat,
         pxtone_misc::{poly_migrate_units, reset_voice_for_units_with_voice_idx},
     },
     anyhow::Context,
@@ -83,6 +88,76 @@ impl SongLock {
     }
 }
 
+/// Polls the opened file for changes on disk
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Default)]
+struct FileWatch {
+    /// egui time of the next check
+    next_check: f64,
+    /// The file being watched, and its modification time when last seen
+    seen: Option<(PathBuf, std::time::SystemTime)>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileWatch {
+    /// Seconds between checks
+    const INTERVAL: f64 = 1.0;
+    /// Returns true if `path` was modified since it was last seen
+    fn poll(&mut self, now: f64, path: &Path) -> bool {
+        if now < self.next_check {
+            return false;
+        }
+        self.next_check = now + Self::INTERVAL;
+        let Ok(mtime) = std::fs::metadata(path).and_then(|meta| meta.modified()) else {
+            return false;
+        };
+        let changed = self
+            .seen
+            .as_ref()
+            .is_some_and(|(seen_path, seen_mtime)| seen_path == path && *seen_mtime != mtime);
+        self.seen = Some((path.to_path_buf(), mtime));
+        changed
+    }
+    /// Forget about modifications of `path` up to now, e.g. because we wrote it ourselves
+    fn mark_seen(&mut self, path: &Path) {
+        if let Ok(mtime) = std::fs::metadata(path).and_then(|meta| meta.modified()) {
+            self.seen = Some((path.to_path_buf(), mtime));
+        }
+    }
+}
+
+/// A project open in a document tab, other than the active one
+///
+/// The active document lives directly in [`App`], and trades places with these on tab switches.
+struct Document {
+    song: SongStateHandle,
+    ui_state: ui::UiState,
+    open_file: Option<PathBuf>,
+    autosave: Autosave,
+    #[cfg(not(target_arch = "wasm32"))]
+    file_watch: FileWatch,
+}
+
+impl Document {
+    /// Document `doc_id` for `song`, which counts as saved if `saved` is true
+    fn new(song: SongState, saved: bool, doc_id: u16) -> Self {
+        let mut autosave = Autosave::new(doc_id);
+        if saved {
+            autosave.set_baseline(&song);
+        }
+        let mut ui_state = ui::UiState::default();
+        ui_state.shared.active_unit = SongState::VOICE_TEST_UNIT_IDX;
+        Self {
+            song: Arc::new(Mutex::new(song)),
+            ui_state,
+            open_file: None,
+            autosave,
+            #[cfg(not(target_arch = "wasm32"))]
+            file_watch: FileWatch::default(),
+        }
+    }
+}
+
 pub struct App {
     pub prefs: Preferences,
     song: SongStateHandle,
@@ -100,14 +175,29 @@ pub struct App {
     pub(crate) cmd: CommandQueue,
     /// If active, we don't try to lock the song Mutex, because it's being used
     song_lock: SongLock,
+    autosave: Autosave,
+    #[cfg(not(target_arch = "wasm32"))]
+    file_watch: FileWatch,
     #[cfg(target_arch = "wasm32")]
     web_cmd: crate::web_glue::WebCmdQueueHandle,
+    /// .wav export in progress, rendered a bit each frame, because the web build can't spawn threads
+    #[cfg(target_arch = "wasm32")]
+    web_wav_render: Option<crate::util::WavRender>,
+    /// Documents in the other tabs, in tab order
+    other_docs: Vec<Document>,
+    /// Tab index of the active document
+    doc_idx: usize,
 }
 
 #[derive(Default)]
 pub struct Preferences {
     pub jp_fallback_font_path: String,
     pub midi_auto_poly_migrate: bool,
+    pub midi_drum_map: crate::midi::DrumMap,
+    /// Reload the opened file when it changes on disk
+    pub watch_open_file: bool,
+    /// Remove redundant events before saving
+    pub compact_on_save: bool,
 }
 
 impl Preferences {
@@ -126,6 +216,18 @@ fn load_persistence(cc: &eframe::CreationContext<'_>, app: &mut App) {
             if let Some(list) = eframe::get_value(storage, "recently-opened") {
                 app.recently_opened = list;
             }
+            if let Some(watch) = eframe::get_value(storage, "watch-open-file") {
+                app.prefs.watch_open_file = watch;
+            }
+        }
+        if let Some(text) = storage.get_string("compact-on-save") {
+            app.prefs.compact_on_save = text == "true";
+        }
+        if let Some(map) = storage
+            .get_string("midi-drum-map")
+            .and_then(|text| crate::midi::DrumMap::from_pref_string(&text))
+        {
+            app.prefs.midi_drum_map = map;
         }
         if let Some(text) = storage.get_string("out-buf-size") {
             if let Ok(num) = text.parse() {
@@ -145,6 +247,8 @@ impl App {
         let sample_rate = 44_100;
         let mut song_state = SongState::new(sample_rate);
         let mut modal = Modal::default();
+        // Look for autosaves before anything can replace them
+        let recovery = autosave::leftover_autosaves();
         if let Some(mid_path) = args.midi_import {
             let mid_data = std::fs::read(&mid_path).unwrap();
             match crate::midi::write_midi_to_pxtone(
@@ -152,6 +256,7 @@ impl App {
                 &mut song_state.herd,
                 &mut song_state.song,
                 &mut song_state.ins,
+                &crate::midi::DrumMap::default(),
             ) {
                 Ok(()) => {
                     song_state.song.recalculate_length();
@@ -182,6 +287,30 @@ impl App {
                 &mut song_state.ins,
             );
         }
+        if let Some(path) = args.tracker_import {
+            let data = std::fs::read(&path).unwrap();
+            if let Err(e) = crate::tracker::Module::parse(&data).and_then(|module| {
+                crate::tracker::import(
+                    &module,
+                    &mut song_state.herd,
+                    &mut song_state.song,
+                    &mut song_state.ins,
+                )
+            }) {
+                modal.err(e);
+            }
+        }
+        if let Some(path) = args.mml_import {
+            let src = std::fs::read_to_string(&path).unwrap();
+            if let Err(e) = crate::mml::import(
+                &src,
+                &mut song_state.herd,
+                &mut song_state.song,
+                &mut song_state.ins,
+            ) {
+                modal.err(e);
+            }
+        }
         if let Some(ptcop_path) = args.voice_import {
             import_voices_from_ptcop(&ptcop_path, &mut song_state);
         }
@@ -197,13 +326,18 @@ impl App {
                 .add_file_filter_extensions(FILT_MIDI.name, FILT_MIDI.exts.into())
                 .add_file_filter_extensions(FILT_PIYOPIYO.name, FILT_PIYOPIYO.exts.into())
                 .add_file_filter_extensions(FILT_ORGANYA.name, FILT_ORGANYA.exts.into())
+                .add_file_filter_extensions(FILT_TRACKER.name, FILT_TRACKER.exts.into())
+                .add_file_filter_extensions(FILT_MML.name, FILT_MML.exts.into())
                 .add_file_filter_extensions(FILT_SF2.name, FILT_SF2.exts.into())
                 .add_file_filter_extensions(FILT_PTVOICE.name, FILT_PTVOICE.exts.into())
                 .add_file_filter_extensions(FILT_PTNOISE.name, FILT_PTNOISE.exts.into())
                 .add_save_extension(FILT_PTCOP.name, FILT_PTCOP.exts[0])
+                .add_save_extension(FILT_PTTUNE.name, FILT_PTTUNE.exts[0])
                 .add_save_extension(FILT_WAV.name, FILT_WAV.exts[0])
                 .add_save_extension(FILT_PTVOICE.name, FILT_PTVOICE.exts[0])
-                .add_save_extension(FILT_PTNOISE.name, FILT_PTNOISE.exts[0]),
+                .add_save_extension(FILT_PTNOISE.name, FILT_PTNOISE.exts[0])
+                .add_save_extension(FILT_MML.name, FILT_MML.exts[0])
+                .add_save_extension(FILT_MUSICXML.name, FILT_MUSICXML.exts[0]),
             #[cfg(not(target_arch = "wasm32"))]
             recently_opened: RecentlyUsedList::default(),
             #[cfg(not(target_arch = "wasm32"))]
@@ -216,8 +350,15 @@ impl App {
             modal,
             cmd: CommandQueue::default(),
             song_lock: SongLock::default(),
+            autosave: Autosave::new(0),
+            #[cfg(not(target_arch = "wasm32"))]
+            file_watch: FileWatch::default(),
+            other_docs: Vec::new(),
+            doc_idx: 0,
             #[cfg(target_arch = "wasm32")]
             web_cmd: Default::default(),
+            #[cfg(target_arch = "wasm32")]
+            web_wav_render: None,
         };
         #[cfg(not(target_arch = "wasm32"))]
         {
@@ -251,13 +392,29 @@ impl App {
         {
             evilscript::exec(cmd, &mut this.song.lock().unwrap());
         }
+        this.autosave.set_baseline(&this.song.lock().unwrap());
+        if !recovery.is_empty() {
+            this.modal.recover_session(
+                recovery
+                    .into_iter()
+                    .map(|(slot, snap)| (slot, snap.origin))
+                    .collect(),
+            );
+        }
         this
     }
 
     fn import_midi_from_bytes(&mut self, mid_data: &[u8]) -> anyhow::Result<()> {
+        self.snapshot_before_replace();
         let mut song = self.song.lock().unwrap();
         let song = &mut *song;
-        crate::midi::write_midi_to_pxtone(mid_data, &mut song.herd, &mut song.song, &mut song.ins)?;
+        crate::midi::write_midi_to_pxtone(
+            mid_data,
+            &mut song.herd,
+            &mut song.song,
+            &mut song.ins,
+            &self.prefs.midi_drum_map,
+        )?;
         song.song.recalculate_length();
         if self.prefs.midi_auto_poly_migrate {
             auto_migrate_all(&mut self.modal, &mut self.ui_state, song);
@@ -268,6 +425,7 @@ impl App {
 
     fn import_piyopiyo_from_bytes(&mut self, data: &[u8]) -> anyhow::Result<()> {
         let piyo = piyopiyo::Song::load(data)?;
+        self.snapshot_before_replace();
         let mut song = self.song.lock().unwrap();
         let song = &mut *song;
         crate::piyopiyo::import(&piyo, &mut song.herd, &mut song.song, &mut song.ins);
@@ -278,12 +436,35 @@ impl App {
     fn import_organya_from_bytes(&mut self, data: &[u8]) -> anyhow::Result<()> {
         let mut org = organyacat::Song::default();
         org.read(data)?;
+        self.snapshot_before_replace();
         let mut song = self.song.lock().unwrap();
         let song = &mut *song;
         crate::organya::import(&org, &mut song.herd, &mut song.song, &mut song.ins);
         post_load_prep(song, &mut self.ui_state.shared.active_unit);
         Ok(())
     }
+
+    fn import_tracker_from_bytes(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let module = crate::tracker::Module::parse(data)?;
+        self.snapshot_before_replace();
+        let mut song = self.song.lock().unwrap();
+        let song = &mut *song;
+        crate::tracker::import(&module, &mut song.herd, &mut song.song, &mut song.ins)?;
+        song.song.recalculate_length();
+        post_load_prep(song, &mut self.ui_state.shared.active_unit);
+        Ok(())
+    }
+
+    fn import_mml_from_bytes(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let src = std::str::from_utf8(data)?;
+        self.snapshot_before_replace();
+        let mut song = self.song.lock().unwrap();
+        let song = &mut *song;
+        crate::mml::import(src, &mut song.herd, &mut song.song, &mut song.ins)?;
+        song.song.recalculate_length();
+        post_load_prep(song, &mut self.ui_state.shared.active_unit);
+        Ok(())
+    }
     #[cfg(not(target_arch = "wasm32"))]
     fn handle_file_dia_update(&mut self, ctx: &egui::Context) -> (Option<PathBuf>, Option<FileOp>) {
         use egui_file_dialog::DialogState;
@@ -336,7 +517,11 @@ impl App {
     }
 
     fn open_file_prompt(&mut self, file_op: FileOp) {
-        let filt = file_op.filt();
+        let mut filt = file_op.filt();
+        // Keep saving in the format of the opened file by default
+        if file_op == FileOp::SaveProjAs && self.open_file_format() == ProjFormat::Tune {
+            filt = FILT_PTTUNE;
+        }
         #[cfg(not(target_arch = "wasm32"))]
         {
             if file_op == FileOp::OpenProj
@@ -359,61 +544,73 @@ impl App {
             use crate::web_glue::WebCmdQueueHandleExt;
             let web_cmd = self.web_cmd.clone();
             if file_op.is_save() {
-                let (data, filename): (Vec<u8>, &str) = match file_op {
+                match file_op {
                     FileOp::SaveProjAs => {
-                        let song = self.song.lock().unwrap();
-                        let data =
-                            ptcow::serialize_project(&song.song, &song.herd, &song.ins).unwrap();
-                        (data, "out.ptcop")
-                    }
-                    FileOp::ExportPtnoise { voice } => {
-                        let song = self.song.lock().unwrap();
-                        let ptcow::VoiceData::Noise(noise) = &song.ins.voices[voice].base.data
-                        else {
-                            return;
+                        let name = match self.open_file_format() {
+                            ProjFormat::Collage => "out.ptcop",
+                            ProjFormat::Tune => "out.pttune",
                         };
-                        (noise.to_ptnoise(), "out.ptnoise")
+                        self.save_project(name.into(), false);
                     }
-                    FileOp::ExportPtvoice { voice } => {
-                        let song = self.song.lock().unwrap();
-                        match song.ins.voices[voice].to_ptvoice() {
-                            Ok(data) => (data, "out.ptvoice"),
-                            Err(e) => {
-                                self.cmd.toast(ToastKind::Error, format!("{e}"), 5.0);
-                                return;
-                            }
+                    FileOp::ExportWav => self.start_web_wav_render(),
+                    _ => {
+                        let result = file_op.export_data(&self.song.lock().unwrap());
+                        match result {
+                            Ok(data) => crate::web_glue::save_file(&data, &file_op.web_file_name()),
+                            Err(e) => self.modal.err(e),
                         }
                     }
-                    FileOp::ExportWavData {
-                        data,
-                        ch_num,
-                        sample_rate,
-                    } => {
-                        use ptcow::ChNum;
-
-                        let mut out = std::io::Cursor::new(Vec::new());
-                        let _ = crate::util::write_wav(
-                            &mut out,
-                            ChNum::Mono,
-                            bytemuck::cast_slice(&data),
-                            sample_rate,
-                        );
-                        (out.into_inner(), "out.wav")
-                    }
-                    _ => return,
-                };
-                crate::web_glue::save_file(&data, filename);
+                }
             } else {
                 wasm_bindgen_futures::spawn_local(async move {
                     let file = crate::web_glue::open_file(&filt.web_filter()).await;
-                    web_cmd.push(crate::web_glue::WebCmd::from_file_op(
-                        file_op, file.data, file.name,
-                    ));
+                    if let Some(cmd) =
+                        crate::web_glue::WebCmd::from_file_op(file_op, file.data, file.name)
+                    {
+                        web_cmd.push(cmd);
+                    }
                 });
             }
         }
     }
 
+    #[cfg(target_arch = "wasm32")]
+    fn start_web_wav_render(&mut self) {
+        // Disable audio device for export duration
+        self.pt_audio_dev = None;
+        self.song_lock.lock("Exporting .wav ...");
+        self.web_wav_render = Some(crate::util::WavRender::new(&mut self.song.lock().unwrap()));
+    }
+
+    /// Advance the web .wav export, and download the result once it's done
+    #[cfg(target_arch = "wasm32")]
+    fn step_web_wav_render(&mut self) {
+        /// Enough to make good progress, while keeping the page responsive
+        const BUFS_PER_FRAME: usize = 16;
+        let Some(mut render) = self.web_wav_render.take() else {
+            return;
+        };
+        let shared = &self.song_lock.shared;
+        let mut song = self.song.lock().unwrap();
+        if shared.cancel_requested.load(Ordering::Relaxed) {
+            *shared.error.write().unwrap() = "Cancelled".into();
+        } else if render.step(&mut song, BUFS_PER_FRAME) {
+            shared.progress.store(
+                crate::util::WavRender::progress(&song).to_bits(),
+                Ordering::Relaxed,
+            );
+            drop(song);
+            self.web_wav_render = Some(render);
+            return;
+        } else {
+            match render.finish(&song) {
+                Ok(data) => crate::web_glue::save_file(&data, "out.wav"),
+                Err(e) => *shared.error.write().unwrap() = e.to_string(),
+            }
+        }
+        shared.can_unlock.store(true, Ordering::Relaxed);
+    }
+
     fn import_ptvoice(&mut self, data: &[u8], path: &Path) {
         match load_and_recalc_voice(data, path, just_load_ptvoice, self.out.rate) {
             Ok(voice) => {
@@ -477,6 +674,9 @@ impl App {
                 let mut song = self.song.lock().unwrap();
                 import_voices_from_ptcop(&path, &mut song);
             }
+            FileOp::MergeProj => {
+                self.modal.merge_project(std::fs::read(&path)?);
+            }
             FileOp::ReplacePtVoiceSingle(voice_idx) => {
                 let data = std::fs::read(&path).unwrap();
                 match load_and_recalc_voice(&data, &path, just_load_ptvoice, self.out.rate) {
@@ -558,19 +758,16 @@ impl App {
                 let data = std::fs::read(&path)?;
                 self.import_organya_from_bytes(&data)?;
             }
+            FileOp::ImportTracker => {
+                let data = std::fs::read(&path)?;
+                self.import_tracker_from_bytes(&data)?;
+            }
+            FileOp::ImportMml => {
+                let data = std::fs::read(&path)?;
+                self.import_mml_from_bytes(&data)?;
+            }
             FileOp::SaveProjAs => {
-                let song = self.song.lock().unwrap();
-                match ptcow::serialize_project(&song.song, &song.herd, &song.ins) {
-                    Ok(bytes) => {
-                        std::fs::write(&path, bytes)?;
-                        self.recently_opened.use_(path.clone());
-                        self.open_file = Some(path);
-                    }
-                    Err(e) => {
-                        self.modal.err(e);
-                    }
-                }
-                drop(song);
+                self.save_project(path, false);
             }
             FileOp::ExportWav => {
                 // Disable audio device for export duration
@@ -598,46 +795,13 @@ impl App {
                     song_lock.can_unlock.store(true, Ordering::Relaxed);
                 });
             }
-            FileOp::ExportWavData {
-                ch_num,
-                data,
-                sample_rate,
-            } => {
-                let f = std::fs::File::create(path).unwrap();
-                match crate::util::write_wav(f, ch_num, bytemuck::cast_slice(&data), sample_rate) {
-                    Ok(()) => (),
-                    Err(e) => {
-                        self.modal.err(e);
-                    }
-                }
-            }
-            FileOp::ExportPtvoice { voice } => {
-                let song = self.song.lock().unwrap();
-                match song.ins.voices[voice].to_ptvoice() {
-                    Ok(data) => {
-                        if let Err(e) = std::fs::write(&path, data) {
-                            self.modal.err(e);
-                        }
-                    }
-                    Err(e) => self.modal.err(e),
-                }
-                self.cmd.toast(
-                    ToastKind::Success,
-                    format_args!("Exported to {}", path.display()),
-                    5.0,
-                );
-            }
-            FileOp::ExportPtnoise { voice } => {
-                use ptcow::VoiceData;
-
-                let song = self.song.lock().unwrap();
-                let voice = &song.ins.voices[voice];
-                let VoiceData::Noise(noise) = &voice.base.data else {
-                    anyhow::bail!("Voice not a noise");
-                };
-                if let Err(e) = std::fs::write(&path, noise.to_ptnoise()) {
-                    self.modal.err(e);
-                }
+            FileOp::ExportMml
+            | FileOp::ExportMusicXml
+            | FileOp::ExportWavData { .. }
+            | FileOp::ExportPtvoice { .. }
+            | FileOp::ExportPtnoise { .. } => {
+                let data = op.export_data(&self.song.lock().unwrap())?;
+                std::fs::write(&path, data)?;
                 self.cmd.toast(
                     ToastKind::Success,
                     format_args!("Exported to {}", path.display()),
@@ -670,6 +834,12 @@ impl App {
                 "org" => {
                     self.import_organya_from_bytes(bytes)?;
                 }
+                "mod" | "s3m" | "xm" => {
+                    self.import_tracker_from_bytes(bytes)?;
+                }
+                "mml" => {
+                    self.import_mml_from_bytes(bytes)?;
+                }
                 _ => {}
             }
             self.open_file = Some(format!("{name}.{ext}").into());
@@ -758,6 +928,8 @@ impl eframe::App for App {
     fn ui(&mut self, ui: &mut egui::Ui, _frame: &mut eframe::Frame) {
         ui.request_repaint();
         if self.song_lock.my.locked {
+            #[cfg(target_arch = "wasm32")]
+            self.step_web_wav_render();
             egui::Modal::new("song_lock".into()).show(ui, |ui| {
                 ui.horizontal(|ui| {
                     ui.label(self.song_lock.my.reason);
@@ -792,6 +964,35 @@ impl eframe::App for App {
             }
             return;
         }
+        let now = ui.input(|inp| inp.time);
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.prefs.watch_open_file
+            && let Some(path) = &self.open_file
+            && self.file_watch.poll(now, path)
+        {
+            if self
+                .autosave
+                .has_unsaved_changes(&self.song.lock().unwrap())
+            {
+                self.modal.confirm_reload(path.clone());
+            } else {
+                self.cmd.push(Cmd::ReloadCurrentFile);
+            }
+        }
+        if let Err(e) =
+            self.autosave
+                .update(now, &self.song.lock().unwrap(), self.open_file.as_deref())
+        {
+            log::warn!("Autosave failed: {e}");
+        }
+        for doc in &mut self.other_docs {
+            if let Err(e) =
+                doc.autosave
+                    .update(now, &doc.song.lock().unwrap(), doc.open_file.as_deref())
+            {
+                log::warn!("Autosave failed: {e}");
+            }
+        }
         // Clean up unused extra freeplay units
         {
             let mut song = self.song.lock().unwrap();
@@ -808,13 +1009,19 @@ impl eframe::App for App {
             });
         }
         egui::Panel::top("top_panel").show_inside(ui, |ui| ui::top_panel::top_panel(self, ui));
+        if self.n_docs() > 1 {
+            egui::Panel::top("doc_tabs").show_inside(ui, |ui| ui::doc_tabs::ui(self, ui));
+        }
         if self.ui_state.show_left_panel() {
             egui::Panel::left("left_panel").show_inside(ui, |ui| ui::left_panel::ui(self, ui));
         }
         egui::CentralPanel::default().show_inside(ui, |ui| ui::central_panel(self, ui));
-        self.ui_state
-            .windows
-            .update(ui, &mut self.song.lock().unwrap(), &mut self.prefs);
+        self.ui_state.windows.update(
+            ui,
+            &mut self.song.lock().unwrap(),
+            &mut self.prefs,
+            &mut self.cmd,
+        );
 
         #[cfg(not(target_arch = "wasm32"))]
         let (mut picked_path, mut file_op) = self.handle_file_dia_update(ui);
@@ -831,6 +1038,8 @@ impl eframe::App for App {
                             "mid" | "midi" => Some(FileOp::ImportMidi),
                             "pmd" => Some(FileOp::ImportPiyoPiyo),
                             "org" => Some(FileOp::ImportOrganya),
+                            "mod" | "s3m" | "xm" => Some(FileOp::ImportTracker),
+                            "mml" => Some(FileOp::ImportMml),
                             _ => None,
                         };
                     }
@@ -850,7 +1059,7 @@ impl eframe::App for App {
                 self.modal.err(e);
             }
         }
-        self.modal.update(ui, &self.song);
+        self.modal.update(ui, &self.song, &mut self.cmd);
         self.ui_state.shared.toasts.show(ui);
         // Do queue commands
         while let Some(cmd) = self.cmd.pop() {
@@ -878,8 +1087,11 @@ impl eframe::App for App {
                 &self.file_dia.storage_mut().pinned_folders,
             );
             eframe::set_value(storage, "recently-opened", &self.recently_opened);
+            eframe::set_value(storage, "watch-open-file", &self.prefs.watch_open_file);
         }
         storage.set_string("out-buf-size", self.out.buf_size.to_string());
+        storage.set_string("compact-on-save", self.prefs.compact_on_save.to_string());
+        storage.set_string("midi-drum-map", self.prefs.midi_drum_map.to_pref_string());
         storage.set_string(
             Preferences::JP_FALLBACK,
             self.prefs.jp_fallback_font_path.clone(),
@@ -898,9 +1110,35 @@ fn import_voices_from_ptcop(path: &Path, song: &mut SongState) {
 }
 
 impl App {
+    /// Keep a snapshot of the project around before it's replaced, in case that was a mistake
+    ///
+    /// Projects without unsaved changes are skipped, as they can be opened again.
+    ///
+    /// INVARIANT: Locks the song
+    fn snapshot_before_replace(&self) {
+        let song = self.song.lock().unwrap();
+        let data = match ptcow::serialize_project(&song.song, &song.herd, &song.ins) {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!("Failed to snapshot project before replacing it: {e}");
+                return;
+            }
+        };
+        if !self.autosave.differs_from_baseline(&data) {
+            return;
+        }
+        if let Err(e) = autosave::write(
+            autosave::Slot::BeforeClear,
+            &data,
+            self.open_file.as_deref(),
+        ) {
+            log::warn!("Failed to snapshot project before replacing it: {e}");
+        }
+    }
     // INVARIANT: Locks the song
     pub fn load_song_from_path(&mut self, mut path: PathBuf) -> anyhow::Result<()> {
         let data = std::fs::read(&path).context("Failed to read file")?;
+        self.snapshot_before_replace();
         self.load_song_from_bytes(&data)?;
         // Canonicalize path, so recently used list works correctly
         if let Ok(canon) = path.canonicalize() {
@@ -920,6 +1158,7 @@ impl App {
         song_ref.herd = herd;
         song_ref.ins = ins;
         post_load_prep(song_ref, &mut self.ui_state.shared.active_unit);
+        self.autosave.set_baseline(song_ref);
         Ok(())
     }
 
@@ -928,6 +1167,7 @@ impl App {
         match cmd {
             Cmd::ReloadCurrentFile => self.reload_current_file(),
             Cmd::SaveCurrentFile => self.save_current_file(),
+            Cmd::SaveProject { path, allow_loss } => self.save_project(path, allow_loss),
             Cmd::OpenEventInEventsTab { index } => {
                 self.ui_state.tab = ui::Tab::Events;
                 self.ui_state.raw_events.go_to = Some(index);
@@ -993,12 +1233,236 @@ impl App {
                 );
             }
             Cmd::ClearProject => {
+                self.snapshot_before_replace();
                 let mut song = self.song.lock().unwrap();
                 *song = SongState::new(self.out.rate);
                 song.prepare();
+                self.autosave.set_baseline(&song);
                 self.open_file = None;
                 self.ui_state.shared.active_unit = SongState::VOICE_TEST_UNIT_IDX;
             }
+            Cmd::MergeProject { data, at_meas } => {
+                if let Err(e) = self.merge_project(&data, at_meas) {
+                    self.modal.err(format!("Error merging project:\n{e}"));
+                }
+            }
+            Cmd::NewDocument => {
+                let mut song = SongState::new(self.out.rate);
+                song.prepare();
+                let doc_id = self.free_doc_id();
+                self.other_docs.push(Document::new(song, true, doc_id));
+                self.switch_document(self.n_docs() - 1);
+            }
+            Cmd::ExtractRange(range) => {
+                let (song, herd, ins) =
+                    crate::transfer::extract_range(&self.song.lock().unwrap(), range);
+                let mut state = SongState::new(self.out.rate);
+                state.song = song;
+                state.herd = herd;
+                state.ins = ins;
+                let doc_id = self.free_doc_id();
+                let mut doc = Document::new(state, false, doc_id);
+                post_load_prep(
+                    &mut doc.song.lock().unwrap(),
+                    &mut doc.ui_state.shared.active_unit,
+                );
+                self.other_docs.push(doc);
+                self.switch_document(self.n_docs() - 1);
+            }
+            Cmd::InsertMeas { at, count } => {
+                self.edit_meas(|song| crate::timing_ops::insert_meas(song, at, count));
+            }
+            Cmd::DeleteMeas(range) => {
+                self.edit_meas(|song| crate::timing_ops::delete_meas(song, range));
+                self.ui_state.map.meas_sel = None;
+            }
+            Cmd::ChangeResolution(ticks_per_beat) => {
+                let report = self
+                    .edit_meas(|song| crate::timing_ops::change_resolution(song, ticks_per_beat));
+                if report.is_lossless() {
+                    self.cmd.toast(
+                        ToastKind::Success,
+                        format_args!("Changed resolution to {ticks_per_beat} ticks per beat"),
+                        3.0,
+                    );
+                } else {
+                    self.cmd.toast(
+                        ToastKind::Warning,
+                        format_args!(
+                            "Changed resolution to {ticks_per_beat} ticks per beat, with rounding:\n\
+                             {} event(s) moved, {} note(s)/slide(s) resized, {} made 1 tick long",
+                            report.moved, report.resized, report.collapsed
+                        ),
+                        10.0,
+                    );
+                }
+            }
+            Cmd::StretchEvents {
+                target,
+                factor,
+                anchor,
+                ripple,
+            } => {
+                self.edit_meas(|song| {
+                    crate::timing_ops::stretch(song, &target, factor, anchor, ripple);
+                });
+            }
+            Cmd::Quantize { target, params } => {
+                let n = self.edit_meas(|song| crate::timing_ops::quantize(song, &target, &params));
+                self.cmd
+                    .toast(ToastKind::Info, format_args!("Quantized {n} note(s)"), 3.0);
+            }
+            Cmd::Humanize { target, params } => {
+                let n = self.edit_meas(|song| crate::timing_ops::humanize(song, &target, &params));
+                self.cmd
+                    .toast(ToastKind::Info, format_args!("Humanized {n} note(s)"), 3.0);
+            }
+            Cmd::Transpose { target, params } => {
+                let mut song = self.song.lock().unwrap();
+                let song = &mut *song;
+                let n =
+                    crate::pitch_ops::transpose(&mut song.song, &song.ins.voices, &target, &params);
+                self.ui_state.events_reindexed();
+                self.cmd
+                    .toast(ToastKind::Info, format_args!("Transposed {n} note(s)"), 3.0);
+            }
+            Cmd::PolySpread(unit) => {
+                let mut song = self.song.lock().unwrap();
+                poly_spread(&mut self.modal, &mut song, unit);
+                self.ui_state.events_reindexed();
+            }
+            Cmd::Arpeggiate {
+                unit,
+                source,
+                arp,
+                replace,
+            } => {
+                let mut song = self.song.lock().unwrap();
+                if song.herd.units.get(unit).is_none() {
+                    self.modal
+                        .err(format!("Can't write arpeggio: there is no unit {}", unit.0));
+                    return;
+                }
+                let chords = match source.chords(&song.song) {
+                    Ok(chords) => chords,
+                    Err(e) => {
+                        self.modal.err(e);
+                        return;
+                    }
+                };
+                let notes = arp.notes(&chords, song.song.master.timing.ticks_per_beat);
+                let n = crate::arp::write_notes(&mut song.song, unit, &notes, &source, replace);
+                self.ui_state.events_reindexed();
+                self.cmd
+                    .toast(ToastKind::Info, format_args!("Wrote {n} note(s)"), 3.0);
+            }
+            Cmd::WriteStepRow { grid, row, steps } => {
+                let mut song = self.song.lock().unwrap();
+                crate::step_seq::write_row(&mut song.song, grid, row, &steps);
+                self.ui_state.events_reindexed();
+            }
+            Cmd::RepeatStepRows { grid, rows, times } => {
+                let mut song = self.song.lock().unwrap();
+                crate::step_seq::repeat_rows(&mut song.song, grid, &rows, times);
+                self.ui_state.events_reindexed();
+            }
+            Cmd::EventsReindexed => self.ui_state.events_reindexed(),
+            Cmd::SwitchDocument(idx) => self.switch_document(idx),
+            Cmd::CloseDocument { idx, force } => self.close_document(idx, force),
+            Cmd::CopyUnitsToDocument { units, doc } => {
+                let title = self.doc_title(doc);
+                let src_handle = self.song.clone();
+                let Some((dst_handle, dst_ui)) = self.other_doc_song(doc) else {
+                    return;
+                };
+                let src = src_handle.lock().unwrap();
+                let mut dst = dst_handle.lock().unwrap();
+                match crate::transfer::copy_units(&src, &units, &mut dst) {
+                    Ok(new) => {
+                        post_load_prep(&mut dst, &mut dst_ui.shared.active_unit);
+                        dst_ui.events_reindexed();
+                        self.cmd.toast(
+                            ToastKind::Success,
+                            format_args!("Copied {} unit(s) to {title}", new.len()),
+                            3.0,
+                        );
+                    }
+                    Err(e) => self.modal.err(e),
+                }
+            }
+            Cmd::CopyEventsToDocument { events, doc } => {
+                let title = self.doc_title(doc);
+                let src_handle = self.song.clone();
+                let Some((dst_handle, dst_ui)) = self.other_doc_song(doc) else {
+                    return;
+                };
+                let src = src_handle.lock().unwrap();
+                let mut dst = dst_handle.lock().unwrap();
+                match crate::transfer::copy_events(&src, &events, &mut dst) {
+                    Ok(n) => {
+                        post_load_prep(&mut dst, &mut dst_ui.shared.active_unit);
+                        dst_ui.events_reindexed();
+                        self.cmd.toast(
+                            ToastKind::Success,
+                            format_args!("Copied {n} event(s) to {title}"),
+                            3.0,
+                        );
+                    }
+                    Err(e) => self.modal.err(e),
+                }
+            }
+            Cmd::CopyVoiceToDocument { voice, doc } => {
+                let title = self.doc_title(doc);
+                let src_handle = self.song.clone();
+                let Some((dst_handle, _)) = self.other_doc_song(doc) else {
+                    return;
+                };
+                let src = src_handle.lock().unwrap();
+                let mut dst = dst_handle.lock().unwrap();
+                let dst = &mut *dst;
+                match crate::transfer::copy_voice(&src.ins, voice, &mut dst.ins) {
+                    Ok(_) => {
+                        ptcow::rebuild_tones(
+                            &mut dst.ins,
+                            &mut dst.herd.delays,
+                            &mut dst.herd.overdrives,
+                            &dst.song.master,
+                        );
+                        self.cmd.toast(
+                            ToastKind::Success,
+                            format_args!("Copied voice to {title}"),
+                            3.0,
+                        );
+                    }
+                    Err(e) => self.modal.err(e),
+                }
+            }
+            Cmd::RecoverSnapshot(slot) => match autosave::read(slot) {
+                Some(snap) => match self.load_song_from_bytes(&snap.data) {
+                    Ok(()) => {
+                        // Keep the snapshot in the slot of this document, so the next autosave
+                        // replaces it instead of leaving it around to recover again
+                        let own_slot = self.autosave.slot();
+                        if matches!(slot, autosave::Slot::Autosave(_)) && slot != own_slot {
+                            match autosave::write(own_slot, &snap.data, snap.origin.as_deref()) {
+                                Ok(()) => autosave::clear(slot),
+                                Err(e) => log::warn!("Failed to move recovery snapshot: {e}"),
+                            }
+                        }
+                        self.open_file = snap.origin;
+                        // The recovered project differs from what's on disk
+                        self.autosave.clear_baseline();
+                        self.cmd.toast(ToastKind::Info, "Recovered project", 3.0);
+                    }
+                    Err(e) => {
+                        self.modal.err(format!("Error loading snapshot:\n{e}"));
+                    }
+                },
+                None => {
+                    self.cmd
+                        .toast(ToastKind::Error, "No snapshot to recover", 5.0);
+                }
+            },
             Cmd::OpenPtcopFromPath { path } => {
                 #[cfg(not(target_arch = "wasm32"))]
                 if let Err(e) = self.load_song_from_path(path) {
@@ -1038,6 +1502,7 @@ impl App {
         use crate::web_glue::WebCmd;
         match cmd {
             WebCmd::OpenFile { data, name } => {
+                self.snapshot_before_replace();
                 if let Err(e) = self.load_song_from_bytes(&data) {
                     self.cmd.toast(ToastKind::Error, format!("{e}"), 5.0);
                 }
@@ -1052,6 +1517,12 @@ impl App {
             WebCmd::ImportOrganya { data } => {
                 self.import_organya_from_bytes(&data);
             }
+            WebCmd::ImportTracker { data } => {
+                self.import_tracker_from_bytes(&data);
+            }
+            WebCmd::ImportMml { data } => {
+                self.import_mml_from_bytes(&data);
+            }
             WebCmd::ImportPtVoice { data, name } => {
                 self.import_ptvoice(&data, name.as_ref());
             }
@@ -1061,6 +1532,9 @@ impl App {
             WebCmd::ImportOggVorbis { data, name } => {
                 self.import_ogg_vorbis(&data, name.as_ref());
             }
+            WebCmd::MergeProj { data } => {
+                self.modal.merge_project(data);
+            }
             WebCmd::ImportAllPtcop { data } => {
                 let (_, _, ins) = ptcow::read_song(&data, 44_100).unwrap();
                 let mut song = self.song.lock().unwrap();
@@ -1125,10 +1599,181 @@ impl App {
             },
         }
     }
+    // INVARIANT: Locks the song
+    fn merge_project(&mut self, data: &[u8], at_meas: Option<u32>) -> anyhow::Result<()> {
+        let (src_song, src_herd, src_ins) = ptcow::read_song(data, self.out.rate)?;
+        let mut song = self.song.lock().unwrap();
+        let at_meas = at_meas.unwrap_or_else(|| crate::transfer::song_end_meas(&song.song));
+        let report =
+            crate::transfer::merge_project(&src_song, &src_herd, &src_ins, &mut song, at_meas);
+        post_load_prep(&mut song, &mut self.ui_state.shared.active_unit);
+        self.ui_state.events_reindexed();
+        let problems = report.problems();
+        if problems.is_empty() {
+            self.cmd.toast(
+                ToastKind::Success,
+                format_args!(
+                    "Merged {} unit(s), {} voice(s) ({} already present), {} effect(s)",
+                    report.units_added,
+                    report.voices_added + report.voices_deduped,
+                    report.voices_deduped,
+                    report.effects_added
+                ),
+                5.0,
+            );
+        } else {
+            self.modal.err(format!(
+                "Merged {} unit(s), with problems:\n{}",
+                report.units_added,
+                problems.join("\n")
+            ));
+        }
+        Ok(())
+    }
+    /// Apply an edit that moves events around in time, and bring everything else up to date
+    fn edit_meas<R>(&mut self, edit: impl FnOnce(&mut ptcow::Song) -> R) -> R {
+        let mut song = self.song.lock().unwrap();
+        let out = edit(&mut song.song);
+        // The edit might have changed the resolution, so let ptcow reconfigure the timing
+        let song = &mut *song;
+        let last_played_sample = song.herd.smp_count;
+        ptcow::moo_prepare(
+            &mut song.ins,
+            &mut song.herd,
+            &song.song,
+            &ptcow::MooPlan {
+                start_pos: ptcow::StartPosPlan::Sample(last_played_sample),
+                meas_end: None,
+                meas_repeat: None,
+                loop_: true,
+            },
+            std::slice::from_ref(&song.preview_voice),
+        );
+        crate::pxtone_misc::reset_loop_points(song);
+        let smp_count = song.herd.smp_count.min(song.herd.smp_end);
+        song.herd.seek_to_sample(smp_count);
+        self.ui_state.events_reindexed();
+        out
+    }
+    pub(crate) fn n_docs(&self) -> usize {
+        self.other_docs.len() + 1
+    }
+    /// Lowest document id that no open document has
+    fn free_doc_id(&self) -> u16 {
+        let taken: Vec<u16> = std::iter::once(&self.autosave)
+            .chain(self.other_docs.iter().map(|doc| &doc.autosave))
+            .map(Autosave::doc_id)
+            .collect();
+        (0..).find(|id| !taken.contains(id)).unwrap_or_default()
+    }
+    /// Index into `other_docs` of the document at tab `idx`, which can't be the active one
+    fn other_doc_idx(&self, idx: usize) -> usize {
+        idx - usize::from(idx > self.doc_idx)
+    }
+    /// Title of the document at tab `idx`
+    pub(crate) fn doc_title(&self, idx: usize) -> String {
+        let open_file = if idx == self.doc_idx {
+            &self.open_file
+        } else {
+            &self.other_docs[self.other_doc_idx(idx)].open_file
+        };
+        match open_file.as_deref().and_then(Path::file_name) {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => "Untitled".into(),
+        }
+    }
+    /// Tab indices and titles of the documents that aren't active
+    pub(crate) fn other_doc_titles(&self) -> Vec<(usize, String)> {
+        (0..self.n_docs())
+            .filter(|&idx| idx != self.doc_idx)
+            .map(|idx| (idx, self.doc_title(idx)))
+            .collect()
+    }
+    fn swap_active_doc(&mut self, doc: &mut Document) {
+        std::mem::swap(&mut self.song, &mut doc.song);
+        std::mem::swap(&mut self.ui_state, &mut doc.ui_state);
+        std::mem::swap(&mut self.open_file, &mut doc.open_file);
+        std::mem::swap(&mut self.autosave, &mut doc.autosave);
+        #[cfg(not(target_arch = "wasm32"))]
+        std::mem::swap(&mut self.file_watch, &mut doc.file_watch);
+    }
+    fn switch_document(&mut self, idx: usize) {
+        if idx == self.doc_idx || idx >= self.n_docs() {
+            return;
+        }
+        // The audio thread only plays the active document
+        self.song.lock().unwrap().pause = true;
+        let mut doc = self.other_docs.remove(self.other_doc_idx(idx));
+        self.swap_active_doc(&mut doc);
+        self.other_docs
+            .insert(self.doc_idx - usize::from(self.doc_idx > idx), doc);
+        self.doc_idx = idx;
+        self.cmd.push(Cmd::ReplaceAudioThread);
+    }
+    fn doc_has_unsaved_changes(&self, idx: usize) -> bool {
+        if idx == self.doc_idx {
+            self.autosave
+                .has_unsaved_changes(&self.song.lock().unwrap())
+        } else {
+            let doc = &self.other_docs[self.other_doc_idx(idx)];
+            doc.autosave.has_unsaved_changes(&doc.song.lock().unwrap())
+        }
+    }
+    fn close_document(&mut self, idx: usize, force: bool) {
+        if self.n_docs() == 1 || idx >= self.n_docs() {
+            return;
+        }
+        if !force && self.doc_has_unsaved_changes(idx) {
+            self.modal.confirm_close_document(idx, self.doc_title(idx));
+            return;
+        }
+        if idx == self.doc_idx {
+            self.switch_document(if idx == 0 { 1 } else { idx - 1 });
+        }
+        let doc = self.other_docs.remove(self.other_doc_idx(idx));
+        // Closing is the user's call, so there's nothing to recover
+        autosave::clear(doc.autosave.slot());
+        if idx < self.doc_idx {
+            self.doc_idx -= 1;
+        }
+    }
+    /// Song of the document at tab `idx`, along with its ui state, if it's not the active one
+    fn other_doc_song(&mut self, idx: usize) -> Option<(SongStateHandle, &mut ui::UiState)> {
+        if idx == self.doc_idx || idx >= self.n_docs() {
+            return None;
+        }
+        let other_idx = self.other_doc_idx(idx);
+        let doc = &mut self.other_docs[other_idx];
+        Some((doc.song.clone(), &mut doc.ui_state))
+    }
+    /// Reload the opened file, keeping the playback position and the ui state
     fn reload_current_file(&mut self) {
+        let (smp_count, n_units) = {
+            let song = self.song.lock().unwrap();
+            (song.herd.smp_count, song.herd.units.len())
+        };
         match self.open_file.clone() {
             Some(path) => match self.load_song_from_path(path.clone()) {
                 Ok(()) => {
+                    let mut song = self.song.lock().unwrap();
+                    song.herd.seek_to_sample(smp_count.min(song.herd.smp_end));
+                    // Units that are gone can't stay hidden or active
+                    let new_n_units = song.herd.units.len();
+                    drop(song);
+                    self.ui_state
+                        .piano_roll
+                        .hidden_units
+                        .retain(|idx| idx.0 < new_n_units);
+                    self.ui_state
+                        .map
+                        .hidden_units
+                        .retain(|idx| *idx < new_n_units);
+                    let active = self.ui_state.shared.active_unit;
+                    if active.0 < n_units && active.0 >= new_n_units {
+                        self.ui_state.shared.active_unit = SongState::VOICE_TEST_UNIT_IDX;
+                    }
+                    #[cfg(not(target_arch = "wasm32"))]
+                    self.file_watch.mark_seen(&path);
                     self.cmd
                         .toast(ToastKind::Info, format!("Reloaded {}", path.display()), 3.0);
                 }
@@ -1142,22 +1787,66 @@ impl App {
         }
     }
     fn save_current_file(&mut self) {
-        if let Some(path) = &self.open_file {
-            let song = self.song.lock().unwrap();
-            match ptcow::serialize_project(&song.song, &song.herd, &song.ins) {
-                Ok(out) => {
-                    std::fs::write(path, out).unwrap();
-                    self.cmd.toast(
-                        ToastKind::Info,
-                        format_args!("Saved {}", path.display()),
-                        3.0,
-                    );
-                }
-                Err(e) => {
-                    self.modal.err(format_args!("Error saving: {e}"));
-                }
+        if let Some(path) = self.open_file.clone() {
+            self.save_project(path, false);
+        }
+    }
+    /// Format of the currently opened file, which is also the format plain saves use
+    fn open_file_format(&self) -> ProjFormat {
+        self.open_file
+            .as_deref()
+            .map_or(ProjFormat::Collage, ProjFormat::from_path)
+    }
+    /// Save the project, in the format given by the extension of `path`
+    ///
+    /// Unless `allow_loss` is set, the user is asked for confirmation first
+    /// if saving as a tune would lose data.
+    fn save_project(&mut self, path: PathBuf, allow_loss: bool) {
+        let format = ProjFormat::from_path(&path);
+        let mut song = self.song.lock().unwrap();
+        if format == ProjFormat::Tune && !allow_loss {
+            let losses = crate::pttune::tune_losses(&song.song, &song.herd, &song.ins);
+            if !losses.is_empty() {
+                self.modal.confirm_lossy_save(path, losses);
+                return;
+            }
+        }
+        if self.prefs.compact_on_save {
+            let compaction = crate::compact::compact(&mut song.song);
+            if compaction.events_removed != 0 {
+                self.cmd.toast(ToastKind::Info, compaction, 5.0);
+                self.ui_state.events_reindexed();
             }
         }
+        let data = match crate::pttune::serialize_project(&song.song, &song.herd, &song.ins, format)
+        {
+            Ok(data) => data,
+            Err(e) => {
+                self.modal.err(format_args!("Error saving: {e}"));
+                return;
+            }
+        };
+        drop(song);
+        #[cfg(target_arch = "wasm32")]
+        crate::web_glue::save_file(&data, &path.to_string_lossy());
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Err(e) = std::fs::write(&path, data) {
+            self.modal.err(format_args!("Error saving: {e}"));
+            return;
+        }
+        self.autosave.set_baseline(&self.song.lock().unwrap());
+        autosave::clear(self.autosave.slot());
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.file_watch.mark_seen(&path);
+            self.cmd.toast(
+                ToastKind::Info,
+                format_args!("Saved {}", path.display()),
+                3.0,
+            );
+            self.recently_opened.use_(path.clone());
+            self.open_file = Some(path);
+        }
     }
     /// Replace already running ptcow audio thread with a new one
     ///
@@ -1228,6 +1917,31 @@ fn do_tick0_events(song: &mut SongState) {
     }
 }
 
+/// Move overlapping notes of `unit` to its poly units, and their overlapping notes to theirs
+///
+/// Unlike [`poly_migrate_single`], poly units that already exist (named like "unit-p") are reused.
+fn poly_spread(app_modal: &mut Modal, song: &mut SongState, mut unit: UnitIdx) {
+    loop {
+        let poly_name = format!("{}-p", song.herd.units[unit].name);
+        let poly_unit = song
+            .herd
+            .units
+            .enumerated()
+            .find_map(|(idx, poly)| (poly.name == poly_name).then_some(idx));
+        let next = match poly_unit {
+            Some(poly_unit) => {
+                poly_migrate_units(unit, poly_unit, &mut song.song.events).then_some(poly_unit)
+            }
+            None => poly_migrate_single(app_modal, song, unit),
+        };
+        match next {
+            Some(next) => unit = next,
+            None => break,
+        }
+    }
+    song.song.events.sort();
+}
+
 fn poly_migrate_single(
     app_modal: &mut Modal,
     song: &mut SongState,
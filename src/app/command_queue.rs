@@ -16,6 +16,12 @@ pub enum Cmd {
     // Replace the current audio thread (should be called after the audio output is reconfigured)
     ReplaceAudioThread,
     SaveCurrentFile,
+    /// Save the project to `path`, in the format given by its extension
+    SaveProject {
+        path: std::path::PathBuf,
+        /// Don't ask before dropping data the format can't store
+        allow_loss: bool,
+    },
     OpenVoice(ptcow::VoiceIdx),
     OverwriteEvent {
         idx: usize,
@@ -45,6 +51,92 @@ pub enum Cmd {
         idx: VoiceIdx,
     },
     FilePrompt(FileOp),
+    /// Load the project from a recovery snapshot
+    RecoverSnapshot(crate::autosave::Slot),
+    /// Merge a serialized project into the song
+    MergeProject {
+        data: Vec<u8>,
+        /// Measure to merge at, or `None` for the end of the song
+        at_meas: Option<u32>,
+    },
+    /// Open a new document tab with an empty project
+    NewDocument,
+    /// Open a range of the song as a project of its own in a new document tab
+    ExtractRange(crate::pxtone_misc::MeasRange),
+    /// Insert `count` empty measures before measure `at`
+    InsertMeas {
+        at: u32,
+        count: u32,
+    },
+    /// Delete a range of measures, moving everything after it back
+    DeleteMeas(crate::pxtone_misc::MeasRange),
+    /// Change the ticks per beat of the song, rescaling events to fit
+    ChangeResolution(u16),
+    /// Scale the timing of events by `factor`, see [`crate::timing_ops::stretch`]
+    StretchEvents {
+        target: crate::timing_ops::EventTarget,
+        factor: f64,
+        /// Tick that stays in place, or `None` for the start of the target
+        anchor: Option<u32>,
+        ripple: bool,
+    },
+    /// Snap notes to a grid
+    Quantize {
+        target: crate::timing_ops::EventTarget,
+        params: crate::timing_ops::Quantize,
+    },
+    /// Randomize the timing and velocity of notes a bit
+    Humanize {
+        target: crate::timing_ops::EventTarget,
+        params: crate::timing_ops::Humanize,
+    },
+    /// Move notes up or down, chromatically or within a scale
+    Transpose {
+        target: crate::timing_ops::EventTarget,
+        params: crate::pitch_ops::Transpose,
+    },
+    /// Spread overlapping notes of a unit across its poly units
+    PolySpread(ptcow::UnitIdx),
+    /// Write an arpeggio of the chords from `source` onto `unit`
+    Arpeggiate {
+        unit: ptcow::UnitIdx,
+        source: crate::arp::ArpSource,
+        arp: crate::arp::Arpeggio,
+        /// Remove the notes of `source`
+        replace: bool,
+    },
+    /// Write the steps of a drum row, see [`crate::step_seq::write_row`]
+    WriteStepRow {
+        grid: crate::step_seq::StepGrid,
+        row: crate::step_seq::StepRow,
+        steps: Vec<Option<i16>>,
+    },
+    /// Copy the steps of drum rows to the grids after them
+    RepeatStepRows {
+        grid: crate::step_seq::StepGrid,
+        rows: Vec<crate::step_seq::StepRow>,
+        times: u32,
+    },
+    /// Events were added or removed in place, so event indices are no longer valid
+    EventsReindexed,
+    SwitchDocument(usize),
+    CloseDocument {
+        idx: usize,
+        /// Close even if there are unsaved changes
+        force: bool,
+    },
+    CopyUnitsToDocument {
+        units: Vec<ptcow::UnitIdx>,
+        doc: usize,
+    },
+    CopyVoiceToDocument {
+        voice: VoiceIdx,
+        doc: usize,
+    },
+    CopyEventsToDocument {
+        events: Vec<usize>,
+        doc: usize,
+    },
 }
 
 impl Cmd {
@@ -54,14 +146,20 @@ impl Cmd {
             Self::ReloadCurrentFile => Some(Self::ReloadCurrentFile),
             Self::SaveCurrentFile => Some(Self::SaveCurrentFile),
             Self::ClearProject => Some(Self::ClearProject),
+            Self::NewDocument => Some(Self::NewDocument),
             Self::FilePrompt(op) => {
                 match op {
                     FileOp::OpenProj
                     | FileOp::ImportAllPtcop
+                    | FileOp::MergeProj
                     | FileOp::ImportMidi
                     | FileOp::SaveProjAs
                     | FileOp::ImportPiyoPiyo
                     | FileOp::ImportOrganya
+                    | FileOp::ImportTracker
+                    | FileOp::ImportMml
+                    | FileOp::ExportMml
+                    | FileOp::ExportMusicXml
                     | FileOp::ExportWav
                     | FileOp::ReplacePtVoiceSingle(..)
                     | FileOp::ReplacePtNoiseSingle(..)
@@ -95,9 +193,31 @@ impl Cmd {
             Cmd::OverwriteEvent { .. } => "ovewrite event",
             Cmd::InsertEvent { .. } => "insert event",
             Cmd::FilePrompt(file_op) => file_op.cmd_label(),
+            Cmd::NewDocument => "new document",
             // These are not user-facing commands
             Cmd::ClearProject
             | Cmd::OpenPtcopFromPath { .. }
+            | Cmd::SaveProject { .. }
+            | Cmd::RecoverSnapshot(..)
+            | Cmd::MergeProject { .. }
+            | Cmd::ExtractRange(..)
+            | Cmd::InsertMeas { .. }
+            | Cmd::DeleteMeas(..)
+            | Cmd::ChangeResolution(..)
+            | Cmd::StretchEvents { .. }
+            | Cmd::Quantize { .. }
+            | Cmd::Humanize { .. }
+            | Cmd::Transpose { .. }
+            | Cmd::PolySpread(..)
+            | Cmd::Arpeggiate { .. }
+            | Cmd::WriteStepRow { .. }
+            | Cmd::RepeatStepRows { .. }
+            | Cmd::EventsReindexed
+            | Cmd::SwitchDocument(..)
+            | Cmd::CloseDocument { .. }
+            | Cmd::CopyUnitsToDocument { .. }
+            | Cmd::CopyVoiceToDocument { .. }
+            | Cmd::CopyEventsToDocument { .. }
             | Cmd::ResetUnitVoice { .. }
             | Cmd::Modal(..)
             | Cmd::ResetVoiceForUnitsWithVoiceIdx { .. }
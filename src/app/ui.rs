@@ -1,3 +1,4 @@
+mod doc_tabs;
 pub mod file_ops;
 mod img;
 pub mod left_panel;
@@ -7,20 +8,29 @@ mod unit;
 pub mod windows;
 
 use {
-    crate::app::{
-        SongState,
-        ui::{
-            left_panel::LeftPanelState,
-            tabs::{
-                effects::EffectsUiState,
-                events::RawEventsUiState,
-                map::MapState,
-                piano_roll::PianoRollState,
-                voices::{SelectedSlot, VoicesUiState},
+    crate::{
+        app::{
+            SongState,
+            command_queue::{Cmd, CommandQueue},
+            ui::{
+                left_panel::LeftPanelState,
+                tabs::{
+                    drums::DrumsState,
+                    effects::EffectsUiState,
+                    events::RawEventsUiState,
+                    map::MapState,
+                    piano_roll::PianoRollState,
+                    tracker::TrackerState,
+                    voices::{SelectedSlot, VoicesUiState},
+                },
+                unit::{unit_color, unit_voice_img},
+                windows::Windows,
             },
-            unit::{unit_color, unit_voice_img},
-            windows::Windows,
         },
+        herd_ext::HerdExt,
+        pitch_ops::Transpose,
+        pxtone_misc::{KEY_NAMES, MeasRange, Scale},
+        timing_ops::{EventTarget, Humanize, Quantize},
     },
     eframe::egui,
     egui_style_editor::StyleEditor,
@@ -30,11 +40,13 @@ use {
 };
 
 pub mod tabs {
+    pub mod drums;
     pub mod effects;
     pub mod events;
     pub mod map;
     pub mod piano_roll;
     pub mod playback;
+    pub mod tracker;
     pub mod unit;
     pub mod voices;
 }
@@ -281,6 +293,33 @@ fn piano_freeplay_play_note(
     state.last_played_key = key;
 }
 
+/// Play a note on a copy of `unit` in the freeplay assist units, leaving the unit itself alone
+fn freeplay_assist_play_note(song: &mut SongState, unit: UnitIdx, key: ptcow::Key, duration: u32) {
+    // Same cap as in `piano_freeplay_play_note`
+    if song.freeplay_assist_units.len() > 200 {
+        return;
+    }
+    let Some(unit) = song.herd.units.get(unit) else {
+        return;
+    };
+    let mut assist = unit.clone();
+    assist.name = "Preview".into();
+    assist.set_key(key);
+    let unit_no =
+        UnitIdx(SongState::EXTRA_UNITS_START_IDX.0 + song.freeplay_assist_units.len() as u8);
+    assist.on(
+        unit_no,
+        &song.ins,
+        &[],
+        0,
+        duration,
+        0,
+        song.herd.smp_end,
+        std::slice::from_ref(&song.preview_voice),
+    );
+    song.freeplay_assist_units.push(assist);
+}
+
 #[derive(Default)]
 pub struct UiState {
     pub tab: Tab,
@@ -289,6 +328,8 @@ pub struct UiState {
     pub raw_events: RawEventsUiState,
     pub voices: VoicesUiState,
     pub effects: EffectsUiState,
+    pub drums: DrumsState,
+    pub tracker: TrackerState,
     pub shared: SharedUiState,
     pub windows: Windows,
     pub left: LeftPanelState,
@@ -326,6 +367,11 @@ impl UiState {
     pub fn show_left_panel(&self) -> bool {
         !matches!(self.tab, Tab::Playback)
     }
+    /// Forget the event indices kept around, after events were added or removed
+    pub fn events_reindexed(&mut self) {
+        self.piano_roll.selected_event_indices.clear();
+        self.raw_events.filter_needs_recalc = true;
+    }
 }
 
 #[derive(Default, PartialEq, Clone, Copy)]
@@ -338,6 +384,8 @@ pub enum Tab {
     Unit,
     Effects,
     Events,
+    Drums,
+    Tracker,
 }
 
 pub fn central_panel(app: &mut super::App, ui: &mut egui::Ui) {
@@ -348,6 +396,7 @@ pub fn central_panel(app: &mut super::App, ui: &mut egui::Ui) {
     #[cfg(target_arch = "wasm32")]
     let file_dia_open = false;
     let [k_space, m_ctrl] = ui.input(|inp| [inp.key_pressed(egui::Key::Space), inp.modifiers.ctrl]);
+    let other_docs = app.other_doc_titles();
     let mut song = app.song.lock().unwrap();
     if k_space {
         if m_ctrl {
@@ -383,6 +432,7 @@ pub fn central_panel(app: &mut super::App, ui: &mut egui::Ui) {
                 &mut app.ui_state.piano_roll,
                 &mut app.ui_state.shared,
                 &mut app.cmd,
+                &other_docs,
             );
         }
         Tab::Events => tabs::events::ui(
@@ -399,6 +449,7 @@ pub fn central_panel(app: &mut super::App, ui: &mut egui::Ui) {
             &mut app.ui_state.voices,
             &mut app.ui_state.shared,
             &mut app.cmd,
+            &other_docs,
             #[cfg(not(target_arch = "wasm32"))]
             &mut app.file_dia,
         ),
@@ -415,6 +466,8 @@ pub fn central_panel(app: &mut super::App, ui: &mut egui::Ui) {
             &mut app.ui_state.effects,
             &mut app.ui_state.shared,
         ),
+        Tab::Drums => tabs::drums::ui(ui, &song, &mut app.ui_state.drums, &mut app.cmd),
+        Tab::Tracker => tabs::tracker::ui(ui, &mut song, &mut app.ui_state.tracker, &mut app.cmd),
     }
     drop(song);
 }
@@ -675,3 +728,321 @@ fn group_idx_slider(ui: &mut egui::Ui, group_idx: &mut GroupIdx) -> egui::Respon
     ui.style_mut().spacing.slider_width = 140.0;
     ui.add(egui::Slider::new(&mut group_idx.0, 0..=GroupIdx::MAX.0))
 }
+
+const RULER_HEIGHT: f32 = 20.0;
+
+/// Measure ruler along the top of the visible part of a roll occupying `rect`
+///
+/// Clicking a measure seeks to it, right clicking opens a menu for inserting and deleting measures.
+/// It covers the top of the roll, so it's only shown along with the measure lines.
+fn meas_ruler(
+    ui: &mut egui::Ui,
+    song: &mut SongState,
+    rect: egui::Rect,
+    tick_div: f32,
+    sel: Option<MeasRange>,
+    cmd: &mut CommandQueue,
+) {
+    let cr = ui.clip_rect();
+    let strip = egui::Rect::from_min_max(cr.min, egui::pos2(cr.max.x, cr.min.y + RULER_HEIGHT));
+    let pnt = ui.painter_at(strip);
+    pnt.rect_filled(strip, 0.0, egui::Color32::from_black_alpha(200));
+    let timing = song.song.master.timing;
+    let meas_ticks = ptcow::timing::meas_to_tick(1, timing).max(1);
+    let meas_at = |x: f32| ((x - rect.min.x).max(0.0) * tick_div) as u32 / meas_ticks;
+    for meas in meas_at(cr.min.x)..=meas_at(cr.max.x) {
+        let x = ptcow::timing::meas_to_tick(meas, timing) as f32 / tick_div + rect.min.x;
+        pnt.line_segment(
+            [egui::pos2(x, strip.min.y), egui::pos2(x, strip.max.y)],
+            egui::Stroke::new(1.0, egui::Color32::LIGHT_GRAY),
+        );
+        pnt.text(
+            egui::pos2(x + 2.0, strip.min.y),
+            egui::Align2::LEFT_TOP,
+            meas,
+            egui::FontId::proportional(16.0),
+            egui::Color32::LIGHT_YELLOW,
+        );
+    }
+    let re = ui
+        .interact(strip, ui.id().with("meas_ruler"), egui::Sense::click())
+        .on_hover_text("Click to seek, right click for measure operations");
+    let menu_meas_id = ui.id().with("meas_ruler_menu_meas");
+    if let Some(pos) = re.interact_pointer_pos() {
+        let meas = meas_at(pos.x);
+        if re.clicked() {
+            song.herd.seek_to_meas(meas, &song.song, &song.ins);
+        }
+        if re.secondary_clicked() {
+            ui.data_mut(|data| data.insert_temp(menu_meas_id, meas));
+        }
+    }
+    re.context_menu(|ui| {
+        let meas = ui.data(|data| data.get_temp(menu_meas_id)).unwrap_or(0);
+        meas_menu_ui(ui, meas, sel, cmd);
+    });
+}
+
+fn meas_menu_ui(ui: &mut egui::Ui, meas: u32, sel: Option<MeasRange>, cmd: &mut CommandQueue) {
+    ui.strong(format!("Measure {meas}"));
+    let count_id = egui::Id::new("insert_meas_count");
+    let mut count = ui.data(|data| data.get_temp(count_id)).unwrap_or(1);
+    ui.horizontal(|ui| {
+        ui.add(egui::DragValue::new(&mut count).range(1..=999));
+        if ui.button("Insert measures before").clicked() {
+            cmd.push(Cmd::InsertMeas { at: meas, count });
+            ui.close();
+        }
+    });
+    ui.data_mut(|data| data.insert_temp(count_id, count));
+    if ui.button("Delete measure").clicked() {
+        cmd.push(Cmd::DeleteMeas(MeasRange {
+            start: meas,
+            end: meas + 1,
+        }));
+        ui.close();
+    }
+    if let Some(sel) = sel
+        && !sel.is_empty()
+        && ui
+            .button(format!(
+                "Delete selected measures ({}..{})",
+                sel.start, sel.end
+            ))
+            .clicked()
+    {
+        cmd.push(Cmd::DeleteMeas(sel));
+        ui.close();
+    }
+}
+
+/// Settings for stretching the timing of events, kept between uses
+pub struct StretchParams {
+    factor: f64,
+    from_playhead: bool,
+    ripple: bool,
+}
+
+impl Default for StretchParams {
+    fn default() -> Self {
+        Self {
+            factor: 2.0,
+            from_playhead: false,
+            ripple: false,
+        }
+    }
+}
+
+fn stretch_ui(
+    ui: &mut egui::Ui,
+    song: &SongState,
+    params: &mut StretchParams,
+    target: impl FnOnce() -> EventTarget,
+    cmd: &mut CommandQueue,
+) {
+    ui.horizontal(|ui| {
+        ui.label("Stretch");
+        ui.add(
+            egui::DragValue::new(&mut params.factor)
+                .range(0.01..=100.0)
+                .speed(0.01)
+                .prefix("×"),
+        );
+        if ui.button("×2").clicked() {
+            params.factor = 2.0;
+        }
+        if ui.button("×0.5").clicked() {
+            params.factor = 0.5;
+        }
+    });
+    ui.checkbox(&mut params.from_playhead, "Anchor at playhead")
+        .on_hover_text("Otherwise the start of the selection stays in place");
+    ui.checkbox(&mut params.ripple, "Ripple")
+        .on_hover_text("Move later events along with the end of the selection");
+    if ui.button("↔ Apply stretch").clicked() {
+        let clock = ptcow::current_tick(&song.herd, &song.ins);
+        cmd.push(Cmd::StretchEvents {
+            target: target(),
+            factor: params.factor,
+            anchor: params.from_playhead.then_some(clock),
+            ripple: params.ripple,
+        });
+    }
+}
+
+/// Quantize settings, shared by every place that offers quantizing
+fn quantize_ui(ui: &mut egui::Ui, target: impl FnOnce() -> EventTarget, cmd: &mut CommandQueue) {
+    let id = egui::Id::new("quantize_params");
+    let mut q: Quantize = ui.data(|data| data.get_temp(id)).unwrap_or_default();
+    egui::Grid::new("quantize_grid").show(ui, |ui| {
+        ui.label("Grid");
+        egui::ComboBox::from_id_salt("quantize_division")
+            .selected_text(format!("1/{} beat", q.division))
+            .show_ui(ui, |ui| {
+                for division in [1, 2, 3, 4, 6, 8, 16] {
+                    ui.selectable_value(&mut q.division, division, format!("1/{division} beat"));
+                }
+            });
+        ui.end_row();
+        ui.label("Tuplet");
+        let tuplets = [
+            ((1, 1), "Straight"),
+            ((3, 2), "Triplets"),
+            ((5, 4), "Quintuplets"),
+            ((7, 4), "Septuplets"),
+        ];
+        egui::ComboBox::from_id_salt("quantize_tuplet")
+            .selected_text(
+                tuplets
+                    .iter()
+                    .find(|(tuplet, _)| *tuplet == q.tuplet)
+                    .map_or("Custom", |(_, name)| *name),
+            )
+            .show_ui(ui, |ui| {
+                for (tuplet, name) in tuplets {
+                    ui.selectable_value(&mut q.tuplet, tuplet, name);
+                }
+            });
+        ui.end_row();
+        ui.label("Quantize");
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut q.start, "Start");
+            ui.checkbox(&mut q.length, "Length");
+        });
+        ui.end_row();
+        ui.label("Strength");
+        let mut strength = q.strength * 100.0;
+        ui.add(egui::Slider::new(&mut strength, 0.0..=100.0).suffix("%"));
+        q.strength = strength / 100.0;
+        ui.end_row();
+        ui.label("Swing");
+        let mut swing = q.swing * 100.0;
+        ui.add(egui::Slider::new(&mut swing, 0.0..=100.0).suffix("%"))
+            .on_hover_text("Delay every other grid line by up to half a step");
+        q.swing = swing / 100.0;
+        ui.end_row();
+    });
+    if ui
+        .add_enabled(q.start || q.length, egui::Button::new("⌗ Apply quantize"))
+        .clicked()
+    {
+        cmd.push(Cmd::Quantize {
+            target: target(),
+            params: q.clone(),
+        });
+    }
+    ui.data_mut(|data| data.insert_temp(id, q));
+}
+
+/// Humanize settings, shared by every place that offers humanizing
+fn humanize_ui(ui: &mut egui::Ui, target: impl FnOnce() -> EventTarget, cmd: &mut CommandQueue) {
+    let id = egui::Id::new("humanize_params");
+    let mut h: Humanize = ui.data(|data| data.get_temp(id)).unwrap_or_default();
+    egui::Grid::new("humanize_grid").show(ui, |ui| {
+        ui.label("Timing ±");
+        ui.add(egui::DragValue::new(&mut h.timing).suffix(" ticks"));
+        ui.end_row();
+        ui.label("Length ±");
+        ui.add(egui::DragValue::new(&mut h.duration).suffix(" ticks"));
+        ui.end_row();
+        ui.label("Velocity ±");
+        ui.add(egui::DragValue::new(&mut h.velocity).range(0..=128));
+        ui.end_row();
+        ui.label("Seed");
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut h.seed));
+            if ui.button("🎲").on_hover_text("Next seed").clicked() {
+                h.seed = h.seed.wrapping_add(1);
+            }
+        });
+        ui.end_row();
+    });
+    if ui.button("🎲 Apply humanize").clicked() {
+        cmd.push(Cmd::Humanize {
+            target: target(),
+            params: h.clone(),
+        });
+    }
+    ui.data_mut(|data| data.insert_temp(id, h));
+}
+
+fn scale_combo(ui: &mut egui::Ui, id_salt: &str, scale: &mut Scale, root: u8) {
+    egui::ComboBox::from_id_salt(id_salt)
+        .selected_text(scale.name())
+        .show_ui(ui, |ui| {
+            for s in Scale::ALL {
+                ui.selectable_value(scale, s, s.name());
+            }
+            let is_custom = matches!(scale, Scale::Custom(_));
+            if ui.selectable_label(is_custom, "Custom").clicked() && !is_custom {
+                *scale = Scale::Custom(scale.mask());
+            }
+        });
+    if let Scale::Custom(mask) = scale {
+        // The root is always in the scale, so only the rest can be toggled
+        for i in 1..12 {
+            let mut on = *mask & (1 << i) != 0;
+            let name = KEY_NAMES[usize::from((root + i) % 12)];
+            if ui.toggle_value(&mut on, name).changed() {
+                *mask ^= 1 << i;
+            }
+        }
+    }
+}
+
+fn root_combo(ui: &mut egui::Ui, id_salt: &str, root: &mut u8) {
+    egui::ComboBox::from_id_salt(id_salt)
+        .selected_text(KEY_NAMES[usize::from(*root % 12)])
+        .show_ui(ui, |ui| {
+            for (pc, name) in (0..).zip(KEY_NAMES) {
+                ui.selectable_value(root, pc, name);
+            }
+        });
+}
+
+/// Transpose settings, shared by every place that offers transposing
+fn transpose_ui(ui: &mut egui::Ui, target: impl FnOnce() -> EventTarget, cmd: &mut CommandQueue) {
+    let id = egui::Id::new("transpose_params");
+    let mut t: Transpose = ui.data(|data| data.get_temp(id)).unwrap_or_default();
+    egui::Grid::new("transpose_grid").show(ui, |ui| {
+        ui.label("Semitones");
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut t.semitones).range(-96..=96));
+            if ui.button("-12").clicked() {
+                t.semitones -= 12;
+            }
+            if ui.button("+12").clicked() {
+                t.semitones += 12;
+            }
+        });
+        ui.end_row();
+        ui.label("Scale");
+        ui.horizontal(|ui| {
+            root_combo(ui, "transpose_root", &mut t.root);
+            scale_combo(ui, "transpose_scale", &mut t.scale, t.root);
+        });
+        ui.end_row();
+        ui.label("Scale degrees")
+            .on_hover_text("Move notes along the scale, keeping them in key");
+        ui.add(egui::DragValue::new(&mut t.degrees).range(-28..=28));
+        ui.end_row();
+        ui.label("Change mode to")
+            .on_hover_text("Map each degree of the scale onto the same degree of this one");
+        ui.horizontal(|ui| {
+            scale_combo(ui, "transpose_to_scale", &mut t.to_scale, t.root);
+        });
+        ui.end_row();
+    });
+    ui.horizontal(|ui| {
+        if ui.button("♯ Apply transpose").clicked() {
+            cmd.push(Cmd::Transpose {
+                target: target(),
+                params: t.clone(),
+            });
+        }
+        if ui.button("Reset").clicked() {
+            t = Transpose::default();
+        }
+    });
+    ui.data_mut(|data| data.insert_temp(id, t));
+}
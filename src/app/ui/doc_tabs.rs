@@ -0,0 +1,23 @@
+use {
+    crate::app::{App, command_queue::Cmd},
+    eframe::egui,
+};
+
+/// Tab bar for switching between the open documents
+pub fn ui(app: &mut App, ui: &mut egui::Ui) {
+    ui.horizontal_wrapped(|ui| {
+        for idx in 0..app.n_docs() {
+            let title = app.doc_title(idx);
+            if ui.selectable_label(idx == app.doc_idx, title).clicked() {
+                app.cmd.push(Cmd::SwitchDocument(idx));
+            }
+            if ui.small_button("×").on_hover_text("Close").clicked() {
+                app.cmd.push(Cmd::CloseDocument { idx, force: false });
+            }
+            ui.separator();
+        }
+        if ui.button("➕").on_hover_text("New tab").clicked() {
+            app.cmd.push(Cmd::NewDocument);
+        }
+    });
+}
@@ -1,13 +1,21 @@
-use ptcow::{ChNum, SourceSampleRate, VoiceIdx};
+use {
+    crate::audio_out::SongState,
+    ptcow::{ChNum, SourceSampleRate, VoiceIdx},
+};
 
 #[derive(Clone, PartialEq, Eq)]
 pub enum FileOp {
     OpenProj,
     ImportAllPtcop,
+    MergeProj,
     ImportMidi,
     SaveProjAs,
     ImportPiyoPiyo,
     ImportOrganya,
+    ImportTracker,
+    ImportMml,
+    ExportMml,
+    ExportMusicXml,
     ExportWav,
     ReplacePtVoiceSingle(VoiceIdx),
     ReplacePtNoiseSingle(VoiceIdx),
@@ -34,9 +42,12 @@ impl FileOp {
         match self {
             FileOp::OpenProj
             | FileOp::ImportAllPtcop
+            | FileOp::MergeProj
             | FileOp::ImportMidi
             | FileOp::ImportPiyoPiyo
             | FileOp::ImportOrganya
+            | FileOp::ImportTracker
+            | FileOp::ImportMml
             | FileOp::ReplacePtVoiceSingle(..)
             | FileOp::ReplacePtNoiseSingle(..)
             | FileOp::ReplaceWavSingle(..)
@@ -44,6 +55,8 @@ impl FileOp {
             | FileOp::ImportPtVoice
             | FileOp::ImportOggVorbis => false,
             FileOp::SaveProjAs
+            | FileOp::ExportMml
+            | FileOp::ExportMusicXml
             | FileOp::ExportWav
             | FileOp::ExportPtvoice { .. }
             | FileOp::ExportPtnoise { .. }
@@ -53,9 +66,14 @@ impl FileOp {
     pub fn filt(&self) -> FileFilt {
         match self {
             FileOp::ImportMidi => FILT_MIDI,
-            FileOp::OpenProj | FileOp::ImportAllPtcop | FileOp::SaveProjAs => FILT_PTCOP,
+            FileOp::OpenProj | FileOp::ImportAllPtcop | FileOp::MergeProj | FileOp::SaveProjAs => {
+                FILT_PTCOP
+            }
             FileOp::ImportPiyoPiyo => FILT_PIYOPIYO,
             FileOp::ImportOrganya => FILT_ORGANYA,
+            FileOp::ImportTracker => FILT_TRACKER,
+            FileOp::ImportMml | FileOp::ExportMml => FILT_MML,
+            FileOp::ExportMusicXml => FILT_MUSICXML,
             FileOp::ExportWav | FileOp::ReplaceWavSingle(..) | FileOp::ExportWavData { .. } => {
                 FILT_WAV
             }
@@ -73,10 +91,15 @@ impl FileOp {
         match self {
             FileOp::OpenProj => "open project",
             FileOp::ImportAllPtcop => "import voices from ptcop",
+            FileOp::MergeProj => "merge project",
             FileOp::ImportMidi => "import midi",
             FileOp::SaveProjAs => "save project as",
             FileOp::ImportPiyoPiyo => "import PiyoPiyo",
             FileOp::ImportOrganya => "import Organya",
+            FileOp::ImportTracker => "import tracker module",
+            FileOp::ImportMml => "import MML",
+            FileOp::ExportMml => "export MML",
+            FileOp::ExportMusicXml => "export MusicXML",
             FileOp::ExportWav => "export .wav",
             FileOp::ReplacePtVoiceSingle(..) => "replace voice with .ptvoice",
             FileOp::ReplacePtNoiseSingle(..) => "replace voice with .ptnoise",
@@ -90,6 +113,52 @@ impl FileOp {
             FileOp::ExportWavData { .. } => "",
         }
     }
+    /// Contents of the file written by an export that can be done in one go
+    ///
+    /// Project saves and .wav renders have their own handling, and are rejected here.
+    pub fn export_data(&self, song: &SongState) -> anyhow::Result<Vec<u8>> {
+        match self {
+            FileOp::ExportMml => Ok(crate::mml::export(&song.song, &song.herd).into_bytes()),
+            FileOp::ExportMusicXml => {
+                Ok(crate::musicxml::export(&song.song, &song.herd).into_bytes())
+            }
+            FileOp::ExportPtvoice { voice } => {
+                let Some(voice) = song.ins.voices.get(*voice, &[]) else {
+                    anyhow::bail!("No voice at index {}", voice.0);
+                };
+                Ok(voice.to_ptvoice()?)
+            }
+            FileOp::ExportPtnoise { voice } => {
+                let Some(voice) = song.ins.voices.get(*voice, &[]) else {
+                    anyhow::bail!("No voice at index {}", voice.0);
+                };
+                let ptcow::VoiceData::Noise(noise) = &voice.base.data else {
+                    anyhow::bail!("Voice not a noise");
+                };
+                Ok(noise.to_ptnoise())
+            }
+            FileOp::ExportWavData {
+                data,
+                ch_num,
+                sample_rate,
+            } => {
+                let mut out = std::io::Cursor::new(Vec::new());
+                crate::util::write_wav(
+                    &mut out,
+                    *ch_num,
+                    bytemuck::cast_slice(data),
+                    *sample_rate,
+                )?;
+                Ok(out.into_inner())
+            }
+            _ => anyhow::bail!("Not a one-shot export: {}", self.cmd_label()),
+        }
+    }
+    /// File name offered for downloads in the web build
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) fn web_file_name(&self) -> String {
+        format!("out.{}", self.filt().exts[0])
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -122,12 +191,51 @@ macro_rules! file_filts {
 
 file_filts! {
     FILT_PTCOP, "PxTone song", "ptcop", "pttune";
+    FILT_PTTUNE, "PxTone tune", "pttune";
     FILT_MIDI, "Midi file", "mid";
     FILT_PIYOPIYO, "PiyoPiyo file", "pmd";
     FILT_ORGANYA, "Organya file", "org";
+    FILT_TRACKER, "Tracker module", "mod", "s3m", "xm";
+    FILT_MML, "MML file", "mml";
+    FILT_MUSICXML, "MusicXML file", "musicxml";
     FILT_WAV, "WAVE file", "wav";
     FILT_OGG, "Ogg/Vorbis file", "ogg";
     FILT_SF2, "SoundFont2 file", "sf2";
     FILT_PTVOICE, "PxTone voice file", "ptvoice";
     FILT_PTNOISE, "PxTone noise file", "ptnoise";
 }
+
+#[test]
+fn test_export_data() {
+    let mut song = SongState::new(44_100);
+    song.ins
+        .voices
+        .push(crate::pxtone_misc::square_wave_voice());
+    // Voice 0 is the default noise voice, voice 1 the square wave we just added
+    let mml = FileOp::ExportMml.export_data(&song).unwrap();
+    assert!(std::str::from_utf8(&mml).is_ok());
+    let xml = FileOp::ExportMusicXml.export_data(&song).unwrap();
+    assert!(xml.starts_with(b"<?xml"));
+    let noise = FileOp::ExportPtnoise { voice: VoiceIdx(0) }
+        .export_data(&song)
+        .unwrap();
+    assert!(ptcow::NoiseData::from_ptnoise(&noise).is_ok());
+    assert!(
+        FileOp::ExportPtnoise { voice: VoiceIdx(1) }
+            .export_data(&song)
+            .is_err()
+    );
+    let voice = FileOp::ExportPtvoice { voice: VoiceIdx(1) }
+        .export_data(&song)
+        .unwrap();
+    assert!(ptcow::Voice::from_ptvoice(&voice).is_ok());
+    let wav = FileOp::ExportWavData {
+        data: vec![0; 64],
+        ch_num: ChNum::Mono,
+        sample_rate: 44_100,
+    }
+    .export_data(&song)
+    .unwrap();
+    assert_eq!(&wav[..4], b"RIFF");
+    assert!(FileOp::ExportWav.export_data(&song).is_err());
+}
@@ -2,7 +2,7 @@ use {
     crate::{
         app::{
             App,
-            command_queue::CommandQueue,
+            command_queue::{Cmd, CommandQueue},
             ui::{
                 UiState,
                 unit::{
@@ -89,6 +89,7 @@ pub fn ui(app: &mut App, ui: &mut egui::Ui) {
     handle_units_command(cmd, song, &mut app.modal, &mut app.ui_state.shared);
     ui.checkbox(&mut app.ui_state.left.select_mode, "Select mode");
     if !app.ui_state.left.selected_units.is_empty() {
+        let other_docs = app.other_doc_titles();
         let button = MenuButton::new("Actions").config(
             MenuConfig::new().close_behavior(egui::PopupCloseBehavior::CloseOnClickOutside),
         );
@@ -108,6 +109,18 @@ pub fn ui(app: &mut App, ui: &mut egui::Ui) {
                     }
                 }
             });
+            if !other_docs.is_empty() {
+                ui.menu_button("Copy to document", |ui| {
+                    for (doc, title) in other_docs {
+                        if ui.button(title).clicked() {
+                            let mut units: Vec<UnitIdx> =
+                                app.ui_state.left.selected_units.iter().copied().collect();
+                            units.sort_by_key(|idx| idx.0);
+                            app.cmd.push(Cmd::CopyUnitsToDocument { units, doc });
+                        }
+                    }
+                });
+            }
         });
     }
     unit_mute_unmute_all_ui(ui, &mut song.herd.units);
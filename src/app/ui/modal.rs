@@ -1,6 +1,14 @@
 use {
-    crate::{app::ui::tabs::voices::SelectedSlot, audio_out::SongStateHandle},
+    crate::{
+        app::{
+            command_queue::{Cmd, CommandQueue},
+            ui::tabs::voices::SelectedSlot,
+        },
+        audio_out::SongStateHandle,
+        autosave,
+    },
     eframe::egui,
+    std::path::PathBuf,
 };
 
 #[derive(Default)]
@@ -27,7 +35,27 @@ impl Modal {
             with,
         });
     }
-    pub fn update(&mut self, ctx: &egui::Context, song: &SongStateHandle) {
+    /// Offer to recover the autosaves in `snapshots`, with the files they came from
+    pub(crate) fn recover_session(&mut self, snapshots: Vec<(autosave::Slot, Option<PathBuf>)>) {
+        self.payload = Some(Payload::RecoverSession { snapshots });
+    }
+    pub(crate) fn merge_project(&mut self, data: Vec<u8>) {
+        self.payload = Some(Payload::MergeProject {
+            data,
+            at_end: true,
+            meas: 0,
+        });
+    }
+    pub(crate) fn confirm_close_document(&mut self, idx: usize, title: String) {
+        self.payload = Some(Payload::ConfirmCloseDocument { idx, title });
+    }
+    pub(crate) fn confirm_reload(&mut self, path: PathBuf) {
+        self.payload = Some(Payload::ConfirmReload { path });
+    }
+    pub(crate) fn confirm_lossy_save(&mut self, path: PathBuf, losses: Vec<String>) {
+        self.payload = Some(Payload::ConfirmLossySave { path, losses });
+    }
+    pub fn update(&mut self, ctx: &egui::Context, song: &SongStateHandle, cmd: &mut CommandQueue) {
         if let Some(payload) = &mut self.payload {
             let mut close = false;
             egui::Modal::new("modal_popup".into()).show(ctx, |ui| match payload {
@@ -83,6 +111,105 @@ impl Modal {
                         }
                     });
                 }
+                Payload::RecoverSession { snapshots } => {
+                    ui.heading("Recover unsaved session?");
+                    ui.label("There are unsaved projects from a previous session:");
+                    for (_, origin) in snapshots.iter() {
+                        match origin {
+                            Some(path) => ui.label(format!("• Changes to {}", path.display())),
+                            None => ui.label("• An untitled project"),
+                        };
+                    }
+                    ui.horizontal(|ui| {
+                        if ui.button("Recover").clicked() {
+                            // The first one replaces the current project, the rest get new tabs
+                            for (i, &(slot, _)) in snapshots.iter().enumerate() {
+                                if i != 0 {
+                                    cmd.push(Cmd::NewDocument);
+                                }
+                                cmd.push(Cmd::RecoverSnapshot(slot));
+                            }
+                            close = true;
+                        }
+                        if ui.button("Discard").clicked() {
+                            for &(slot, _) in snapshots.iter() {
+                                autosave::clear(slot);
+                            }
+                            close = true;
+                        }
+                    });
+                }
+                Payload::MergeProject { data, at_end, meas } => {
+                    ui.heading("Merge project");
+                    ui.radio_value(at_end, true, "At the end of the song");
+                    ui.horizontal(|ui| {
+                        ui.radio_value(at_end, false, "At measure");
+                        ui.add_enabled(!*at_end, egui::DragValue::new(meas));
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.button("Merge").clicked() {
+                            cmd.push(Cmd::MergeProject {
+                                data: std::mem::take(data),
+                                at_meas: (!*at_end).then_some(*meas),
+                            });
+                            close = true;
+                        }
+                        if ui.button("Cancel").clicked() {
+                            close = true;
+                        }
+                    });
+                }
+                Payload::ConfirmCloseDocument { idx, title } => {
+                    ui.heading("Close document?");
+                    ui.label(format!("{title} has unsaved changes."));
+                    ui.horizontal(|ui| {
+                        if ui.button("Close and lose changes").clicked() {
+                            cmd.push(Cmd::CloseDocument {
+                                idx: *idx,
+                                force: true,
+                            });
+                            close = true;
+                        }
+                        if ui.button("Cancel").clicked() {
+                            close = true;
+                        }
+                    });
+                }
+                Payload::ConfirmReload { path } => {
+                    ui.heading("File changed on disk");
+                    ui.label(format!(
+                        "{} was modified, but there are unsaved local edits.",
+                        path.display()
+                    ));
+                    ui.horizontal(|ui| {
+                        if ui.button("Reload and lose local edits").clicked() {
+                            cmd.push(Cmd::ReloadCurrentFile);
+                            close = true;
+                        }
+                        if ui.button("Keep local edits").clicked() {
+                            close = true;
+                        }
+                    });
+                }
+                Payload::ConfirmLossySave { path, losses } => {
+                    ui.heading("Save as tune?");
+                    ui.label("The following will not be saved in the tune format:");
+                    for loss in losses.iter() {
+                        ui.label(format!("• {loss}"));
+                    }
+                    ui.horizontal(|ui| {
+                        if ui.button("Save anyway").clicked() {
+                            cmd.push(Cmd::SaveProject {
+                                path: std::mem::take(path),
+                                allow_loss: true,
+                            });
+                            close = true;
+                        }
+                        if ui.button("Cancel").clicked() {
+                            close = true;
+                        }
+                    });
+                }
             });
             if close {
                 self.payload = None;
@@ -111,4 +238,24 @@ enum Payload {
         slot: SelectedSlot,
         with: ptcow::WaveDataPoints,
     },
+    RecoverSession {
+        snapshots: Vec<(autosave::Slot, Option<PathBuf>)>,
+    },
+    ConfirmReload {
+        path: PathBuf,
+    },
+    ConfirmCloseDocument {
+        idx: usize,
+        title: String,
+    },
+    MergeProject {
+        data: Vec<u8>,
+        /// Merge at the end of the song instead of at `meas`
+        at_end: bool,
+        meas: u32,
+    },
+    ConfirmLossySave {
+        path: PathBuf,
+        losses: Vec<String>,
+    },
 }
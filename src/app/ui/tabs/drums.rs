@@ -0,0 +1,339 @@
+//! UI code for the Drums tab, a step sequencer
+
+use {
+    crate::{
+        app::{
+            command_queue::{Cmd, CommandQueue},
+            ui::{tabs::events::unit_rich_text, unit_color},
+        },
+        audio_out::SongState,
+        pxtone_misc::KeyInfo,
+        step_seq::{StepGrid, StepRow, euclid, read_row},
+    },
+    eframe::egui,
+};
+
+pub struct DrumsState {
+    grid: StepGrid,
+    rows: Vec<StepRow>,
+    /// How many times "Repeat" copies the grid after itself
+    repeat_times: u32,
+    euclid: Euclid,
+}
+
+impl Default for DrumsState {
+    fn default() -> Self {
+        Self {
+            grid: StepGrid {
+                start_meas: 0,
+                meas_count: 1,
+                steps_per_meas: 16,
+            },
+            rows: Vec::new(),
+            repeat_times: 1,
+            euclid: Euclid {
+                hits: 4,
+                steps: 16,
+                rotation: 0,
+            },
+        }
+    }
+}
+
+/// Settings for filling a row with a euclidean rhythm
+struct Euclid {
+    hits: u8,
+    steps: u8,
+    rotation: u8,
+}
+
+const CELL_SIZE: f32 = 22.0;
+const NEW_HIT_VELOCITY: i16 = 104;
+
+enum RowCmd {
+    Remove(usize),
+    MoveUp(usize),
+}
+
+pub fn ui(ui: &mut egui::Ui, song: &SongState, state: &mut DrumsState, cmd: &mut CommandQueue) {
+    top_ui(ui, song, state, cmd);
+    ui.separator();
+    if state.rows.is_empty() {
+        ui.label("Add rows for the units to sequence with \"+ Row\"");
+        return;
+    }
+    let timing = song.song.master.timing;
+    let clock = ptcow::current_tick(&song.herd, &song.ins);
+    let playing_step = state.grid.step_at(clock, timing);
+    let mut row_cmd = None;
+    egui::ScrollArea::both().auto_shrink(false).show(ui, |ui| {
+        egui::Grid::new("drum_grid").show(ui, |ui| {
+            for (row_idx, row) in state.rows.iter_mut().enumerate() {
+                let name = song
+                    .herd
+                    .units
+                    .get(row.unit)
+                    .map_or("(no unit)", |unit| &unit.name);
+                let re = ui.label(unit_rich_text(row.unit, name));
+                re.context_menu(|ui| {
+                    row_menu_ui(
+                        ui,
+                        song,
+                        state.grid,
+                        row,
+                        row_idx,
+                        &mut state.euclid,
+                        &mut row_cmd,
+                        cmd,
+                    );
+                });
+                match row.key {
+                    Some(key) => {
+                        let info = KeyInfo::from_semitone((key / 256).clamp(0, 255) as u8);
+                        ui.label(format!("{}{}", info.notation(), info.octave));
+                    }
+                    None => {
+                        ui.label("");
+                    }
+                }
+                let mut steps = read_row(&song.song, state.grid, *row);
+                if steps_ui(
+                    ui,
+                    state.grid,
+                    &mut steps,
+                    unit_color(row.unit),
+                    playing_step,
+                ) {
+                    cmd.push(Cmd::WriteStepRow {
+                        grid: state.grid,
+                        row: *row,
+                        steps,
+                    });
+                }
+                ui.menu_button("⋯", |ui| {
+                    row_menu_ui(
+                        ui,
+                        song,
+                        state.grid,
+                        row,
+                        row_idx,
+                        &mut state.euclid,
+                        &mut row_cmd,
+                        cmd,
+                    );
+                });
+                ui.end_row();
+            }
+        });
+        ui.separator();
+        ui.label("Click a step to toggle it, drag up/down with rmb to change its velocity");
+    });
+    match row_cmd {
+        Some(RowCmd::Remove(idx)) => {
+            state.rows.remove(idx);
+        }
+        Some(RowCmd::MoveUp(idx)) => state.rows.swap(idx, idx - 1),
+        None => {}
+    }
+}
+
+fn top_ui(ui: &mut egui::Ui, song: &SongState, state: &mut DrumsState, cmd: &mut CommandQueue) {
+    ui.horizontal(|ui| {
+        ui.label("Measure");
+        ui.add(egui::DragValue::new(&mut state.grid.start_meas));
+        ui.label("Length");
+        ui.add(
+            egui::DragValue::new(&mut state.grid.meas_count)
+                .range(1..=16)
+                .suffix(" meas"),
+        );
+        ui.label("Steps");
+        ui.add(
+            egui::DragValue::new(&mut state.grid.steps_per_meas)
+                .range(1..=64)
+                .suffix(" per meas"),
+        );
+        ui.separator();
+        ui.add(
+            egui::DragValue::new(&mut state.repeat_times)
+                .range(1..=999)
+                .prefix("× "),
+        );
+        if ui
+            .button("🔁 Repeat")
+            .on_hover_text("Copy the steps of every row to the measures that follow")
+            .clicked()
+        {
+            cmd.push(Cmd::RepeatStepRows {
+                grid: state.grid,
+                rows: state.rows.clone(),
+                times: state.repeat_times,
+            });
+        }
+        ui.separator();
+        ui.menu_button("+ Row", |ui| {
+            if ui.button("All units").clicked() {
+                for (unit, _) in song.herd.units.enumerated() {
+                    let row = StepRow { unit, key: None };
+                    if !state.rows.contains(&row) {
+                        state.rows.push(row);
+                    }
+                }
+            }
+            ui.separator();
+            for (unit, unit_ref) in song.herd.units.enumerated() {
+                if ui.button(unit_rich_text(unit, &unit_ref.name)).clicked() {
+                    state.rows.push(StepRow { unit, key: None });
+                }
+            }
+        });
+    });
+}
+
+/// Returns whether `steps` changed
+fn steps_ui(
+    ui: &mut egui::Ui,
+    grid: StepGrid,
+    steps: &mut [Option<i16>],
+    color: egui::Color32,
+    playing_step: Option<usize>,
+) -> bool {
+    let mut changed = false;
+    let steps_per_meas = usize::from(grid.steps_per_meas);
+    // Group steps by quarter of a measure, which is a beat in 4/4
+    let group = (steps_per_meas / 4).max(1);
+    ui.horizontal(|ui| {
+        ui.spacing_mut().item_spacing.x = 2.0;
+        for (i, step) in steps.iter_mut().enumerate() {
+            let (rect, re) = ui.allocate_exact_size(
+                egui::vec2(CELL_SIZE, CELL_SIZE),
+                egui::Sense::click_and_drag(),
+            );
+            let bg = if playing_step == Some(i) {
+                egui::Color32::from_gray(110)
+            } else if i % steps_per_meas == 0 {
+                egui::Color32::from_gray(70)
+            } else if i % group == 0 {
+                egui::Color32::from_gray(55)
+            } else {
+                egui::Color32::from_gray(35)
+            };
+            let pnt = ui.painter();
+            pnt.rect_filled(rect, 2.0, bg);
+            if let Some(vel) = *step {
+                // The fill shows the velocity
+                let height = rect.height() * (f32::from(vel) / 128.0).clamp(0.1, 1.0);
+                let fill =
+                    egui::Rect::from_min_max(egui::pos2(rect.min.x, rect.max.y - height), rect.max);
+                pnt.rect_filled(fill, 2.0, color);
+            }
+            if re.clicked() {
+                *step = match step {
+                    Some(_) => None,
+                    None => Some(NEW_HIT_VELOCITY),
+                };
+                changed = true;
+            }
+            if let Some(vel) = step
+                && re.dragged_by(egui::PointerButton::Secondary)
+            {
+                let new = (f32::from(*vel) - re.drag_delta().y).clamp(0.0, 128.0) as i16;
+                changed |= new != *vel;
+                *vel = new;
+            }
+            if let Some(vel) = *step {
+                re.on_hover_text(format!("Step {}, velocity {vel}", i + 1));
+            }
+        }
+    });
+    changed
+}
+
+fn row_menu_ui(
+    ui: &mut egui::Ui,
+    song: &SongState,
+    grid: StepGrid,
+    row: &mut StepRow,
+    row_idx: usize,
+    euclid_params: &mut Euclid,
+    row_cmd: &mut Option<RowCmd>,
+    cmd: &mut CommandQueue,
+) {
+    ui.horizontal(|ui| {
+        let mut only_key = row.key.is_some();
+        if ui
+            .checkbox(&mut only_key, "Only key")
+            .on_hover_text("Only sequence the notes of this key, so a unit can have a row per key")
+            .changed()
+        {
+            row.key = only_key.then_some(ptcow::DEFAULT_KEY);
+        }
+        if let Some(key) = &mut row.key {
+            let mut semitone = *key / 256;
+            ui.add(
+                egui::DragValue::new(&mut semitone)
+                    .range(0..=255)
+                    .custom_formatter(|val, _| {
+                        let info = KeyInfo::from_semitone(val as u8);
+                        format!("{}{}", info.notation(), info.octave)
+                    }),
+            );
+            *key = semitone * 256;
+        }
+    });
+    ui.separator();
+    ui.horizontal(|ui| {
+        let Euclid {
+            hits,
+            steps,
+            rotation,
+        } = euclid_params;
+        ui.add(egui::DragValue::new(steps).range(1..=64).prefix("Steps "));
+        ui.add(egui::DragValue::new(hits).range(0..=*steps).prefix("Hits "));
+        ui.add(
+            egui::DragValue::new(rotation)
+                .range(0..=*steps - 1)
+                .prefix("Rotation "),
+        );
+    });
+    if ui
+        .button("Euclidean fill")
+        .on_hover_text("Spread the hits as evenly as possible, repeating across the row")
+        .clicked()
+    {
+        let pattern = euclid(
+            usize::from(euclid_params.hits),
+            usize::from(euclid_params.steps),
+            usize::from(euclid_params.rotation),
+        );
+        let old = read_row(&song.song, grid, *row);
+        let new: Vec<_> = old
+            .iter()
+            .zip(pattern.iter().cycle())
+            .map(|(old, &hit)| hit.then(|| old.unwrap_or(NEW_HIT_VELOCITY)))
+            .collect();
+        cmd.push(Cmd::WriteStepRow {
+            grid,
+            row: *row,
+            steps: new,
+        });
+        ui.close();
+    }
+    if ui.button("Clear").clicked() {
+        cmd.push(Cmd::WriteStepRow {
+            grid,
+            row: *row,
+            steps: vec![None; grid.len()],
+        });
+        ui.close();
+    }
+    ui.separator();
+    if row_idx > 0 && ui.button("⏶ Move up").clicked() {
+        *row_cmd = Some(RowCmd::MoveUp(row_idx));
+        ui.close();
+    }
+    if ui.button("🗑 Remove row").clicked() {
+        *row_cmd = Some(RowCmd::Remove(row_idx));
+        ui.close();
+    }
+}
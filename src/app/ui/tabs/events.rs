@@ -11,6 +11,8 @@ use {
         },
         audio_out::SongState,
         evilscript,
+        find_replace::{EventQuery, Replacement, Transform, payload_value, replace},
+        pxtone_misc::KeyInfo,
     },
     eframe::egui::{self, AtomExt},
     egui_extras::{Column, TableBody},
@@ -28,6 +30,7 @@ pub struct RawEventsUiState {
     pub filter_needs_recalc: bool,
     preview_unit_changes: bool,
     cut_event: Option<Event>,
+    find: FindReplace,
 }
 
 impl Default for RawEventsUiState {
@@ -42,6 +45,7 @@ impl Default for RawEventsUiState {
             filter_needs_recalc: true,
             preview_unit_changes: true,
             cut_event: None,
+            find: FindReplace::default(),
         }
     }
 }
@@ -67,6 +71,17 @@ impl Filter {
     }
 }
 
+#[derive(Default)]
+struct FindReplace {
+    open: bool,
+    query: EventQuery,
+    replacement: Replacement,
+    /// Indices of the events matching `query`, while the window is open
+    matches: Vec<usize>,
+}
+
+const MATCH_COLOR: egui::Color32 = egui::Color32::from_rgb(200, 160, 40);
+
 pub fn ui(
     ui: &mut egui::Ui,
     song: &mut SongState,
@@ -89,6 +104,11 @@ pub fn ui(
         }
         return;
     }
+    ui_state.find.matches = if ui_state.find.open {
+        ui_state.find.query.find(&song.song.events)
+    } else {
+        Vec::new()
+    };
     // Work around overlapping borrows of units
     let unit_names: Vec<String> = song
         .herd
@@ -186,6 +206,7 @@ pub fn ui(
         }
     }
     handle_units_command(unit_cmd, song, app_modal, shared);
+    find_replace_window_ui(ui.ctx(), song, ui_state, app_cmd);
     if let Some(cmd) = ev_list_cmd {
         match cmd {
             EventListCmd::Remove { idx } => {
@@ -250,8 +271,15 @@ fn table_body_ui(
             });
             return;
         };
+        let is_match = ui_state.find.matches.binary_search(&idx).is_ok();
         let (_rect, re) = row.col(|ui| {
-            ui.add(egui::Label::new(idx.to_string()).sense(egui::Sense::click()))
+            let mut text = egui::RichText::new(idx.to_string());
+            if is_match {
+                text = text
+                    .color(egui::Color32::BLACK)
+                    .background_color(MATCH_COLOR);
+            }
+            ui.add(egui::Label::new(text).sense(egui::Sense::click()))
                 .context_menu(|ui| {
                     ui.horizontal(|ui| {
                         if ui.button("⬆").clicked() {
@@ -407,10 +435,35 @@ fn table_body_ui(
                 &mut song.ins,
                 std::slice::from_ref(&song.preview_voice),
             );
+            let replacement = &ui_state.find.replacement;
+            if is_match && !replacement.is_noop() {
+                let new = replacement.apply(*ev);
+                let mut text = String::from("→");
+                if replacement.value.is_some()
+                    && let Some(value) = payload_value(new.payload)
+                {
+                    text.push(' ');
+                    text.push_str(&value_text(value));
+                }
+                if let Some(unit) = replacement.unit {
+                    let name = unit_names
+                        .get(usize::from(unit.0))
+                        .map_or("?", String::as_str);
+                    text.push(' ');
+                    text.push_str(name);
+                }
+                ui.label(egui::RichText::new(text).color(MATCH_COLOR));
+            }
         });
     });
 }
 
+/// A value with up to two decimals, without trailing zeros
+fn value_text(value: f64) -> String {
+    let text = format!("{value:.2}");
+    text.trim_end_matches('0').trim_end_matches('.').to_owned()
+}
+
 fn event_payload_select_ui(ui: &mut egui::Ui) -> Option<EventPayload> {
     let mut payload = None;
     if ui.button("Null").clicked() {
@@ -680,6 +733,7 @@ fn top_ui(
             ui_state.filter = Filter::default();
             ui_state.filter_needs_recalc = true;
         }
+        ui.toggle_value(&mut ui_state.find.open, "🔍 Find/replace");
         ui.separator();
         ui.checkbox(&mut ui_state.preview_unit_changes, "Preview unit changes");
         if ui
@@ -697,6 +751,18 @@ fn top_ui(
             );
             ui_state.filter_needs_recalc = true;
         }
+        if ui
+            .button("🗜 Compact")
+            .on_hover_text("Remove events that set a unit to the value it already has")
+            .clicked()
+        {
+            let compaction = crate::compact::compact(&mut song.song);
+            if compaction.events_removed != 0 {
+                app_cmd.push(Cmd::EventsReindexed);
+            }
+            app_cmd.toast(ToastKind::Info, compaction, 8.0);
+            ui_state.filter_needs_recalc = true;
+        }
         // Recalculate filtered events if filter changed
         if ui_state.filter_needs_recalc {
             ui_state.filtered_events = song
@@ -755,7 +821,7 @@ const fn ev_discr_name(discr: u8) -> &'static str {
     }
 }
 
-fn unit_rich_text(idx: UnitIdx, text: &str) -> egui::RichText {
+pub fn unit_rich_text(idx: UnitIdx, text: &str) -> egui::RichText {
     let color = unit_color(idx);
     egui::RichText::new(text)
         .color(invert_color(color))
@@ -773,3 +839,198 @@ pub fn invert_color(color: egui::Color32) -> egui::Color32 {
         a, // keep alpha the same
     )
 }
+
+fn find_replace_window_ui(
+    ctx: &egui::Context,
+    song: &mut SongState,
+    ui_state: &mut RawEventsUiState,
+    app_cmd: &mut CommandQueue,
+) {
+    let find = &mut ui_state.find;
+    if !find.open {
+        return;
+    }
+    let mut go_to = None;
+    let mut replaced = false;
+    let end_tick =
+        ptcow::timing::meas_to_tick(song.song.master.end_meas(), song.song.master.timing);
+    let a4 = DEFAULT_KEY / 256;
+    let mut open = true;
+    egui::Window::new("Find and replace")
+        .open(&mut open)
+        .show(ctx, |ui| {
+            ui.strong("Find");
+            egui::Grid::new("find_grid").num_columns(2).show(ui, |ui| {
+                ui.label("Payload");
+                egui::ComboBox::new("find_payload_cb", "")
+                    .selected_text(find.query.kind.map_or("Any", ev_discr_name))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut find.query.kind, None, "Any");
+                        for i in 0..=16 {
+                            ui.selectable_value(&mut find.query.kind, Some(i), ev_discr_name(i));
+                        }
+                    });
+                ui.end_row();
+                ui.label("Unit");
+                unit_select_ui(ui, "find_unit_cb", &mut find.query.unit, "Any", song);
+                ui.end_row();
+                range_ui(ui, "Ticks", &mut find.query.ticks, (0, end_tick), false);
+                range_ui(ui, "Value", &mut find.query.values, (0.0, 128.0), false);
+                range_ui(ui, "Key", &mut find.query.keys, (a4 - 12, a4 + 12), true);
+            });
+            ui.separator();
+            ui.strong("Replace");
+            egui::Grid::new("replace_grid")
+                .num_columns(2)
+                .show(ui, |ui| {
+                    ui.label("Value");
+                    ui.horizontal(|ui| {
+                        transform_ui(ui, &mut find.replacement.value);
+                    });
+                    ui.end_row();
+                    ui.label("Unit");
+                    unit_select_ui(
+                        ui,
+                        "replace_unit_cb",
+                        &mut find.replacement.unit,
+                        "Keep",
+                        song,
+                    );
+                    ui.end_row();
+                });
+            ui.label("Values of keys are in semitones");
+            ui.separator();
+            ui.horizontal(|ui| {
+                let matches = &find.matches;
+                ui.label(format!("{} matches", matches.len()));
+                let current = ui_state.highlight;
+                if ui
+                    .add_enabled(!matches.is_empty(), egui::Button::new("⏶ Previous"))
+                    .clicked()
+                {
+                    go_to = matches
+                        .iter()
+                        .rev()
+                        .find(|&&idx| current.is_some_and(|cur| idx < cur))
+                        .or(matches.last())
+                        .copied();
+                }
+                if ui
+                    .add_enabled(!matches.is_empty(), egui::Button::new("⏷ Next"))
+                    .clicked()
+                {
+                    go_to = matches
+                        .iter()
+                        .find(|&&idx| current.is_none_or(|cur| idx > cur))
+                        .or(matches.first())
+                        .copied();
+                }
+                let can_replace = !matches.is_empty() && !find.replacement.is_noop();
+                if ui
+                    .add_enabled(can_replace, egui::Button::new("Replace all"))
+                    .clicked()
+                {
+                    replace(&mut song.song, matches, &find.replacement);
+                    app_cmd.toast(
+                        ToastKind::Info,
+                        format_args!("Replaced {} events", matches.len()),
+                        5.0,
+                    );
+                    replaced = true;
+                }
+            });
+        });
+    find.open = open;
+    if go_to.is_some() {
+        ui_state.go_to = go_to;
+    }
+    if replaced {
+        ui_state.filter_needs_recalc = true;
+    }
+}
+
+fn unit_select_ui(
+    ui: &mut egui::Ui,
+    id: &str,
+    unit: &mut Option<UnitIdx>,
+    none_text: &str,
+    song: &SongState,
+) {
+    let selected_text = match *unit {
+        Some(idx) => unit_rich_text(
+            idx,
+            song.herd
+                .units
+                .get(idx)
+                .map_or("unresolved", |unit| &unit.name),
+        ),
+        None => none_text.into(),
+    };
+    egui::ComboBox::new(id, "")
+        .selected_text(selected_text)
+        .show_ui(ui, |ui| {
+            ui.selectable_value(unit, None, none_text);
+            for (idx, unit_ref) in song.herd.units.enumerated() {
+                ui.selectable_value(unit, Some(idx), unit_rich_text(idx, &unit_ref.name));
+            }
+        });
+}
+
+/// A checkbox for whether `range` is used, and drag values for its ends
+fn range_ui<T: egui::emath::Numeric>(
+    ui: &mut egui::Ui,
+    label: &str,
+    range: &mut Option<(T, T)>,
+    default: (T, T),
+    key_names: bool,
+) {
+    let mut enabled = range.is_some();
+    if ui.checkbox(&mut enabled, label).changed() {
+        *range = enabled.then_some(default);
+    }
+    ui.horizontal(|ui| {
+        if let Some((min, max)) = range {
+            ui.add(range_end_drag_value(min, key_names));
+            ui.label("to");
+            ui.add(range_end_drag_value(max, key_names));
+        }
+    });
+    ui.end_row();
+}
+
+fn range_end_drag_value<T: egui::emath::Numeric>(
+    value: &mut T,
+    key_names: bool,
+) -> egui::DragValue<'_> {
+    let drag = egui::DragValue::new(value);
+    if key_names {
+        drag.custom_formatter(|semitone, _| {
+            let info = KeyInfo::from_semitone(semitone.clamp(0.0, 255.0) as u8);
+            format!("{}{}", info.notation(), info.octave)
+        })
+    } else {
+        drag
+    }
+}
+
+fn transform_ui(ui: &mut egui::Ui, transform: &mut Option<Transform>) {
+    let name = match transform {
+        None => "Keep",
+        Some(Transform::Set(_)) => "Set to",
+        Some(Transform::Add(_)) => "Add",
+        Some(Transform::Multiply(_)) => "Multiply by",
+    };
+    egui::ComboBox::new("transform_cb", "")
+        .selected_text(name)
+        .show_ui(ui, |ui| {
+            ui.selectable_value(transform, None, "Keep");
+            ui.selectable_value(transform, Some(Transform::Set(0.0)), "Set to");
+            ui.selectable_value(transform, Some(Transform::Add(0.0)), "Add");
+            ui.selectable_value(transform, Some(Transform::Multiply(1.0)), "Multiply by");
+        });
+    if let Some(Transform::Set(value) | Transform::Add(value) | Transform::Multiply(value)) =
+        transform
+    {
+        ui.add(egui::DragValue::new(value).speed(0.1));
+    }
+}
@@ -2,10 +2,12 @@ use {
     crate::{
         app::{
             command_queue::{Cmd, CommandQueue},
-            ui::unit_color,
+            ui::{StretchParams, stretch_ui, unit_color},
         },
         audio_out::SongState,
         herd_ext::HerdExt,
+        pxtone_misc::MeasRange,
+        timing_ops::EventTarget,
     },
     eframe::egui::{self, scroll_area::ScrollBarVisibility},
     ptcow::{
@@ -26,6 +28,11 @@ pub struct MapState {
     pub hidden_units: FxHashSet<u8>,
     draw_meas_lines: bool,
     ui_cmd: Option<UiCmd>,
+    /// Selected measures
+    pub meas_sel: Option<MeasRange>,
+    /// Measure the selection was started from, which stays selected when extending it
+    sel_anchor: u32,
+    stretch: StretchParams,
 }
 
 enum UiCmd {
@@ -44,11 +51,14 @@ impl Default for MapState {
             hidden_units: FxHashSet::default(),
             draw_meas_lines: true,
             ui_cmd: None,
+            meas_sel: None,
+            sel_anchor: 0,
+            stretch: StretchParams::default(),
         }
     }
 }
 
-fn top_ui(ui: &mut egui::Ui, song: &mut SongState, state: &mut MapState) {
+fn top_ui(ui: &mut egui::Ui, song: &mut SongState, state: &mut MapState, cmd: &mut CommandQueue) {
     ui.horizontal(|ui| {
         let re = ui
             .add(egui::Label::new("▶ Follow").sense(egui::Sense::click()))
@@ -59,6 +69,7 @@ fn top_ui(ui: &mut egui::Ui, song: &mut SongState, state: &mut MapState) {
         ui.checkbox(&mut state.follow_playhead, "");
         piano_roll_config_popup_button(ui, state);
         loop_points_popup_button(ui, song);
+        range_popup_button(ui, song, state, cmd);
         experimental_popup_button(ui, song, state);
         help_popup_button(ui);
     });
@@ -66,7 +77,7 @@ fn top_ui(ui: &mut egui::Ui, song: &mut SongState, state: &mut MapState) {
 }
 
 pub fn ui(ui: &mut egui::Ui, song: &mut SongState, state: &mut MapState, cmd: &mut CommandQueue) {
-    top_ui(ui, song, state);
+    top_ui(ui, song, state, cmd);
     ui.horizontal_top(|ui| {
         ui.style_mut().spacing.item_spacing = egui::Vec2::ZERO;
         left_side_units_ui(song, state, ui, state.prev_frame_piano_roll_y_offset);
@@ -106,7 +117,8 @@ fn roll_ui_inner(
     // Leave some "breathing room" at end, so new events can be placed, etc.
     approx_end += 200.0;
     let clock = ptcow::current_tick(&song.herd, &song.ins);
-    let [mod_shift, mod_alt] = ui.input(|inp| [inp.modifiers.shift, inp.modifiers.alt]);
+    let [mod_shift, mod_alt, mod_ctrl] =
+        ui.input(|inp| [inp.modifiers.shift, inp.modifiers.alt, inp.modifiers.ctrl]);
     let n_units = song.herd.units.len();
     let (rect, re) = ui.allocate_exact_size(
         egui::vec2(approx_end, f32::from(n_units) * state.row_size),
@@ -185,7 +197,8 @@ fn roll_ui_inner(
             && ui.ui_contains_pointer()
         {
             if irect.contains(mp) {
-                if ui.input(|inp| inp.pointer.primary_clicked()) {
+                // Ctrl+click selects measures instead
+                if !mod_ctrl && ui.input(|inp| inp.pointer.primary_clicked()) {
                     cmd.push(Cmd::OpenEventInEventsTab { index: ev_idx });
                 }
                 if mod_alt {
@@ -198,6 +211,22 @@ fn roll_ui_inner(
     if state.draw_meas_lines {
         draw_meas_lines(song, state, last_tick, rect, &pnt, cr, &mut lines_drawn);
     }
+    if let Some(sel) = state.meas_sel {
+        let (start, end) = sel.ticks(song.song.master.timing);
+        let sel_rect = egui::Rect::from_x_y_ranges(
+            (start as f32 / state.tick_div + rect.min.x)
+                ..=(end as f32 / state.tick_div + rect.min.x),
+            rect.y_range(),
+        );
+        pnt.rect_filled(
+            sel_rect,
+            0.0,
+            egui::Color32::from_rgba_unmultiplied(100, 150, 255, 40),
+        );
+    }
+    if state.draw_meas_lines {
+        crate::app::ui::meas_ruler(ui, song, rect, state.tick_div, state.meas_sel, cmd);
+    }
 
     if state.draw_debug_info {
         if let Some(mouse_pos) = ui.input(|inp| inp.pointer.latest_pos())
@@ -261,7 +290,14 @@ fn roll_ui_inner(
         let sample = scaled * song.ins.samples_per_tick;
         let sample = sample as SampleT;
         if re.clicked_by(egui::PointerButton::Primary) {
-            if mod_shift {
+            if mod_ctrl {
+                let meas_ticks = ptcow::timing::meas_to_tick(1, song.song.master.timing).max(1);
+                let meas = scaled as u32 / meas_ticks;
+                if !mod_shift || state.meas_sel.is_none() {
+                    state.sel_anchor = meas;
+                }
+                state.meas_sel = Some(MeasRange::spanning(state.sel_anchor, meas));
+            } else if mod_shift {
                 song.herd.seek_to_sample(sample);
             }
         }
@@ -308,13 +344,6 @@ fn draw_meas_lines(
             egui::Stroke::new(1.0, egui::Color32::LIGHT_GRAY),
         );
         *lines_drawn += 1;
-        pnt.text(
-            egui::pos2(x + 2.0, cr.min.y),
-            egui::Align2::LEFT_TOP,
-            meas,
-            egui::FontId::proportional(16.0),
-            egui::Color32::LIGHT_YELLOW,
-        );
     }
 }
 
@@ -468,6 +497,72 @@ fn loop_points_popup_button(ui: &mut egui::Ui, song: &mut SongState) {
         });
 }
 
+fn range_popup_button(
+    ui: &mut egui::Ui,
+    song: &SongState,
+    state: &mut MapState,
+    cmd: &mut CommandQueue,
+) {
+    let re = ui.button("📐 Range");
+    egui::Popup::menu(&re)
+        .close_behavior(egui::PopupCloseBehavior::CloseOnClickOutside)
+        .show(|ui| {
+            if ui.button("Select loop points").clicked() {
+                let loop_points = &song.song.master.loop_points;
+                let end = loop_points
+                    .last
+                    .map_or(song.song.master.end_meas(), |last| last.get());
+                state.meas_sel = Some(MeasRange {
+                    start: loop_points.repeat,
+                    end,
+                });
+            }
+            let Some(sel) = &mut state.meas_sel else {
+                ui.label("Ctrl+click a measure to select it");
+                return;
+            };
+            egui::Grid::new("meas_sel_grid").show(ui, |ui| {
+                ui.label("Start meas");
+                ui.add(egui::DragValue::new(&mut sel.start).speed(0.1));
+                ui.end_row();
+                ui.label("End meas");
+                ui.add(egui::DragValue::new(&mut sel.end).speed(0.1))
+                    .on_hover_text("Not included in the range");
+                ui.end_row();
+            });
+            sel.end = sel.end.max(sel.start);
+            let sel = *sel;
+            if ui
+                .add_enabled(!sel.is_empty(), egui::Button::new("✂ Extract to new tab"))
+                .on_hover_text("Open the range as a project of its own")
+                .clicked()
+            {
+                cmd.push(Cmd::ExtractRange(sel));
+            }
+            if ui
+                .add_enabled(!sel.is_empty(), egui::Button::new("🗑 Delete measures"))
+                .on_hover_text("Remove the range from the song, moving everything after it back")
+                .clicked()
+            {
+                cmd.push(Cmd::DeleteMeas(sel));
+            }
+            ui.separator();
+            ui.add_enabled_ui(!sel.is_empty(), |ui| {
+                stretch_ui(
+                    ui,
+                    song,
+                    &mut state.stretch,
+                    || EventTarget::Range(sel),
+                    cmd,
+                );
+            });
+            ui.separator();
+            if ui.button("Clear selection").clicked() {
+                state.meas_sel = None;
+            }
+        });
+}
+
 fn experimental_popup_button(ui: &mut egui::Ui, song: &mut SongState, state: &mut MapState) {
     let re = ui.button("🐛 Debug/Experimental");
     egui::Popup::menu(&re).show(|ui| {
@@ -497,6 +592,15 @@ fn help_popup_button(ui: &mut egui::Ui) {
             ui.end_row();
             ui.label("Hover info");
             ui.input_label("Hold alt");
+            ui.end_row();
+            ui.label("Select measure");
+            ui.input_label("Ctrl+lmb");
+            ui.end_row();
+            ui.label("Extend selection");
+            ui.input_label("Ctrl+Shift+lmb");
+            ui.end_row();
+            ui.label("Insert/delete measures (measure lines on)");
+            ui.input_label("rmb on ruler");
         });
     });
 }
@@ -2,11 +2,18 @@ use {
     crate::{
         app::{
             command_queue::{Cmd, CommandQueue},
-            ui::{SharedUiState, piano_freeplay_play_note, tabs::events::invert_color, unit_color},
+            ui::{
+                SharedUiState, StretchParams, freeplay_assist_play_note, humanize_ui,
+                piano_freeplay_play_note, quantize_ui, root_combo, scale_combo, stretch_ui,
+                tabs::events::{invert_color, unit_rich_text},
+                transpose_ui, unit_color,
+            },
         },
+        arp::{ArpNote, ArpPattern, ArpSource, Arpeggio},
         audio_out::SongState,
         herd_ext::HerdExt,
-        pxtone_misc::KeyInfo,
+        pxtone_misc::{Chord, ChordKind, KeyInfo, Scale, Voicing, chord_spans, pitch_class},
+        timing_ops::EventTarget,
     },
     arrayvec::ArrayVec,
     eframe::egui::{self, PopupAnchor, scroll_area::ScrollBarVisibility},
@@ -35,12 +42,95 @@ pub struct PianoRollState {
     /// Origin for things like selection boxes
     lmb_drag_origin: Option<egui::Pos2>,
     /// Using a `BTreeSet`, so indices are sorted
-    selected_event_indices: BTreeSet<usize>,
+    pub selected_event_indices: BTreeSet<usize>,
     /// Information about note "just" placed with lmb press (haven't released lmb yet)
     just_placed_note: Option<PlacedNote>,
     /// Snap placed notes to quarter beat granularity
     snap_to_quarter_beat: bool,
+    /// Scale to highlight, if any
+    scale: Option<Scale>,
+    /// Root of `scale`, as a pitch class (0 is C)
+    scale_root: u8,
+    /// Snap placed notes to `scale`
+    snap_to_scale: bool,
+    /// Chord to place instead of single notes, if any
+    chord: Option<Chord>,
+    /// Show the names of the chords played above the ruler
+    show_chords: bool,
+    arp: ArpWindow,
     draw_tempo_lines: bool,
+    stretch: StretchParams,
+}
+
+/// State of the arpeggiator window
+struct ArpWindow {
+    open: bool,
+    source: ArpSourceKind,
+    /// Chord symbols for [`ArpSource::Progression`]
+    progression: String,
+    start_meas: u32,
+    beats_per_chord: u8,
+    octave: i32,
+    arp: Arpeggio,
+    /// Unit to write the notes to
+    unit: UnitIdx,
+    /// Remove the source notes when writing
+    replace: bool,
+    preview: Option<ArpPreview>,
+    /// Why the chords couldn't be figured out, if they couldn't
+    error: Option<String>,
+}
+
+impl Default for ArpWindow {
+    fn default() -> Self {
+        Self {
+            open: false,
+            source: ArpSourceKind::Selection,
+            progression: String::new(),
+            start_meas: 0,
+            beats_per_chord: 4,
+            octave: 4,
+            arp: Arpeggio::default(),
+            unit: UnitIdx(0),
+            replace: false,
+            preview: None,
+            error: None,
+        }
+    }
+}
+
+impl ArpWindow {
+    fn source(&self, selection: &BTreeSet<usize>) -> ArpSource {
+        match self.source {
+            ArpSourceKind::Selection => ArpSource::Notes(EventTarget::Indices(selection.clone())),
+            ArpSourceKind::Unit(unit) => ArpSource::Notes(EventTarget::Unit(unit)),
+            ArpSourceKind::Progression => ArpSource::Progression {
+                text: self.progression.clone(),
+                start_meas: self.start_meas,
+                beats_per_chord: self.beats_per_chord,
+                octave: self.octave,
+            },
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum ArpSourceKind {
+    Selection,
+    Unit(UnitIdx),
+    Progression,
+}
+
+/// Arpeggio being played through the freeplay assist units
+struct ArpPreview {
+    notes: Vec<ArpNote>,
+    unit: UnitIdx,
+    /// Ui time the preview started at
+    start_time: f64,
+    /// Tick of the first note
+    start_tick: u32,
+    /// Index of the next note to play
+    next: usize,
 }
 
 struct PlacedNote {
@@ -84,7 +174,14 @@ impl Default for PianoRollState {
             selected_event_indices: BTreeSet::default(),
             just_placed_note: None,
             snap_to_quarter_beat: true,
+            scale: None,
+            scale_root: 0,
+            snap_to_scale: false,
+            chord: None,
+            show_chords: false,
+            arp: ArpWindow::default(),
             draw_tempo_lines: true,
+            stretch: StretchParams::default(),
         }
     }
 }
@@ -94,6 +191,8 @@ fn top_ui(
     song: &mut SongState,
     state: &mut PianoRollState,
     shared: &mut SharedUiState,
+    cmd: &mut CommandQueue,
+    other_docs: &[(usize, String)],
 ) {
     ui.horizontal(|ui| {
         let [key_f1, key_f2, key_f3] = ui.input(|inp| {
@@ -129,6 +228,9 @@ fn top_ui(
         }
         ui.checkbox(&mut state.follow_playhead, "");
         piano_roll_config_popup_button(ui, state);
+        scale_popup_button(ui, state);
+        chord_popup_button(ui, state);
+        ui.toggle_value(&mut state.arp.open, "🎶 Arpeggiator");
         loop_points_popup_button(ui, song);
         if !state.selected_event_indices.is_empty() {
             ui.separator();
@@ -148,6 +250,60 @@ fn top_ui(
             if ui.button("Clear").clicked() || ui.input(|inp| inp.key_pressed(egui::Key::Escape)) {
                 state.selected_event_indices.clear();
             }
+            let re = ui.button("↔ Stretch");
+            egui::Popup::menu(&re)
+                .close_behavior(egui::PopupCloseBehavior::CloseOnClickOutside)
+                .show(|ui| {
+                    stretch_ui(
+                        ui,
+                        song,
+                        &mut state.stretch,
+                        || EventTarget::Indices(state.selected_event_indices.clone()),
+                        cmd,
+                    );
+                });
+            let re = ui.button("⌗ Quantize");
+            egui::Popup::menu(&re)
+                .close_behavior(egui::PopupCloseBehavior::CloseOnClickOutside)
+                .show(|ui| {
+                    quantize_ui(
+                        ui,
+                        || EventTarget::Indices(state.selected_event_indices.clone()),
+                        cmd,
+                    );
+                });
+            let re = ui.button("🎲 Humanize");
+            egui::Popup::menu(&re)
+                .close_behavior(egui::PopupCloseBehavior::CloseOnClickOutside)
+                .show(|ui| {
+                    humanize_ui(
+                        ui,
+                        || EventTarget::Indices(state.selected_event_indices.clone()),
+                        cmd,
+                    );
+                });
+            let re = ui.button("♯ Transpose");
+            egui::Popup::menu(&re)
+                .close_behavior(egui::PopupCloseBehavior::CloseOnClickOutside)
+                .show(|ui| {
+                    transpose_ui(
+                        ui,
+                        || EventTarget::Indices(state.selected_event_indices.clone()),
+                        cmd,
+                    );
+                });
+            if !other_docs.is_empty() {
+                ui.menu_button("📋 Copy to document", |ui| {
+                    for (doc, title) in other_docs {
+                        if ui.button(title).clicked() {
+                            cmd.push(Cmd::CopyEventsToDocument {
+                                events: state.selected_event_indices.iter().copied().collect(),
+                                doc: *doc,
+                            });
+                        }
+                    }
+                });
+            }
         }
         ui.separator();
         help_popup_button(ui, state.interact_mode);
@@ -161,8 +317,9 @@ pub fn ui(
     state: &mut PianoRollState,
     shared: &mut SharedUiState,
     cmd: &mut CommandQueue,
+    other_docs: &[(usize, String)],
 ) {
-    top_ui(ui, song, state, shared);
+    top_ui(ui, song, state, shared, cmd, other_docs);
     ui.horizontal_top(|ui| {
         ui.style_mut().spacing.item_spacing = egui::Vec2::ZERO;
         piano_ui(
@@ -174,6 +331,7 @@ pub fn ui(
         );
         roll_ui(song, state, shared, ui, cmd);
     });
+    arp_window_ui(ui, song, state, cmd);
 }
 
 fn roll_ui(
@@ -311,6 +469,15 @@ fn roll_ui_inner(
     if state.draw_meas_lines {
         draw_meas_lines(song, state, last_tick, rect, &pnt, cr, mouse_screen_pos);
     }
+    if state.show_chords {
+        draw_chord_strip(song, state, ui, rect, cr);
+        // The ruler goes below the chord names
+        ui.set_clip_rect(cr.with_min_y(cr.min.y + CHORD_STRIP_HEIGHT));
+    }
+    if state.draw_meas_lines {
+        crate::app::ui::meas_ruler(ui, song, rect, state.tick_div, None, cmd);
+    }
+    ui.set_clip_rect(cr);
 
     // Draw play repeat line
     let x = (song.herd.smp_repeat as f32 / song.ins.samples_per_tick / state.tick_div) + rect.min.x;
@@ -348,9 +515,14 @@ fn roll_ui_inner(
                         return;
                     }
                     let unit = shared.active_unit;
-                    let Some(piano_key) = piano_key_from_y(pos, &re, rect, state) else {
+                    let Some(mut piano_key) = piano_key_from_y(pos, &re, rect, state) else {
                         return;
                     };
+                    if let Some(scale) = state.scale
+                        && state.snap_to_scale
+                    {
+                        piano_key = scale.snap(state.scale_root, piano_key);
+                    }
                     let key = piano_key * 256;
                     let ticks_per_q_beat = u32::from(song.song.master.timing.ticks_per_beat) / 4;
                     let tick = if state.snap_to_quarter_beat {
@@ -385,30 +557,46 @@ fn roll_ui_inner(
                 if duration == 0 {
                     break 'block;
                 }
+                let keys: ArrayVec<Key, 4> = match state.chord {
+                    Some(chord) => chord
+                        .semitones(placed.key / 256)
+                        .iter()
+                        .map(|semitone| semitone * 256)
+                        .collect(),
+                    None => std::iter::once(placed.key).collect(),
+                };
                 // Draw "just placed" note
                 let orig_x = placed.tick as f32 / state.tick_div;
                 let dura_w = duration as f32 / state.tick_div;
                 let x = orig_x + rect.min.x;
-                let y = key_y(state.lowest_semitone, state.row_size, rect, placed.key);
-                let rect = egui::Rect::from_min_max(
-                    egui::pos2(x, y),
-                    egui::pos2(x + dura_w, y + state.row_size),
-                );
-                pnt.debug_rect(rect, unit_color(placed.unit), "Just placed");
+                for &key in &keys {
+                    let y = key_y(state.lowest_semitone, state.row_size, rect, key);
+                    let rect = egui::Rect::from_min_max(
+                        egui::pos2(x, y),
+                        egui::pos2(x + dura_w, y + state.row_size),
+                    );
+                    pnt.debug_rect(rect, unit_color(placed.unit), "Just placed");
+                }
                 // "Finalize" note when lmb is released
                 if lmb_released {
-                    song.song.events.push(ptcow::Event {
-                        payload: EventPayload::Key(placed.key),
-                        unit: placed.unit,
-                        tick: placed.tick,
-                    });
-                    song.song.events.push(ptcow::Event {
-                        payload: EventPayload::On { duration },
-                        unit: placed.unit,
-                        tick: placed.tick,
-                    });
+                    for &key in &keys {
+                        song.song.events.push(ptcow::Event {
+                            payload: EventPayload::Key(key),
+                            unit: placed.unit,
+                            tick: placed.tick,
+                        });
+                        song.song.events.push(ptcow::Event {
+                            payload: EventPayload::On { duration },
+                            unit: placed.unit,
+                            tick: placed.tick,
+                        });
+                    }
                     song.song.events.sort();
                     song.song.recalculate_length();
+                    // A unit can only play one note at a time
+                    if keys.len() > 1 {
+                        cmd.push(Cmd::PolySpread(placed.unit));
+                    }
                     state.just_placed_note = None;
                 }
             }
@@ -458,6 +646,43 @@ fn roll_ui_inner(
     }
 }
 
+const CHORD_STRIP_HEIGHT: f32 = 18.0;
+
+/// Draw the names of the chords played by the visible units in a strip at the top
+fn draw_chord_strip(
+    song: &SongState,
+    state: &PianoRollState,
+    ui: &egui::Ui,
+    rect: egui::Rect,
+    cr: egui::Rect,
+) {
+    let strip =
+        egui::Rect::from_min_max(cr.min, egui::pos2(cr.max.x, cr.min.y + CHORD_STRIP_HEIGHT));
+    let pnt = ui.painter_at(strip);
+    pnt.rect_filled(strip, 0.0, egui::Color32::from_black_alpha(200));
+    let tick_at = |x: f32| ((x - rect.min.x).max(0.0) * state.tick_div) as u32;
+    let spans = chord_spans(
+        &song.song.events,
+        |unit| !state.hidden_units.contains(&unit),
+        tick_at(cr.min.x),
+        tick_at(cr.max.x) + 1,
+    );
+    for span in spans {
+        let x = span.start as f32 / state.tick_div + rect.min.x;
+        pnt.line_segment(
+            [egui::pos2(x, strip.min.y), egui::pos2(x, strip.max.y)],
+            egui::Stroke::new(1.0, egui::Color32::GRAY),
+        );
+        pnt.text(
+            egui::pos2(x.max(strip.min.x) + 2.0, strip.center().y),
+            egui::Align2::LEFT_CENTER,
+            span.name,
+            egui::FontId::proportional(14.0),
+            egui::Color32::LIGHT_BLUE,
+        );
+    }
+}
+
 fn draw_playhead_line(
     song: &mut SongState,
     state: &mut PianoRollState,
@@ -503,7 +728,9 @@ fn draw_piano_roll_items(
     );
     // INVARIANT/TODO: This assumes there are enough units in the herd so no event refers to an
     // out of bounds index. Might not always hold true. Especially if deleting units is allowed.
+    // "Check song" finds and removes the events that break it.
     let mut unit_key_ys = vec![default_y; usize::from(song.herd.units.len())];
+    let mut unit_keys = vec![ptcow::DEFAULT_KEY; usize::from(song.herd.units.len())];
     let mut hovered_events = Vec::new();
     for (ev_idx, ev) in song.song.events.iter().enumerate() {
         if state.hidden_units.contains(&ev.unit) {
@@ -536,6 +763,7 @@ fn draw_piano_roll_items(
                     };
                     unit_key_ys[ev.unit.usize()] =
                         key_y(state.lowest_semitone, state.row_size, rect, key);
+                    unit_keys[ev.unit.usize()] = key;
                 }
                 let y = unit_key_ys[ev.unit.usize()];
                 let rect = egui::Rect::from_min_max(
@@ -559,10 +787,22 @@ fn draw_piano_roll_items(
                         egui::StrokeKind::Outside,
                     );
                 }
+                // Mark notes that are out of key
+                if let Some(scale) = state.scale
+                    && !scale.contains(state.scale_root, pitch_class(unit_keys[ev.unit.usize()]))
+                {
+                    pnt.rect_stroke(
+                        shrink_rect,
+                        2.0,
+                        egui::Stroke::new(2.0, egui::Color32::RED),
+                        egui::StrokeKind::Inside,
+                    );
+                }
             }
             EventPayload::Key(k) => {
                 let y = key_y(state.lowest_semitone, state.row_size, rect, k);
                 unit_key_ys[ev.unit.usize()] = y;
+                unit_keys[ev.unit.usize()] = k;
                 let radius = state.row_size / 4.0;
                 // We skip drawing the circle if it's outside to the left of the clip rect
                 if x + radius < cr.min.x {
@@ -627,6 +867,9 @@ fn draw_piano_roll_items(
     hovered_events
 }
 
+const SCALE_ROW_COLOR: egui::Color32 = egui::Color32::from_rgba_premultiplied(20, 30, 50, 40);
+const SCALE_ROOT_ROW_COLOR: egui::Color32 = egui::Color32::from_rgba_premultiplied(50, 40, 10, 60);
+
 fn draw_piano_roll_rows(
     state: &PianoRollState,
     rect: egui::Rect,
@@ -648,6 +891,14 @@ fn draw_piano_roll_rows(
         if sharp[info.c_scale_idx as usize] {
             pnt.rect_filled(row_rect, 0.0, egui::Color32::BLACK);
         }
+        if let Some(scale) = state.scale {
+            let pitch_class = info.c_scale_idx as u8;
+            if pitch_class == state.scale_root {
+                pnt.rect_filled(row_rect, 0.0, SCALE_ROOT_ROW_COLOR);
+            } else if scale.contains(state.scale_root, pitch_class) {
+                pnt.rect_filled(row_rect, 0.0, SCALE_ROW_COLOR);
+            }
+        }
         pnt.line_segment(
             [egui::pos2(cr.min.x, y), egui::pos2(cr.max.x, y)],
             egui::Stroke::new(1.0, egui::Color32::DARK_GRAY),
@@ -712,6 +963,211 @@ fn alt_hover_tooltip_ui(
     }
 }
 
+fn arp_window_ui(
+    ui: &mut egui::Ui,
+    song: &mut SongState,
+    state: &mut PianoRollState,
+    cmd: &mut CommandQueue,
+) {
+    let win = &mut state.arp;
+    if let Some(preview) = &mut win.preview {
+        let timing = song.song.master.timing;
+        let elapsed = ui.input(|inp| inp.time) - preview.start_time;
+        let beats = elapsed * f64::from(timing.bpm) / 60.0;
+        let tick = preview.start_tick + (beats * f64::from(timing.ticks_per_beat)) as u32;
+        while let Some(note) = preview.notes.get(preview.next)
+            && note.tick <= tick
+        {
+            freeplay_assist_play_note(song, preview.unit, note.key, note.duration);
+            preview.next += 1;
+        }
+        if preview.next < preview.notes.len() {
+            ui.ctx().request_repaint();
+        } else {
+            win.preview = None;
+        }
+    }
+    if !win.open {
+        win.preview = None;
+        return;
+    }
+    let n_selected = state.selected_event_indices.len();
+    let mut open = true;
+    egui::Window::new("Arpeggiator")
+        .open(&mut open)
+        .show(ui.ctx(), |ui| {
+            egui::Grid::new("arp_grid").show(ui, |ui| {
+                ui.label("Chords from");
+                let selected_text = match win.source {
+                    ArpSourceKind::Selection => format!("Selected notes ({n_selected})"),
+                    ArpSourceKind::Unit(_) => "Notes of unit".into(),
+                    ArpSourceKind::Progression => "Chord progression".into(),
+                };
+                egui::ComboBox::from_id_salt("arp_source")
+                    .selected_text(selected_text)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut win.source,
+                            ArpSourceKind::Selection,
+                            format!("Selected notes ({n_selected})"),
+                        );
+                        if ui
+                            .selectable_label(
+                                matches!(win.source, ArpSourceKind::Unit(_)),
+                                "Notes of unit",
+                            )
+                            .clicked()
+                        {
+                            win.source = ArpSourceKind::Unit(win.unit);
+                        }
+                        ui.selectable_value(
+                            &mut win.source,
+                            ArpSourceKind::Progression,
+                            "Chord progression",
+                        );
+                    });
+                ui.end_row();
+                match &mut win.source {
+                    ArpSourceKind::Selection => {}
+                    ArpSourceKind::Unit(unit) => {
+                        ui.label("Source unit");
+                        unit_combo(ui, "arp_source_unit", song, unit);
+                        ui.end_row();
+                    }
+                    ArpSourceKind::Progression => {
+                        ui.label("Chords");
+                        ui.add(
+                            egui::TextEdit::singleline(&mut win.progression).hint_text("C Am F G7"),
+                        )
+                        .on_hover_text("Chord symbols separated by spaces\n- holds the last chord");
+                        ui.end_row();
+                        ui.label("Chord length");
+                        ui.add(
+                            egui::DragValue::new(&mut win.beats_per_chord)
+                                .range(1..=64)
+                                .suffix(" beats"),
+                        );
+                        ui.end_row();
+                        ui.label("Start measure");
+                        ui.add(egui::DragValue::new(&mut win.start_meas));
+                        ui.end_row();
+                        ui.label("Octave");
+                        ui.add(egui::DragValue::new(&mut win.octave).range(-2..=10));
+                        ui.end_row();
+                    }
+                }
+                ui.label("Pattern");
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_salt("arp_pattern")
+                        .selected_text(win.arp.pattern.name())
+                        .show_ui(ui, |ui| {
+                            for pattern in ArpPattern::PRESETS {
+                                let name = pattern.name();
+                                ui.selectable_value(&mut win.arp.pattern, pattern, name);
+                            }
+                            let is_custom = matches!(win.arp.pattern, ArpPattern::Custom(_));
+                            if ui.selectable_label(is_custom, "Custom").clicked() && !is_custom {
+                                win.arp.pattern = ArpPattern::Custom("1 2 3 2".into());
+                            }
+                        });
+                    match &mut win.arp.pattern {
+                        ArpPattern::Custom(text) => {
+                            ui.text_edit_singleline(text).on_hover_text(
+                                "Notes by number, lowest first, separated by spaces\n- is a rest",
+                            );
+                        }
+                        ArpPattern::Random => {
+                            ui.add(egui::DragValue::new(&mut win.arp.seed).prefix("Seed "));
+                        }
+                        _ => {}
+                    }
+                });
+                ui.end_row();
+                ui.label("Rate");
+                ui.add(
+                    egui::DragValue::new(&mut win.arp.rate)
+                        .range(1..=32)
+                        .suffix(" per beat"),
+                );
+                ui.end_row();
+                ui.label("Gate");
+                ui.add(egui::Slider::new(&mut win.arp.gate, 0.05..=1.0));
+                ui.end_row();
+                ui.label("Octaves");
+                ui.add(egui::DragValue::new(&mut win.arp.octaves).range(1..=4));
+                ui.end_row();
+                ui.label("Write to");
+                unit_combo(ui, "arp_unit", song, &mut win.unit);
+                ui.end_row();
+            });
+            ui.add_enabled(
+                win.source != ArpSourceKind::Progression,
+                egui::Checkbox::new(&mut win.replace, "Remove the source notes"),
+            );
+            ui.separator();
+            ui.horizontal(|ui| {
+                if win.preview.is_some() {
+                    if ui.button("⏹ Stop").clicked() {
+                        win.preview = None;
+                    }
+                } else if ui.button("▶ Preview").clicked() {
+                    let source = win.source(&state.selected_event_indices);
+                    match source.chords(&song.song) {
+                        Ok(chords) => {
+                            let notes = win
+                                .arp
+                                .notes(&chords, song.song.master.timing.ticks_per_beat);
+                            win.preview = Some(ArpPreview {
+                                start_tick: notes.first().map_or(0, |note| note.tick),
+                                notes,
+                                unit: win.unit,
+                                start_time: ui.input(|inp| inp.time),
+                                next: 0,
+                            });
+                            win.error = None;
+                        }
+                        Err(e) => win.error = Some(e.to_string()),
+                    }
+                }
+                if ui
+                    .add_enabled(
+                        song.herd.units.get(win.unit).is_some(),
+                        egui::Button::new("Write"),
+                    )
+                    .on_disabled_hover_text("Pick a unit to write to")
+                    .clicked()
+                {
+                    win.preview = None;
+                    cmd.push(Cmd::Arpeggiate {
+                        unit: win.unit,
+                        source: win.source(&state.selected_event_indices),
+                        arp: win.arp.clone(),
+                        replace: win.replace && win.source != ArpSourceKind::Progression,
+                    });
+                }
+            });
+            if let Some(error) = &win.error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+        });
+    win.open = open;
+}
+
+fn unit_combo(ui: &mut egui::Ui, id_salt: &str, song: &SongState, unit: &mut UnitIdx) {
+    let name = song
+        .herd
+        .units
+        .get(*unit)
+        .map_or("(none)", |unit| &unit.name);
+    egui::ComboBox::from_id_salt(id_salt)
+        .selected_text(unit_rich_text(*unit, name))
+        .show_ui(ui, |ui| {
+            for (idx, other) in song.herd.units.enumerated() {
+                ui.selectable_value(unit, idx, unit_rich_text(idx, &other.name));
+            }
+        });
+}
+
 fn events_popup_window_ui(
     song: &mut SongState,
     state: &mut PianoRollState,
@@ -864,13 +1320,6 @@ fn draw_meas_lines(
             [egui::pos2(x, rect.min.y), egui::pos2(x, rect.max.y)],
             egui::Stroke::new(1.0, egui::Color32::LIGHT_GRAY),
         );
-        pnt.text(
-            egui::pos2(x + 2.0, cr.min.y),
-            egui::Align2::LEFT_TOP,
-            meas,
-            egui::FontId::proportional(16.0),
-            egui::Color32::LIGHT_YELLOW,
-        );
         if let Some(mouse_pos) = mouse_pos {
             let (_mouse_tick, mouse_meas) =
                 mouse_tick_meas(mouse_pos, rect, state.tick_div, song.song.master.timing);
@@ -1077,6 +1526,83 @@ fn piano_roll_config_popup_button(ui: &mut egui::Ui, state: &mut PianoRollState)
         });
 }
 
+fn scale_popup_button(ui: &mut egui::Ui, state: &mut PianoRollState) {
+    let re = ui.button("🎼 Scale");
+    egui::Popup::menu(&re)
+        .close_behavior(egui::PopupCloseBehavior::CloseOnClickOutside)
+        .show(|ui| {
+            let mut highlight = state.scale.is_some();
+            if ui.checkbox(&mut highlight, "Highlight scale").changed() {
+                state.scale = highlight.then_some(Scale::Major);
+            }
+            let Some(scale) = &mut state.scale else {
+                return;
+            };
+            ui.horizontal(|ui| {
+                root_combo(ui, "roll_scale_root", &mut state.scale_root);
+                scale_combo(ui, "roll_scale", scale, state.scale_root);
+            });
+            ui.checkbox(&mut state.snap_to_scale, "Snap placed notes to scale");
+            ui.horizontal(|ui| {
+                ui.label("Out of key notes are marked with");
+                ui.colored_label(egui::Color32::RED, "red");
+            });
+        });
+}
+
+fn chord_popup_button(ui: &mut egui::Ui, state: &mut PianoRollState) {
+    let re = ui.button("♬ Chord");
+    egui::Popup::menu(&re)
+        .close_behavior(egui::PopupCloseBehavior::CloseOnClickOutside)
+        .show(|ui| {
+            let mut stamp = state.chord.is_some();
+            if ui
+                .checkbox(&mut stamp, "Place chords")
+                .on_hover_text(
+                    "Place a chord instead of a single note.\n\
+                     Its notes are spread across poly units,\n\
+                     like with \"Split > Overlapping events\".",
+                )
+                .changed()
+            {
+                state.chord = stamp.then_some(Chord {
+                    kind: ChordKind::Major,
+                    inversion: 0,
+                    voicing: Voicing::Close,
+                });
+            }
+            if let Some(chord) = &mut state.chord {
+                egui::Grid::new("roll_chord_grid").show(ui, |ui| {
+                    ui.label("Chord");
+                    egui::ComboBox::from_id_salt("roll_chord_kind")
+                        .selected_text(chord.kind.name())
+                        .show_ui(ui, |ui| {
+                            for kind in ChordKind::ALL {
+                                ui.selectable_value(&mut chord.kind, kind, kind.name());
+                            }
+                        });
+                    ui.end_row();
+                    ui.label("Inversion");
+                    let max = chord.kind.intervals().len() as u8 - 1;
+                    chord.inversion = chord.inversion.min(max);
+                    ui.add(egui::DragValue::new(&mut chord.inversion).range(0..=max));
+                    ui.end_row();
+                    ui.label("Voicing");
+                    egui::ComboBox::from_id_salt("roll_chord_voicing")
+                        .selected_text(chord.voicing.name())
+                        .show_ui(ui, |ui| {
+                            for voicing in Voicing::ALL {
+                                ui.selectable_value(&mut chord.voicing, voicing, voicing.name());
+                            }
+                        });
+                });
+            }
+            ui.separator();
+            ui.checkbox(&mut state.show_chords, "Show chord names")
+                .on_hover_text("Name the chords played by the visible units, above the ruler");
+        });
+}
+
 fn loop_points_popup_button(ui: &mut egui::Ui, song: &mut SongState) {
     let re = ui.button("🔁 Loop points");
     egui::Popup::menu(&re)
@@ -1156,6 +1682,9 @@ fn help_popup_button(ui: &mut egui::Ui, interact_mode: InteractMode) {
             ui.label("Seek");
             ui.input_label("Shift+lmb");
             ui.end_row();
+            ui.label("Insert/delete measures (measure lines on)");
+            ui.input_label("rmb on ruler");
+            ui.end_row();
             match interact_mode {
                 InteractMode::View => {
                     ui.label("Note info");
@@ -1166,7 +1695,7 @@ fn help_popup_button(ui: &mut egui::Ui, interact_mode: InteractMode) {
                     ui.input_label("lmb drag");
                 }
                 InteractMode::Place => {
-                    ui.label("Place note");
+                    ui.label("Place note or chord");
                     ui.input_label("lmb");
                     ui.end_row();
                     ui.label("Remove note");
@@ -1196,7 +1725,7 @@ fn help_popup_button(ui: &mut egui::Ui, interact_mode: InteractMode) {
     });
 }
 
-trait PianoRollUiExt {
+pub trait PianoRollUiExt {
     fn input_label(&mut self, text: &str);
 }
 
@@ -0,0 +1,528 @@
+//! UI code for the Tracker tab, a keyboard driven view of the events in rows
+
+use {
+    super::piano_roll::PianoRollUiExt as _,
+    crate::{
+        app::{
+            command_queue::{Cmd, CommandQueue},
+            ui::{freeplay_assist_play_note, unit_color},
+        },
+        audio_out::SongState,
+        pxtone_misc::KeyInfo,
+        row_edit::{Cell, Field, clear, read_cells, write_note, write_off, write_value},
+    },
+    eframe::egui,
+    ptcow::UnitIdx,
+};
+
+pub struct TrackerState {
+    rows_per_beat: u16,
+    cursor: Cursor,
+    /// Octave of the lower row of the piano keys
+    octave: i16,
+    /// Rows the cursor moves down after entering something
+    edit_step: usize,
+    /// Length of entered notes, in rows
+    note_rows: u32,
+    /// Keep the playing row in view
+    follow: bool,
+}
+
+impl Default for TrackerState {
+    fn default() -> Self {
+        Self {
+            rows_per_beat: 4,
+            cursor: Cursor {
+                row: 0,
+                unit: 0,
+                field: Field::Note,
+                digit: 0,
+            },
+            octave: 4,
+            edit_step: 1,
+            note_rows: 4,
+            follow: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Cursor {
+    row: usize,
+    unit: usize,
+    field: Field,
+    /// Which digit of a hex field is entered next, 0 being the high one
+    digit: u8,
+}
+
+/// Piano keys, by semitones above the C of the octave
+const PIANO_KEYS: [egui::Key; 29] = {
+    use egui::Key as K;
+    [
+        // Lower
+        K::Z,
+        K::S,
+        K::X,
+        K::D,
+        K::C,
+        K::V,
+        K::G,
+        K::B,
+        K::H,
+        K::N,
+        K::J,
+        K::M,
+        // Upper
+        K::Q,
+        K::Num2,
+        K::W,
+        K::Num3,
+        K::E,
+        K::R,
+        K::Num5,
+        K::T,
+        K::Num6,
+        K::Y,
+        K::Num7,
+        K::U,
+        K::I,
+        K::Num9,
+        K::O,
+        K::Num0,
+        K::P,
+    ]
+};
+const NOTE_OFF_KEY: egui::Key = egui::Key::Num1;
+
+const FONT_SIZE: f32 = 13.0;
+const ROW_HEIGHT: f32 = 16.0;
+/// Width of the row labels, in characters
+const LABEL_CHARS: f32 = 7.0;
+/// Width of a unit column, in characters
+const COLUMN_CHARS: f32 = 13.0;
+
+/// First character and length of `field` in the text of a cell
+fn field_chars(field: Field) -> (usize, usize) {
+    match field {
+        Field::Note => (0, 3),
+        Field::Velocity => (4, 2),
+        Field::Volume => (7, 2),
+        Field::Pan => (10, 2),
+    }
+}
+
+fn field_at_char(char_idx: usize) -> Field {
+    match char_idx {
+        0..4 => Field::Note,
+        4..7 => Field::Velocity,
+        7..10 => Field::Volume,
+        _ => Field::Pan,
+    }
+}
+
+fn field_text(cell: &Cell, field: Field) -> Option<String> {
+    match field {
+        Field::Note if cell.off => Some("===".into()),
+        Field::Note => cell.note.map(|key| {
+            let info = KeyInfo::from_semitone((key / 256).clamp(0, 255) as u8);
+            format!("{:-<2}{}", info.notation(), info.octave)
+        }),
+        Field::Velocity => cell.velocity.map(|vel| format!("{vel:02X}")),
+        Field::Volume => cell.volume.map(|vol| format!("{vol:02X}")),
+        Field::Pan => cell.pan.map(|pan| format!("{pan:02X}")),
+    }
+}
+
+fn field_value(cell: &Cell, field: Field) -> Option<u8> {
+    match field {
+        Field::Note => None,
+        Field::Velocity => cell.velocity.map(|vel| vel.clamp(0, 255) as u8),
+        Field::Volume => cell.volume.map(|vol| vol.clamp(0, 255) as u8),
+        Field::Pan => cell.pan,
+    }
+}
+
+pub fn ui(
+    ui: &mut egui::Ui,
+    song: &mut SongState,
+    state: &mut TrackerState,
+    cmd: &mut CommandQueue,
+) {
+    top_ui(ui, state);
+    ui.separator();
+    let unit_count = usize::from(song.herd.units.len());
+    if unit_count == 0 {
+        ui.label("There are no units to show");
+        return;
+    }
+    let timing = song.song.master.timing;
+    let row_ticks = (u32::from(timing.ticks_per_beat) / u32::from(state.rows_per_beat)).max(1);
+    let rows_per_meas =
+        (usize::from(state.rows_per_beat) * usize::from(timing.beats_per_meas)).max(1);
+    let end = ptcow::timing::meas_to_tick(song.song.master.end_meas(), timing);
+    // An extra measure to write past the end in
+    let row_count = (end / row_ticks) as usize + rows_per_meas;
+    state.cursor.row = state.cursor.row.min(row_count - 1);
+    state.cursor.unit = state.cursor.unit.min(unit_count - 1);
+    let mut cursor_moved = false;
+    if !ui.egui_wants_keyboard_input() && !egui::Popup::is_any_open(ui.ctx()) {
+        let (moved, edited) = keyboard_input(ui, song, state, row_ticks, row_count, rows_per_meas);
+        cursor_moved = moved;
+        if edited {
+            cmd.push(Cmd::EventsReindexed);
+        }
+    }
+    let clock = ptcow::current_tick(&song.herd, &song.ins);
+    let playing_row = (clock / row_ticks) as usize;
+    let font = egui::FontId::monospace(FONT_SIZE);
+    let char_w = ui
+        .painter()
+        .layout_no_wrap("0".into(), font.clone(), egui::Color32::WHITE)
+        .size()
+        .x;
+    let label_w = LABEL_CHARS * char_w;
+    let column_w = COLUMN_CHARS * char_w;
+    egui::ScrollArea::both()
+        .auto_shrink(false)
+        .show_viewport(ui, |ui, viewport| {
+            let size = egui::vec2(
+                label_w + column_w * unit_count as f32,
+                ROW_HEIGHT * (row_count + 1) as f32,
+            );
+            let (rect, re) = ui.allocate_exact_size(size, egui::Sense::click());
+            // The first row is taken by the header
+            let row_rect = |row: usize| {
+                egui::Rect::from_min_size(
+                    rect.min + egui::vec2(0.0, ROW_HEIGHT * (row + 1) as f32),
+                    egui::vec2(size.x, ROW_HEIGHT),
+                )
+            };
+            let field_rect = |row: usize, unit: usize, field: Field| {
+                let (first, len) = field_chars(field);
+                let min = row_rect(row).min
+                    + egui::vec2(
+                        label_w + column_w * unit as f32 + first as f32 * char_w,
+                        0.0,
+                    );
+                egui::Rect::from_min_size(min, egui::vec2(len as f32 * char_w, ROW_HEIGHT))
+            };
+            if let Some(pos) = re.interact_pointer_pos()
+                && re.clicked()
+            {
+                let rel = pos - rect.min;
+                let row = (rel.y / ROW_HEIGHT) as usize;
+                if row > 0 && rel.x > label_w {
+                    let x = rel.x - label_w;
+                    state.cursor = Cursor {
+                        row: (row - 1).min(row_count - 1),
+                        unit: ((x / column_w) as usize).min(unit_count - 1),
+                        field: field_at_char(((x % column_w) / char_w) as usize),
+                        digit: 0,
+                    };
+                }
+            }
+            let cursor = state.cursor;
+            if cursor_moved {
+                // Leave room for the header
+                ui.scroll_to_rect(
+                    field_rect(cursor.row, cursor.unit, cursor.field)
+                        .expand2(egui::vec2(0.0, ROW_HEIGHT * 2.0)),
+                    None,
+                );
+            } else if state.follow && !song.pause {
+                ui.scroll_to_rect(row_rect(playing_row), Some(egui::Align::Center));
+            }
+            let first_row = ((viewport.min.y / ROW_HEIGHT) as usize).saturating_sub(1);
+            let last_row = ((viewport.max.y / ROW_HEIGHT).ceil() as usize).min(row_count);
+            let cells = read_cells(&song.song, row_ticks, first_row..last_row, unit_count);
+            let pnt = ui.painter_at(rect);
+            for (row, cells) in (first_row..).zip(&cells) {
+                let bg_rect = row_rect(row);
+                let bg = if row == playing_row {
+                    egui::Color32::from_rgb(40, 70, 40)
+                } else if row == cursor.row {
+                    egui::Color32::from_gray(50)
+                } else if row % rows_per_meas == 0 {
+                    egui::Color32::from_gray(38)
+                } else if row % usize::from(state.rows_per_beat) == 0 {
+                    egui::Color32::from_gray(28)
+                } else {
+                    egui::Color32::TRANSPARENT
+                };
+                pnt.rect_filled(bg_rect, 0.0, bg);
+                pnt.text(
+                    bg_rect.min,
+                    egui::Align2::LEFT_TOP,
+                    format!("{:>3}:{:02}", row / rows_per_meas, row % rows_per_meas),
+                    font.clone(),
+                    egui::Color32::GRAY,
+                );
+                for (unit, cell) in cells.iter().enumerate() {
+                    for field in Field::ALL {
+                        let text_rect = field_rect(row, unit, field);
+                        let (text, color) = match field_text(cell, field) {
+                            Some(text) if field == Field::Note => {
+                                (text, unit_color(UnitIdx(unit as u8)))
+                            }
+                            Some(text) => (text, egui::Color32::LIGHT_GRAY),
+                            None => {
+                                let len = field_chars(field).1;
+                                let blank = if field == Field::Note { "-" } else { "." };
+                                (blank.repeat(len), egui::Color32::from_gray(80))
+                            }
+                        };
+                        pnt.text(
+                            text_rect.min,
+                            egui::Align2::LEFT_TOP,
+                            text,
+                            font.clone(),
+                            color,
+                        );
+                    }
+                }
+            }
+            let cursor_rect = field_rect(cursor.row, cursor.unit, cursor.field);
+            pnt.rect_stroke(
+                cursor_rect,
+                0.0,
+                egui::Stroke::new(1.0, egui::Color32::YELLOW),
+                egui::StrokeKind::Outside,
+            );
+            if cursor.field != Field::Note {
+                let x = cursor_rect.min.x + f32::from(cursor.digit) * char_w;
+                pnt.hline(
+                    x..=x + char_w,
+                    cursor_rect.max.y - 1.0,
+                    egui::Stroke::new(1.0, egui::Color32::YELLOW),
+                );
+            }
+            // The header stays at the top of the view
+            let header_rect = egui::Rect::from_min_size(
+                rect.min + egui::vec2(0.0, viewport.min.y),
+                egui::vec2(size.x, ROW_HEIGHT),
+            );
+            pnt.rect_filled(header_rect, 0.0, egui::Color32::from_gray(20));
+            for (unit, unit_ref) in song.herd.units.enumerated() {
+                let min = header_rect.min + egui::vec2(label_w + column_w * f32::from(unit.0), 0.0);
+                let name: String = unit_ref
+                    .name
+                    .chars()
+                    .take(COLUMN_CHARS as usize - 1)
+                    .collect();
+                pnt.text(
+                    min,
+                    egui::Align2::LEFT_TOP,
+                    name,
+                    font.clone(),
+                    unit_color(unit),
+                );
+            }
+        });
+}
+
+fn top_ui(ui: &mut egui::Ui, state: &mut TrackerState) {
+    ui.horizontal(|ui| {
+        ui.label("Rows per beat");
+        ui.add(egui::DragValue::new(&mut state.rows_per_beat).range(1..=48));
+        ui.label("Octave");
+        ui.add(egui::DragValue::new(&mut state.octave).range(0..=8));
+        ui.label("Step");
+        ui.add(egui::DragValue::new(&mut state.edit_step).range(0..=64))
+            .on_hover_text("Rows to move down after entering something");
+        ui.label("Note length");
+        ui.add(
+            egui::DragValue::new(&mut state.note_rows)
+                .range(1..=256)
+                .suffix(" rows"),
+        );
+        ui.checkbox(&mut state.follow, "Follow playback");
+        help_popup_button(ui);
+    });
+}
+
+fn help_popup_button(ui: &mut egui::Ui) {
+    let re = ui.button("？ Help");
+    egui::Popup::menu(&re).show(|ui| {
+        egui::Grid::new("tracker_help_popup").show(ui, |ui| {
+            ui.label("Move cursor");
+            ui.input_label("Arrows, PgUp/PgDn, Home/End");
+            ui.end_row();
+            ui.label("Enter note (lower octave)");
+            ui.input_label("Z S X D C V G B H N J M");
+            ui.end_row();
+            ui.label("Enter note (upper octave)");
+            ui.input_label("Q 2 W 3 E R 5 T 6 Y 7 U");
+            ui.end_row();
+            ui.label("Note off");
+            ui.input_label("1");
+            ui.end_row();
+            ui.label("Enter velocity, volume or pan");
+            ui.input_label("0-9 A-F");
+            ui.end_row();
+            ui.label("Clear");
+            ui.input_label("Del");
+            ui.end_row();
+        });
+    });
+}
+
+/// Move the cursor and edit the events by keyboard.
+///
+/// Returns whether the cursor moved, and whether the events were edited.
+fn keyboard_input(
+    ui: &egui::Ui,
+    song: &mut SongState,
+    state: &mut TrackerState,
+    row_ticks: u32,
+    row_count: usize,
+    rows_per_meas: usize,
+) -> (bool, bool) {
+    let keys: Vec<egui::Key> = ui.input(|inp| {
+        inp.events
+            .iter()
+            .filter_map(|ev| match ev {
+                egui::Event::Key {
+                    key,
+                    pressed: true,
+                    modifiers,
+                    ..
+                } if !(modifiers.ctrl || modifiers.alt || modifiers.command) => Some(*key),
+                _ => None,
+            })
+            .collect()
+    });
+    let unit_count = usize::from(song.herd.units.len());
+    let mut moved = false;
+    let mut edited = false;
+    for key in keys {
+        let cursor = &mut state.cursor;
+        let unit = UnitIdx(cursor.unit as u8);
+        let field_idx = Field::ALL
+            .iter()
+            .position(|&f| f == cursor.field)
+            .unwrap_or(0);
+        let last_row = row_count - 1;
+        let edit_step = state.edit_step;
+        let advance = |cursor: &mut Cursor| {
+            cursor.row = (cursor.row + edit_step).min(last_row);
+            cursor.digit = 0;
+        };
+        match key {
+            egui::Key::ArrowUp => cursor.row = cursor.row.saturating_sub(1),
+            egui::Key::ArrowDown => cursor.row = (cursor.row + 1).min(last_row),
+            egui::Key::PageUp => cursor.row = cursor.row.saturating_sub(rows_per_meas),
+            egui::Key::PageDown => cursor.row = (cursor.row + rows_per_meas).min(last_row),
+            egui::Key::Home => cursor.row = 0,
+            egui::Key::End => cursor.row = last_row,
+            egui::Key::ArrowLeft => {
+                if field_idx > 0 {
+                    cursor.field = Field::ALL[field_idx - 1];
+                } else if cursor.unit > 0 {
+                    cursor.unit -= 1;
+                    cursor.field = Field::Pan;
+                }
+            }
+            egui::Key::ArrowRight => {
+                if field_idx + 1 < Field::ALL.len() {
+                    cursor.field = Field::ALL[field_idx + 1];
+                } else if cursor.unit + 1 < unit_count {
+                    cursor.unit += 1;
+                    cursor.field = Field::Note;
+                }
+            }
+            egui::Key::Delete => {
+                clear(&mut song.song, row_ticks, cursor.row, unit, cursor.field);
+                edited = true;
+                advance(cursor);
+            }
+            NOTE_OFF_KEY if cursor.field == Field::Note => {
+                write_off(&mut song.song, row_ticks, cursor.row, unit);
+                edited = true;
+                advance(cursor);
+            }
+            _ if cursor.field == Field::Note => {
+                let Some(semitones) = PIANO_KEYS.iter().position(|&k| k == key) else {
+                    continue;
+                };
+                // Same octave numbering as `KeyInfo`
+                let c_semitone = (i32::from(state.octave) + 4) * 12 - 9;
+                let note_key = (c_semitone + semitones as i32) * 256;
+                let duration = state.note_rows * row_ticks;
+                write_note(
+                    &mut song.song,
+                    row_ticks,
+                    cursor.row,
+                    unit,
+                    note_key,
+                    duration,
+                );
+                freeplay_assist_play_note(song, unit, note_key, duration);
+                edited = true;
+                advance(cursor);
+            }
+            _ => {
+                let Some(digit) = hex_digit(key) else {
+                    continue;
+                };
+                let cells = read_cells(
+                    &song.song,
+                    row_ticks,
+                    cursor.row..cursor.row + 1,
+                    unit_count,
+                );
+                let old = field_value(&cells[0][cursor.unit], cursor.field).unwrap_or(0);
+                let value = if cursor.digit == 0 {
+                    (digit << 4) | (old & 0x0F)
+                } else {
+                    (old & 0xF0) | digit
+                };
+                write_value(
+                    &mut song.song,
+                    row_ticks,
+                    cursor.row,
+                    unit,
+                    cursor.field,
+                    value,
+                );
+                edited = true;
+                if cursor.digit == 0 {
+                    cursor.digit = 1;
+                } else {
+                    advance(cursor);
+                }
+                // The digit doesn't move with the cursor
+                moved = true;
+                continue;
+            }
+        }
+        state.cursor.digit = 0;
+        moved = true;
+    }
+    (moved, edited)
+}
+
+fn hex_digit(key: egui::Key) -> Option<u8> {
+    use egui::Key as K;
+    let digits = [
+        K::Num0,
+        K::Num1,
+        K::Num2,
+        K::Num3,
+        K::Num4,
+        K::Num5,
+        K::Num6,
+        K::Num7,
+        K::Num8,
+        K::Num9,
+        K::A,
+        K::B,
+        K::C,
+        K::D,
+        K::E,
+        K::F,
+    ];
+    digits
+        .iter()
+        .position(|&k| k == key)
+        .map(|digit| digit as u8)
+}
@@ -103,6 +103,7 @@ pub fn ui(
     ui_state: &mut VoicesUiState,
     shared: &mut SharedUiState,
     app_cmd: &mut CommandQueue,
+    other_docs: &[(usize, String)],
     #[cfg(not(target_arch = "wasm32"))] app_file_dia: &mut egui_file_dialog::FileDialog,
 ) {
     let mut op = None;
@@ -215,6 +216,7 @@ pub fn ui(
             &mut song.herd,
             &mut song.freeplay_assist_units[0],
             app_cmd,
+            other_docs,
         );
     }
     if let Some(op) = op {
@@ -345,6 +347,7 @@ fn voice_ui(
     herd: &mut ptcow::Herd,
     voice_test_unit: &mut ptcow::Unit,
     app_cmd: &mut CommandQueue,
+    other_docs: &[(usize, String)],
 ) {
     ui.horizontal(|ui| {
         ui.text_edit_singleline(&mut voice.name);
@@ -412,6 +415,18 @@ fn voice_ui(
             }
             _ => {}
         }
+        if !other_docs.is_empty() {
+            ui.menu_button("📋 Copy to document", |ui| {
+                for (doc, title) in other_docs {
+                    if ui.button(title).clicked() {
+                        app_cmd.push(Cmd::CopyVoiceToDocument {
+                            voice: idx,
+                            doc: *doc,
+                        });
+                    }
+                }
+            });
+        }
     });
 
     ui.horizontal(|ui| {
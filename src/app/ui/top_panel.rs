@@ -7,11 +7,12 @@ use {
                 Tab, UiState,
                 file_ops::FileOp,
                 modal::Modal,
-                piano_freeplay_ui,
-                windows::{LogWindow, TitleAndCommentWindow, Windows},
+                piano_freeplay_ui, transpose_ui,
+                windows::{LogWindow, SongCheckWindow, TitleAndCommentWindow, Windows},
             },
         },
         audio_out::{OutParams, prepare_song},
+        timing_ops::EventTarget,
     },
     eframe::egui::{
         self, KeyboardShortcut,
@@ -56,6 +57,8 @@ pub fn top_panel(app: &mut crate::app::App, ui: &mut egui::Ui) {
         k_f8,
         k_f9,
         k_f10,
+        k_f11,
+        k_f12,
     ] = ui.input_mut(|inp| {
         [
             inp.consume_shortcut(&NEW_SHORTCUT),
@@ -70,6 +73,8 @@ pub fn top_panel(app: &mut crate::app::App, ui: &mut egui::Ui) {
             inp.key_pressed(egui::Key::F8),
             inp.key_pressed(egui::Key::F9),
             inp.key_pressed(egui::Key::F10),
+            inp.key_pressed(egui::Key::F11),
+            inp.key_pressed(egui::Key::F12),
         ]
     });
     let [mut bt_open, mut bt_reload, mut bt_save] = [false; _];
@@ -148,6 +153,8 @@ pub fn top_panel(app: &mut crate::app::App, ui: &mut egui::Ui) {
         tab(Tab::Voices, "📢 Voices", "F8", k_f8);
         tab(Tab::Unit, "🐄 Unit", "F9", k_f9);
         tab(Tab::Effects, "🔊 Effects", "F10", k_f10);
+        tab(Tab::Drums, "🥁 Drums", "F11", k_f11);
+        tab(Tab::Tracker, "▤ Tracker", "F12", k_f12);
         // Do some init logic for some tabs.
         // TODO: Can probably done in a cleaner way
         if prev_tab != app.ui_state.tab {
@@ -196,14 +203,16 @@ pub fn top_panel(app: &mut crate::app::App, ui: &mut egui::Ui) {
                 .update_while_editing(false),
         );
         ui.separator();
+        #[cfg(not(target_arch = "wasm32"))]
+        let file_dia_open = app.file_dia.state() == &egui_file_dialog::DialogState::Open;
+        #[cfg(target_arch = "wasm32")]
+        let file_dia_open = false;
         piano_freeplay_ui(
             &mut song_g,
             ui,
             &mut app.ui_state.shared,
-            #[cfg(not(target_arch = "wasm32"))]
-            (app.file_dia.state() == &egui_file_dialog::DialogState::Open),
-            #[cfg(target_arch = "wasm32")]
-            false,
+            // The tracker tab enters notes with the same keys
+            app.ui_state.tab == Tab::Tracker || file_dia_open,
             true,
         );
     });
@@ -281,6 +290,9 @@ fn song_menu_ui(
             song.song.events.clear();
         }
     });
+    ui.menu_button("Transpose", |ui| {
+        transpose_ui(ui, || EventTarget::All, app_cmd);
+    });
     if ui.button("Remove unused voices").clicked() {
         let used_voices = used_voices(&song.song.events);
         let mut idx = VoiceIdx(0);
@@ -312,6 +324,13 @@ fn song_menu_ui(
     if ui.button("Title and comment").clicked() {
         app_ui_state.windows.toggle::<TitleAndCommentWindow>();
     }
+    if ui
+        .button("Check song")
+        .on_hover_text("Find problems like events of missing units, and fix them")
+        .clicked()
+    {
+        app_ui_state.windows.toggle::<SongCheckWindow>();
+    }
     ui.separator();
     if let Some(cmd) = app_cmd.last() {
         if ui
@@ -343,6 +362,20 @@ fn file_menu_ui(
     {
         app_cmd.push(Cmd::ClearProject);
     }
+    if ui
+        .button("New tab")
+        .on_hover_text("Open an empty project in a new document tab")
+        .clicked()
+    {
+        app_cmd.push(Cmd::NewDocument);
+    }
+    if ui
+        .button("Restore replaced project")
+        .on_hover_text("Restore the project as it was before the last \"New\", \"Open\" or import")
+        .clicked()
+    {
+        app_cmd.push(Cmd::RecoverSnapshot(crate::autosave::Slot::BeforeClear));
+    }
     *bt_open = ui
         .add(egui::Button::new("Open").shortcut_text(ui.format_shortcut(&OPEN_SHORTCUT)))
         .clicked();
@@ -394,30 +427,45 @@ fn file_menu_ui(
         app_cmd.push(Cmd::FilePrompt(FileOp::SaveProjAs));
     }
     ui.separator();
+    if ui
+        .button("Merge project")
+        .on_hover_text("Add the units, voices and effects of another project to this one")
+        .clicked()
+    {
+        app_cmd.push(Cmd::FilePrompt(FileOp::MergeProj));
+    }
     if ui.button("Import midi").clicked() {
         app_cmd.push(Cmd::FilePrompt(FileOp::ImportMidi));
     }
+    if ui.button("Midi drum map").clicked() {
+        windows.toggle::<crate::app::ui::windows::MidiDrumMapWindow>();
+    }
     if ui.button("Import PiyoPiyo").clicked() {
         app_cmd.push(Cmd::FilePrompt(FileOp::ImportPiyoPiyo));
     }
     if ui.button("Import Organya").clicked() {
         app_cmd.push(Cmd::FilePrompt(FileOp::ImportOrganya));
     }
+    if ui.button("Import tracker module").clicked() {
+        app_cmd.push(Cmd::FilePrompt(FileOp::ImportTracker));
+    }
+    if ui.button("Import MML").clicked() {
+        app_cmd.push(Cmd::FilePrompt(FileOp::ImportMml));
+    }
     ui.separator();
     if ui.button("Preferences").clicked() {
         windows.toggle::<crate::app::ui::windows::PreferencesWindow>();
     }
     ui.separator();
-
-    #[cfg(not(target_arch = "wasm32"))]
+    if ui.button("Export MML").clicked() {
+        app_cmd.push(Cmd::FilePrompt(FileOp::ExportMml));
+    }
+    if ui.button("Export MusicXML").clicked() {
+        app_cmd.push(Cmd::FilePrompt(FileOp::ExportMusicXml));
+    }
     if ui.button("Export wav").clicked() {
         app_cmd.push(Cmd::FilePrompt(FileOp::ExportWav));
     }
-    #[cfg(target_arch = "wasm32")]
-    ui.hyperlink_to(
-        " Get the desktop version for .wav export and more",
-        "https://github.com/crumblingstatue/ptcowlage?tab=readme-ov-file#building",
-    );
     ui.separator();
     if cfg!(not(target_arch = "wasm32")) {
         if ui.button("Quit").clicked() {
@@ -445,8 +493,10 @@ fn timing_popup_ui(
         )
         .changed();
     ui.end_row();
-    ui.label("Ticks per beat")
-        .on_hover_text("How many clock ticks happen during a beat");
+    ui.label("Ticks per beat").on_hover_text(
+        "How many clock ticks happen during a beat\n\
+         (Existing events stay on the same ticks, use Resolution to rescale them)",
+    );
     timing_changed ^= ui
         .add(
             egui::DragValue::new(&mut song.song.master.timing.ticks_per_beat)
@@ -471,6 +521,22 @@ fn timing_popup_ui(
         );
     }
     ui.end_row();
+    ui.label("Resolution")
+        .on_hover_text("Change ticks per beat, rescaling all events to keep the rhythm");
+    ui.horizontal(|ui| {
+        let id = egui::Id::new("resolution_ticks_per_beat");
+        let current = song.song.master.timing.ticks_per_beat;
+        let mut ticks_per_beat = ui.data(|data| data.get_temp(id)).unwrap_or(current);
+        ui.add(egui::DragValue::new(&mut ticks_per_beat).range(1..=u16::MAX));
+        if ui
+            .add_enabled(ticks_per_beat != current, egui::Button::new("Rescale"))
+            .clicked()
+        {
+            app_cmd.push(Cmd::ChangeResolution(ticks_per_beat));
+        }
+        ui.data_mut(|data| data.insert_temp(id, ticks_per_beat));
+    });
+    ui.end_row();
     h_sep(ui, full_w);
     ui.label("Samples per tick");
     ui.add(egui::DragValue::new(&mut song.ins.samples_per_tick).speed(0.01));
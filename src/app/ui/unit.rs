@@ -6,12 +6,13 @@ use {
             command_queue::{Cmd, CommandQueue},
             poly_migrate_single,
             ui::{
-                SharedUiState, Tab, group_idx_slider, img, modal::Modal, tabs::events::Filter,
-                voice_img,
+                SharedUiState, Tab, group_idx_slider, humanize_ui, img, modal::Modal, quantize_ui,
+                tabs::events::Filter, transpose_ui, voice_img,
             },
         },
         audio_out::SongState,
         egui_ext::ImageExt as _,
+        timing_ops::EventTarget,
     },
     eframe::egui::{self, AtomExt},
     ptcow::{
@@ -78,6 +79,15 @@ pub fn unit_ui(
                 *cmd = Some(UnitsCmd::SplitByKey { idx });
             }
         });
+        ui.menu_button("Quantize", |ui| {
+            quantize_ui(ui, || EventTarget::Unit(idx), app_cmd);
+        });
+        ui.menu_button("Humanize", |ui| {
+            humanize_ui(ui, || EventTarget::Unit(idx), app_cmd);
+        });
+        ui.menu_button("Transpose", |ui| {
+            transpose_ui(ui, || EventTarget::Unit(idx), app_cmd);
+        });
     });
 
     ui.separator();
@@ -1,6 +1,14 @@
 use {
-    crate::{app::Preferences, audio_out::SongState},
+    crate::{
+        app::{
+            Preferences,
+            command_queue::{Cmd, CommandQueue},
+        },
+        audio_out::SongState,
+        song_check::{Problem, ProblemKind},
+    },
     eframe::egui,
+    egui_toast::ToastKind,
     rustc_hash::FxHashMap,
     std::{any::TypeId, collections::hash_map::Entry},
 };
@@ -19,13 +27,19 @@ impl Windows {
             self.inner.remove(&type_id);
         }
     }
-    pub fn update(&mut self, ctx: &egui::Context, song: &mut SongState, prefs: &mut Preferences) {
+    pub fn update(
+        &mut self,
+        ctx: &egui::Context,
+        song: &mut SongState,
+        prefs: &mut Preferences,
+        cmd: &mut CommandQueue,
+    ) {
         self.inner.retain(|_typeid, window| {
             let mut open = true;
             egui::Window::new(window.title())
                 .open(&mut open)
                 .show(ctx, |ui| {
-                    window.update(ui, song, prefs);
+                    window.update(ui, song, prefs, cmd);
                 });
             open
         });
@@ -34,7 +48,13 @@ impl Windows {
 
 pub trait Window {
     fn title(&self) -> &str;
-    fn update(&mut self, ui: &mut egui::Ui, song: &mut SongState, prefs: &mut Preferences);
+    fn update(
+        &mut self,
+        ui: &mut egui::Ui,
+        song: &mut SongState,
+        prefs: &mut Preferences,
+        cmd: &mut CommandQueue,
+    );
 }
 
 #[derive(Default)]
@@ -44,7 +64,13 @@ impl Window for TitleAndCommentWindow {
     fn title(&self) -> &'static str {
         "Title and comment"
     }
-    fn update(&mut self, ui: &mut egui::Ui, song: &mut SongState, _prefs: &mut Preferences) {
+    fn update(
+        &mut self,
+        ui: &mut egui::Ui,
+        song: &mut SongState,
+        _prefs: &mut Preferences,
+        _cmd: &mut CommandQueue,
+    ) {
         ui.strong("Title");
         ui.text_edit_singleline(&mut song.song.text.name);
         ui.strong("Comment");
@@ -60,7 +86,13 @@ impl Window for LogWindow {
         "Log viewer"
     }
 
-    fn update(&mut self, ui: &mut egui::Ui, _song: &mut SongState, _prefs: &mut Preferences) {
+    fn update(
+        &mut self,
+        ui: &mut egui::Ui,
+        _song: &mut SongState,
+        _prefs: &mut Preferences,
+        _cmd: &mut CommandQueue,
+    ) {
         egui_logger::logger_ui().show(ui);
     }
 }
@@ -76,7 +108,13 @@ impl Window for PreferencesWindow {
         "Preferences"
     }
 
-    fn update(&mut self, ui: &mut egui::Ui, _song: &mut SongState, prefs: &mut Preferences) {
+    fn update(
+        &mut self,
+        ui: &mut egui::Ui,
+        _song: &mut SongState,
+        prefs: &mut Preferences,
+        _cmd: &mut CommandQueue,
+    ) {
         #[cfg(not(target_arch = "wasm32"))]
         ui.horizontal(|ui| {
             ui.label("Japanese fallback font");
@@ -93,6 +131,16 @@ impl Window for PreferencesWindow {
             &mut prefs.midi_auto_poly_migrate,
             "Auto poly-migrate on midi import",
         );
+        ui.checkbox(
+            &mut prefs.compact_on_save,
+            "Remove redundant events on save",
+        )
+        .on_hover_text("Events that set a unit to the value it already has");
+        #[cfg(not(target_arch = "wasm32"))]
+        ui.checkbox(
+            &mut prefs.watch_open_file,
+            "Reload opened file when it changes on disk",
+        );
         #[cfg(not(target_arch = "wasm32"))]
         {
             self.file_dia.update(ui.ctx());
@@ -105,3 +153,162 @@ impl Window for PreferencesWindow {
         }
     }
 }
+
+#[derive(Default)]
+pub struct MidiDrumMapWindow;
+
+impl Window for MidiDrumMapWindow {
+    fn title(&self) -> &'static str {
+        "Midi drum map"
+    }
+
+    fn update(
+        &mut self,
+        ui: &mut egui::Ui,
+        _song: &mut SongState,
+        prefs: &mut Preferences,
+        _cmd: &mut CommandQueue,
+    ) {
+        use crate::midi::{DrumKind, GM_DRUM_FIRST_NOTE, GM_DRUM_NAMES};
+        let map = &mut prefs.midi_drum_map;
+        ui.label("Drum channel notes are split into one unit per drum kind on midi import");
+        if ui.button("Reset to defaults").clicked() {
+            *map = crate::midi::DrumMap::default();
+        }
+        ui.separator();
+        ui.columns(2, |cols| {
+            cols[0].strong("Notes");
+            egui::ScrollArea::vertical()
+                .id_salt("notes")
+                .max_height(400.0)
+                .show(&mut cols[0], |ui| {
+                    egui::Grid::new("notes_grid").striped(true).show(ui, |ui| {
+                        for (i, name) in GM_DRUM_NAMES.iter().enumerate() {
+                            let note = usize::from(GM_DRUM_FIRST_NOTE) + i;
+                            ui.label(format!("{note} {name}"));
+                            let kind = &mut map.notes[note];
+                            egui::ComboBox::new(("drum_kind", note), "")
+                                .selected_text(kind.map_or("(skip)", DrumKind::label))
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(kind, None, "(skip)");
+                                    for k in DrumKind::ALL {
+                                        ui.selectable_value(kind, Some(k), k.label());
+                                    }
+                                });
+                            ui.end_row();
+                        }
+                    });
+                });
+            cols[1].strong("Organya drum samples");
+            egui::Grid::new("samples_grid")
+                .striped(true)
+                .show(&mut cols[1], |ui| {
+                    for kind in DrumKind::ALL {
+                        ui.label(kind.label());
+                        ui.add(
+                            egui::DragValue::new(map.sample_for_mut(kind))
+                                .range(0..=crate::organya::DRUM_SAMPLE_COUNT - 1),
+                        );
+                        ui.end_row();
+                    }
+                });
+        });
+    }
+}
+
+#[derive(Default)]
+pub struct SongCheckWindow {
+    /// Problems found by the last check, checked again when `None`
+    problems: Option<Vec<Problem>>,
+    /// [`crate::song_check::fingerprint`] of the song at the last check
+    fingerprint: u64,
+}
+
+impl Window for SongCheckWindow {
+    fn title(&self) -> &'static str {
+        "Check song"
+    }
+
+    fn update(
+        &mut self,
+        ui: &mut egui::Ui,
+        song: &mut SongState,
+        _prefs: &mut Preferences,
+        cmd: &mut CommandQueue,
+    ) {
+        let unit_count = song.herd.units.len();
+        let voice_count = song.ins.voices.len();
+        // The song might have been edited, or replaced by another one
+        let fingerprint = crate::song_check::fingerprint(&song.song, unit_count, voice_count);
+        if fingerprint != self.fingerprint {
+            self.fingerprint = fingerprint;
+            self.problems = None;
+        }
+        let problems = self
+            .problems
+            .get_or_insert_with(|| crate::song_check::check(&song.song, unit_count, voice_count));
+        let mut fix_kinds = Vec::new();
+        if problems.is_empty() {
+            ui.label("No problems found");
+        } else if ui
+            .button("Fix all")
+            .on_hover_text("Fix every kind of problem, in the order listed")
+            .clicked()
+        {
+            fix_kinds.extend(ProblemKind::ALL);
+        }
+        ui.separator();
+        egui::Grid::new("problem_kinds").show(ui, |ui| {
+            for kind in ProblemKind::ALL {
+                let count = problems
+                    .iter()
+                    .filter(|problem| problem.kind == kind)
+                    .count();
+                if count == 0 {
+                    continue;
+                }
+                ui.label(format!("{} × {count}", kind.name()));
+                if ui
+                    .button("Fix")
+                    .on_hover_text(kind.fix_description())
+                    .clicked()
+                {
+                    fix_kinds.push(kind);
+                }
+                ui.end_row();
+            }
+        });
+        if !problems.is_empty() {
+            ui.separator();
+            egui::ScrollArea::vertical()
+                .max_height(300.0)
+                .show(ui, |ui| {
+                    for problem in problems.iter() {
+                        ui.horizontal(|ui| {
+                            ui.strong(problem.kind.name());
+                            ui.label(&problem.message);
+                            if let Some(index) = problem.event
+                                && ui.link(format!("Event {index}")).clicked()
+                            {
+                                cmd.push(Cmd::OpenEventInEventsTab { index });
+                            }
+                        });
+                    }
+                });
+        }
+        if !fix_kinds.is_empty() {
+            let before = song.song.events.len();
+            for kind in fix_kinds {
+                crate::song_check::fix(&mut song.song, kind, unit_count, voice_count);
+            }
+            crate::pxtone_misc::reset_loop_points(song);
+            cmd.push(Cmd::EventsReindexed);
+            let removed = before.saturating_sub(song.song.events.len());
+            cmd.toast(
+                ToastKind::Success,
+                format!("Fixed problems, removing {removed} events"),
+                3.0,
+            );
+        }
+    }
+}
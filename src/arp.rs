@@ -0,0 +1,381 @@
+//! Arpeggiator, for breaking chords up into patterns of notes
+
+use {
+    crate::{
+        pxtone_misc::{HeldChord, held_chords, parse_chord_symbol},
+        timing_ops::{EventTarget, NoteValue, Rng, set_note_values},
+    },
+    anyhow::Context as _,
+    ptcow::{Event, EventPayload, Key, Song, UnitIdx},
+    rustc_hash::{FxHashMap, FxHashSet},
+    std::collections::BTreeMap,
+};
+
+/// Order to play the notes of a chord in
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ArpPattern {
+    Up,
+    Down,
+    UpDown,
+    Random,
+    /// Notes by their number (1 is the lowest), separated by spaces. `-` is a rest.
+    Custom(String),
+}
+
+impl ArpPattern {
+    pub const PRESETS: [Self; 4] = [Self::Up, Self::Down, Self::UpDown, Self::Random];
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Up => "Up",
+            Self::Down => "Down",
+            Self::UpDown => "Up-down",
+            Self::Random => "Random",
+            Self::Custom(_) => "Custom",
+        }
+    }
+}
+
+/// Settings for arpeggiating chords
+#[derive(Clone)]
+pub struct Arpeggio {
+    pub pattern: ArpPattern,
+    /// Notes per beat
+    pub rate: u8,
+    /// Length of each note, relative to the time until the next one
+    pub gate: f32,
+    /// How many octaves the pattern goes up through
+    pub octaves: u8,
+    /// Seed for the `Random` pattern
+    pub seed: u64,
+}
+
+impl Default for Arpeggio {
+    fn default() -> Self {
+        Self {
+            pattern: ArpPattern::Up,
+            rate: 4,
+            gate: 0.5,
+            octaves: 1,
+            seed: 0,
+        }
+    }
+}
+
+/// A note made by the arpeggiator
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ArpNote {
+    pub tick: u32,
+    pub duration: u32,
+    pub key: Key,
+}
+
+impl Arpeggio {
+    /// Arpeggiate `chords`, restarting the pattern at every chord
+    pub fn notes(&self, chords: &[HeldChord], ticks_per_beat: u16) -> Vec<ArpNote> {
+        let step = (u32::from(ticks_per_beat) / u32::from(self.rate.max(1))).max(1);
+        let duration = ((f64::from(step) * f64::from(self.gate)).round() as u32).max(1);
+        let custom: Vec<Option<usize>> = match &self.pattern {
+            ArpPattern::Custom(text) => text
+                .split_whitespace()
+                .filter_map(|token| match token {
+                    "-" => Some(None),
+                    _ => token
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .map(|n| Some(n - 1)),
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut rng = Rng(self.seed);
+        let mut notes = Vec::new();
+        for chord in chords {
+            let mut tones: Vec<Key> = (0..i32::from(self.octaves.max(1)))
+                .flat_map(|octave| chord.keys.iter().map(move |key| key + octave * 12 * 256))
+                .collect();
+            tones.sort_unstable();
+            tones.dedup();
+            let n = tones.len();
+            if n == 0 {
+                continue;
+            }
+            for (i, tick) in (chord.start..chord.end).step_by(step as usize).enumerate() {
+                let tone = match &self.pattern {
+                    ArpPattern::Up => i % n,
+                    ArpPattern::Down => n - 1 - i % n,
+                    ArpPattern::UpDown if n < 2 => 0,
+                    ArpPattern::UpDown => {
+                        let pos = i % (2 * n - 2);
+                        if pos < n { pos } else { 2 * n - 2 - pos }
+                    }
+                    ArpPattern::Random => (rng.next() % n as u64) as usize,
+                    ArpPattern::Custom(_) => {
+                        let Some(&Some(tone)) = custom.get(i % custom.len().max(1)) else {
+                            continue;
+                        };
+                        tone % n
+                    }
+                };
+                notes.push(ArpNote {
+                    tick,
+                    duration: duration.min(chord.end - tick),
+                    key: tones[tone],
+                });
+            }
+        }
+        notes
+    }
+}
+
+/// Where the chords to arpeggiate come from
+pub enum ArpSource {
+    /// The chords held by these notes
+    Notes(EventTarget),
+    /// Chord symbols separated by spaces, like "C Am F G7". `-` holds the chord before it.
+    Progression {
+        text: String,
+        start_meas: u32,
+        beats_per_chord: u8,
+        /// Octave the roots are in
+        octave: i32,
+    },
+}
+
+impl ArpSource {
+    pub fn chords(&self, song: &Song) -> anyhow::Result<Vec<HeldChord>> {
+        let timing = song.master.timing;
+        match self {
+            Self::Notes(target) => Ok(held_chords(
+                &song.events,
+                |idx, ev| target.contains(idx, ev, timing),
+                0,
+                u32::MAX,
+            )),
+            Self::Progression {
+                text,
+                start_meas,
+                beats_per_chord,
+                octave,
+            } => {
+                // Same octave numbering as `KeyInfo`
+                let c_semitone = (octave + 4) * 12 - 9;
+                let len = u32::from(timing.ticks_per_beat) * u32::from(*beats_per_chord);
+                let mut tick = ptcow::timing::meas_to_tick(*start_meas, timing);
+                let mut chords: Vec<HeldChord> = Vec::new();
+                for symbol in text.split_whitespace() {
+                    if symbol == "-" {
+                        let last = chords.last_mut().context("Nothing to hold before `-`")?;
+                        last.end += len;
+                    } else {
+                        let keys = parse_chord_symbol(symbol, c_semitone)
+                            .with_context(|| format!("Unknown chord: {symbol}"))?;
+                        chords.push(HeldChord {
+                            start: tick,
+                            end: tick + len,
+                            keys: keys.to_vec(),
+                        });
+                    }
+                    tick += len;
+                }
+                Ok(chords)
+            }
+        }
+    }
+}
+
+/// Write `notes` to `unit`, returning how many there were
+///
+/// If `replace` is set, notes of `source` are removed first, along with their key and velocity.
+pub fn write_notes(
+    song: &mut Song,
+    unit: UnitIdx,
+    notes: &[ArpNote],
+    source: &ArpSource,
+    replace: bool,
+) -> usize {
+    if replace && let ArpSource::Notes(target) = source {
+        remove_notes(song, target);
+    }
+    let keys: FxHashMap<u32, Key> = notes.iter().map(|note| (note.tick, note.key)).collect();
+    for note in notes {
+        song.events.push(Event {
+            payload: EventPayload::On {
+                duration: note.duration,
+            },
+            unit,
+            tick: note.tick,
+        });
+    }
+    song.events.sort();
+    let new_notes: BTreeMap<usize, Key> = song
+        .events
+        .iter()
+        .enumerate()
+        .filter(|(_, ev)| ev.unit == unit && matches!(ev.payload, EventPayload::On { .. }))
+        .filter_map(|(idx, ev)| Some((idx, *keys.get(&ev.tick)?)))
+        .collect();
+    set_note_values(
+        song,
+        &EventTarget::Indices(new_notes.keys().copied().collect()),
+        NoteValue::Key,
+        |idx, _| new_notes[&idx],
+    );
+    song.events.sort();
+    song.recalculate_length();
+    notes.len()
+}
+
+/// Remove the notes of `target`, and the key and velocity events of the notes
+///
+/// Later notes that got their key or velocity from the removed events keep them.
+fn remove_notes(song: &mut Song, target: &EventTarget) {
+    let timing = song.master.timing;
+    let kinds = [NoteValue::Key, NoteValue::Velocity];
+    let old_values = kinds.map(|kind| kind.of_notes(&song.events));
+    let mut removed = FxHashSet::default();
+    // Values of the notes that stay, by unit and tick
+    let mut kept = FxHashMap::default();
+    for (idx, ev) in song.events.iter().enumerate() {
+        if !matches!(ev.payload, EventPayload::On { .. }) {
+            continue;
+        }
+        let at = (ev.unit.0, ev.tick);
+        if target.contains(idx, ev, timing) {
+            removed.insert(idx);
+        } else {
+            kept.insert(at, old_values.each_ref().map(|values| values[&idx]));
+        }
+    }
+    let removed_at: FxHashSet<_> = removed
+        .iter()
+        .map(|&idx| (song.events[idx].unit.0, song.events[idx].tick))
+        .filter(|at| !kept.contains_key(at))
+        .collect();
+    let mut idx = 0;
+    song.events.retain(|ev| {
+        let remove = match ev.payload {
+            EventPayload::On { .. } => removed.contains(&idx),
+            EventPayload::Key(_) | EventPayload::Velocity(_) => {
+                removed_at.contains(&(ev.unit.0, ev.tick))
+            }
+            _ => false,
+        };
+        idx += 1;
+        !remove
+    });
+    for (i, kind) in kinds.into_iter().enumerate() {
+        let values = kind.of_notes(&song.events);
+        let changed: BTreeMap<usize, i32> = song
+            .events
+            .iter()
+            .enumerate()
+            .filter(|(_, ev)| matches!(ev.payload, EventPayload::On { .. }))
+            .filter_map(|(idx, ev)| {
+                let old = kept.get(&(ev.unit.0, ev.tick))?[i];
+                (values.get(&idx) != Some(&old)).then_some((idx, old))
+            })
+            .collect();
+        set_note_values(
+            song,
+            &EventTarget::Indices(changed.keys().copied().collect()),
+            kind,
+            |idx, _| changed[&idx],
+        );
+        song.events.sort();
+    }
+}
+
+#[test]
+fn test_arpeggio() {
+    let c = ptcow::DEFAULT_KEY + 3 * 256;
+    let chords = [HeldChord {
+        start: 0,
+        end: 480,
+        keys: vec![c, c + 4 * 256, c + 7 * 256],
+    }];
+    let arp = Arpeggio::default();
+    let notes = arp.notes(&chords, 480);
+    let ticks: Vec<_> = notes.iter().map(|note| note.tick).collect();
+    assert_eq!(ticks, [0, 120, 240, 360]);
+    assert!(notes.iter().all(|note| note.duration == 60));
+    let keys: Vec<_> = notes.iter().map(|note| (note.key - c) / 256).collect();
+    assert_eq!(keys, [0, 4, 7, 0]);
+    let arp = Arpeggio {
+        pattern: ArpPattern::UpDown,
+        rate: 8,
+        octaves: 2,
+        ..Arpeggio::default()
+    };
+    let keys: Vec<_> = arp
+        .notes(&chords, 480)
+        .iter()
+        .map(|note| (note.key - c) / 256)
+        .collect();
+    assert_eq!(keys, [0, 4, 7, 12, 16, 19, 16, 12]);
+    let arp = Arpeggio {
+        pattern: ArpPattern::Custom("1 - 3 5".into()),
+        ..Arpeggio::default()
+    };
+    let keys: Vec<_> = arp
+        .notes(&chords, 480)
+        .iter()
+        .map(|note| (note.key - c) / 256)
+        .collect();
+    assert_eq!(keys, [0, 7, 4]);
+}
+
+#[test]
+fn test_write_notes() {
+    let ev = |tick, unit, payload| Event {
+        payload,
+        unit: UnitIdx(unit),
+        tick,
+    };
+    let key = ptcow::DEFAULT_KEY + 3 * 256;
+    let mut song = Song::default();
+    song.events.extend([
+        ev(0, 0, EventPayload::Key(key)),
+        ev(0, 0, EventPayload::Velocity(50)),
+        ev(0, 0, EventPayload::On { duration: 100 }),
+        ev(100, 0, EventPayload::On { duration: 100 }),
+        ev(500, 1, EventPayload::On { duration: 100 }),
+    ]);
+    let notes = [0, 120].map(|tick| ArpNote {
+        tick,
+        duration: 60,
+        key: key + 7 * 256,
+    });
+    let source = ArpSource::Notes(EventTarget::Indices([2].into()));
+    assert_eq!(write_notes(&mut song, UnitIdx(1), &notes, &source, true), 2);
+    // The note after the removed one keeps the key and velocity it had
+    let unit_0: Vec<_> = song
+        .events
+        .iter()
+        .filter(|ev| ev.unit == UnitIdx(0))
+        .map(|ev| (ev.tick, ev.payload))
+        .collect();
+    assert_eq!(unit_0.len(), 3);
+    assert!(unit_0.iter().all(|(tick, _)| *tick == 100));
+    // New notes get their keys, and the note after them is back to the key it had
+    let keys = NoteValue::Key.of_notes(&song.events);
+    let unit_1: Vec<_> = song
+        .events
+        .iter()
+        .enumerate()
+        .filter(|(_, ev)| ev.unit == UnitIdx(1) && matches!(ev.payload, EventPayload::On { .. }))
+        .map(|(idx, ev)| (ev.tick, keys[&idx]))
+        .collect();
+    let new_key = key + 7 * 256;
+    assert_eq!(
+        unit_1,
+        [(0, new_key), (120, new_key), (500, ptcow::DEFAULT_KEY)]
+    );
+    let vels = NoteValue::Velocity.of_notes(&song.events);
+    let note = song
+        .events
+        .iter()
+        .position(|ev| ev.unit == UnitIdx(0) && matches!(ev.payload, EventPayload::On { .. }))
+        .unwrap();
+    assert_eq!((keys[&note], vels[&note]), (key, 50));
+}
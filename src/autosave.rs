@@ -0,0 +1,266 @@
+//! Crash recovery snapshots of the project
+//!
+//! Snapshots are collages, kept in the storage directory of the app on desktop,
+//! and in local storage on web.
+
+use {
+    crate::audio_out::SongState,
+    std::{
+        hash::{DefaultHasher, Hash, Hasher},
+        path::{Path, PathBuf},
+    },
+};
+
+/// Seconds between autosaves
+const INTERVAL: f64 = 60.0;
+
+/// Documents get the lowest id that's free, so the ids of older sessions are below this
+const MAX_DOC_ID: u16 = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Slot {
+    /// Written periodically while the document with this id has unsaved changes
+    Autosave(u16),
+    /// Written before the project is replaced, by clearing it, opening another or an import
+    BeforeClear,
+}
+
+impl Slot {
+    fn name(self) -> String {
+        match self {
+            Self::Autosave(id) => format!("autosave-{id}"),
+            Self::BeforeClear => "before-clear".into(),
+        }
+    }
+}
+
+/// Autosaves left behind by an earlier session, that are newer than the files they came from
+pub fn leftover_autosaves() -> Vec<(Slot, Snapshot)> {
+    (0..MAX_DOC_ID)
+        .map(Slot::Autosave)
+        .filter_map(|slot| read(slot).map(|snap| (slot, snap)))
+        .filter(|(slot, snap)| is_newer_than_origin(*slot, snap))
+        .collect()
+}
+
+pub struct Snapshot {
+    pub data: Vec<u8>,
+    /// The file the project was opened from, if any
+    pub origin: Option<PathBuf>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn dir() -> Option<PathBuf> {
+    eframe::storage_dir("ptcowlage").map(|dir| dir.join("recovery"))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn slot_paths(slot: Slot) -> Option<(PathBuf, PathBuf)> {
+    let dir = dir()?;
+    Some((
+        dir.join(format!("{}.ptcop", slot.name())),
+        dir.join(format!("{}.origin", slot.name())),
+    ))
+}
+
+pub fn write(slot: Slot, data: &[u8], origin: Option<&Path>) -> anyhow::Result<()> {
+    let origin = origin.map_or(String::new(), |path| path.to_string_lossy().into_owned());
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let Some((data_path, origin_path)) = slot_paths(slot) else {
+            anyhow::bail!("No storage directory for recovery snapshots");
+        };
+        if let Some(dir) = data_path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(data_path, data)?;
+        std::fs::write(origin_path, origin)?;
+    }
+    #[cfg(target_arch = "wasm32")]
+    if !crate::web_glue::recovery_store(&slot.name(), data, &origin) {
+        anyhow::bail!("Local storage is full or unavailable");
+    }
+    Ok(())
+}
+
+pub fn read(slot: Slot) -> Option<Snapshot> {
+    #[cfg(not(target_arch = "wasm32"))]
+    let (data, origin) = {
+        let (data_path, origin_path) = slot_paths(slot)?;
+        (
+            std::fs::read(data_path).ok()?,
+            std::fs::read_to_string(origin_path).ok(),
+        )
+    };
+    #[cfg(target_arch = "wasm32")]
+    let (data, origin) = (
+        crate::web_glue::recovery_load(&slot.name())?,
+        crate::web_glue::recovery_origin(&slot.name()),
+    );
+    Some(Snapshot {
+        data,
+        origin: origin.filter(|s| !s.is_empty()).map(PathBuf::from),
+    })
+}
+
+pub fn clear(slot: Slot) {
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some((data_path, origin_path)) = slot_paths(slot) {
+        let _ = std::fs::remove_file(data_path);
+        let _ = std::fs::remove_file(origin_path);
+    }
+    #[cfg(target_arch = "wasm32")]
+    crate::web_glue::recovery_clear(&slot.name());
+}
+
+/// Whether the snapshot in `slot` was written after its origin file was last modified
+///
+/// If it can't be told, it's assumed to be newer.
+pub fn is_newer_than_origin(slot: Slot, snapshot: &Snapshot) -> bool {
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(origin) = &snapshot.origin
+        && let Some((data_path, _)) = slot_paths(slot)
+        && let Ok(snap_time) = std::fs::metadata(data_path).and_then(|meta| meta.modified())
+        && let Ok(origin_time) = std::fs::metadata(origin).and_then(|meta| meta.modified())
+    {
+        return snap_time > origin_time;
+    }
+    #[cfg(target_arch = "wasm32")]
+    let _ = (slot, snapshot);
+    true
+}
+
+fn hash_bytes(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Keeps track of when to write the autosaves of a document
+pub struct Autosave {
+    /// Id of the document, which picks the slot its autosaves go in
+    doc_id: u16,
+    /// egui time of the next autosave
+    next: f64,
+    /// Hash of the project as last opened or saved, which needs no autosave
+    baseline: Option<u64>,
+    /// Hash of the project as last autosaved
+    written: Option<u64>,
+}
+
+impl Autosave {
+    /// Autosave for the document with id `doc_id`
+    pub fn new(doc_id: u16) -> Self {
+        Self {
+            doc_id,
+            // Give the user time to answer the recovery prompt before the old autosave is replaced
+            next: INTERVAL,
+            baseline: None,
+            written: None,
+        }
+    }
+    pub fn doc_id(&self) -> u16 {
+        self.doc_id
+    }
+    pub fn slot(&self) -> Slot {
+        Slot::Autosave(self.doc_id)
+    }
+    /// Mark the current state of the project as the one on disk
+    pub fn set_baseline(&mut self, song: &SongState) {
+        let data = ptcow::serialize_project(&song.song, &song.herd, &song.ins).ok();
+        self.set_baseline_data(data.as_deref());
+    }
+    /// Mark the project serialized as `data` as the one on disk, or none if it couldn't be
+    fn set_baseline_data(&mut self, data: Option<&[u8]>) {
+        self.baseline = data.map(hash_bytes);
+        self.written = None;
+    }
+    /// Mark the current project as having unsaved changes
+    pub fn clear_baseline(&mut self) {
+        self.baseline = None;
+        self.written = None;
+    }
+    /// Whether the project differs from its state as last opened or saved
+    pub fn has_unsaved_changes(&self, song: &SongState) -> bool {
+        ptcow::serialize_project(&song.song, &song.herd, &song.ins)
+            .map_or(true, |data| self.differs_from_baseline(&data))
+    }
+    /// Whether the project serialized as `data` differs from its state as last opened or saved
+    pub fn differs_from_baseline(&self, data: &[u8]) -> bool {
+        Some(hash_bytes(data)) != self.baseline
+    }
+    /// What to do with the slot, for a project with the hash `hash`
+    fn slot_action(&self, hash: u64) -> SlotAction {
+        if Some(hash) == self.written {
+            SlotAction::Keep
+        } else if Some(hash) == self.baseline {
+            // Back to the saved state, nothing to recover
+            SlotAction::Clear
+        } else {
+            SlotAction::Write
+        }
+    }
+    /// Write an autosave if it's time, and the project changed since the last one
+    pub fn update(
+        &mut self,
+        now: f64,
+        song: &SongState,
+        origin: Option<&Path>,
+    ) -> anyhow::Result<()> {
+        if now < self.next {
+            return Ok(());
+        }
+        self.next = now + INTERVAL;
+        let data = ptcow::serialize_project(&song.song, &song.herd, &song.ins)?;
+        let hash = hash_bytes(&data);
+        match self.slot_action(hash) {
+            SlotAction::Keep => return Ok(()),
+            SlotAction::Clear => clear(self.slot()),
+            SlotAction::Write => write(self.slot(), &data, origin)?,
+        }
+        self.written = Some(hash);
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum SlotAction {
+    /// The autosave is already up to date
+    Keep,
+    Clear,
+    Write,
+}
+
+#[test]
+fn test_slot_names() {
+    assert_eq!(Slot::Autosave(3).name(), "autosave-3");
+    assert_eq!(Slot::BeforeClear.name(), "before-clear");
+    // Every slot gets files of its own
+    let mut names: Vec<_> = (0..MAX_DOC_ID)
+        .map(|id| Slot::Autosave(id).name())
+        .collect();
+    names.push(Slot::BeforeClear.name());
+    let n_names = names.len();
+    names.sort();
+    names.dedup();
+    assert_eq!(names.len(), n_names);
+}
+
+#[test]
+fn test_autosave_baseline() {
+    let (saved, edited) = (b"saved".as_slice(), b"edited".as_slice());
+    let mut autosave = Autosave::new(0);
+    // New projects always have something to lose
+    assert!(autosave.differs_from_baseline(saved));
+    autosave.set_baseline_data(Some(saved));
+    assert!(!autosave.differs_from_baseline(saved));
+    assert!(autosave.differs_from_baseline(edited));
+    assert_eq!(autosave.slot_action(hash_bytes(edited)), SlotAction::Write);
+    autosave.written = Some(hash_bytes(edited));
+    assert_eq!(autosave.slot_action(hash_bytes(edited)), SlotAction::Keep);
+    // Undoing the edits leaves nothing to recover
+    assert_eq!(autosave.slot_action(hash_bytes(saved)), SlotAction::Clear);
+    // Projects that can't be serialized can't be told apart from their saved state
+    autosave.set_baseline_data(None);
+    assert!(autosave.differs_from_baseline(saved));
+}
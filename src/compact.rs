@@ -0,0 +1,191 @@
+//! Removing events that don't change the state of their unit, like the ones imports leave behind
+
+use {
+    ptcow::{Event, EventPayload, Song},
+    rustc_hash::FxHashMap,
+};
+
+/// What [`compact`] saved
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Compaction {
+    pub events_removed: usize,
+    pub bytes_saved: usize,
+}
+
+impl std::fmt::Display for Compaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Removed {} redundant events, saving {} bytes",
+            self.events_removed, self.bytes_saved
+        )
+    }
+}
+
+/// Indices of the events that set a unit to the value it already has
+///
+/// Values set before the repeat point aren't known when playback loops back to it,
+/// so the first event of each kind after it is always kept.
+pub fn redundant_events(events: &[Event], repeat_tick: u32) -> Vec<usize> {
+    // Last value of each kind, by unit and discriminant
+    let mut values = FxHashMap::default();
+    // Portament of each unit, if known. Key events restart the slide, even to the same key.
+    let mut portaments = [Some(0); 256];
+    let mut past_repeat = repeat_tick == 0;
+    let mut redundant = Vec::new();
+    for (idx, ev) in events.iter().enumerate() {
+        if !past_repeat && ev.tick >= repeat_tick {
+            values.clear();
+            portaments = [None; 256];
+            past_repeat = true;
+        }
+        let unit = usize::from(ev.unit.0);
+        match ev.payload {
+            EventPayload::Portament { duration } => portaments[unit] = Some(duration),
+            EventPayload::Key(_) if portaments[unit] != Some(0) => continue,
+            EventPayload::Key(_)
+            | EventPayload::PanVol(_)
+            | EventPayload::Velocity(_)
+            | EventPayload::Volume(_)
+            | EventPayload::Tuning(_)
+            | EventPayload::PanTime(_) => {}
+            _ => continue,
+        }
+        if values.insert((ev.unit.0, ev.payload.discriminant()), ev.payload) == Some(ev.payload) {
+            redundant.push(idx);
+        }
+    }
+    redundant
+}
+
+/// Remove the events of `song` that don't change the state of their unit
+pub fn compact(song: &mut Song) -> Compaction {
+    let repeat_tick =
+        ptcow::timing::meas_to_tick(song.master.loop_points.repeat, song.master.timing);
+    let redundant = redundant_events(&song.events, repeat_tick);
+    if redundant.is_empty() {
+        return Compaction::default();
+    }
+    let size_before = events_size(&song.events);
+    let mut idx = 0;
+    song.events.retain(|_| {
+        let keep = redundant.binary_search(&idx).is_err();
+        idx += 1;
+        keep
+    });
+    song.recalculate_length();
+    Compaction {
+        events_removed: redundant.len(),
+        bytes_saved: size_before.saturating_sub(events_size(&song.events)),
+    }
+}
+
+/// Size of `events` as PxTone stores them: the tick delta and the value as variable length
+/// integers, with a byte each for the unit and the kind of event
+pub fn events_size(events: &[Event]) -> usize {
+    let mut prev_tick = 0;
+    events
+        .iter()
+        .map(|ev| {
+            let delta = ev.tick.wrapping_sub(prev_tick);
+            prev_tick = ev.tick;
+            varint_size(delta) + 2 + varint_size(raw_value(ev.payload))
+        })
+        .sum()
+}
+
+fn varint_size(val: u32) -> usize {
+    (32 - val.leading_zeros()).div_ceil(7).max(1) as usize
+}
+
+/// The value of a payload, as it's stored
+pub fn raw_value(payload: EventPayload) -> u32 {
+    match payload {
+        EventPayload::On { duration } | EventPayload::Portament { duration } => duration,
+        EventPayload::Key(key) | EventPayload::PtcowDebug(key) => key as u32,
+        EventPayload::PanVol(vol) => u32::from(vol),
+        EventPayload::Velocity(vel) | EventPayload::Volume(vel) => i32::from(vel) as u32,
+        EventPayload::BeatTempo(val) | EventPayload::Tuning(val) => val.to_bits(),
+        EventPayload::SetVoice(voice) => u32::from(voice.0),
+        EventPayload::SetGroup(group) => u32::from(group.0),
+        EventPayload::PanTime(pan) => u32::from(pan.0),
+        _ => 0,
+    }
+}
+
+#[test]
+fn test_redundant_events() {
+    use ptcow::UnitIdx;
+    let ev = |tick, unit, payload| Event {
+        payload,
+        unit: UnitIdx(unit),
+        tick,
+    };
+    let events = [
+        ev(0, 0, EventPayload::Volume(100)),
+        ev(0, 1, EventPayload::Volume(100)),
+        ev(0, 0, EventPayload::Key(ptcow::DEFAULT_KEY)),
+        ev(0, 0, EventPayload::On { duration: 10 }),
+        ev(10, 0, EventPayload::Volume(100)),
+        ev(10, 0, EventPayload::Key(ptcow::DEFAULT_KEY)),
+        ev(10, 0, EventPayload::On { duration: 10 }),
+        ev(20, 0, EventPayload::Portament { duration: 5 }),
+        ev(20, 0, EventPayload::Key(ptcow::DEFAULT_KEY)),
+        ev(20, 1, EventPayload::Velocity(64)),
+        ev(30, 1, EventPayload::Volume(100)),
+        ev(40, 1, EventPayload::Volume(100)),
+        ev(40, 1, EventPayload::Velocity(64)),
+    ];
+    // Keys aren't redundant with portament, as they restart the slide
+    assert_eq!(redundant_events(&events, 0), [4, 5, 10, 11, 12]);
+    // After the repeat point, the first value of each kind is kept
+    assert_eq!(redundant_events(&events, 30), [4, 5, 11]);
+    assert_eq!(events_size(&events[..1]), 1 + 2 + 1);
+    assert_eq!(events_size(&events[2..3]), 1 + 2 + 3);
+}
+
+#[test]
+fn test_compact_renders_the_same() {
+    use {
+        crate::{audio_out::SongState, util::WavRender},
+        ptcow::{Unit, UnitIdx, VoiceIdx},
+    };
+    let mut song = SongState::new(44_100);
+    let mut voice = crate::pxtone_misc::hat_close_voice();
+    voice.recalculate(&ptcow::NoiseTable::generate(), 44_100);
+    song.ins.voices.push(voice);
+    song.herd.units.push(Unit::default());
+    song.song.master.loop_points.last = std::num::NonZeroU32::new(1);
+    let meas = ptcow::timing::meas_to_tick(1, song.song.master.timing);
+    song.song.events.push(Event {
+        payload: EventPayload::SetVoice(VoiceIdx(0)),
+        unit: UnitIdx(0),
+        tick: 0,
+    });
+    for i in 0..4 {
+        for payload in [
+            EventPayload::Volume(80),
+            EventPayload::Velocity(100),
+            EventPayload::PanVol(64),
+            EventPayload::Key(ptcow::DEFAULT_KEY),
+            EventPayload::On { duration: meas / 8 },
+        ] {
+            song.song.events.push(Event {
+                payload,
+                unit: UnitIdx(0),
+                tick: i * meas / 4,
+            });
+        }
+    }
+    let render = |song: &mut SongState| {
+        let mut render = WavRender::new(song);
+        while render.step(song, 16) {}
+        render.finish(song).unwrap()
+    };
+    let before = render(&mut song);
+    let compaction = compact(&mut song.song);
+    // Everything but the notes repeats the values of the first tick
+    assert_eq!(compaction.events_removed, 3 * 4);
+    assert!(compaction.bytes_saved > 0);
+    assert_eq!(render(&mut song), before);
+}
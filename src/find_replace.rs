@@ -0,0 +1,238 @@
+//! Finding events by what they are and when they happen, and replacing their values
+
+use {
+    crate::timing_ops::NoteValue,
+    ptcow::{Event, EventPayload, GroupIdx, PanTime, Song, UnitIdx, VoiceIdx},
+};
+
+/// Which events to find. Parts that aren't set match every event.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct EventQuery {
+    /// Kind of payload, by its discriminant
+    pub kind: Option<u8>,
+    pub unit: Option<UnitIdx>,
+    /// Inclusive range of ticks
+    pub ticks: Option<(u32, u32)>,
+    /// Inclusive range of the payload value, see [`payload_value`]
+    pub values: Option<(f64, f64)>,
+    /// Inclusive range of the key the unit is at when the event happens, in semitones
+    pub keys: Option<(i32, i32)>,
+}
+
+impl EventQuery {
+    /// Indices of the matching events
+    pub fn find(&self, events: &[Event]) -> Vec<usize> {
+        let note_keys = NoteValue::Key.of_notes(events);
+        let mut current_keys = [ptcow::DEFAULT_KEY; 256];
+        let mut matches = Vec::new();
+        for (idx, ev) in events.iter().enumerate() {
+            let unit_key = &mut current_keys[usize::from(ev.unit.0)];
+            if let EventPayload::Key(key) = ev.payload {
+                *unit_key = key;
+            }
+            let key = note_keys.get(&idx).copied().unwrap_or(*unit_key);
+            let in_range = |range: Option<(f64, f64)>, value: Option<f64>| {
+                range.is_none_or(|(min, max)| value.is_some_and(|val| (min..=max).contains(&val)))
+            };
+            if self
+                .kind
+                .is_none_or(|kind| kind == ev.payload.discriminant())
+                && self.unit.is_none_or(|unit| unit == ev.unit)
+                && self
+                    .ticks
+                    .is_none_or(|(min, max)| (min..=max).contains(&ev.tick))
+                && in_range(self.values, payload_value(ev.payload))
+                && self
+                    .keys
+                    .is_none_or(|(min, max)| (min..=max).contains(&key.div_euclid(256)))
+            {
+                matches.push(idx);
+            }
+        }
+        matches
+    }
+}
+
+/// The value of a payload, if it has one. Keys are in semitones.
+pub fn payload_value(payload: EventPayload) -> Option<f64> {
+    Some(match payload {
+        EventPayload::On { duration } | EventPayload::Portament { duration } => f64::from(duration),
+        EventPayload::Key(key) => f64::from(key) / 256.0,
+        EventPayload::PanVol(vol) => f64::from(vol),
+        EventPayload::Velocity(vel) => f64::from(vel),
+        EventPayload::Volume(vol) => f64::from(vol),
+        EventPayload::BeatTempo(tempo) => f64::from(tempo),
+        EventPayload::SetVoice(voice) => f64::from(voice.0),
+        EventPayload::SetGroup(group) => f64::from(group.0),
+        EventPayload::Tuning(tuning) => f64::from(tuning),
+        EventPayload::PanTime(pan) => f64::from(pan.0),
+        EventPayload::PtcowDebug(val) => f64::from(val),
+        _ => return None,
+    })
+}
+
+/// `payload` with its value set to `value`, clamped to what the payload can hold
+///
+/// Notes are at least a tick long, zero length notes are never what's wanted. Zero length
+/// portament turns portamento off, so that one is kept.
+pub fn with_value(payload: EventPayload, value: f64) -> EventPayload {
+    // Float to int casts saturate
+    let int = value.round();
+    match payload {
+        EventPayload::On { .. } => EventPayload::On {
+            duration: (int as u32).max(1),
+        },
+        EventPayload::Portament { .. } => EventPayload::Portament {
+            duration: int as u32,
+        },
+        EventPayload::Key(_) => EventPayload::Key((value * 256.0).round() as i32),
+        EventPayload::PanVol(_) => EventPayload::PanVol(int as u8),
+        EventPayload::Velocity(_) => EventPayload::Velocity(int as i16),
+        EventPayload::Volume(_) => EventPayload::Volume(int as i16),
+        EventPayload::BeatTempo(_) => EventPayload::BeatTempo(value as f32),
+        EventPayload::SetVoice(_) => EventPayload::SetVoice(VoiceIdx(int as u8)),
+        EventPayload::SetGroup(_) => EventPayload::SetGroup(GroupIdx(int as u8)),
+        EventPayload::Tuning(_) => EventPayload::Tuning(value as f32),
+        EventPayload::PanTime(_) => EventPayload::PanTime(PanTime(int as u8)),
+        EventPayload::PtcowDebug(_) => EventPayload::PtcowDebug(int as i32),
+        other => other,
+    }
+}
+
+/// A change to the value of a payload
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Transform {
+    Set(f64),
+    Add(f64),
+    Multiply(f64),
+}
+
+impl Transform {
+    pub fn apply(self, value: f64) -> f64 {
+        match self {
+            Self::Set(new) => new,
+            Self::Add(amount) => value + amount,
+            Self::Multiply(factor) => value * factor,
+        }
+    }
+}
+
+/// What to change about the events found. Parts that aren't set are kept.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Replacement {
+    pub value: Option<Transform>,
+    pub unit: Option<UnitIdx>,
+}
+
+impl Replacement {
+    pub fn is_noop(&self) -> bool {
+        self.value.is_none() && self.unit.is_none()
+    }
+    pub fn apply(&self, mut ev: Event) -> Event {
+        if let Some(transform) = self.value
+            && let Some(value) = payload_value(ev.payload)
+        {
+            ev.payload = with_value(ev.payload, transform.apply(value));
+        }
+        ev.unit = self.unit.unwrap_or(ev.unit);
+        ev
+    }
+}
+
+/// Apply `replacement` to the events at `indices`
+pub fn replace(song: &mut Song, indices: &[usize], replacement: &Replacement) {
+    for &idx in indices {
+        if let Some(ev) = song.events.get_mut(idx) {
+            *ev = replacement.apply(*ev);
+        }
+    }
+    song.recalculate_length();
+}
+
+#[test]
+fn test_find_replace() {
+    let ev = |tick, unit, payload| Event {
+        payload,
+        unit: UnitIdx(unit),
+        tick,
+    };
+    let a4 = ptcow::DEFAULT_KEY;
+    let mut song = Song::default();
+    song.events.extend([
+        ev(0, 0, EventPayload::Key(a4 + 12 * 256)),
+        ev(0, 0, EventPayload::On { duration: 100 }),
+        ev(0, 1, EventPayload::On { duration: 100 }),
+        ev(50, 0, EventPayload::Volume(100)),
+        ev(100, 1, EventPayload::Volume(50)),
+        ev(200, 1, EventPayload::Key(a4 - 256)),
+        ev(200, 1, EventPayload::On { duration: 100 }),
+    ]);
+    let volume = EventPayload::Volume(0).discriminant();
+    let on = EventPayload::On { duration: 0 }.discriminant();
+    let query = EventQuery {
+        kind: Some(volume),
+        ..EventQuery::default()
+    };
+    assert_eq!(query.find(&song.events), [3, 4]);
+    let query = EventQuery {
+        kind: Some(volume),
+        values: Some((60.0, 127.0)),
+        ..EventQuery::default()
+    };
+    assert_eq!(query.find(&song.events), [3]);
+    // Events get the key of the unit at the time, or the key of their note
+    let a4_semitone = a4 / 256;
+    let query = EventQuery {
+        keys: Some((a4_semitone + 12, a4_semitone + 12)),
+        ..EventQuery::default()
+    };
+    assert_eq!(query.find(&song.events), [0, 1, 3]);
+    let query = EventQuery {
+        kind: Some(on),
+        unit: Some(UnitIdx(1)),
+        ticks: Some((100, 300)),
+        ..EventQuery::default()
+    };
+    let matches = query.find(&song.events);
+    assert_eq!(matches, [6]);
+    let query = EventQuery {
+        kind: Some(volume),
+        ..EventQuery::default()
+    };
+    let matches = query.find(&song.events);
+    let replacement = Replacement {
+        value: Some(Transform::Multiply(0.8)),
+        unit: None,
+    };
+    replace(&mut song, &matches, &replacement);
+    assert_eq!(payload_value(song.events[3].payload), Some(80.0));
+    assert_eq!(payload_value(song.events[4].payload), Some(40.0));
+    let replacement = Replacement {
+        value: Some(Transform::Add(12.0)),
+        unit: Some(UnitIdx(2)),
+    };
+    replace(&mut song, &[5], &replacement);
+    assert_eq!(song.events[5].unit, UnitIdx(2));
+    assert!(matches!(song.events[5].payload, EventPayload::Key(key) if key == a4 + 11 * 256));
+    // Values are clamped to what the payload can hold
+    let replacement = Replacement {
+        value: Some(Transform::Set(-5.0)),
+        unit: None,
+    };
+    replace(&mut song, &[1], &replacement);
+    assert!(matches!(
+        song.events[1].payload,
+        EventPayload::On { duration: 1 }
+    ));
+    // Portamento stays off
+    song.events[1].payload = EventPayload::Portament { duration: 0 };
+    let replacement = Replacement {
+        value: Some(Transform::Multiply(1.0)),
+        unit: None,
+    };
+    replace(&mut song, &[1], &replacement);
+    assert!(matches!(
+        song.events[1].payload,
+        EventPayload::Portament { duration: 0 }
+    ));
+}
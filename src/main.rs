@@ -24,16 +24,30 @@
 use std::path::PathBuf;
 
 mod app;
+mod arp;
 mod audio_out;
+mod autosave;
+mod compact;
 mod egui_ext;
 mod evilscript;
+mod find_replace;
 #[cfg(not(target_arch = "wasm32"))]
 mod font_fallback;
 mod herd_ext;
 mod midi;
+mod mml;
+mod musicxml;
 mod organya;
+mod pitch_ops;
 mod piyopiyo;
+mod pttune;
 mod pxtone_misc;
+mod row_edit;
+mod song_check;
+mod step_seq;
+mod timing_ops;
+mod tracker;
+mod transfer;
 mod util;
 #[cfg(target_arch = "wasm32")]
 mod web_glue;
@@ -51,6 +65,12 @@ struct CliArgs {
     piyo_import: Option<PathBuf>,
     #[arg(long)]
     org_import: Option<PathBuf>,
+    /// Import a tracker module (.mod, .s3m, .xm)
+    #[arg(long)]
+    tracker_import: Option<PathBuf>,
+    /// Import an MML (Music Macro Language) text file
+    #[arg(long)]
+    mml_import: Option<PathBuf>,
     #[arg(long)]
     voice_import: Option<PathBuf>,
     /// Optionally open a PxTone collage (.ptcop) file on startup
@@ -70,6 +90,8 @@ struct CliArgs {
     midi_import: Option<PathBuf>,
     piyo_import: Option<PathBuf>,
     org_import: Option<PathBuf>,
+    tracker_import: Option<PathBuf>,
+    mml_import: Option<PathBuf>,
     voice_import: Option<PathBuf>,
     /// Optionally open a PxTone collage (.ptcop) file on startup
     open: Option<PathBuf>,
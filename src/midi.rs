@@ -1,5 +1,5 @@
 use {
-    crate::pxtone_misc::{hat_close_voice, square_wave_voice},
+    crate::pxtone_misc::square_wave_voice,
     midly::{MetaMessage, MidiMessage, TrackEventKind, num::u7},
     ptcow::{Event, EventPayload, Herd, MooInstructions, Song, Unit, UnitIdx, VoiceIdx},
     rustc_hash::FxHashMap,
@@ -40,8 +40,213 @@ fn midi_tracks_to_event_stream<'a>(smf: &'a midly::Smf<'a>) -> Vec<MidiEv<'a>> {
 }
 
 const DRUM_CH: u8 = 9;
-// "Special" drum program to map to a drum instrument
-const DRUM_PRG: u8 = 255;
+
+/// Kinds of drum instruments that General MIDI percussion notes are grouped into.
+///
+/// Each kind used by a song gets its own unit and voice.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum DrumKind {
+    Kick,
+    Snare,
+    Clap,
+    ClosedHat,
+    OpenHat,
+    LowTom,
+    MidTom,
+    HighTom,
+    Crash,
+    Ride,
+    Cowbell,
+    Percussion,
+}
+
+impl DrumKind {
+    pub const ALL: [Self; 12] = [
+        Self::Kick,
+        Self::Snare,
+        Self::Clap,
+        Self::ClosedHat,
+        Self::OpenHat,
+        Self::LowTom,
+        Self::MidTom,
+        Self::HighTom,
+        Self::Crash,
+        Self::Ride,
+        Self::Cowbell,
+        Self::Percussion,
+    ];
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Kick => "kick",
+            Self::Snare => "snare",
+            Self::Clap => "clap",
+            Self::ClosedHat => "closed hat",
+            Self::OpenHat => "open hat",
+            Self::LowTom => "low tom",
+            Self::MidTom => "mid tom",
+            Self::HighTom => "high tom",
+            Self::Crash => "crash",
+            Self::Ride => "ride",
+            Self::Cowbell => "cowbell",
+            Self::Percussion => "percussion",
+        }
+    }
+    fn from_label(label: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.label() == label)
+    }
+    /// Default sample from the Organya drum bank for this kind of drum
+    fn default_org_sample(self) -> u8 {
+        match self {
+            Self::Kick => 0,
+            Self::Snare => 2,
+            Self::Clap => 36,
+            Self::ClosedHat => 5,
+            Self::OpenHat => 6,
+            Self::LowTom => 4,
+            Self::MidTom => 11,
+            Self::HighTom => 23,
+            Self::Crash => 7,
+            Self::Ride => 22,
+            Self::Cowbell => 26,
+            Self::Percussion => 8,
+        }
+    }
+    /// Default General MIDI note mapping
+    fn from_gm_note(note: u8) -> Option<Self> {
+        Some(match note {
+            35 | 36 => Self::Kick,
+            38 | 40 => Self::Snare,
+            39 => Self::Clap,
+            42 | 44 => Self::ClosedHat,
+            46 => Self::OpenHat,
+            41 | 43 => Self::LowTom,
+            45 | 47 => Self::MidTom,
+            48 | 50 => Self::HighTom,
+            49 | 52 | 55 | 57 => Self::Crash,
+            51 | 53 | 59 => Self::Ride,
+            56 => Self::Cowbell,
+            37 | 54 | 58 | 60..=81 => Self::Percussion,
+            _ => return None,
+        })
+    }
+}
+
+/// Mapping of drum channel notes to drum voices, used by the midi import
+#[derive(Clone)]
+pub struct DrumMap {
+    /// Which kind of drum each note of the drum channel plays. Unmapped notes are skipped.
+    pub notes: [Option<DrumKind>; 128],
+    /// Organya drum bank sample for each [`DrumKind`], indexed by `DrumKind as usize`
+    pub samples: [u8; DrumKind::ALL.len()],
+}
+
+impl Default for DrumMap {
+    fn default() -> Self {
+        Self {
+            notes: std::array::from_fn(|note| DrumKind::from_gm_note(note as u8)),
+            samples: DrumKind::ALL.map(DrumKind::default_org_sample),
+        }
+    }
+}
+
+impl DrumMap {
+    pub fn sample_for(&self, kind: DrumKind) -> u8 {
+        self.samples[kind as usize]
+    }
+    pub fn sample_for_mut(&mut self, kind: DrumKind) -> &mut u8 {
+        &mut self.samples[kind as usize]
+    }
+    /// The map as text for storing, with the samples and the drum kind of each note
+    pub fn to_pref_string(&self) -> String {
+        let samples: Vec<String> = self.samples.iter().map(u8::to_string).collect();
+        let notes: Vec<&str> = self
+            .notes
+            .iter()
+            .map(|kind| kind.map_or("", DrumKind::label))
+            .collect();
+        format!("{};{}", samples.join(","), notes.join(","))
+    }
+    /// Parse the text of [`Self::to_pref_string`], if it's a valid map
+    pub fn from_pref_string(text: &str) -> Option<Self> {
+        let (samples, notes) = text.split_once(';')?;
+        let samples: Vec<u8> = samples
+            .split(',')
+            .map(|val| val.parse().ok())
+            .collect::<Option<_>>()?;
+        // Samples past the end of the drum bank would panic on import
+        if samples
+            .iter()
+            .any(|&smp| smp >= crate::organya::DRUM_SAMPLE_COUNT)
+        {
+            return None;
+        }
+        let notes: Vec<Option<DrumKind>> = notes
+            .split(',')
+            .map(|label| match label {
+                "" => Some(None),
+                _ => DrumKind::from_label(label).map(Some),
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            notes: notes.try_into().ok()?,
+            samples: samples.try_into().ok()?,
+        })
+    }
+}
+
+/// First note of [`GM_DRUM_NAMES`]
+pub const GM_DRUM_FIRST_NOTE: u8 = 35;
+
+/// General MIDI percussion key map, starting at [`GM_DRUM_FIRST_NOTE`]
+pub const GM_DRUM_NAMES: [&str; 47] = [
+    "Acoustic Bass Drum",
+    "Bass Drum 1",
+    "Side Stick",
+    "Acoustic Snare",
+    "Hand Clap",
+    "Electric Snare",
+    "Low Floor Tom",
+    "Closed Hi-Hat",
+    "High Floor Tom",
+    "Pedal Hi-Hat",
+    "Low Tom",
+    "Open Hi-Hat",
+    "Low-Mid Tom",
+    "Hi-Mid Tom",
+    "Crash Cymbal 1",
+    "High Tom",
+    "Ride Cymbal 1",
+    "Chinese Cymbal",
+    "Ride Bell",
+    "Tambourine",
+    "Splash Cymbal",
+    "Cowbell",
+    "Crash Cymbal 2",
+    "Vibraslap",
+    "Ride Cymbal 2",
+    "Hi Bongo",
+    "Low Bongo",
+    "Mute Hi Conga",
+    "Open Hi Conga",
+    "Low Conga",
+    "High Timbale",
+    "Low Timbale",
+    "High Agogo",
+    "Low Agogo",
+    "Cabasa",
+    "Maracas",
+    "Short Whistle",
+    "Long Whistle",
+    "Short Guiro",
+    "Long Guiro",
+    "Claves",
+    "Hi Wood Block",
+    "Low Wood Block",
+    "Mute Cuica",
+    "Open Cuica",
+    "Mute Triangle",
+    "Open Triangle",
+];
 
 struct ChannelState {
     rpn_lsb: u8,
@@ -49,6 +254,10 @@ struct ChannelState {
     pitch_bend: f64,
     pitch_bend_range_semitones: u8,
     last_key: Option<midly::num::u7>,
+    /// Last volume controller value, replayed on drum units created later
+    last_volume: Option<u7>,
+    /// Last pan controller value, replayed on drum units created later
+    last_pan: Option<u7>,
 }
 
 impl Default for ChannelState {
@@ -59,31 +268,56 @@ impl Default for ChannelState {
             pitch_bend: 0.0,
             pitch_bend_range_semitones: 2,
             last_key: None,
+            last_volume: None,
+            last_pan: None,
         }
     }
 }
 
-/// Unit index to channel mapping
+/// What a unit created by the import plays
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum UnitSrc {
+    Channel(u8),
+    Drum(DrumKind),
+}
+
+/// Unit index to unit source mapping
 #[derive(Default)]
-struct ChannelMapping {
-    vec: Vec<u8>,
+struct UnitMapping {
+    vec: Vec<UnitSrc>,
 }
 
-impl ChannelMapping {
-    fn get_or_insert_for_ch(&mut self, ch: midly::num::u4) -> UnitIdx {
-        if let Some(pos) = self.vec.iter().position(|ch2| *ch2 == ch.as_int()) {
-            UnitIdx(pos as u8)
+impl UnitMapping {
+    /// Returns the unit index for `src`, and whether it was newly inserted
+    fn get_or_insert(&mut self, src: UnitSrc) -> (UnitIdx, bool) {
+        if let Some(pos) = self.vec.iter().position(|src2| *src2 == src) {
+            (UnitIdx(pos as u8), false)
         } else {
-            self.vec.push(ch.as_int());
-            UnitIdx((self.vec.len() - 1) as u8)
+            self.vec.push(src);
+            (UnitIdx((self.vec.len() - 1) as u8), true)
         }
     }
-    fn into_iter(self) -> impl Iterator<Item = u8> {
+    fn drum_units(&self) -> Vec<UnitIdx> {
+        self.vec
+            .iter()
+            .enumerate()
+            .filter(|(_, src)| matches!(src, UnitSrc::Drum(_)))
+            .map(|(i, _)| UnitIdx(i as u8))
+            .collect()
+    }
+    fn into_iter(self) -> impl Iterator<Item = UnitSrc> {
         self.vec.into_iter()
     }
 }
 
-type UsedPrograms = FxHashMap<u8, VoiceIdx>;
+/// What a voice created by the import is made from
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum VoiceSrc {
+    Program(u8),
+    Drum(DrumKind),
+}
+
+type UsedVoices = FxHashMap<VoiceSrc, VoiceIdx>;
 
 /// Write midi song to pxtone
 pub fn write_midi_to_pxtone(
@@ -91,8 +325,9 @@ pub fn write_midi_to_pxtone(
     herd: &mut Herd,
     song: &mut Song,
     ins: &mut MooInstructions,
+    drum_map: &DrumMap,
 ) -> anyhow::Result<()> {
-    let mut used_programs: UsedPrograms = FxHashMap::default();
+    let mut used_voices: UsedVoices = FxHashMap::default();
     let smf = midly::Smf::parse(mid_data)?;
     let ticks_per_beat = match smf.header.timing {
         midly::Timing::Metrical(u15) => u15.as_int(),
@@ -103,29 +338,27 @@ pub fn write_midi_to_pxtone(
     song.master.timing.bpm = 120.0;
     song.events.eves.clear();
     song.master.timing.ticks_per_beat = ticks_per_beat;
-    let mut ch_map = ChannelMapping::default();
+    let mut unit_map = UnitMapping::default();
     let mut channel_states: FxHashMap<u8, ChannelState> = FxHashMap::default();
     for (ev_idx, event) in events.iter().enumerate() {
         match *event.payload {
             TrackEventKind::Midi { channel, message } => {
-                let unit = ch_map.get_or_insert_for_ch(channel);
+                let is_drum_ch = channel.as_int() == DRUM_CH;
+                // Drum channel units are created on demand for each kind of drum
+                let ch_unit = (!is_drum_ch)
+                    .then(|| unit_map.get_or_insert(UnitSrc::Channel(channel.as_int())).0);
                 let state = channel_states.entry(channel.as_int()).or_insert_with(|| {
                     // Here we can put code that runs once on each new channel.
 
-                    // Always insert drum voice for the drum channel, even if there is no
-                    // midi program change event that does it
-                    if channel == DRUM_CH {
-                        program_change(
+                    // Use "piano" program for channels that don't have a program change event
+                    if let Some(unit) = ch_unit {
+                        set_voice(
                             song,
-                            &mut used_programs,
+                            &mut used_voices,
                             event.tick,
-                            channel,
                             unit,
-                            DRUM_PRG,
+                            VoiceSrc::Program(0),
                         );
-                    } else {
-                        // Do the same, but use "piano" program for other channels
-                        program_change(song, &mut used_programs, event.tick, channel, unit, 0);
                     }
                     ChannelState::default()
                 });
@@ -134,8 +367,28 @@ pub fn write_midi_to_pxtone(
                         // We calculate how long notes last in the `NoteOn` event, so we do nothing here
                     }
                     MidiMessage::NoteOn { key, vel } => {
-                        state.last_key = Some(key);
-                        push_key_event(song, unit, event.tick, state, key);
+                        let (unit, drum_kind) = match ch_unit {
+                            Some(unit) => {
+                                state.last_key = Some(key);
+                                push_key_event(song, unit, event.tick, state, key);
+                                (unit, None)
+                            }
+                            None => {
+                                let Some(kind) = drum_map.notes[usize::from(key.as_int())] else {
+                                    log::warn!("Unmapped drum note {key}");
+                                    continue;
+                                };
+                                let unit = drum_unit(
+                                    song,
+                                    &mut unit_map,
+                                    &mut used_voices,
+                                    state,
+                                    event.tick,
+                                    kind,
+                                );
+                                (unit, Some(kind))
+                            }
+                        };
                         // If velocity is zero, we don't want to emit an `On` event.
                         if vel == 0 {
                             //continue;
@@ -146,7 +399,7 @@ pub fn write_midi_to_pxtone(
                             tick: event.tick,
                         });
                         // Find the next note off event for the duration
-                        let duration = 'block: {
+                        let mut duration = 'block: {
                             for ev_after in events.iter().skip(ev_idx) {
                                 if let TrackEventKind::Midi {
                                     channel: ch2,
@@ -171,6 +424,11 @@ pub fn write_midi_to_pxtone(
                             // Fall back to the last event's tick to determine note duration
                             events.last().unwrap().tick - event.tick
                         };
+                        // Drum notes are usually very short, but we want the sample to ring out
+                        if let Some(kind) = drum_kind {
+                            duration = duration
+                                .max(drum_sample_ticks(drum_map.sample_for(kind), ticks_per_beat));
+                        }
                         song.events.eves.push(Event {
                             payload: EventPayload::On { duration },
                             unit,
@@ -178,31 +436,44 @@ pub fn write_midi_to_pxtone(
                         });
                     }
                     MidiMessage::ProgramChange { program } => {
-                        program_change(
-                            song,
-                            &mut used_programs,
-                            event.tick,
-                            channel,
-                            unit,
-                            program.as_int(),
-                        );
+                        // Drum units keep the voice of their drum kind
+                        if let Some(unit) = ch_unit {
+                            log::info!("Instrument change of {channel} to {program}");
+                            set_voice(
+                                song,
+                                &mut used_voices,
+                                event.tick,
+                                unit,
+                                VoiceSrc::Program(program.as_int()),
+                            );
+                        }
                     }
                     MidiMessage::PitchBend { bend } => {
                         state.pitch_bend = bend.as_f64();
-                        if let Some(last) = state.last_key {
+                        if let Some(unit) = ch_unit
+                            && let Some(last) = state.last_key
+                        {
                             push_key_event(song, unit, event.tick, state, last);
                         }
                     }
                     MidiMessage::Controller { controller, value } => {
+                        // Channel-wide controllers apply to every drum unit of the drum channel
+                        let units = match ch_unit {
+                            Some(unit) => vec![unit],
+                            None => unit_map.drum_units(),
+                        };
                         match controller.as_int() {
                             // 7: "Channel volume"
                             // 11: "Expression" or secondary volume controller
                             7 | 11 => {
-                                song.events.eves.push(Event {
-                                    payload: EventPayload::Volume(i16::from(value.as_int())),
-                                    unit,
-                                    tick: event.tick,
-                                });
+                                state.last_volume = Some(value);
+                                for unit in units {
+                                    song.events.eves.push(Event {
+                                        payload: EventPayload::Volume(i16::from(value.as_int())),
+                                        unit,
+                                        tick: event.tick,
+                                    });
+                                }
                             }
                             6 => {
                                 if state.rpn_lsb == 0 && state.rpn_msb == 0 {
@@ -214,11 +485,14 @@ pub fn write_midi_to_pxtone(
                             }
                             10 => {
                                 // Pan
-                                song.events.eves.push(Event {
-                                    payload: EventPayload::PanVol(value.as_int()),
-                                    unit,
-                                    tick: event.tick,
-                                });
+                                state.last_pan = Some(value);
+                                for unit in units {
+                                    song.events.eves.push(Event {
+                                        payload: EventPayload::PanVol(value.as_int()),
+                                        unit,
+                                        tick: event.tick,
+                                    });
+                                }
                             }
                             38 => {
                                 if state.rpn_lsb == 0 && state.rpn_msb == 0 {
@@ -263,11 +537,10 @@ pub fn write_midi_to_pxtone(
     }
 
     herd.units.clear();
-    for ch in ch_map.into_iter() {
-        let name = if ch == DRUM_CH {
-            "drum ch".into()
-        } else {
-            format!("ch{ch}")
+    for src in unit_map.into_iter() {
+        let name = match src {
+            UnitSrc::Channel(ch) => format!("ch{ch}"),
+            UnitSrc::Drum(kind) => format!("drum {}", kind.label()),
         };
         herd.units.push(Unit {
             name,
@@ -275,7 +548,7 @@ pub fn write_midi_to_pxtone(
         });
     }
 
-    replace_voices(ins, used_programs);
+    replace_voices(ins, used_voices, drum_map);
     // Unset the last point (let it be calculated by PxTone)
     song.master.loop_points.last = None;
 
@@ -284,24 +557,55 @@ pub fn write_midi_to_pxtone(
     Ok(())
 }
 
-fn program_change(
+/// Get the unit for a drum kind, creating it if this is the first note of that kind
+fn drum_unit(
     song: &mut Song,
-    used_programs: &mut UsedPrograms,
+    unit_map: &mut UnitMapping,
+    used_voices: &mut UsedVoices,
+    state: &ChannelState,
+    clock: u32,
+    kind: DrumKind,
+) -> UnitIdx {
+    let (unit, new) = unit_map.get_or_insert(UnitSrc::Drum(kind));
+    if new {
+        set_voice(song, used_voices, clock, unit, VoiceSrc::Drum(kind));
+        // Catch up with the channel-wide controllers that happened before this unit existed
+        if let Some(vol) = state.last_volume {
+            song.events.eves.push(Event {
+                payload: EventPayload::Volume(i16::from(vol.as_int())),
+                unit,
+                tick: clock,
+            });
+        }
+        if let Some(pan) = state.last_pan {
+            song.events.eves.push(Event {
+                payload: EventPayload::PanVol(pan.as_int()),
+                unit,
+                tick: clock,
+            });
+        }
+    }
+    unit
+}
+
+/// Length of an Organya drum sample in ticks, assuming the default midi tempo
+fn drum_sample_ticks(sample: u8, ticks_per_beat: u16) -> u32 {
+    let n_samples = crate::organya::find_drum_sample(sample).len() as u32;
+    // Samples are 22050 Hz, and default tempo of 120 bpm means 2 beats per second
+    n_samples * 2 * u32::from(ticks_per_beat) / crate::organya::DRUM_SAMPLE_RATE
+}
+
+fn set_voice(
+    song: &mut Song,
+    used_voices: &mut UsedVoices,
     clock: u32,
-    channel: midly::num::u4,
     unit: UnitIdx,
-    program: u8,
+    src: VoiceSrc,
 ) {
-    let len = used_programs.len();
-    let program = if channel.as_int() == DRUM_CH {
-        DRUM_PRG
-    } else {
-        program
-    };
-    let idx = used_programs
-        .entry(program)
+    let len = used_voices.len();
+    let idx = used_voices
+        .entry(src)
         .or_insert(VoiceIdx(len.try_into().unwrap()));
-    log::info!("Instrument change of {channel} to {program}");
     song.events.eves.push(Event {
         payload: EventPayload::SetVoice(*idx),
         unit,
@@ -309,22 +613,25 @@ fn program_change(
     });
 }
 
-/// Replace the existing voices with a voice mapped for each "program"
-fn replace_voices(ins: &mut MooInstructions, used_programs: UsedPrograms) {
+/// Replace the existing voices with a voice mapped for each "program" and drum kind
+fn replace_voices(ins: &mut MooInstructions, used_voices: UsedVoices, drum_map: &DrumMap) {
     ins.voices.clear();
-    let mut pairs: Vec<_> = used_programs.into_iter().collect();
+    let mut pairs: Vec<_> = used_voices.into_iter().collect();
     pairs.sort_by_key(|pair| pair.1.0);
-    for (prg, _) in pairs {
-        if prg == DRUM_PRG {
-            let mut voice = hat_close_voice();
-            voice.name = "drum".into();
-            ins.voices.push(voice);
-        } else {
-            let mut voice =
-                ptcow::Voice::from_ptvoice(include_bytes!("../res/soft-saw.ptvoice")).unwrap();
-            let nam = PROGRAM_NAMES[prg as usize];
-            voice.name = format!("[{prg}] {nam}");
-            ins.voices.push(voice);
+    for (src, _) in pairs {
+        match src {
+            VoiceSrc::Drum(kind) => {
+                let mut voice = crate::organya::drum_sample_voice(drum_map.sample_for(kind));
+                voice.name = format!("drum {}", kind.label());
+                ins.voices.push(voice);
+            }
+            VoiceSrc::Program(prg) => {
+                let mut voice =
+                    ptcow::Voice::from_ptvoice(include_bytes!("../res/soft-saw.ptvoice")).unwrap();
+                let nam = PROGRAM_NAMES[prg as usize];
+                voice.name = format!("[{prg}] {nam}");
+                ins.voices.push(voice);
+            }
         }
     }
     // If there were no program events or whatever, we still want at least one voice
@@ -490,3 +797,18 @@ const PROGRAM_NAMES: [&str; 128] = [
     "applause",
     "gun shot",
 ];
+
+#[test]
+fn test_drum_map_pref_string() {
+    let mut map = DrumMap::default();
+    map.notes[36] = None;
+    map.notes[100] = Some(DrumKind::OpenHat);
+    *map.sample_for_mut(DrumKind::Kick) = 42;
+    let parsed = DrumMap::from_pref_string(&map.to_pref_string()).unwrap();
+    assert_eq!(parsed.notes, map.notes);
+    assert_eq!(parsed.samples, map.samples);
+    assert!(DrumMap::from_pref_string("").is_none());
+    assert!(DrumMap::from_pref_string("1,2;").is_none());
+    *map.sample_for_mut(DrumKind::Kick) = crate::organya::DRUM_SAMPLE_COUNT;
+    assert!(DrumMap::from_pref_string(&map.to_pref_string()).is_none());
+}
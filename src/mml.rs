@@ -0,0 +1,673 @@
+//! MML (Music Macro Language) import and export
+//!
+//! Tracks are separated by `;`, and `//` starts a comment that runs until the end of the line.
+//! The last comment before the first command of a track is used as the unit name.
+//!
+//! | Command        | Meaning                                                      |
+//! |----------------|--------------------------------------------------------------|
+//! | `c`..`b`       | Note, followed by `+`/`#` (sharp) or `-` (flat), and length   |
+//! | `r`            | Rest                                                         |
+//! | `o4`, `<`, `>` | Set octave, octave down, octave up                           |
+//! | `l8`           | Default length                                               |
+//! | `&`            | Tie the previous note to the next one (legato if pitch differs) |
+//! | `t120`         | Tempo in beats per minute                                    |
+//! | `v12`          | Volume (0..=15)                                              |
+//! | `V104`         | PxTone volume (0..=128)                                      |
+//! | `p64`          | Pan (0..=128)                                                |
+//! | `@1`           | Voice                                                        |
+//! | `[ ]3`         | Repeat 3 times (default 2)                                   |
+//!
+//! Lengths are a note division (`4` = quarter note) followed by dots, or `%` followed by a
+//! number of ticks, at 480 ticks per beat. Lengths can be joined with `^`, e.g. `c4.^16`.
+
+use {
+    crate::pxtone_misc::{KeyInfo, square_wave_voice},
+    anyhow::bail,
+    ptcow::{
+        DEFAULT_KEY, Event, EventPayload, Herd, MooInstructions, Song, Unit, UnitIdx, VoiceIdx,
+    },
+    std::fmt::Write as _,
+};
+
+/// Offset between MML octaves/notes and PxTone semitones (o4c = C4)
+const SEMITONE_OFFSET: i32 = 39;
+const TICKS_PER_BEAT: u16 = 480;
+/// Guard against loops blowing up the event list
+const MAX_EVENTS: usize = 1_000_000;
+/// Guard against loops running for too long, even if they produce no events
+const MAX_STEPS: u32 = 10_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LenBase {
+    /// Note division, e.g. 4 for quarter notes
+    Div(u32),
+    Ticks(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct LenPart {
+    /// `None` means the default length
+    base: Option<LenBase>,
+    dots: u8,
+}
+
+type Len = Vec<LenPart>;
+
+#[derive(Debug)]
+enum Cmd {
+    /// Semitone relative to C of the current octave
+    Note(i32, Len),
+    Rest(Len),
+    Octave(i32),
+    OctaveUp,
+    OctaveDown,
+    DefaultLen(LenPart),
+    Tempo(f32),
+    Volume(i16),
+    Pan(u8),
+    Voice(u8),
+    Tie,
+    Loop(Vec<Cmd>, u32),
+}
+
+struct Track {
+    name: Option<String>,
+    cmds: Vec<Cmd>,
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    track: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+    fn eat(&mut self, ch: u8) -> bool {
+        if self.peek() == Some(ch) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn err(&self, msg: &str) -> anyhow::Error {
+        anyhow::anyhow!("MML track {}, offset {}: {msg}", self.track + 1, self.pos)
+    }
+    /// Skip whitespace and comments, returning the text of the last comment
+    fn skip_trivia(&mut self) -> Option<String> {
+        let mut comment = None;
+        loop {
+            match self.peek() {
+                Some(ch) if ch.is_ascii_whitespace() => self.pos += 1,
+                Some(b'/') if self.src.get(self.pos + 1) == Some(&b'/') => {
+                    let start = self.pos + 2;
+                    while self.peek().is_some_and(|ch| ch != b'\n') {
+                        self.pos += 1;
+                    }
+                    comment = Some(
+                        String::from_utf8_lossy(&self.src[start..self.pos])
+                            .trim()
+                            .to_owned(),
+                    );
+                }
+                _ => return comment,
+            }
+        }
+    }
+    fn number(&mut self) -> Option<u32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|ch| ch.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.src[start..self.pos])
+            .ok()?
+            .parse()
+            .ok()
+    }
+    fn expect_number(&mut self) -> anyhow::Result<u32> {
+        self.number().ok_or_else(|| self.err("expected number"))
+    }
+    fn signed_number(&mut self) -> anyhow::Result<i32> {
+        let neg = self.eat(b'-');
+        let num = i32::try_from(self.expect_number()?)?;
+        Ok(if neg { -num } else { num })
+    }
+    fn float(&mut self) -> anyhow::Result<f32> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|ch| ch.is_ascii_digit() || ch == b'.')
+        {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.src[start..self.pos])?
+            .parse()
+            .map_err(|_| self.err("expected number"))
+    }
+    fn len_part(&mut self) -> anyhow::Result<LenPart> {
+        let base = if self.eat(b'%') {
+            Some(LenBase::Ticks(self.expect_number()?))
+        } else {
+            match self.number() {
+                Some(0) => return Err(self.err("length can't be 0")),
+                Some(div) => Some(LenBase::Div(div)),
+                None => None,
+            }
+        };
+        let mut dots = 0;
+        while self.eat(b'.') {
+            dots += 1;
+        }
+        Ok(LenPart { base, dots })
+    }
+    fn len(&mut self) -> anyhow::Result<Len> {
+        let mut len = vec![self.len_part()?];
+        while self.eat(b'^') {
+            len.push(self.len_part()?);
+        }
+        Ok(len)
+    }
+    fn u8_arg(&mut self, max: u8) -> anyhow::Result<u8> {
+        match u8::try_from(self.expect_number()?) {
+            Ok(val) if val <= max => Ok(val),
+            _ => Err(self.err(&format!("value out of range (0..={max})"))),
+        }
+    }
+    /// Parse commands until the end of the track or loop
+    fn cmds(&mut self, in_loop: bool) -> anyhow::Result<Vec<Cmd>> {
+        let mut cmds = Vec::new();
+        loop {
+            self.skip_trivia();
+            let Some(ch) = self.peek() else {
+                if in_loop {
+                    return Err(self.err("unclosed loop"));
+                }
+                return Ok(cmds);
+            };
+            self.pos += 1;
+            let cmd = match ch.to_ascii_lowercase() {
+                b'c' | b'd' | b'e' | b'f' | b'g' | b'a' | b'b' => {
+                    let mut semi = match ch.to_ascii_lowercase() {
+                        b'c' => 0,
+                        b'd' => 2,
+                        b'e' => 4,
+                        b'f' => 5,
+                        b'g' => 7,
+                        b'a' => 9,
+                        _ => 11,
+                    };
+                    loop {
+                        if self.eat(b'+') || self.eat(b'#') {
+                            semi += 1;
+                        } else if self.eat(b'-') {
+                            semi -= 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    Cmd::Note(semi, self.len()?)
+                }
+                b'r' => Cmd::Rest(self.len()?),
+                b'o' => Cmd::Octave(self.signed_number()?),
+                b'>' => Cmd::OctaveUp,
+                b'<' => Cmd::OctaveDown,
+                b'l' => Cmd::DefaultLen(self.len_part()?),
+                b't' => Cmd::Tempo(self.float()?),
+                b'v' if ch == b'V' => Cmd::Volume(i16::from(self.u8_arg(128)?)),
+                b'v' => Cmd::Volume(i16::from(self.u8_arg(15)?) * 128 / 15),
+                b'p' => Cmd::Pan(self.u8_arg(128)?),
+                b'@' => Cmd::Voice(self.u8_arg(u8::MAX)?),
+                b'&' => Cmd::Tie,
+                b'[' => {
+                    let body = self.cmds(true)?;
+                    Cmd::Loop(body, self.number().unwrap_or(2))
+                }
+                b']' if in_loop => return Ok(cmds),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.err(&format!("unexpected '{}'", char::from(ch))));
+                }
+            };
+            cmds.push(cmd);
+        }
+    }
+}
+
+fn parse(src: &str) -> anyhow::Result<Vec<Track>> {
+    let mut tracks = Vec::new();
+    for (i, text) in src.split(';').enumerate() {
+        let mut parser = Parser {
+            src: text.as_bytes(),
+            pos: 0,
+            track: i,
+        };
+        let name = parser.skip_trivia();
+        let cmds = parser.cmds(false)?;
+        // Allow a trailing `;`, or comments after the last track
+        if cmds.is_empty() && name.is_none() {
+            continue;
+        }
+        tracks.push(Track { name, cmds });
+    }
+    Ok(tracks)
+}
+
+/// Ticks of `part`, or `None` if there are too many
+fn part_ticks(part: LenPart, default: u32) -> Option<u32> {
+    let whole = u32::from(TICKS_PER_BEAT) * 4;
+    let base = match part.base {
+        Some(LenBase::Div(div)) => whole / div,
+        Some(LenBase::Ticks(ticks)) => ticks,
+        None => default,
+    };
+    let (mut total, mut add) = (base, base);
+    for _ in 0..part.dots {
+        add /= 2;
+        total = total.checked_add(add)?;
+    }
+    Some(total)
+}
+
+/// Ticks of `len`, or `None` if there are too many
+fn len_ticks(len: &Len, default: u32) -> Option<u32> {
+    len.iter().try_fold(0u32, |sum, part| {
+        sum.checked_add(part_ticks(*part, default)?)
+    })
+}
+
+struct TrackState {
+    unit: UnitIdx,
+    tick: u32,
+    octave: i32,
+    default_len: u32,
+    key: Option<i32>,
+    /// Index of the `On` event of the last note, if it can be tied to
+    last_on: Option<usize>,
+    tie: bool,
+    used_voice: bool,
+    max_voice: u8,
+    /// Commands and loop iterations executed so far
+    steps: u32,
+}
+
+impl TrackState {
+    fn step(&mut self) -> anyhow::Result<()> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            bail!("MML runs for too long (runaway loop?)");
+        }
+        Ok(())
+    }
+    /// Move the track forward by `ticks`
+    fn advance(&mut self, ticks: Option<u32>) -> anyhow::Result<()> {
+        match ticks.and_then(|ticks| self.tick.checked_add(ticks)) {
+            Some(tick) => self.tick = tick,
+            None => bail!("MML track is too long"),
+        }
+        Ok(())
+    }
+    fn exec(&mut self, cmds: &[Cmd], song: &mut Song) -> anyhow::Result<()> {
+        for cmd in cmds {
+            if song.events.len() > MAX_EVENTS {
+                bail!("MML produces too many events (runaway loop?)");
+            }
+            self.step()?;
+            let unit = self.unit;
+            let tick = self.tick;
+            let mut push = |payload| {
+                song.events.push(Event {
+                    payload,
+                    unit,
+                    tick,
+                });
+            };
+            match cmd {
+                Cmd::Note(semi, len) => {
+                    let Some(ticks) = len_ticks(len, self.default_len) else {
+                        bail!("MML note is too long");
+                    };
+                    let Some(key) = self
+                        .octave
+                        .checked_mul(12)
+                        .and_then(|semis| semis.checked_add(SEMITONE_OFFSET + semi))
+                        .and_then(|semis| semis.checked_mul(256))
+                    else {
+                        bail!("MML note is out of range (octave {})", self.octave);
+                    };
+                    if self.key != Some(key) {
+                        push(EventPayload::Key(key));
+                        self.key = Some(key);
+                    }
+                    if let Some(idx) = self.last_on.filter(|_| self.tie) {
+                        if let EventPayload::On { duration } = &mut song.events[idx].payload {
+                            let Some(tied) = duration.checked_add(ticks) else {
+                                bail!("MML note is too long");
+                            };
+                            *duration = tied;
+                        }
+                    } else {
+                        self.last_on = Some(song.events.len());
+                        song.events.push(Event {
+                            payload: EventPayload::On { duration: ticks },
+                            unit,
+                            tick,
+                        });
+                    }
+                    self.tie = false;
+                    self.advance(Some(ticks))?;
+                }
+                Cmd::Rest(len) => {
+                    self.last_on = None;
+                    self.tie = false;
+                    self.advance(len_ticks(len, self.default_len))?;
+                }
+                Cmd::Octave(octave) => self.octave = *octave,
+                Cmd::OctaveUp => self.octave = self.octave.saturating_add(1),
+                Cmd::OctaveDown => self.octave = self.octave.saturating_sub(1),
+                Cmd::DefaultLen(part) => {
+                    let Some(ticks) = part_ticks(*part, self.default_len) else {
+                        bail!("MML default length is too long");
+                    };
+                    self.default_len = ticks;
+                }
+                Cmd::Tempo(bpm) => {
+                    if tick == 0 {
+                        song.master.timing.bpm = *bpm;
+                    } else {
+                        song.events.push(Event {
+                            payload: EventPayload::BeatTempo(*bpm),
+                            unit: UnitIdx(0),
+                            tick,
+                        });
+                    }
+                }
+                Cmd::Volume(vol) => push(EventPayload::Volume(*vol)),
+                Cmd::Pan(pan) => push(EventPayload::PanVol(*pan)),
+                Cmd::Voice(voice) => {
+                    push(EventPayload::SetVoice(VoiceIdx(*voice)));
+                    self.used_voice = true;
+                    self.max_voice = self.max_voice.max(*voice);
+                }
+                Cmd::Tie => self.tie = true,
+                Cmd::Loop(body, count) => {
+                    for _ in 0..*count {
+                        self.step()?;
+                        self.exec(body, song)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Import MML text into a PxTone song
+pub fn import(
+    src: &str,
+    herd: &mut Herd,
+    song: &mut Song,
+    ins: &mut MooInstructions,
+) -> anyhow::Result<()> {
+    let tracks = parse(src)?;
+    if tracks.len() > 50 {
+        bail!(
+            "MML has {} tracks, but PxTone supports at most 50 units",
+            tracks.len()
+        );
+    }
+    song.events.clear();
+    herd.units.clear();
+    ins.voices.clear();
+    song.master.timing.ticks_per_beat = TICKS_PER_BEAT;
+    song.master.timing.bpm = 120.;
+    song.master.loop_points.repeat = 0;
+    song.master.loop_points.last = None;
+    let mut n_voices = 1;
+    for (i, track) in tracks.iter().enumerate() {
+        let unit = UnitIdx(i as u8);
+        let mut state = TrackState {
+            unit,
+            tick: 0,
+            octave: 4,
+            default_len: u32::from(TICKS_PER_BEAT),
+            key: None,
+            last_on: None,
+            tie: false,
+            used_voice: false,
+            max_voice: 0,
+            steps: 0,
+        };
+        let first_ev = song.events.len();
+        state.exec(&track.cmds, song)?;
+        if !state.used_voice {
+            song.events.insert(
+                first_ev,
+                Event {
+                    payload: EventPayload::SetVoice(VoiceIdx(0)),
+                    unit,
+                    tick: 0,
+                },
+            );
+        }
+        n_voices = n_voices.max(usize::from(state.max_voice) + 1);
+        herd.units.push(Unit {
+            name: track
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("track{}", i + 1)),
+            ..Default::default()
+        });
+    }
+    for i in 0..n_voices {
+        let mut voice = square_wave_voice();
+        voice.name = format!("mml @{i}");
+        ins.voices.push(voice);
+    }
+    song.events.sort_by_key(|ev| ev.tick);
+    ptcow::rebuild_tones(ins, &mut herd.delays, &mut herd.overdrives, &song.master);
+    Ok(())
+}
+
+/// Split a tick length into MML lengths, joined by `^`
+fn length_text(ticks: u32, ticks_per_beat: u32) -> String {
+    let whole = ticks_per_beat * 4;
+    let mut candidates = Vec::new();
+    for div in [1, 2, 4, 8, 16, 32, 64] {
+        if whole.is_multiple_of(div) {
+            let base = whole / div;
+            if base.is_multiple_of(2) {
+                candidates.push((base * 3 / 2, format!("{div}.")));
+            }
+            candidates.push((base, div.to_string()));
+        }
+    }
+    let mut parts = Vec::new();
+    let mut remaining = ticks;
+    while remaining > 0 {
+        if let Some((len, text)) = candidates.iter().find(|(len, _)| *len <= remaining) {
+            parts.push(text.clone());
+            remaining -= len;
+        } else {
+            // Tick lengths are read at the import resolution, not the song's
+            let tpb = u64::from(ticks_per_beat);
+            let ticks = (u64::from(remaining) * u64::from(TICKS_PER_BEAT) + tpb / 2) / tpb;
+            parts.push(format!("%{}", ticks.max(1)));
+            remaining = 0;
+        }
+    }
+    parts.join("^")
+}
+
+struct TrackWriter {
+    out: String,
+    ticks_per_beat: u32,
+    /// Ticks before this have been written
+    cursor: u32,
+    octave: Option<i16>,
+    /// End tick and key of the sounding note
+    note: Option<(u32, i32)>,
+    /// Whether the sounding note was already partly written, and needs a tie to continue
+    tie: bool,
+    key: i32,
+}
+
+impl TrackWriter {
+    fn cmd(&mut self, args: std::fmt::Arguments) {
+        let _ = write!(self.out, "{args} ");
+    }
+    /// Write notes and rests up until `tick`
+    fn advance(&mut self, tick: u32) {
+        while self.cursor < tick {
+            if let Some((end, key)) = self.note {
+                let seg_end = end.min(tick);
+                self.write_note(key, seg_end - self.cursor);
+                self.cursor = seg_end;
+                if seg_end == end {
+                    self.note = None;
+                    self.tie = false;
+                } else {
+                    self.tie = true;
+                }
+            } else {
+                let len = length_text(tick - self.cursor, self.ticks_per_beat);
+                self.cmd(format_args!("r{len}"));
+                self.cursor = tick;
+            }
+        }
+    }
+    fn write_note(&mut self, key: i32, ticks: u32) {
+        let info = KeyInfo::from_semitone((key / 256).clamp(0, 255) as u8);
+        match self.octave {
+            Some(oct) if oct == info.octave => {}
+            Some(oct) if oct + 1 == info.octave => self.out.push_str("> "),
+            Some(oct) if oct - 1 == info.octave => self.out.push_str("< "),
+            _ => self.cmd(format_args!("o{}", info.octave)),
+        }
+        self.octave = Some(info.octave);
+        let tie = if self.tie { "&" } else { "" };
+        let name = info.notation().to_ascii_lowercase();
+        let len = length_text(ticks, self.ticks_per_beat);
+        self.cmd(format_args!("{tie}{name}{len}"));
+    }
+}
+
+/// Export the units of a song as MML text, one track per unit
+///
+/// Key events that aren't on a semitone boundary are rounded down.
+pub fn export(song: &Song, herd: &Herd) -> String {
+    let mut out = String::new();
+    for (i, (unit_idx, unit)) in herd.units.enumerated().enumerate() {
+        let mut w = TrackWriter {
+            out: String::new(),
+            ticks_per_beat: u32::from(song.master.timing.ticks_per_beat),
+            cursor: 0,
+            octave: None,
+            note: None,
+            tie: false,
+            key: DEFAULT_KEY,
+        };
+        // Tempo is global, so we only write it to the first track
+        let first = i == 0;
+        if first {
+            w.cmd(format_args!("t{}", song.master.timing.bpm));
+        }
+        for ev in song.events.iter() {
+            let is_tempo = matches!(ev.payload, EventPayload::BeatTempo(_));
+            if ev.unit != unit_idx && !(first && is_tempo) {
+                continue;
+            }
+            w.advance(ev.tick);
+            match ev.payload {
+                EventPayload::On { duration } => {
+                    // Overlapping notes get cut off
+                    w.tie = false;
+                    w.note = Some((ev.tick + duration, w.key));
+                }
+                EventPayload::Key(key) => {
+                    w.key = key;
+                    if let Some((_, note_key)) = &mut w.note {
+                        // Change pitch without retriggering
+                        *note_key = key;
+                    }
+                }
+                EventPayload::Volume(vol) => w.cmd(format_args!("V{}", vol.clamp(0, 128))),
+                EventPayload::PanVol(pan) => w.cmd(format_args!("p{}", pan.min(128))),
+                EventPayload::SetVoice(voice) => w.cmd(format_args!("@{}", voice.0)),
+                EventPayload::BeatTempo(bpm) if first => w.cmd(format_args!("t{bpm}")),
+                _ => {}
+            }
+        }
+        if let Some((end, _)) = w.note {
+            w.advance(end);
+        }
+        let _ = writeln!(out, "// {}", unit.name);
+        out.push_str(w.out.trim_end());
+        out.push_str(";\n\n");
+    }
+    out
+}
+
+#[test]
+fn test_mml_lengths() {
+    assert_eq!(length_text(480, 480), "4");
+    assert_eq!(length_text(720 + 120, 480), "4.^16");
+    assert_eq!(length_text(1920 + 7, 480), "1^%7");
+    let mut parser = Parser {
+        src: b"4.^16 %7",
+        pos: 0,
+        track: 0,
+    };
+    assert_eq!(len_ticks(&parser.len().unwrap(), 480), Some(840));
+    parser.skip_trivia();
+    assert_eq!(len_ticks(&parser.len().unwrap(), 480), Some(7));
+}
+
+#[test]
+fn test_mml_runaway() {
+    let try_import = |src| {
+        let mut ins = MooInstructions::new(44_100);
+        import(src, &mut Herd::default(), &mut Song::default(), &mut ins)
+    };
+    // Overflowing lengths and loops that don't end in a reasonable time are errors
+    assert!(try_import("c%4294967295 c").is_err());
+    assert!(try_import("c%4294967295&c%1").is_err());
+    assert!(try_import("l%4294967295. c").is_err());
+    assert!(try_import("[]4294967295").is_err());
+    assert!(try_import("[[]65535]65535").is_err());
+    assert!(try_import("[c]3 r [d]2").is_ok());
+    assert!(try_import("o10000000 c").is_err());
+    assert!(try_import("o2147483647 > c").is_err());
+}
+
+#[test]
+fn test_mml_round_trip() {
+    // Lengths shorter than a 64th note have to survive the change to the import resolution
+    let mut song = Song::default();
+    song.master.timing.ticks_per_beat = 96;
+    let mut herd = Herd::default();
+    herd.units.push(Unit::default());
+    for (tick, duration) in [(0, 96 + 5), (104, 2), (106, 384)] {
+        song.events.push(Event {
+            payload: EventPayload::On { duration },
+            unit: UnitIdx(0),
+            tick,
+        });
+    }
+    let text = export(&song, &herd);
+    let mut imported = Song::default();
+    let mut ins = MooInstructions::new(44_100);
+    import(&text, &mut Herd::default(), &mut imported, &mut ins).unwrap();
+    let notes = |song: &Song, scale| {
+        song.events
+            .iter()
+            .filter_map(|ev| match ev.payload {
+                EventPayload::On { duration } => Some((ev.tick * scale, duration * scale)),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(notes(&imported, 1), notes(&song, 5));
+}
@@ -0,0 +1,370 @@
+//! MusicXML export for sheet music
+//!
+//! Each unit becomes a part. Note positions and lengths are quantized to a 32nd note grid,
+//! assuming a beat is a quarter note.
+
+use {
+    crate::pxtone_misc::KeyInfo,
+    ptcow::{DEFAULT_KEY, EventPayload, Herd, Song, UnitIdx},
+    std::fmt::Write as _,
+};
+
+/// Divisions per quarter note (32nd note grid)
+const DIVISIONS: u32 = 8;
+/// Note types and their lengths in divisions, longest first
+const NOTE_TYPES: [(u32, &str); 6] = [
+    (32, "whole"),
+    (16, "half"),
+    (8, "quarter"),
+    (4, "eighth"),
+    (2, "16th"),
+    (1, "32nd"),
+];
+/// PxTone semitone of middle C, used to pick a clef
+const MIDDLE_C: i32 = 87;
+
+/// A note or rest on the division grid
+struct Span {
+    start: u32,
+    end: u32,
+    /// Semitones of the notes (empty for rests)
+    pitches: Vec<i32>,
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+/// Note spans of a unit, without overlaps. Notes starting at the same division become chords.
+fn unit_spans(song: &Song, unit: UnitIdx) -> Vec<Span> {
+    let tpb = u32::from(song.master.timing.ticks_per_beat).max(1);
+    let quantize =
+        |tick: u32| (f64::from(tick) * f64::from(DIVISIONS) / f64::from(tpb)).round() as u32;
+    let mut key = DEFAULT_KEY;
+    let mut spans: Vec<Span> = Vec::new();
+    for ev in song.events.iter().filter(|ev| ev.unit == unit) {
+        match ev.payload {
+            EventPayload::Key(new) => key = new,
+            EventPayload::On { duration } => {
+                let start = quantize(ev.tick);
+                // Even very short notes should show up
+                let end = quantize(ev.tick + duration).max(start + 1);
+                let pitch = key / 256;
+                match spans.last_mut() {
+                    Some(last) if last.start == start => {
+                        if !last.pitches.contains(&pitch) {
+                            last.pitches.push(pitch);
+                        }
+                        last.end = last.end.max(end);
+                    }
+                    _ => spans.push(Span {
+                        start,
+                        end,
+                        pitches: vec![pitch],
+                    }),
+                }
+            }
+            _ => {}
+        }
+    }
+    // Overlapping notes are cut off by the next one
+    for i in 1..spans.len() {
+        let next_start = spans[i].start;
+        let prev = &mut spans[i - 1];
+        prev.end = prev.end.min(next_start);
+    }
+    spans
+}
+
+/// Split a length into note types with dots (base length, type name, dotted)
+fn note_pieces(mut len: u32) -> Vec<(u32, &'static str, bool)> {
+    let mut pieces = Vec::new();
+    while len > 0 {
+        for (base, name) in NOTE_TYPES {
+            if base > 1 && base * 3 / 2 <= len {
+                pieces.push((base * 3 / 2, name, true));
+                len -= base * 3 / 2;
+                break;
+            }
+            if base <= len {
+                pieces.push((base, name, false));
+                len -= base;
+                break;
+            }
+        }
+    }
+    pieces
+}
+
+fn write_pitch(out: &mut String, semitone: i32) {
+    let info = KeyInfo::from_semitone(semitone.clamp(0, 255) as u8);
+    let notation = info.notation();
+    let step = &notation[..1];
+    let _ = write!(out, "<pitch><step>{step}</step>");
+    if notation.ends_with('#') {
+        out.push_str("<alter>1</alter>");
+    }
+    let _ = write!(out, "<octave>{}</octave></pitch>", info.octave);
+}
+
+/// Write a note or rest that fits in a measure, split into tied note types as necessary
+fn write_notes(out: &mut String, len: u32, pitches: &[i32], tie_in: bool, tie_out: bool) {
+    let pieces = note_pieces(len);
+    let n_pieces = pieces.len();
+    for (i, (piece_len, type_name, dotted)) in pieces.into_iter().enumerate() {
+        let tie_start = !pitches.is_empty() && (tie_out || i + 1 < n_pieces);
+        let tie_stop = !pitches.is_empty() && (tie_in || i > 0);
+        if pitches.is_empty() {
+            let _ = write!(
+                out,
+                "<note><rest/><duration>{piece_len}</duration><voice>1</voice>\
+                 <type>{type_name}</type>"
+            );
+            if dotted {
+                out.push_str("<dot/>");
+            }
+            out.push_str("</note>\n");
+            continue;
+        }
+        for (j, pitch) in pitches.iter().enumerate() {
+            out.push_str("<note>");
+            if j > 0 {
+                out.push_str("<chord/>");
+            }
+            write_pitch(out, *pitch);
+            let _ = write!(out, "<duration>{piece_len}</duration>");
+            if tie_stop {
+                out.push_str(r#"<tie type="stop"/>"#);
+            }
+            if tie_start {
+                out.push_str(r#"<tie type="start"/>"#);
+            }
+            let _ = write!(out, "<voice>1</voice><type>{type_name}</type>");
+            if dotted {
+                out.push_str("<dot/>");
+            }
+            if tie_start || tie_stop {
+                out.push_str("<notations>");
+                if tie_stop {
+                    out.push_str(r#"<tied type="stop"/>"#);
+                }
+                if tie_start {
+                    out.push_str(r#"<tied type="start"/>"#);
+                }
+                out.push_str("</notations>");
+            }
+            out.push_str("</note>\n");
+        }
+    }
+}
+
+/// Export the song as an uncompressed MusicXML score
+pub fn export(song: &Song, herd: &Herd) -> String {
+    let timing = &song.master.timing;
+    let beats = u32::from(timing.beats_per_meas).max(1);
+    let meas_len = beats * DIVISIONS;
+    let parts: Vec<_> = herd
+        .units
+        .enumerated()
+        .map(|(idx, unit)| (unit, unit_spans(song, idx)))
+        .collect();
+    let end = parts
+        .iter()
+        .filter_map(|(_, spans)| spans.last().map(|span| span.end))
+        .max()
+        .unwrap_or(0);
+    let n_meas = end.div_ceil(meas_len).max(1);
+
+    let mut out = String::new();
+    out.push_str(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n\
+         <!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \
+         \"http://www.musicxml.org/dtds/partwise.dtd\">\n\
+         <score-partwise version=\"4.0\">\n",
+    );
+    let _ = writeln!(
+        out,
+        "<work><work-title>{}</work-title></work>",
+        escape(&song.text.name)
+    );
+    out.push_str("<identification><encoding><software>ptcowlage</software></encoding>");
+    if !song.text.comment.is_empty() {
+        let _ = write!(
+            out,
+            "<miscellaneous><miscellaneous-field name=\"comment\">{}</miscellaneous-field>\
+             </miscellaneous>",
+            escape(&song.text.comment)
+        );
+    }
+    out.push_str("</identification>\n");
+    if !song.text.name.is_empty() {
+        let _ = writeln!(
+            out,
+            "<credit page=\"1\"><credit-type>title</credit-type>\
+             <credit-words justify=\"center\" valign=\"top\">{}</credit-words></credit>",
+            escape(&song.text.name)
+        );
+    }
+    out.push_str("<part-list>\n");
+    for (i, (unit, _)) in parts.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "<score-part id=\"P{}\"><part-name>{}</part-name></score-part>",
+            i + 1,
+            escape(&unit.name)
+        );
+    }
+    out.push_str("</part-list>\n");
+    for (i, (_, spans)) in parts.iter().enumerate() {
+        let _ = writeln!(out, "<part id=\"P{}\">", i + 1);
+        let n_pitches: usize = spans.iter().map(|span| span.pitches.len()).sum();
+        let avg_pitch = if n_pitches == 0 {
+            MIDDLE_C
+        } else {
+            spans.iter().flat_map(|span| &span.pitches).sum::<i32>() / n_pitches as i32
+        };
+        let clef = if avg_pitch < MIDDLE_C - 5 {
+            "<clef><sign>F</sign><line>4</line></clef>"
+        } else {
+            "<clef><sign>G</sign><line>2</line></clef>"
+        };
+        let mut spans = spans.iter().peekable();
+        // Position up to which the part has been written
+        let mut pos = 0;
+        for meas in 0..n_meas {
+            let _ = writeln!(out, "<measure number=\"{}\">", meas + 1);
+            if meas == 0 {
+                let _ = writeln!(
+                    out,
+                    "<attributes><divisions>{DIVISIONS}</divisions><key><fifths>0</fifths></key>\
+                     <time><beats>{beats}</beats><beat-type>4</beat-type></time>{clef}</attributes>"
+                );
+                if i == 0 {
+                    let _ = writeln!(
+                        out,
+                        "<direction placement=\"above\"><direction-type><metronome>\
+                         <beat-unit>quarter</beat-unit><per-minute>{bpm}</per-minute></metronome>\
+                         </direction-type><sound tempo=\"{bpm}\"/></direction>",
+                        bpm = timing.bpm
+                    );
+                }
+            }
+            let meas_start = meas * meas_len;
+            let meas_end = meas_start + meas_len;
+            if spans.peek().is_none_or(|span| span.start >= meas_end) && pos <= meas_start {
+                let _ = writeln!(
+                    out,
+                    "<note><rest measure=\"yes\"/><duration>{meas_len}</duration>\
+                     <voice>1</voice></note>"
+                );
+                pos = meas_end;
+            }
+            while pos < meas_end {
+                match spans.peek() {
+                    Some(span) if span.start <= pos => {
+                        let piece_end = span.end.min(meas_end);
+                        write_notes(
+                            &mut out,
+                            piece_end - pos,
+                            &span.pitches,
+                            pos > span.start,
+                            span.end > meas_end,
+                        );
+                        pos = piece_end;
+                        if piece_end == span.end {
+                            spans.next();
+                        }
+                    }
+                    Some(span) => {
+                        let rest_end = span.start.min(meas_end);
+                        write_notes(&mut out, rest_end - pos, &[], false, false);
+                        pos = rest_end;
+                    }
+                    None => {
+                        write_notes(&mut out, meas_end - pos, &[], false, false);
+                        pos = meas_end;
+                    }
+                }
+            }
+            out.push_str("</measure>\n");
+        }
+        out.push_str("</part>\n");
+    }
+    out.push_str("</score-partwise>\n");
+    out
+}
+
+#[test]
+fn test_musicxml_export() {
+    use ptcow::{Event, Unit};
+    assert_eq!(note_pieces(12), [(12, "quarter", true)]);
+    assert_eq!(note_pieces(7), [(6, "eighth", true), (1, "32nd", false)]);
+    let mut song = Song::default();
+    // A tick per division, 4 beats per measure
+    song.master.timing.ticks_per_beat = 8;
+    song.master.timing.beats_per_meas = 4;
+    let mut herd = Herd::default();
+    herd.units.push(Unit::default());
+    let a4 = DEFAULT_KEY;
+    for (tick, payload) in [
+        (0, EventPayload::On { duration: 12 }),
+        (0, EventPayload::Key(a4 + 4 * 256)),
+        (0, EventPayload::On { duration: 8 }),
+        // Crosses the barline
+        (16, EventPayload::On { duration: 24 }),
+        // Cut off by the next note
+        (40, EventPayload::On { duration: 10 }),
+        (44, EventPayload::On { duration: 4 }),
+    ] {
+        song.events.push(Event {
+            payload,
+            unit: UnitIdx(0),
+            tick,
+        });
+    }
+    let spans: Vec<_> = unit_spans(&song, UnitIdx(0))
+        .into_iter()
+        .map(|span| (span.start, span.end, span.pitches.len()))
+        .collect();
+    assert_eq!(spans, [(0, 12, 2), (16, 40, 1), (40, 44, 1), (44, 48, 1)]);
+    let xml = export(&song, &herd);
+    let durations: Vec<_> = xml
+        .lines()
+        .filter(|line| line.starts_with("<note>") && !line.contains("<chord/>"))
+        .map(|line| {
+            let rest = line.contains("<rest/>");
+            let start = line.find("<duration>").unwrap() + "<duration>".len();
+            let len = line[start..].split('<').next().unwrap();
+            (len.parse::<u32>().unwrap(), rest)
+        })
+        .collect();
+    // The dotted chord, a rest, the tied note, the cut off note, and the rest of the measure
+    assert_eq!(
+        durations,
+        [
+            (12, false),
+            (4, true),
+            (16, false),
+            (8, false),
+            (4, false),
+            (4, false),
+            (16, true)
+        ]
+    );
+    assert_eq!(xml.matches("<measure ").count(), 2);
+    assert_eq!(xml.matches("<chord/>").count(), 1);
+    assert_eq!(xml.matches(r#"<tie type="start"/>"#).count(), 1);
+    assert_eq!(xml.matches(r#"<tie type="stop"/>"#).count(), 1);
+    assert_eq!(xml.matches("<dot/>").count(), 2);
+}
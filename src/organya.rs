@@ -111,10 +111,21 @@ fn wave_voice(ch: &organyacat::Channel) -> Voice {
 }
 
 fn drum_voice(ch: &organyacat::Channel) -> Voice {
-    let smp = find_drum_sample(ch.instrument).to_vec();
+    let mut voice = drum_sample_voice(ch.instrument);
+    voice.name = format!("org drum {}", ch.instrument);
+    voice
+}
+
+/// Number of samples in the Organya drum bank
+pub const DRUM_SAMPLE_COUNT: u8 = 45;
+pub const DRUM_SAMPLE_RATE: u32 = 22050;
+
+/// Create a PCM voice out of a sample from the Organya drum bank
+pub fn drum_sample_voice(n: u8) -> Voice {
+    let smp = find_drum_sample(n).to_vec();
     let pcm = PcmData {
         ch: ptcow::ChNum::Mono,
-        sps: 22050,
+        sps: DRUM_SAMPLE_RATE,
         bps: ptcow::Bps::B8,
         num_samples: smp.len() as u32,
         smp,
@@ -123,12 +134,10 @@ fn drum_voice(ch: &organyacat::Channel) -> Voice {
         flags: VoiceFlags::SMOOTH,
         ..VoiceUnit::default()
     };
-    let mut voice = Voice::from_unit_and_data(unit, ptcow::VoiceData::Pcm(pcm));
-    voice.name = format!("org drum {}", ch.instrument);
-    voice
+    Voice::from_unit_and_data(unit, ptcow::VoiceData::Pcm(pcm))
 }
 
-fn find_drum_sample(n: u8) -> &'static [u8] {
+pub fn find_drum_sample(n: u8) -> &'static [u8] {
     let mut data = DRUM_DATA;
     let mut i = 0;
     loop {
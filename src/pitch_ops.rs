@@ -0,0 +1,267 @@
+//! Operations that change the pitch of notes
+
+use {
+    crate::{
+        pxtone_misc::Scale,
+        timing_ops::{EventTarget, NoteValue, set_note_values_with, values_at},
+    },
+    ptcow::{DEFAULT_BASICKEY, Event, EventPayload, Key, Song, Voice, VoiceIdx, Voices},
+    rustc_hash::{FxHashMap, FxHashSet},
+};
+
+/// Settings for transposing notes
+#[derive(Clone)]
+pub struct Transpose {
+    /// Root of the scale, as a pitch class (0 is C)
+    pub root: u8,
+    /// Scale the notes are in, for moving by `degrees` and mapping onto `to_scale`
+    pub scale: Scale,
+    /// Scale degrees to move notes by
+    pub degrees: i32,
+    /// Scale to map the degrees of `scale` onto, for changing modes (e.g. major to minor)
+    pub to_scale: Scale,
+    /// Semitones to move notes by, after the scale mapping
+    pub semitones: i32,
+}
+
+impl Default for Transpose {
+    fn default() -> Self {
+        Self {
+            root: 0,
+            scale: Scale::Major,
+            degrees: 0,
+            to_scale: Scale::Major,
+            semitones: 0,
+        }
+    }
+}
+
+impl Transpose {
+    fn is_diatonic(&self) -> bool {
+        self.degrees != 0 || self.scale != self.to_scale
+    }
+    /// Transpose a key as it's heard
+    ///
+    /// Notes outside the scale keep their distance from the degree below them.
+    /// Detune below a semitone is kept as is.
+    /// Degrees are moved within `scale`, then mapped onto `to_scale` (see `map_degree`).
+    fn apply(&self, key: Key) -> Key {
+        let mut semitone = key.div_euclid(256);
+        let detune = key.rem_euclid(256);
+        if self.is_diatonic() {
+            let from = self.scale.intervals();
+            let to = self.to_scale.intervals();
+            // Semitones above the root of the lowest octave, see `pxtone_misc::pitch_class`
+            let rel = semitone + 9 - i32::from(self.root);
+            let octave = rel.div_euclid(12);
+            let rel = rel.rem_euclid(12);
+            let degree = from.iter().rposition(|&i| i32::from(i) <= rel).unwrap_or(0);
+            let chromatic = rel - i32::from(from[degree]);
+            let new_degree = degree as i32 + self.degrees;
+            let n_degrees = from.len() as i32;
+            let new_octave = octave + new_degree.div_euclid(n_degrees);
+            let new_degree = map_degree(
+                new_degree.rem_euclid(n_degrees) as usize,
+                from.len(),
+                to.len(),
+            );
+            let new_rel = i32::from(to[new_degree]) + chromatic;
+            semitone = new_octave * 12 + new_rel + i32::from(self.root) - 9;
+        }
+        (semitone + self.semitones) * 256 + detune
+    }
+}
+
+/// Degree of a scale with `to_len` degrees for `degree` of one with `from_len` degrees
+///
+/// Degrees are spread out evenly, and stay in the same octave. Going to a bigger scale keeps every
+/// degree apart, going to a smaller one merges neighbouring degrees into the lower one.
+fn map_degree(degree: usize, from_len: usize, to_len: usize) -> usize {
+    if to_len >= from_len {
+        degree * to_len / from_len
+    } else {
+        ((degree + 1) * to_len - 1) / from_len
+    }
+}
+
+/// Transpose the notes of `target`, returning how many notes there were
+///
+/// Key changes in the middle of the notes, like the ones portamento slides to, are transposed
+/// along with them. Scale degrees are figured out from the key that's heard, which depends on the
+/// basic key and tuning of the voice playing the note, as well as `Tuning` events.
+pub fn transpose(song: &mut Song, voices: &Voices, target: &EventTarget, t: &Transpose) -> usize {
+    let offsets = if t.is_diatonic() {
+        heard_key_offsets(song, voices)
+    } else {
+        FxHashMap::default()
+    };
+    let slides = slide_keys(song, target);
+    let n = set_note_values_with(song, target, NoteValue::Key, &slides, |idx, key| {
+        let offset = offsets.get(&idx).copied().unwrap_or(0);
+        t.apply(key + offset) - offset
+    });
+    song.events.sort();
+    n
+}
+
+/// Indices of the `Key` events of `target` that aren't at the start of a note
+///
+/// These are the ones inside the notes of `target`, and with a unit or the whole song as the
+/// target, every one of them.
+fn slide_keys(song: &Song, target: &EventTarget) -> FxHashSet<usize> {
+    let timing = song.master.timing;
+    let note_starts: FxHashSet<_> = song
+        .events
+        .iter()
+        .filter(|ev| matches!(ev.payload, EventPayload::On { .. }))
+        .map(|ev| (ev.unit.0, ev.tick))
+        .collect();
+    let whole_units = matches!(target, EventTarget::Unit(_) | EventTarget::All);
+    // Tick at which the notes of `target` that started so far end, by unit
+    let mut note_ends = [0; 256];
+    let mut slides = FxHashSet::default();
+    for (idx, ev) in song.events.iter().enumerate() {
+        let unit = usize::from(ev.unit.0);
+        match ev.payload {
+            EventPayload::On { duration } if target.contains(idx, ev, timing) => {
+                note_ends[unit] = note_ends[unit].max(ev.tick + duration);
+            }
+            EventPayload::Key(_) if !note_starts.contains(&(ev.unit.0, ev.tick)) => {
+                if ev.tick < note_ends[unit] || (whole_units && target.contains(idx, ev, timing)) {
+                    slides.insert(idx);
+                }
+            }
+            _ => {}
+        }
+    }
+    slides
+}
+
+/// How much the key heard differs from the `Key` of each note and key change, by event index
+fn heard_key_offsets(song: &Song, voices: &Voices) -> FxHashMap<usize, Key> {
+    let at = |ev: &Event| matches!(ev.payload, EventPayload::On { .. } | EventPayload::Key(_));
+    let set_voices = values_at(
+        &song.events,
+        at,
+        |payload| match payload {
+            EventPayload::SetVoice(voice) => Some(voice),
+            _ => None,
+        },
+        VoiceIdx(0),
+    );
+    let tunings = values_at(
+        &song.events,
+        at,
+        |payload| match payload {
+            EventPayload::Tuning(tuning) => Some(tuning),
+            _ => None,
+        },
+        1.0,
+    );
+    set_voices
+        .into_iter()
+        .map(|(idx, voice)| (idx, key_offset(voices.get(voice, &[]), tunings[&idx])))
+        .collect()
+}
+
+fn key_offset(voice: Option<&Voice>, tuning: f32) -> Key {
+    let (basic_key, voice_tuning) = voice.map_or((DEFAULT_BASICKEY, 1.0), |voice| {
+        (voice.base.unit.basic_key, voice.base.unit.tuning)
+    });
+    // Tuning is a frequency multiplier
+    let tuning = f64::from(tuning * voice_tuning);
+    let tuning_offset = if tuning > 0.0 {
+        (tuning.log2() * 12.0 * 256.0).round() as Key
+    } else {
+        0
+    };
+    DEFAULT_BASICKEY - basic_key + tuning_offset
+}
+
+#[test]
+fn test_transpose_apply() {
+    let a4 = ptcow::DEFAULT_KEY;
+    let t = Transpose {
+        degrees: 2,
+        ..Transpose::default()
+    };
+    // A -> C in C major
+    assert_eq!(t.apply(a4), a4 + 3 * 256);
+    let t = Transpose {
+        to_scale: Scale::NaturalMinor,
+        ..Transpose::default()
+    };
+    // Major to minor turns the major third into a minor one, and keeps detune
+    assert_eq!(t.apply(a4 - 5 * 256 + 10), a4 - 6 * 256 + 10);
+    // Out of scale notes keep their distance from the degree below
+    let t = Transpose {
+        degrees: 1,
+        semitones: 12,
+        ..Transpose::default()
+    };
+    assert_eq!(t.apply(a4 + 256), a4 + 15 * 256);
+    // Scales of different sizes map degrees without leaving the octave
+    let t = Transpose {
+        to_scale: Scale::MajorPentatonic,
+        ..Transpose::default()
+    };
+    let c4 = a4 - 9 * 256;
+    let major = [0, 2, 4, 5, 7, 9, 11, 12].map(|semi| t.apply(c4 + semi * 256));
+    assert_eq!(major, [0, 2, 4, 4, 7, 9, 9, 12].map(|semi| c4 + semi * 256));
+    let t = Transpose {
+        scale: Scale::MajorPentatonic,
+        to_scale: Scale::Major,
+        ..Transpose::default()
+    };
+    let pentatonic = [0, 2, 4, 7, 9, 12].map(|semi| t.apply(c4 + semi * 256));
+    assert_eq!(pentatonic, [0, 2, 4, 7, 9, 12].map(|semi| c4 + semi * 256));
+}
+
+#[test]
+fn test_transpose() {
+    use ptcow::{Event, UnitIdx};
+    let mut ins = ptcow::MooInstructions::new(44_100);
+    let mut voice = crate::pxtone_misc::hat_close_voice();
+    // Sounds 2 semitones higher than its keys
+    voice.base.unit.basic_key = DEFAULT_BASICKEY - 2 * 256;
+    voice.base.unit.tuning = 1.0;
+    ins.voices.push(voice);
+    let cs4 = ptcow::DEFAULT_KEY - 8 * 256;
+    let mut song = Song::default();
+    for (tick, payload) in [
+        // Another semitone higher, so C#4 sounds as E4
+        (0, EventPayload::Tuning(2f32.powf(1.0 / 12.0))),
+        (0, EventPayload::Key(cs4)),
+        (0, EventPayload::On { duration: 100 }),
+        (0, EventPayload::Portament { duration: 50 }),
+        // Slides to G#4, which sounds as B4
+        (50, EventPayload::Key(cs4 + 7 * 256)),
+        (200, EventPayload::On { duration: 100 }),
+    ] {
+        song.events.push(Event {
+            payload,
+            unit: UnitIdx(0),
+            tick,
+        });
+    }
+    let t = Transpose {
+        degrees: 1,
+        ..Transpose::default()
+    };
+    let target = EventTarget::Indices([2].into());
+    assert_eq!(transpose(&mut song, &ins.voices, &target, &t), 1);
+    let keys: Vec<_> = song
+        .events
+        .iter()
+        .filter_map(|ev| match ev.payload {
+            EventPayload::Key(key) => Some((ev.tick, key)),
+            _ => None,
+        })
+        .collect();
+    // E4 -> F4 and B4 -> C5 are a semitone up, where the keys as written would go up by two.
+    // The note after keeps the key it had.
+    assert_eq!(
+        keys,
+        [(0, cs4 + 256), (50, cs4 + 8 * 256), (200, cs4 + 7 * 256)]
+    );
+}
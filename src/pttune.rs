@@ -0,0 +1,163 @@
+//! Saving projects as compact tunes (.pttune)
+//!
+//! A tune is a collage without the editing metadata: unit names, voice names, and the song
+//! title and comment. ptcow only writes collages, so tunes are made by rewriting one.
+
+use {
+    ptcow::{Herd, MooInstructions, Song},
+    std::path::Path,
+};
+
+const CODE_COLLAGE: &[u8; 16] = b"PTCOLLAGE-071119";
+const CODE_TUNE: &[u8; 16] = b"PTTUNE--20071119";
+/// Version code, followed by the exe version and a dummy field
+const HEADER_LEN: usize = 20;
+/// Chunks that only exist in collages
+const COLLAGE_ONLY_CHUNKS: [&[u8; 8]; 4] = [b"textNAME", b"textCOMM", b"assiWOIC", b"assiUNIT"];
+const CHUNK_END: &[u8; 8] = b"pxtoneND";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProjFormat {
+    /// .ptcop
+    Collage,
+    /// .pttune
+    Tune,
+}
+
+impl ProjFormat {
+    /// Format to save as, going by the extension of `path`
+    pub fn from_path(path: &Path) -> Self {
+        if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("pttune"))
+        {
+            Self::Tune
+        } else {
+            Self::Collage
+        }
+    }
+}
+
+/// Serialize the project in the requested format
+pub fn serialize_project(
+    song: &Song,
+    herd: &Herd,
+    ins: &MooInstructions,
+    format: ProjFormat,
+) -> anyhow::Result<Vec<u8>> {
+    let collage = ptcow::serialize_project(song, herd, ins)?;
+    match format {
+        ProjFormat::Collage => Ok(collage),
+        ProjFormat::Tune => collage_to_tune(&collage),
+    }
+}
+
+/// Human readable list of things that would be lost by saving as a tune
+pub fn tune_losses(song: &Song, herd: &Herd, ins: &MooInstructions) -> Vec<String> {
+    let mut losses = Vec::new();
+    if !song.text.name.is_empty() {
+        losses.push(format!("Song title \"{}\"", song.text.name));
+    }
+    if !song.text.comment.is_empty() {
+        losses.push("Song comment".into());
+    }
+    let unit_names = herd
+        .units
+        .enumerated()
+        .filter(|(_, unit)| !unit.name.is_empty())
+        .count();
+    if unit_names != 0 {
+        losses.push(format!("Names of {unit_names} unit(s)"));
+    }
+    let voice_names = ins
+        .voices
+        .iter()
+        .filter(|voice| !voice.name.is_empty())
+        .count();
+    if voice_names != 0 {
+        losses.push(format!("Names of {voice_names} voice(s)"));
+    }
+    losses
+}
+
+/// Turn a serialized collage into a tune by replacing the version code and dropping the
+/// collage-only chunks
+pub fn collage_to_tune(collage: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let Some((header, mut rest)) = collage.split_at_checked(HEADER_LEN) else {
+        anyhow::bail!("Collage too short");
+    };
+    if !header.starts_with(CODE_COLLAGE) {
+        anyhow::bail!("Not a collage");
+    }
+    let mut out = Vec::with_capacity(collage.len());
+    out.extend_from_slice(CODE_TUNE);
+    out.extend_from_slice(&header[CODE_TUNE.len()..]);
+    loop {
+        let Some((code, after_code)) = rest.split_first_chunk::<8>() else {
+            anyhow::bail!("Unexpected end of collage");
+        };
+        let Some((size, after_size)) = after_code.split_first_chunk::<4>() else {
+            anyhow::bail!("Unexpected end of collage");
+        };
+        let size = u32::from_le_bytes(*size) as usize;
+        let Some((_, after_body)) = after_size.split_at_checked(size) else {
+            anyhow::bail!("Chunk {} overruns the collage", code.escape_ascii());
+        };
+        let chunk = &rest[..8 + 4 + size];
+        rest = after_body;
+        if COLLAGE_ONLY_CHUNKS.contains(&code) {
+            continue;
+        }
+        out.extend_from_slice(chunk);
+        if code == CHUNK_END {
+            return Ok(out);
+        }
+    }
+}
+
+#[test]
+fn test_collage_to_tune() {
+    fn chunk(code: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut out = code.to_vec();
+        out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        out.extend_from_slice(body);
+        out
+    }
+    let mut collage = CODE_COLLAGE.to_vec();
+    collage.extend_from_slice(&[1, 2, 3, 4]);
+    collage.extend(chunk(b"MasterV5", &[5; 15]));
+    collage.extend(chunk(b"textNAME", b"title"));
+    collage.extend(chunk(b"assiUNIT", &[6; 20]));
+    collage.extend(chunk(b"num UNIT", &[1, 0, 0, 0]));
+    collage.extend(chunk(CHUNK_END, &[]));
+    let tune = collage_to_tune(&collage).unwrap();
+    let mut expected = CODE_TUNE.to_vec();
+    expected.extend_from_slice(&[1, 2, 3, 4]);
+    expected.extend(chunk(b"MasterV5", &[5; 15]));
+    expected.extend(chunk(b"num UNIT", &[1, 0, 0, 0]));
+    expected.extend(chunk(CHUNK_END, &[]));
+    assert_eq!(tune, expected);
+    assert!(collage_to_tune(&collage[..collage.len() - 4]).is_err());
+}
+
+#[test]
+fn test_tune_round_trip() {
+    let collage = include_bytes!("../bundled-songs/The_Watcher_From_Afar.ptcop");
+    let (song, herd, ins) = ptcow::read_song(collage, 44_100).unwrap();
+    let tune = serialize_project(&song, &herd, &ins, ProjFormat::Tune).unwrap();
+    assert!(tune.starts_with(CODE_TUNE));
+    // ptcow has to accept the tune, with everything but the names intact
+    let (tune_song, tune_herd, tune_ins) = ptcow::read_song(&tune, 44_100).unwrap();
+    assert!(tune_song.text.name.is_empty());
+    assert_eq!(tune_song.events.len(), song.events.len());
+    assert_eq!(tune_herd.units.len(), herd.units.len());
+    assert_eq!(tune_ins.voices.len(), ins.voices.len());
+    assert_eq!(
+        tune_song.master.timing.ticks_per_beat,
+        song.master.timing.ticks_per_beat
+    );
+    assert_eq!(
+        tune_song.master.loop_points.repeat,
+        song.master.loop_points.repeat
+    );
+}
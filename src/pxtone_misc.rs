@@ -8,6 +8,7 @@ use std::collections::BTreeMap;
 use std::fmt::Write as _;
 
 use crate::audio_out::SongState;
+use crate::timing_ops::NoteValue;
 
 /// Migrate overlapping 'on' events from one unit to another
 pub fn poly_migrate_units(src_unit: UnitIdx, dst_unit: UnitIdx, events: &mut EveList) -> bool {
@@ -343,9 +344,493 @@ pub const KEY_NAMES: [&str; 12] = [
     "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
 ];
 
+/// Pitch class of a key (0 is C), ignoring any detune below a semitone
+pub fn pitch_class(key: ptcow::Key) -> u8 {
+    // Same offset as in `KeyInfo::from_semitone`
+    (key.div_euclid(256) + 9).rem_euclid(12) as u8
+}
+
+/// A musical scale
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scale {
+    Major,
+    NaturalMinor,
+    HarmonicMinor,
+    MelodicMinor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+    /// Bit `n` set means the note `n` semitones above the root is in the scale
+    Custom(u16),
+}
+
+impl Scale {
+    /// All the scales that aren't custom
+    pub const ALL: [Self; 12] = [
+        Self::Major,
+        Self::NaturalMinor,
+        Self::HarmonicMinor,
+        Self::MelodicMinor,
+        Self::Dorian,
+        Self::Phrygian,
+        Self::Lydian,
+        Self::Mixolydian,
+        Self::Locrian,
+        Self::MajorPentatonic,
+        Self::MinorPentatonic,
+        Self::Blues,
+    ];
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Major => "Major",
+            Self::NaturalMinor => "Natural minor",
+            Self::HarmonicMinor => "Harmonic minor",
+            Self::MelodicMinor => "Melodic minor",
+            Self::Dorian => "Dorian",
+            Self::Phrygian => "Phrygian",
+            Self::Lydian => "Lydian",
+            Self::Mixolydian => "Mixolydian",
+            Self::Locrian => "Locrian",
+            Self::MajorPentatonic => "Major pentatonic",
+            Self::MinorPentatonic => "Minor pentatonic",
+            Self::Blues => "Blues",
+            Self::Custom(_) => "Custom",
+        }
+    }
+    /// Semitones of each degree above the root
+    pub fn intervals(self) -> ArrayVec<u8, 12> {
+        let intervals: &[u8] = match self {
+            Self::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Self::NaturalMinor => &[0, 2, 3, 5, 7, 8, 10],
+            Self::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            Self::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+            Self::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Self::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            Self::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            Self::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Self::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            Self::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Self::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Self::Blues => &[0, 3, 5, 6, 7, 10],
+            // The root is always in the scale
+            Self::Custom(mask) => {
+                return (0..12)
+                    .filter(|&i| i == 0 || mask & (1 << i) != 0)
+                    .collect();
+            }
+        };
+        intervals.iter().copied().collect()
+    }
+    /// Bit mask of the scale, as in `Custom`
+    pub fn mask(self) -> u16 {
+        self.intervals().iter().fold(0, |mask, i| mask | 1 << i)
+    }
+    /// Whether `pitch_class` is in the scale starting at `root` (both 0 for C)
+    pub fn contains(self, root: u8, pitch_class: u8) -> bool {
+        let rel = (pitch_class + 12 - root % 12) % 12;
+        self.intervals().contains(&rel)
+    }
+    /// The nearest semitone to `semitone` that's in the scale, preferring lower ones
+    pub fn snap(self, root: u8, semitone: i32) -> i32 {
+        (0..12)
+            .flat_map(|dist| [semitone - dist, semitone + dist])
+            .find(|&semi| self.contains(root, pitch_class(semi * 256)))
+            .unwrap_or(semitone)
+    }
+}
+
 #[test]
 fn test_key_info() {
     let info = KeyInfo::from_semitone((DEFAULT_KEY / 256) as u8);
     assert_eq!(info.notation(), "A");
     assert_eq!(info.octave, 4);
 }
+
+#[test]
+fn test_scale() {
+    assert_eq!(Scale::Major.mask(), 0b1010_1011_0101);
+    assert_eq!(
+        Scale::Custom(Scale::Major.mask()).intervals(),
+        Scale::Major.intervals()
+    );
+    // C# snaps down to C in C major, but is already in D major
+    let c5 = DEFAULT_KEY / 256 + 3;
+    assert_eq!(Scale::Major.snap(0, c5 + 1), c5);
+    assert_eq!(Scale::Major.snap(2, c5 + 1), c5 + 1);
+}
+
+/// Kind of chord, by the intervals of its notes
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChordKind {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    Sus2,
+    Sus4,
+    Major7,
+    Minor7,
+    Dominant7,
+    HalfDiminished7,
+    Diminished7,
+}
+
+impl ChordKind {
+    pub const ALL: [Self; 11] = [
+        Self::Major,
+        Self::Minor,
+        Self::Diminished,
+        Self::Augmented,
+        Self::Sus2,
+        Self::Sus4,
+        Self::Major7,
+        Self::Minor7,
+        Self::Dominant7,
+        Self::HalfDiminished7,
+        Self::Diminished7,
+    ];
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Major => "Major",
+            Self::Minor => "Minor",
+            Self::Diminished => "Diminished",
+            Self::Augmented => "Augmented",
+            Self::Sus2 => "Suspended 2nd",
+            Self::Sus4 => "Suspended 4th",
+            Self::Major7 => "Major 7th",
+            Self::Minor7 => "Minor 7th",
+            Self::Dominant7 => "Dominant 7th",
+            Self::HalfDiminished7 => "Half-diminished 7th",
+            Self::Diminished7 => "Diminished 7th",
+        }
+    }
+    /// What comes after the root in a chord symbol, like the "m7" in "Am7"
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Major => "",
+            Self::Minor => "m",
+            Self::Diminished => "dim",
+            Self::Augmented => "aug",
+            Self::Sus2 => "sus2",
+            Self::Sus4 => "sus4",
+            Self::Major7 => "maj7",
+            Self::Minor7 => "m7",
+            Self::Dominant7 => "7",
+            Self::HalfDiminished7 => "m7b5",
+            Self::Diminished7 => "dim7",
+        }
+    }
+    /// Semitones of each note above the root
+    pub fn intervals(self) -> &'static [u8] {
+        match self {
+            Self::Major => &[0, 4, 7],
+            Self::Minor => &[0, 3, 7],
+            Self::Diminished => &[0, 3, 6],
+            Self::Augmented => &[0, 4, 8],
+            Self::Sus2 => &[0, 2, 7],
+            Self::Sus4 => &[0, 5, 7],
+            Self::Major7 => &[0, 4, 7, 11],
+            Self::Minor7 => &[0, 3, 7, 10],
+            Self::Dominant7 => &[0, 4, 7, 10],
+            Self::HalfDiminished7 => &[0, 3, 6, 10],
+            Self::Diminished7 => &[0, 3, 6, 9],
+        }
+    }
+    /// Bit mask of the intervals, like [`Scale::mask`]
+    fn mask(self) -> u16 {
+        self.intervals().iter().fold(0, |mask, i| mask | 1 << i)
+    }
+}
+
+/// How the notes of a chord are spread out
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Voicing {
+    /// All the notes within an octave
+    Close,
+    /// The second highest note dropped an octave
+    Drop2,
+    /// The third highest note dropped an octave
+    Drop3,
+    /// Every other note raised an octave
+    Open,
+}
+
+impl Voicing {
+    pub const ALL: [Self; 4] = [Self::Close, Self::Drop2, Self::Drop3, Self::Open];
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Close => "Close",
+            Self::Drop2 => "Drop 2",
+            Self::Drop3 => "Drop 3",
+            Self::Open => "Open",
+        }
+    }
+}
+
+/// A chord shape that can be stamped at any root
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Chord {
+    pub kind: ChordKind,
+    /// How many times the lowest note is moved up an octave
+    pub inversion: u8,
+    pub voicing: Voicing,
+}
+
+impl Chord {
+    /// Semitones of the notes, lowest first, for a chord rooted at `root`
+    pub fn semitones(self, root: i32) -> ArrayVec<i32, 4> {
+        let mut notes: ArrayVec<i32, 4> = self
+            .kind
+            .intervals()
+            .iter()
+            .map(|&i| root + i32::from(i))
+            .collect();
+        let len = notes.len();
+        for _ in 0..usize::from(self.inversion) % len {
+            let lowest = notes.remove(0);
+            notes.push(lowest + 12);
+        }
+        match self.voicing {
+            Voicing::Close => {}
+            Voicing::Drop2 => notes[len - 2] -= 12,
+            Voicing::Drop3 => notes[len - 3] -= 12,
+            Voicing::Open => {
+                for note in notes.iter_mut().skip(1).step_by(2) {
+                    *note += 12;
+                }
+            }
+        }
+        notes.sort_unstable();
+        notes
+    }
+}
+
+/// Name the chord made up of `keys`, like "Am7" or "C/E"
+///
+/// Returns `None` if the notes aren't one of the [`ChordKind`]s.
+pub fn chord_name(keys: impl IntoIterator<Item = ptcow::Key>) -> Option<String> {
+    let mut mask = 0u16;
+    let mut lowest = None;
+    for key in keys {
+        mask |= 1 << pitch_class(key);
+        lowest = Some(lowest.map_or(key, |low: ptcow::Key| low.min(key)));
+    }
+    let bass = pitch_class(lowest?);
+    // Try the bass first, so chords that are inversions of each other are named after it
+    let roots = std::iter::once(bass).chain((0..12).filter(|&pc| pc != bass));
+    for root in roots {
+        if mask & (1 << root) == 0 {
+            continue;
+        }
+        // Intervals above `root`
+        let rel = (mask >> root | mask << (12 - root)) & 0xFFF;
+        if let Some(kind) = ChordKind::ALL.into_iter().find(|kind| kind.mask() == rel) {
+            let mut name = format!("{}{}", KEY_NAMES[usize::from(root)], kind.symbol());
+            if root != bass {
+                let _ = write!(name, "/{}", KEY_NAMES[usize::from(bass)]);
+            }
+            return Some(name);
+        }
+    }
+    None
+}
+
+/// Parse a chord symbol like "Am7" or "C/E" into the keys of its notes, lowest first
+///
+/// The root is put in the octave starting at `c_semitone`, and a bass note below it.
+pub fn parse_chord_symbol(symbol: &str, c_semitone: i32) -> Option<ArrayVec<ptcow::Key, 5>> {
+    let (symbol, bass) = match symbol.split_once('/') {
+        Some((symbol, bass)) => (symbol, Some(parse_note_name(bass)?)),
+        None => (symbol, None),
+    };
+    let mut chars = symbol.char_indices();
+    let root_len = match (chars.next()?, chars.next()) {
+        (_, Some((i, '#' | 'b'))) => i + 1,
+        ((_, c), _) => c.len_utf8(),
+    };
+    let root = parse_note_name(&symbol[..root_len])?;
+    let kind = ChordKind::ALL
+        .into_iter()
+        .find(|kind| kind.symbol() == &symbol[root_len..])?;
+    let root = c_semitone + i32::from(root);
+    let mut keys = ArrayVec::new();
+    if let Some(bass) = bass {
+        let bass = c_semitone + i32::from(bass);
+        keys.push((if bass >= root { bass - 12 } else { bass }) * 256);
+    }
+    for interval in kind.intervals() {
+        keys.push((root + i32::from(*interval)) * 256);
+    }
+    Some(keys)
+}
+
+/// Pitch class of a note name like "C#" or "Eb"
+fn parse_note_name(name: &str) -> Option<u8> {
+    let mut chars = name.chars();
+    let natural = chars.next()?;
+    let natural = KEY_NAMES
+        .iter()
+        .position(|name| name.len() == 1 && name.starts_with(natural))? as u8;
+    let pc = match chars.as_str() {
+        "" => natural,
+        "#" => natural + 1,
+        "b" => natural + 11,
+        _ => return None,
+    };
+    Some(pc % 12)
+}
+
+/// Notes held together from `start` up to `end`
+pub struct HeldChord {
+    pub start: u32,
+    pub end: u32,
+    /// Lowest first
+    pub keys: Vec<ptcow::Key>,
+}
+
+/// Find the chords held by the notes `include` lets through, between ticks `from` and `to`
+///
+/// A new chord starts whenever a note starts or ends. Single notes count as chords too.
+pub fn held_chords(
+    events: &EveList,
+    include: impl Fn(usize, &Event) -> bool,
+    from: u32,
+    to: u32,
+) -> Vec<HeldChord> {
+    let keys = NoteValue::Key.of_notes(events);
+    let mut notes = Vec::new();
+    for (idx, ev) in events.iter().enumerate() {
+        if ev.tick >= to {
+            break;
+        }
+        if let EventPayload::On { duration } = ev.payload
+            && ev.tick + duration > from
+            && include(idx, ev)
+        {
+            notes.push((ev.tick, ev.tick + duration, keys[&idx]));
+        }
+    }
+    let mut bounds: Vec<u32> = notes
+        .iter()
+        .flat_map(|&(start, end, _)| [start, end])
+        .map(|tick| tick.clamp(from, to))
+        .collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+    let mut chords: Vec<HeldChord> = Vec::new();
+    for pair in bounds.windows(2) {
+        let [start, end] = [pair[0], pair[1]];
+        let mut keys: Vec<_> = notes
+            .iter()
+            .filter(|&&(note_start, note_end, _)| note_start <= start && note_end > start)
+            .map(|&(_, _, key)| key)
+            .collect();
+        if keys.is_empty() {
+            continue;
+        }
+        keys.sort_unstable();
+        keys.dedup();
+        match chords.last_mut() {
+            Some(last) if last.end == start && last.keys == keys => last.end = end,
+            _ => chords.push(HeldChord { start, end, keys }),
+        }
+    }
+    chords
+}
+
+/// A span of ticks where the same chord is held
+pub struct ChordSpan {
+    pub start: u32,
+    pub end: u32,
+    pub name: String,
+}
+
+/// Find the chords played by the notes of units `include` lets through, between ticks `from`
+/// and `to`
+pub fn chord_spans(
+    events: &EveList,
+    include: impl Fn(UnitIdx) -> bool,
+    from: u32,
+    to: u32,
+) -> Vec<ChordSpan> {
+    let mut spans: Vec<ChordSpan> = Vec::new();
+    for chord in held_chords(events, |_, ev| include(ev.unit), from, to) {
+        let Some(name) = chord_name(chord.keys) else {
+            continue;
+        };
+        match spans.last_mut() {
+            Some(last) if last.end == chord.start && last.name == name => last.end = chord.end,
+            _ => spans.push(ChordSpan {
+                start: chord.start,
+                end: chord.end,
+                name,
+            }),
+        }
+    }
+    spans
+}
+
+#[test]
+fn test_chord() {
+    let c5 = DEFAULT_KEY / 256 + 3;
+    let chord = Chord {
+        kind: ChordKind::Major,
+        inversion: 1,
+        voicing: Voicing::Close,
+    };
+    assert_eq!(chord.semitones(c5).as_slice(), [c5 + 4, c5 + 7, c5 + 12]);
+    let chord = Chord {
+        kind: ChordKind::Dominant7,
+        inversion: 0,
+        voicing: Voicing::Drop2,
+    };
+    assert_eq!(
+        chord.semitones(c5).as_slice(),
+        [c5 - 5, c5, c5 + 4, c5 + 10]
+    );
+    let keys = |semis: &[i32]| semis.iter().map(|s| (c5 + s) * 256).collect::<Vec<_>>();
+    assert_eq!(chord_name(keys(&[0, 4, 7])).as_deref(), Some("C"));
+    assert_eq!(chord_name(keys(&[4, 7, 12])).as_deref(), Some("C/E"));
+    assert_eq!(chord_name(keys(&[-3, 0, 4, 7])).as_deref(), Some("Am7"));
+    assert_eq!(chord_name(keys(&[0, 4, 7, 9])).as_deref(), Some("Am7/C"));
+    assert_eq!(chord_name(keys(&[0, 1, 2])), None);
+    let parsed = |symbol| parse_chord_symbol(symbol, c5).map(|keys| keys.to_vec());
+    assert_eq!(parsed("Am7"), Some(keys(&[9, 12, 16, 19])));
+    assert_eq!(parsed("Ebsus2/C"), Some(keys(&[0, 3, 5, 10])));
+    assert_eq!(parsed("H"), None);
+}
+
+/// A range of measures, from `start` up to but not including `end`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MeasRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl MeasRange {
+    /// Range covering measures `a` and `b`, and everything in between
+    pub fn spanning(a: u32, b: u32) -> Self {
+        Self {
+            start: a.min(b),
+            end: a.max(b) + 1,
+        }
+    }
+    pub fn len(self) -> u32 {
+        self.end.saturating_sub(self.start)
+    }
+    pub fn is_empty(self) -> bool {
+        self.len() == 0
+    }
+    /// Start and end tick of the range
+    pub fn ticks(self, timing: ptcow::Timing) -> (u32, u32) {
+        (
+            ptcow::timing::meas_to_tick(self.start, timing),
+            ptcow::timing::meas_to_tick(self.end, timing),
+        )
+    }
+}
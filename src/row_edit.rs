@@ -0,0 +1,212 @@
+//! Tracker style editing, where a row is a span of ticks and each unit has a column
+
+use {
+    crate::timing_ops::{EventTarget, NoteValue, set_note_values},
+    ptcow::{Event, EventPayload, Key, Song, UnitIdx},
+    std::{collections::BTreeSet, ops::Range},
+};
+
+/// What a unit does during a row
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Cell {
+    /// Key of the first note starting in the row
+    pub note: Option<Key>,
+    /// Whether a note ends in the row, without a new one starting
+    pub off: bool,
+    pub velocity: Option<i16>,
+    pub volume: Option<i16>,
+    pub pan: Option<u8>,
+}
+
+/// Column of a cell
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Field {
+    Note,
+    Velocity,
+    Volume,
+    Pan,
+}
+
+impl Field {
+    pub const ALL: [Self; 4] = [Self::Note, Self::Velocity, Self::Volume, Self::Pan];
+    /// Largest value of the hex fields
+    pub fn max(self) -> u8 {
+        match self {
+            Self::Note => 0,
+            Self::Velocity | Self::Pan => 128,
+            Self::Volume => 255,
+        }
+    }
+    fn matches(self, payload: EventPayload) -> bool {
+        matches!(
+            (self, payload),
+            (Self::Note, EventPayload::On { .. })
+                | (Self::Velocity, EventPayload::Velocity(_))
+                | (Self::Volume, EventPayload::Volume(_))
+                | (Self::Pan, EventPayload::PanVol(_))
+        )
+    }
+}
+
+fn row_span(row: usize, row_ticks: u32) -> Range<u32> {
+    let start = row as u32 * row_ticks;
+    start..start + row_ticks
+}
+
+/// Cells of the first `unit_count` units in `rows`, by row, then unit
+pub fn read_cells(
+    song: &Song,
+    row_ticks: u32,
+    rows: Range<usize>,
+    unit_count: usize,
+) -> Vec<Vec<Cell>> {
+    let mut cells = vec![vec![Cell::default(); unit_count]; rows.len()];
+    let keys = NoteValue::Key.of_notes(&song.events);
+    let row_ticks = row_ticks.max(1);
+    let row_of = |tick: u32| (tick / row_ticks) as usize;
+    for (idx, ev) in song.events.iter().enumerate() {
+        let unit = usize::from(ev.unit.0);
+        if let EventPayload::On { duration } = ev.payload
+            && let Some(cell) = cell_mut(&mut cells, &rows, row_of(ev.tick + duration), unit)
+        {
+            cell.off = true;
+        }
+        let Some(cell) = cell_mut(&mut cells, &rows, row_of(ev.tick), unit) else {
+            continue;
+        };
+        match ev.payload {
+            EventPayload::On { .. } => cell.note = cell.note.or(keys.get(&idx).copied()),
+            EventPayload::Velocity(vel) => cell.velocity = cell.velocity.or(Some(vel)),
+            EventPayload::Volume(vol) => cell.volume = cell.volume.or(Some(vol)),
+            EventPayload::PanVol(pan) => cell.pan = cell.pan.or(Some(pan)),
+            _ => {}
+        }
+    }
+    for cell in cells.iter_mut().flatten() {
+        cell.off &= cell.note.is_none();
+    }
+    cells
+}
+
+fn cell_mut<'a>(
+    cells: &'a mut [Vec<Cell>],
+    rows: &Range<usize>,
+    row: usize,
+    unit: usize,
+) -> Option<&'a mut Cell> {
+    if !rows.contains(&row) {
+        return None;
+    }
+    cells[row - rows.start].get_mut(unit)
+}
+
+/// Remove the `field` events `unit` has in row `row`
+///
+/// Clearing notes leaves their `Key` events alone, so the notes after them keep their key.
+pub fn clear(song: &mut Song, row_ticks: u32, row: usize, unit: UnitIdx, field: Field) {
+    let span = row_span(row, row_ticks);
+    song.events
+        .retain(|ev| !(ev.unit == unit && span.contains(&ev.tick) && field.matches(ev.payload)));
+    song.recalculate_length();
+}
+
+/// Start a note of `key` in row `row`, replacing the notes starting in it
+pub fn write_note(
+    song: &mut Song,
+    row_ticks: u32,
+    row: usize,
+    unit: UnitIdx,
+    key: Key,
+    duration: u32,
+) {
+    clear(song, row_ticks, row, unit, Field::Note);
+    let tick = row_span(row, row_ticks).start;
+    song.events.push(Event {
+        payload: EventPayload::On { duration },
+        unit,
+        tick,
+    });
+    song.events.sort();
+    let note = song.events.iter().position(|ev| {
+        ev.unit == unit && ev.tick == tick && matches!(ev.payload, EventPayload::On { .. })
+    });
+    set_note_values(
+        song,
+        &EventTarget::Indices(note.into_iter().collect::<BTreeSet<_>>()),
+        NoteValue::Key,
+        |_, _| key,
+    );
+    song.events.sort();
+    song.recalculate_length();
+}
+
+/// End the notes of `unit` that are still playing when row `row` starts
+pub fn write_off(song: &mut Song, row_ticks: u32, row: usize, unit: UnitIdx) {
+    clear(song, row_ticks, row, unit, Field::Note);
+    let tick = row_span(row, row_ticks).start;
+    for ev in song.events.iter_mut() {
+        if ev.unit == unit
+            && let EventPayload::On { duration } = &mut ev.payload
+            && ev.tick < tick
+            && ev.tick + *duration > tick
+        {
+            *duration = tick - ev.tick;
+        }
+    }
+    song.recalculate_length();
+}
+
+/// Set a hex field of `unit` in row `row`, replacing its events in the row
+pub fn write_value(
+    song: &mut Song,
+    row_ticks: u32,
+    row: usize,
+    unit: UnitIdx,
+    field: Field,
+    value: u8,
+) {
+    let value = value.min(field.max());
+    let payload = match field {
+        Field::Note => return,
+        Field::Velocity => EventPayload::Velocity(i16::from(value)),
+        Field::Volume => EventPayload::Volume(i16::from(value)),
+        Field::Pan => EventPayload::PanVol(value),
+    };
+    clear(song, row_ticks, row, unit, field);
+    song.events.push(Event {
+        payload,
+        unit,
+        tick: row_span(row, row_ticks).start,
+    });
+    song.events.sort();
+}
+
+#[test]
+fn test_row_edit() {
+    let mut song = Song::default();
+    let unit = UnitIdx(1);
+    let key = ptcow::DEFAULT_KEY + 3 * 256;
+    write_note(&mut song, 120, 2, unit, key, 240);
+    write_note(&mut song, 120, 6, unit, ptcow::DEFAULT_KEY, 120);
+    write_value(&mut song, 120, 2, unit, Field::Velocity, 0x40);
+    write_value(&mut song, 120, 3, unit, Field::Pan, 0xFF);
+    let cells = read_cells(&song, 120, 1..8, 2);
+    let column: Vec<_> = cells.iter().map(|row| row[1]).collect();
+    assert_eq!(column[1].note, Some(key));
+    assert_eq!(column[1].velocity, Some(0x40));
+    assert_eq!(column[2].pan, Some(128));
+    assert!(column[3].off);
+    assert_eq!(column[5].note, Some(ptcow::DEFAULT_KEY));
+    assert!(cells.iter().all(|row| row[0] == Cell::default()));
+    // Halving the rows puts the note at row 4
+    let cells = read_cells(&song, 60, 4..5, 2);
+    assert_eq!(cells[0][1].note, Some(key));
+    write_off(&mut song, 120, 3, unit);
+    let cells = read_cells(&song, 120, 3..4, 2);
+    assert!(cells[0][1].off);
+    clear(&mut song, 120, 2, unit, Field::Note);
+    let cells = read_cells(&song, 120, 2..7, 2);
+    assert_eq!(cells[0][1].note, None);
+    assert_eq!(cells[0][1].velocity, Some(0x40));
+    assert_eq!(cells[4][1].note, Some(ptcow::DEFAULT_KEY));
+}
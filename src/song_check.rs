@@ -0,0 +1,327 @@
+//! Checking songs for problems, like the ones hand edited or imported songs can have
+
+use {
+    crate::{compact::raw_value, pxtone_misc::clean_losing_events, transfer::song_end_meas},
+    ptcow::{EventPayload, Song, timing::NonZeroMeas},
+    rustc_hash::FxHasher,
+    std::hash::{Hash as _, Hasher as _},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProblemKind {
+    MissingUnit,
+    MissingVoice,
+    Unsorted,
+    LosingEvent,
+    OverlappingNote,
+    EmptyNote,
+    UnusedKey,
+    LoopPastEnd,
+}
+
+impl ProblemKind {
+    /// In the order they should be fixed in, as fixes rely on the ones before them
+    pub const ALL: [Self; 8] = [
+        Self::MissingUnit,
+        Self::MissingVoice,
+        Self::Unsorted,
+        Self::LosingEvent,
+        Self::OverlappingNote,
+        Self::EmptyNote,
+        Self::UnusedKey,
+        Self::LoopPastEnd,
+    ];
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::MissingUnit => "Missing unit",
+            Self::MissingVoice => "Missing voice",
+            Self::Unsorted => "Unsorted event",
+            Self::LosingEvent => "Losing event",
+            Self::OverlappingNote => "Overlapping note",
+            Self::EmptyNote => "Zero length note",
+            Self::UnusedKey => "Unused key",
+            Self::LoopPastEnd => "Loop point past the end",
+        }
+    }
+    /// What fixing problems of this kind does
+    pub fn fix_description(self) -> &'static str {
+        match self {
+            Self::MissingUnit => "Remove the events",
+            Self::MissingVoice => "Remove the voice changes",
+            Self::Unsorted => "Sort the events by tick",
+            Self::LosingEvent => "Remove the events that lose",
+            Self::OverlappingNote => "Shorten the earlier notes",
+            Self::EmptyNote => "Remove the notes",
+            Self::UnusedKey => "Remove the key changes",
+            Self::LoopPastEnd => "Move the loop points back inside the song",
+        }
+    }
+}
+
+pub struct Problem {
+    pub kind: ProblemKind,
+    /// Index of the event with the problem, if it's about an event
+    pub event: Option<usize>,
+    pub message: String,
+}
+
+/// Find the problems of `song`, given how many units and voices it has
+pub fn check(song: &Song, unit_count: u8, voice_count: u8) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut push = |kind, event, message| {
+        problems.push(Problem {
+            kind,
+            event,
+            message,
+        });
+    };
+    let events = &song.events;
+    // Per unit state, for the notes and keys
+    let mut note_ends = [0; 256];
+    let mut last_notes: [Option<usize>; 256] = [None; 256];
+    let mut pending_keys: [Option<usize>; 256] = [None; 256];
+    for (idx, ev) in events.iter().enumerate() {
+        let unit = usize::from(ev.unit.0);
+        if ev.unit.0 >= unit_count {
+            push(
+                ProblemKind::MissingUnit,
+                Some(idx),
+                format!("Event is for unit {}, which doesn't exist", ev.unit.0),
+            );
+        }
+        if let EventPayload::SetVoice(voice) = ev.payload
+            && voice.0 >= voice_count
+        {
+            push(
+                ProblemKind::MissingVoice,
+                Some(idx),
+                format!("Event sets voice {}, which doesn't exist", voice.0),
+            );
+        }
+        if let Some(prev) = idx.checked_sub(1).and_then(|prev| events.get(prev))
+            && ev.tick < prev.tick
+        {
+            push(
+                ProblemKind::Unsorted,
+                Some(idx),
+                format!("Event at tick {} comes after tick {}", ev.tick, prev.tick),
+            );
+        }
+        if let Some(next) = events.get(idx + 1)
+            && next.tick == ev.tick
+            && next.unit == ev.unit
+            && next.payload.discriminant() == ev.payload.discriminant()
+        {
+            push(
+                ProblemKind::LosingEvent,
+                Some(idx),
+                "Event has no effect, as the next one sets the same on the same tick".into(),
+            );
+        }
+        match ev.payload {
+            EventPayload::On { duration } => {
+                if duration == 0 {
+                    push(
+                        ProblemKind::EmptyNote,
+                        Some(idx),
+                        "Note has no length".into(),
+                    );
+                }
+                if ev.tick < note_ends[unit]
+                    && let Some(last) = last_notes[unit]
+                {
+                    push(
+                        ProblemKind::OverlappingNote,
+                        Some(idx),
+                        format!("Note starts before the note of event {last} ends"),
+                    );
+                }
+                note_ends[unit] = note_ends[unit].max(ev.tick + duration);
+                last_notes[unit] = Some(idx);
+                pending_keys[unit] = None;
+            }
+            EventPayload::Key(_) => {
+                if let Some(key) = pending_keys[unit] {
+                    push(ProblemKind::UnusedKey, Some(key), unused_key_message());
+                }
+                // Keys change the notes playing and the notes on the same tick, regardless of
+                // the order the events are in
+                let applies_now = ev.tick < note_ends[unit]
+                    || last_notes[unit].is_some_and(|last| events[last].tick == ev.tick);
+                pending_keys[unit] = (!applies_now).then_some(idx);
+            }
+            _ => {}
+        }
+    }
+    for key in pending_keys.into_iter().flatten() {
+        push(ProblemKind::UnusedKey, Some(key), unused_key_message());
+    }
+    let end = song.master.end_meas();
+    let repeat = song.master.loop_points.repeat;
+    if repeat >= end {
+        push(
+            ProblemKind::LoopPastEnd,
+            None,
+            format!("Repeat measure {repeat} is past the end of the song (measure {end})"),
+        );
+    }
+    // Silence after the last event is fine, as long as the song is that long
+    let song_end = song_end_meas(song);
+    if let Some(last) = song.master.loop_points.last
+        && last.get() > song_end
+    {
+        push(
+            ProblemKind::LoopPastEnd,
+            None,
+            format!("Last measure {last} is past the end of the song (measure {song_end})"),
+        );
+    }
+    problems.sort_by_key(|problem| problem.event);
+    problems
+}
+
+fn unused_key_message() -> String {
+    "Key change isn't followed by a note".into()
+}
+
+/// Fix the problems of `kind`
+///
+/// Fixes can cause problems of the kinds after them in [`ProblemKind::ALL`], so those should be
+/// fixed after, or checked again.
+pub fn fix(song: &mut Song, kind: ProblemKind, unit_count: u8, voice_count: u8) {
+    let problem_events: Vec<usize> = check(song, unit_count, voice_count)
+        .into_iter()
+        .filter(|problem| problem.kind == kind)
+        .filter_map(|problem| problem.event)
+        .collect();
+    let remove_problem_events = |song: &mut Song| {
+        let mut idx = 0;
+        song.events.retain(|_| {
+            let keep = problem_events.binary_search(&idx).is_err();
+            idx += 1;
+            keep
+        });
+    };
+    match kind {
+        ProblemKind::MissingUnit
+        | ProblemKind::MissingVoice
+        | ProblemKind::EmptyNote
+        | ProblemKind::UnusedKey => remove_problem_events(song),
+        ProblemKind::Unsorted => song.events.sort(),
+        ProblemKind::LosingEvent => clean_losing_events(&mut song.events),
+        ProblemKind::OverlappingNote => {
+            let mut last_notes: [Option<usize>; 256] = [None; 256];
+            for idx in 0..song.events.len() {
+                let ev = song.events[idx];
+                if !matches!(ev.payload, EventPayload::On { .. }) {
+                    continue;
+                }
+                let last_note = &mut last_notes[usize::from(ev.unit.0)];
+                if let Some(last) = *last_note {
+                    let last_tick = song.events[last].tick;
+                    if let EventPayload::On { duration } = &mut song.events[last].payload {
+                        *duration = (*duration).min(ev.tick.saturating_sub(last_tick));
+                    }
+                }
+                *last_note = Some(idx);
+            }
+        }
+        ProblemKind::LoopPastEnd => {
+            let song_end = song_end_meas(song);
+            if let Some(last) = song.master.loop_points.last {
+                song.master.loop_points.last = NonZeroMeas::new(last.get().min(song_end));
+            }
+            if song.master.loop_points.repeat >= song.master.end_meas() {
+                song.master.loop_points.repeat = 0;
+            }
+        }
+    }
+    song.recalculate_length();
+}
+
+/// Hash of everything [`check`] looks at, for telling whether the song changed since a check
+pub fn fingerprint(song: &Song, unit_count: u8, voice_count: u8) -> u64 {
+    let mut hasher = FxHasher::default();
+    let master = &song.master;
+    (unit_count, voice_count).hash(&mut hasher);
+    (master.timing.ticks_per_beat, master.timing.beats_per_meas).hash(&mut hasher);
+    (
+        master.loop_points.repeat,
+        master.loop_points.last,
+        master.end_meas(),
+    )
+        .hash(&mut hasher);
+    for ev in song.events.iter() {
+        (
+            ev.tick,
+            ev.unit.0,
+            ev.payload.discriminant(),
+            raw_value(ev.payload),
+        )
+            .hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Fix the problems of every kind
+pub fn fix_all(song: &mut Song, unit_count: u8, voice_count: u8) {
+    for kind in ProblemKind::ALL {
+        fix(song, kind, unit_count, voice_count);
+    }
+}
+
+#[test]
+fn test_song_check() {
+    use ptcow::{Event, UnitIdx, VoiceIdx};
+    let ev = |tick, unit, payload| Event {
+        payload,
+        unit: UnitIdx(unit),
+        tick,
+    };
+    let mut song = Song::default();
+    song.master.timing.ticks_per_beat = 4;
+    song.master.timing.beats_per_meas = 4;
+    // Silence after the events is fine, but repeating from the end isn't
+    song.master.loop_points.repeat = 4;
+    song.master.loop_points.last = NonZeroMeas::new(4);
+    song.events.extend([
+        ev(0, 0, EventPayload::Key(ptcow::DEFAULT_KEY)),
+        ev(0, 0, EventPayload::On { duration: 8 }),
+        ev(4, 0, EventPayload::On { duration: 8 }),
+        ev(4, 1, EventPayload::Key(ptcow::DEFAULT_KEY)),
+        ev(6, 0, EventPayload::Key(ptcow::DEFAULT_KEY)),
+        ev(8, 1, EventPayload::SetVoice(VoiceIdx(3))),
+        ev(8, 1, EventPayload::Volume(100)),
+        ev(8, 1, EventPayload::Volume(80)),
+        ev(7, 2, EventPayload::On { duration: 0 }),
+        ev(12, 1, EventPayload::Key(ptcow::DEFAULT_KEY)),
+        ev(12, 1, EventPayload::On { duration: 4 }),
+    ]);
+    let problems = check(&song, 2, 1);
+    let found: Vec<_> = problems
+        .iter()
+        .map(|problem| (problem.kind, problem.event))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            (ProblemKind::LoopPastEnd, None),
+            (ProblemKind::OverlappingNote, Some(2)),
+            (ProblemKind::UnusedKey, Some(3)),
+            (ProblemKind::MissingVoice, Some(5)),
+            (ProblemKind::LosingEvent, Some(6)),
+            (ProblemKind::MissingUnit, Some(8)),
+            (ProblemKind::Unsorted, Some(8)),
+            (ProblemKind::EmptyNote, Some(8)),
+        ]
+    );
+    fix_all(&mut song, 2, 1);
+    assert!(check(&song, 2, 1).is_empty());
+    assert_eq!(song.events.len(), 7);
+    assert!(matches!(
+        song.events[1].payload,
+        EventPayload::On { duration: 4 }
+    ));
+    assert_eq!(song.master.loop_points.repeat, 0);
+    assert_eq!(song.master.loop_points.last, NonZeroMeas::new(4));
+}
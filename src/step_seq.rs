@@ -0,0 +1,226 @@
+//! Step sequencing, for writing rhythms like drum parts on a grid
+
+use {
+    crate::timing_ops::{EventTarget, NoteValue, set_note_values},
+    ptcow::{Event, EventPayload, Key, Song, Timing, UnitIdx},
+    rustc_hash::{FxHashMap, FxHashSet},
+    std::collections::BTreeSet,
+};
+
+/// A row of the grid: the notes of a unit, or only the ones with a certain key
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StepRow {
+    pub unit: UnitIdx,
+    pub key: Option<Key>,
+}
+
+/// Measures the grid covers, and how many steps each is divided into
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StepGrid {
+    pub start_meas: u32,
+    pub meas_count: u32,
+    pub steps_per_meas: u16,
+}
+
+impl StepGrid {
+    pub fn len(self) -> usize {
+        self.meas_count as usize * usize::from(self.steps_per_meas)
+    }
+    /// Tick step `step` starts at
+    pub fn step_tick(self, step: usize, timing: Timing) -> u32 {
+        let meas_ticks = u64::from(ptcow::timing::meas_to_tick(1, timing));
+        let start = ptcow::timing::meas_to_tick(self.start_meas, timing);
+        start + (step as u64 * meas_ticks / u64::from(self.steps_per_meas.max(1))) as u32
+    }
+    /// Which step `tick` falls into, if any
+    pub fn step_at(self, tick: u32, timing: Timing) -> Option<usize> {
+        let start = self.step_tick(0, timing);
+        if tick < start || tick >= self.step_tick(self.len(), timing) {
+            return None;
+        }
+        let meas_ticks = u64::from(ptcow::timing::meas_to_tick(1, timing)).max(1);
+        let mut step =
+            (u64::from(tick - start) * u64::from(self.steps_per_meas) / meas_ticks) as usize;
+        // Steps don't always start on a whole tick, so the estimate can be off by one
+        while self.step_tick(step + 1, timing) <= tick {
+            step += 1;
+        }
+        while self.step_tick(step, timing) > tick {
+            step -= 1;
+        }
+        Some(step)
+    }
+    /// The same grid, `n` grid lengths later
+    pub fn shifted(self, n: u32) -> Self {
+        Self {
+            start_meas: self.start_meas + n * self.meas_count,
+            ..self
+        }
+    }
+}
+
+/// `hits` hits spread as evenly as possible over `steps` steps, moved `rotation` steps later
+pub fn euclid(hits: usize, steps: usize, rotation: usize) -> Vec<bool> {
+    (0..steps)
+        .map(|i| (i + steps - rotation % steps) % steps * hits % steps < hits)
+        .collect()
+}
+
+fn is_row_note(ev: &Event, row: StepRow, key: Option<&i32>) -> bool {
+    ev.unit == row.unit
+        && matches!(ev.payload, EventPayload::On { .. })
+        && row.key.is_none_or(|row_key| key == Some(&row_key))
+}
+
+/// Velocity of the note at each step of `row`, or `None` for steps without one
+pub fn read_row(song: &Song, grid: StepGrid, row: StepRow) -> Vec<Option<i16>> {
+    let timing = song.master.timing;
+    let keys = NoteValue::Key.of_notes(&song.events);
+    let velocities = NoteValue::Velocity.of_notes(&song.events);
+    let mut steps = vec![None; grid.len()];
+    for (idx, ev) in song.events.iter().enumerate() {
+        if !is_row_note(ev, row, keys.get(&idx)) {
+            continue;
+        }
+        if let Some(step) = grid.step_at(ev.tick, timing) {
+            steps[step] = velocities.get(&idx).map(|&vel| vel as i16);
+        }
+    }
+    steps
+}
+
+/// Make the notes of `row` match `steps`
+///
+/// New notes last a step. Notes that stay keep their length, only their velocity is set.
+pub fn write_row(song: &mut Song, grid: StepGrid, row: StepRow, steps: &[Option<i16>]) {
+    let timing = song.master.timing;
+    let keys = NoteValue::Key.of_notes(&song.events);
+    let mut kept = vec![false; steps.len()];
+    let mut idx = 0;
+    song.events.retain(|ev| {
+        let is_note = is_row_note(ev, row, keys.get(&idx));
+        idx += 1;
+        if !is_note {
+            return true;
+        }
+        match grid.step_at(ev.tick, timing) {
+            Some(step) if step < steps.len() => {
+                kept[step] |= steps[step].is_some();
+                steps[step].is_some()
+            }
+            _ => true,
+        }
+    });
+    let mut new_ticks = FxHashSet::default();
+    for (step, vel) in steps.iter().enumerate() {
+        if vel.is_none() || kept[step] {
+            continue;
+        }
+        let tick = grid.step_tick(step, timing);
+        new_ticks.insert(tick);
+        song.events.push(Event {
+            payload: EventPayload::On {
+                duration: grid.step_tick(step + 1, timing) - tick,
+            },
+            unit: row.unit,
+            tick,
+        });
+    }
+    song.events.sort();
+    // New notes don't have their key yet
+    let keys = NoteValue::Key.of_notes(&song.events);
+    let mut notes = BTreeSet::new();
+    let mut new_notes = BTreeSet::new();
+    let mut velocities = FxHashMap::default();
+    for (idx, ev) in song.events.iter().enumerate() {
+        let is_new = ev.unit == row.unit
+            && matches!(ev.payload, EventPayload::On { .. })
+            && new_ticks.contains(&ev.tick);
+        if !(is_new || is_row_note(ev, row, keys.get(&idx))) {
+            continue;
+        }
+        let Some(Some(vel)) = grid
+            .step_at(ev.tick, timing)
+            .and_then(|step| steps.get(step))
+        else {
+            continue;
+        };
+        notes.insert(idx);
+        velocities.insert(idx, i32::from(*vel));
+        if is_new {
+            new_notes.insert(idx);
+        }
+    }
+    set_note_values(
+        song,
+        &EventTarget::Indices(notes),
+        NoteValue::Velocity,
+        |idx, _| velocities[&idx],
+    );
+    if let Some(key) = row.key {
+        set_note_values(
+            song,
+            &EventTarget::Indices(new_notes),
+            NoteValue::Key,
+            |_, _| key,
+        );
+    }
+    song.events.sort();
+    song.recalculate_length();
+}
+
+/// Copy the notes of `rows` in `grid` to the `times` grids after it
+pub fn repeat_rows(song: &mut Song, grid: StepGrid, rows: &[StepRow], times: u32) {
+    for &row in rows {
+        let steps = read_row(song, grid, row);
+        for n in 1..=times {
+            write_row(song, grid.shifted(n), row, &steps);
+        }
+    }
+}
+
+#[test]
+fn test_step_seq() {
+    let hits: Vec<_> = euclid(3, 8, 0).iter().map(|&hit| u8::from(hit)).collect();
+    assert_eq!(hits, [1, 0, 0, 1, 0, 0, 1, 0]);
+    let hits: Vec<_> = euclid(3, 8, 1).iter().map(|&hit| u8::from(hit)).collect();
+    assert_eq!(hits, [0, 1, 0, 0, 1, 0, 0, 1]);
+    let mut song = Song::default();
+    song.master.timing.ticks_per_beat = 4;
+    song.master.timing.beats_per_meas = 4;
+    let grid = StepGrid {
+        start_meas: 1,
+        meas_count: 1,
+        steps_per_meas: 8,
+    };
+    let kick = StepRow {
+        unit: UnitIdx(0),
+        key: None,
+    };
+    let steps: Vec<_> = euclid(3, 8, 0)
+        .iter()
+        .map(|&hit| hit.then_some(100))
+        .collect();
+    write_row(&mut song, grid, kick, &steps);
+    assert_eq!(read_row(&song, grid, kick), steps);
+    assert_eq!(read_row(&song, grid.shifted(1), kick), [None; 8]);
+    repeat_rows(&mut song, grid, &[kick], 1);
+    assert_eq!(read_row(&song, grid.shifted(1), kick), steps);
+    // Rows of one key leave other keys of the unit alone
+    let snare = StepRow {
+        unit: UnitIdx(0),
+        key: Some(ptcow::DEFAULT_KEY + 256),
+    };
+    let mut snare_steps = vec![None; 8];
+    snare_steps[2] = Some(80);
+    write_row(&mut song, grid, snare, &snare_steps);
+    assert_eq!(read_row(&song, grid, snare), snare_steps);
+    let mut all_steps = steps.clone();
+    all_steps[2] = Some(80);
+    assert_eq!(read_row(&song, grid, kick), all_steps);
+    let kick = StepRow {
+        key: Some(ptcow::DEFAULT_KEY),
+        ..kick
+    };
+    assert_eq!(read_row(&song, grid, kick), steps);
+}
@@ -0,0 +1,808 @@
+//! Operations that move events around in time
+
+use {
+    crate::pxtone_misc::MeasRange,
+    ptcow::{DEFAULT_KEY, Event, EventPayload, Song, Timing, UnitIdx},
+    rustc_hash::{FxHashMap, FxHashSet},
+    std::{collections::BTreeSet, num::NonZeroU32},
+};
+
+/// Insert `count` empty measures before measure `at`, moving everything after it
+pub fn insert_meas(song: &mut Song, at: u32, count: u32) {
+    let timing = song.master.timing;
+    let at_tick = ptcow::timing::meas_to_tick(at, timing);
+    let shift = ptcow::timing::meas_to_tick(count, timing);
+    for ev in song.events.iter_mut() {
+        if ev.tick >= at_tick {
+            ev.tick += shift;
+        }
+    }
+    let loop_points = &mut song.master.loop_points;
+    if loop_points.repeat >= at {
+        loop_points.repeat += count;
+    }
+    if let Some(last) = &mut loop_points.last
+        && last.get() > at
+    {
+        *last = last.saturating_add(count);
+    }
+}
+
+/// Delete the measures in `range`, moving everything after it back
+///
+/// Notes crossing the range lose the part inside it. State changes (volume, voice, etc.) inside
+/// the range are kept at its start, so later notes still sound the same.
+pub fn delete_meas(song: &mut Song, range: MeasRange) {
+    if range.is_empty() {
+        return;
+    }
+    let (start, end) = range.ticks(song.master.timing);
+    let len = end - start;
+    song.events.retain_mut(|ev| {
+        if let EventPayload::On { duration } = &mut ev.payload {
+            let note_end = ev.tick + *duration;
+            if ev.tick < start {
+                // Starts before the range, cut out the part inside it
+                *duration -= note_end.clamp(start, end) - start;
+            } else if ev.tick < end {
+                // Starts inside the range, keep what sticks out of it
+                if note_end <= end {
+                    return false;
+                }
+                *duration = note_end - end;
+                ev.tick = start;
+            } else {
+                ev.tick -= len;
+            }
+        } else if ev.tick >= end {
+            ev.tick -= len;
+        } else if ev.tick >= start {
+            ev.tick = start;
+        }
+        true
+    });
+    song.events.sort();
+    let loop_points = &mut song.master.loop_points;
+    loop_points.repeat = shrink_meas(loop_points.repeat, range);
+    if let Some(last) = &mut loop_points.last {
+        *last = NonZeroU32::new(shrink_meas(last.get(), range)).unwrap_or(NonZeroU32::MIN);
+    }
+}
+
+/// What got lost to rounding when changing the resolution of a song
+#[derive(Default)]
+pub struct RescaleReport {
+    /// Events that didn't land exactly on a tick of the new resolution
+    pub moved: usize,
+    /// Notes and portamento slides that got a bit longer or shorter
+    pub resized: usize,
+    /// Notes and slides that would have become zero length, so they were made 1 tick long instead
+    pub collapsed: usize,
+}
+
+impl RescaleReport {
+    pub fn is_lossless(&self) -> bool {
+        self.moved == 0 && self.resized == 0 && self.collapsed == 0
+    }
+}
+
+/// Change the ticks per beat of the song, rescaling events so the rhythm stays the same
+///
+/// Note ends are rounded separately from their starts, so notes that touched still touch after.
+/// Portamento slides are rescaled the same way, except for the ones of zero length.
+pub fn change_resolution(song: &mut Song, ticks_per_beat: u16) -> RescaleReport {
+    let mut report = RescaleReport::default();
+    let old = u64::from(song.master.timing.ticks_per_beat.max(1));
+    let new = u64::from(ticks_per_beat.max(1));
+    // Round to nearest, and tell whether it was exact
+    let rescale = |tick: u32| {
+        let scaled = u64::from(tick) * new;
+        ((scaled + old / 2) / old) as u32
+    };
+    let exact = |tick: u32| (u64::from(tick) * new).is_multiple_of(old);
+    for ev in song.events.iter_mut() {
+        let start = rescale(ev.tick);
+        if !exact(ev.tick) {
+            report.moved += 1;
+        }
+        if let EventPayload::On { duration } | EventPayload::Portament { duration } =
+            &mut ev.payload
+            && *duration != 0
+        {
+            let end = ev.tick + *duration;
+            let new_duration = rescale(end) - start;
+            if new_duration == 0 {
+                report.collapsed += 1;
+                *duration = 1;
+            } else {
+                if !exact(end) || !exact(ev.tick) {
+                    report.resized += 1;
+                }
+                *duration = new_duration;
+            }
+        }
+        ev.tick = start;
+    }
+    song.events.sort();
+    song.master.timing.ticks_per_beat = ticks_per_beat.max(1);
+    report
+}
+
+/// Which events an operation applies to
+pub enum EventTarget {
+    /// Events at these indices
+    Indices(BTreeSet<usize>),
+    /// Every event starting inside the range
+    Range(MeasRange),
+    /// Every event of a unit
+    Unit(UnitIdx),
+    /// Every event of the song
+    All,
+}
+
+impl EventTarget {
+    pub fn contains(&self, idx: usize, ev: &Event, timing: Timing) -> bool {
+        match self {
+            Self::Indices(indices) => indices.contains(&idx),
+            Self::Range(range) => {
+                let (start, end) = range.ticks(timing);
+                (start..end).contains(&ev.tick)
+            }
+            Self::Unit(unit) => ev.unit == *unit,
+            Self::All => true,
+        }
+    }
+    /// First and last tick covered by the target, or `None` if it has no events
+    fn span(&self, song: &Song) -> Option<(u32, u32)> {
+        let ev_span = |ev: &Event| match ev.payload {
+            EventPayload::On { duration } => (ev.tick, ev.tick + duration),
+            _ => (ev.tick, ev.tick),
+        };
+        let join =
+            |(start, end): (u32, u32), (ev_start, ev_end)| (start.min(ev_start), end.max(ev_end));
+        match self {
+            Self::Indices(indices) => indices
+                .iter()
+                .filter_map(|&idx| song.events.get(idx))
+                .map(ev_span)
+                .reduce(join),
+            Self::Range(range) => {
+                let ticks = range.ticks(song.master.timing);
+                song.events
+                    .iter()
+                    .any(|ev| (ticks.0..ticks.1).contains(&ev.tick))
+                    .then_some(ticks)
+            }
+            Self::Unit(unit) => song
+                .events
+                .iter()
+                .filter(|ev| ev.unit == *unit)
+                .map(ev_span)
+                .reduce(join),
+            Self::All => song.events.iter().map(ev_span).reduce(join),
+        }
+    }
+}
+
+/// Scale the timing of `target` by `factor` around `anchor` (the start of the target by default)
+///
+/// With `ripple`, events after the target move along with its end. Loop points are left alone.
+pub fn stretch(
+    song: &mut Song,
+    target: &EventTarget,
+    factor: f64,
+    anchor: Option<u32>,
+    ripple: bool,
+) {
+    let Some((start, end)) = target.span(song) else {
+        return;
+    };
+    let anchor = f64::from(anchor.unwrap_or(start));
+    let scale = |tick: u32| {
+        (anchor + (f64::from(tick) - anchor) * factor)
+            .round()
+            .max(0.0) as u32
+    };
+    let shift = i64::from(scale(end)) - i64::from(end);
+    let timing = song.master.timing;
+    for (idx, ev) in song.events.iter_mut().enumerate() {
+        if target.contains(idx, ev, timing) {
+            let new_tick = scale(ev.tick);
+            match &mut ev.payload {
+                EventPayload::On { duration } => {
+                    *duration = scale(ev.tick + *duration).saturating_sub(new_tick).max(1);
+                }
+                // Slides of zero length turn portamento off, so they stay that way
+                EventPayload::Portament { duration } if *duration != 0 => {
+                    *duration = scale(ev.tick + *duration).saturating_sub(new_tick).max(1);
+                }
+                _ => {}
+            }
+            ev.tick = new_tick;
+        } else if ripple && ev.tick >= end {
+            ev.tick = (i64::from(ev.tick) + shift).max(0) as u32;
+        }
+    }
+    song.events.sort();
+}
+
+/// Settings for snapping notes to a grid
+#[derive(Clone)]
+pub struct Quantize {
+    /// Grid steps per beat, before applying `tuplet`
+    pub division: u16,
+    /// Fit `tuplet.0` grid steps into the space of `tuplet.1`
+    pub tuplet: (u8, u8),
+    /// Quantize note starts
+    pub start: bool,
+    /// Quantize note ends
+    pub length: bool,
+    /// How far to move towards the grid, from 0 to 1
+    pub strength: f64,
+    /// How much every other grid line is delayed, from 0 (straight) to 1 (by half a step)
+    pub swing: f64,
+}
+
+impl Default for Quantize {
+    fn default() -> Self {
+        Self {
+            division: 4,
+            tuplet: (1, 1),
+            start: true,
+            length: false,
+            strength: 1.0,
+            swing: 0.0,
+        }
+    }
+}
+
+impl Quantize {
+    fn step(&self, timing: Timing) -> f64 {
+        f64::from(timing.ticks_per_beat) / f64::from(self.division.max(1))
+            * f64::from(self.tuplet.1.max(1))
+            / f64::from(self.tuplet.0.max(1))
+    }
+    fn grid_line(&self, k: i64, step: f64) -> f64 {
+        let swing = if k.rem_euclid(2) == 1 {
+            self.swing * step / 2.0
+        } else {
+            0.0
+        };
+        k as f64 * step + swing
+    }
+    /// Move `tick` towards the nearest grid line according to the strength
+    fn snap(&self, tick: u32, step: f64) -> f64 {
+        let tick = f64::from(tick);
+        let k = (tick / step).floor() as i64;
+        let nearest = (k - 1..=k + 1)
+            .map(|k| self.grid_line(k, step))
+            .min_by(|a, b| (a - tick).abs().total_cmp(&(b - tick).abs()))
+            .unwrap_or(tick);
+        tick + (nearest - tick) * self.strength
+    }
+}
+
+/// Snap the notes of `target` to a grid, returning how many of them changed
+///
+/// `Key` and `Velocity` events on the same tick as a note move along with it.
+pub fn quantize(song: &mut Song, target: &EventTarget, q: &Quantize) -> usize {
+    let timing = song.master.timing;
+    let step = q.step(timing);
+    if !step.is_normal() {
+        return 0;
+    }
+    // (unit, old tick) -> new tick, for moving along the events belonging to the notes
+    let mut moved = FxHashMap::default();
+    let mut n_changed = 0;
+    for (idx, ev) in song.events.iter_mut().enumerate() {
+        if !target.contains(idx, ev, timing) {
+            continue;
+        }
+        let EventPayload::On { duration } = &mut ev.payload else {
+            continue;
+        };
+        let end = ev.tick + *duration;
+        let new_start = if q.start {
+            q.snap(ev.tick, step).round().max(0.0)
+        } else {
+            f64::from(ev.tick)
+        };
+        let new_end = if q.length {
+            q.snap(end, step)
+        } else {
+            new_start + f64::from(*duration)
+        };
+        let new_duration = if new_end - new_start >= 1.0 {
+            (new_end - new_start).round() as u32
+        } else {
+            (step.round() as u32).max(1)
+        };
+        let new_start = new_start as u32;
+        if new_start == ev.tick && new_duration == *duration {
+            continue;
+        }
+        *duration = new_duration;
+        if new_start != ev.tick {
+            moved.insert((ev.unit.0, ev.tick), new_start);
+            ev.tick = new_start;
+        }
+        n_changed += 1;
+    }
+    move_along(song, &moved);
+    n_changed
+}
+
+/// Move `Key` and `Velocity` events along with the notes they belong to, then restore event order
+///
+/// `moved` maps (unit, old tick) of the moved notes to their new tick.
+fn move_along(song: &mut Song, moved: &FxHashMap<(u8, u32), u32>) {
+    for ev in song.events.iter_mut() {
+        if matches!(ev.payload, EventPayload::Key(_) | EventPayload::Velocity(_))
+            && let Some(&new_tick) = moved.get(&(ev.unit.0, ev.tick))
+        {
+            ev.tick = new_tick;
+        }
+    }
+    song.events.sort();
+}
+
+/// Settings for randomizing notes a bit
+#[derive(Clone)]
+pub struct Humanize {
+    /// Maximum ticks to move notes by, in either direction
+    pub timing: u32,
+    /// Maximum ticks to lengthen or shorten notes by
+    pub duration: u32,
+    /// Maximum amount to change velocity by, in either direction
+    pub velocity: u32,
+    /// The same seed gives the same result
+    pub seed: u64,
+}
+
+impl Default for Humanize {
+    fn default() -> Self {
+        Self {
+            timing: 3,
+            duration: 0,
+            velocity: 8,
+            seed: 0,
+        }
+    }
+}
+
+/// Velocity of notes until a `Velocity` event says otherwise
+const DEFAULT_VELOCITY: i16 = 104;
+
+/// `SplitMix64`, so humanizing doesn't need a dependency, and results are the same everywhere
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+    /// Random number in `-max..=max`
+    fn offset(&mut self, max: u32) -> i64 {
+        if max == 0 {
+            return 0;
+        }
+        (self.next() % (u64::from(max) * 2 + 1)) as i64 - i64::from(max)
+    }
+}
+
+/// Randomize the timing, length and velocity of the notes of `target`, returning how many notes
+/// were touched
+pub fn humanize(song: &mut Song, target: &EventTarget, h: &Humanize) -> usize {
+    let mut rng = Rng(h.seed);
+    if h.velocity != 0 {
+        set_note_values(song, target, NoteValue::Velocity, |_, vel| {
+            (i64::from(vel) + rng.offset(h.velocity)).clamp(0, 128) as i32
+        });
+    }
+    let timing = song.master.timing;
+    let mut moved = FxHashMap::default();
+    let mut n_changed = 0;
+    for (idx, ev) in song.events.iter_mut().enumerate() {
+        if !target.contains(idx, ev, timing) {
+            continue;
+        }
+        let EventPayload::On { duration } = &mut ev.payload else {
+            continue;
+        };
+        let new_tick = (i64::from(ev.tick) + rng.offset(h.timing)).max(0) as u32;
+        *duration = (i64::from(*duration) + rng.offset(h.duration)).max(1) as u32;
+        if new_tick != ev.tick {
+            moved.insert((ev.unit.0, ev.tick), new_tick);
+            ev.tick = new_tick;
+        }
+        n_changed += 1;
+    }
+    move_along(song, &moved);
+    n_changed
+}
+
+/// A per-unit value that notes pick up from the last event setting it
+#[derive(Clone, Copy)]
+pub enum NoteValue {
+    Key,
+    Velocity,
+}
+
+impl NoteValue {
+    fn default(self) -> i32 {
+        match self {
+            Self::Key => DEFAULT_KEY,
+            Self::Velocity => i32::from(DEFAULT_VELOCITY),
+        }
+    }
+    fn get(self, payload: EventPayload) -> Option<i32> {
+        match (self, payload) {
+            (Self::Key, EventPayload::Key(key)) => Some(key),
+            (Self::Velocity, EventPayload::Velocity(vel)) => Some(i32::from(vel)),
+            _ => None,
+        }
+    }
+    fn payload(self, value: i32) -> EventPayload {
+        match self {
+            Self::Key => EventPayload::Key(value),
+            Self::Velocity => EventPayload::Velocity(value as i16),
+        }
+    }
+    /// The value each note has, by the index of its `On` event
+    pub fn of_notes(self, events: &[Event]) -> FxHashMap<usize, i32> {
+        values_at(
+            events,
+            |ev| matches!(ev.payload, EventPayload::On { .. }),
+            |payload| self.get(payload),
+            self.default(),
+        )
+    }
+}
+
+/// The value `get` finds for the unit of each event `at` lets through, by event index
+///
+/// Units have `default` until an event sets another value.
+pub fn values_at<T: Copy>(
+    events: &[Event],
+    at: impl Fn(&Event) -> bool,
+    get: impl Fn(EventPayload) -> Option<T>,
+    default: T,
+) -> FxHashMap<usize, T> {
+    // Events on the same tick as a note apply to it, regardless of the order they're in
+    let mut same_tick = FxHashMap::default();
+    for ev in events {
+        if let Some(value) = get(ev.payload) {
+            same_tick.insert((ev.unit.0, ev.tick), value);
+        }
+    }
+    let mut current = [default; 256];
+    let mut values = FxHashMap::default();
+    for (idx, ev) in events.iter().enumerate() {
+        let unit = usize::from(ev.unit.0);
+        if let Some(value) = get(ev.payload) {
+            current[unit] = value;
+        } else if at(ev) {
+            let value = same_tick.get(&(ev.unit.0, ev.tick)).copied();
+            values.insert(idx, value.unwrap_or(current[unit]));
+        }
+    }
+    values
+}
+
+/// Give each note of `target` its own `kind` event, with the value `f` returns for
+/// (index of the note, value the note had)
+///
+/// Values stick until the next event setting them, so notes following a changed note get their
+/// original value restored. New events are pushed to the end so indices stay valid, which means
+/// the events need sorting afterwards. Returns the number of notes in `target`.
+pub fn set_note_values(
+    song: &mut Song,
+    target: &EventTarget,
+    kind: NoteValue,
+    f: impl FnMut(usize, i32) -> i32,
+) -> usize {
+    set_note_values_with(song, target, kind, &FxHashSet::default(), f)
+}
+
+/// [`set_note_values`], also changing the `kind` events at the indices of `also`, for events that
+/// aren't at the start of a note. `f` gets the index of the event itself for these.
+pub fn set_note_values_with(
+    song: &mut Song,
+    target: &EventTarget,
+    kind: NoteValue,
+    also: &FxHashSet<usize>,
+    mut f: impl FnMut(usize, i32) -> i32,
+) -> usize {
+    let timing = song.master.timing;
+    let mut value_events = FxHashMap::default();
+    let mut target_notes = FxHashSet::default();
+    for (idx, ev) in song.events.iter().enumerate() {
+        if kind.get(ev.payload).is_some() {
+            value_events.insert((ev.unit.0, ev.tick), idx);
+        } else if matches!(ev.payload, EventPayload::On { .. }) && target.contains(idx, ev, timing)
+        {
+            target_notes.insert((ev.unit.0, ev.tick));
+        }
+    }
+    let mut current = [kind.default(); 256];
+    // Whether the value of a unit has been changed from what follows
+    let mut dirty = [false; 256];
+    let mut changes = Vec::new();
+    let mut new_events = Vec::new();
+    for (idx, ev) in song.events.iter().enumerate() {
+        let unit = usize::from(ev.unit.0);
+        let key = (ev.unit.0, ev.tick);
+        if let Some(value) = kind.get(ev.payload) {
+            current[unit] = value;
+            dirty[unit] = target_notes.contains(&key);
+            if also.contains(&idx) {
+                changes.push((idx, f(idx, value)));
+                dirty[unit] = true;
+            }
+            continue;
+        }
+        if !matches!(ev.payload, EventPayload::On { .. }) {
+            continue;
+        }
+        let own = value_events.get(&key).copied();
+        if target_notes.contains(&key) {
+            let old = own
+                .and_then(|idx| kind.get(song.events[idx].payload))
+                .unwrap_or(current[unit]);
+            let new = f(idx, old);
+            match own {
+                Some(idx) => changes.push((idx, new)),
+                None => new_events.push(Event {
+                    payload: kind.payload(new),
+                    unit: ev.unit,
+                    tick: ev.tick,
+                }),
+            }
+            dirty[unit] = true;
+        } else if own.is_none() && dirty[unit] {
+            new_events.push(Event {
+                payload: kind.payload(current[unit]),
+                unit: ev.unit,
+                tick: ev.tick,
+            });
+            dirty[unit] = false;
+        }
+    }
+    for (idx, value) in changes {
+        song.events[idx].payload = kind.payload(value);
+    }
+    for ev in new_events {
+        song.events.push(ev);
+    }
+    target_notes.len()
+}
+
+/// Where measure `meas` ends up after deleting `range`
+fn shrink_meas(meas: u32, range: MeasRange) -> u32 {
+    if meas >= range.end {
+        meas - range.len()
+    } else {
+        meas.min(range.start)
+    }
+}
+
+#[test]
+fn test_delete_meas() {
+    let mut song = Song::default();
+    song.master.timing.ticks_per_beat = 10;
+    song.master.timing.beats_per_meas = 1;
+    for (payload, tick) in [
+        // Crosses the start of the range
+        (EventPayload::On { duration: 15 }, 5),
+        (EventPayload::Volume(50), 15),
+        // Crosses the end of the range
+        (EventPayload::On { duration: 20 }, 25),
+        (EventPayload::On { duration: 5 }, 40),
+    ] {
+        song.events.push(ptcow::Event {
+            payload,
+            unit: ptcow::UnitIdx(0),
+            tick,
+        });
+    }
+    song.master.loop_points.repeat = 2;
+    song.master.loop_points.last = NonZeroU32::new(5);
+    delete_meas(&mut song, MeasRange { start: 1, end: 3 });
+    let events: Vec<_> = song.events.iter().map(|ev| (ev.tick, ev.payload)).collect();
+    assert_eq!(
+        events,
+        [
+            (5, EventPayload::On { duration: 5 }),
+            (10, EventPayload::Volume(50)),
+            (10, EventPayload::On { duration: 15 }),
+            (20, EventPayload::On { duration: 5 }),
+        ]
+    );
+    assert_eq!(song.master.loop_points.repeat, 1);
+    assert_eq!(song.master.loop_points.last, NonZeroU32::new(3));
+}
+
+#[test]
+fn test_change_resolution() {
+    let mut song = Song::default();
+    song.master.timing.ticks_per_beat = 96;
+    for (payload, tick) in [
+        (EventPayload::On { duration: 48 }, 0),
+        (EventPayload::On { duration: 49 }, 48),
+        (EventPayload::On { duration: 1 }, 97),
+        (EventPayload::Portament { duration: 0 }, 192),
+        (EventPayload::Portament { duration: 96 }, 192),
+        (EventPayload::Portament { duration: 33 }, 288),
+    ] {
+        song.events.push(ptcow::Event {
+            payload,
+            unit: ptcow::UnitIdx(0),
+            tick,
+        });
+    }
+    let report = change_resolution(&mut song, 48);
+    let events: Vec<_> = song.events.iter().map(|ev| (ev.tick, ev.payload)).collect();
+    assert_eq!(
+        events,
+        [
+            (0, EventPayload::On { duration: 24 }),
+            (24, EventPayload::On { duration: 25 }),
+            (49, EventPayload::On { duration: 1 }),
+            (96, EventPayload::Portament { duration: 0 }),
+            (96, EventPayload::Portament { duration: 48 }),
+            (144, EventPayload::Portament { duration: 17 }),
+        ]
+    );
+    assert_eq!(song.master.timing.ticks_per_beat, 48);
+    assert_eq!((report.moved, report.resized, report.collapsed), (1, 2, 1));
+}
+
+#[test]
+fn test_stretch() {
+    let mut song = Song::default();
+    song.master.timing.ticks_per_beat = 10;
+    song.master.timing.beats_per_meas = 1;
+    for (payload, tick) in [
+        (EventPayload::On { duration: 5 }, 0),
+        (EventPayload::On { duration: 5 }, 10),
+        (EventPayload::Portament { duration: 4 }, 12),
+        (EventPayload::Volume(50), 15),
+        (EventPayload::On { duration: 10 }, 20),
+    ] {
+        song.events.push(ptcow::Event {
+            payload,
+            unit: ptcow::UnitIdx(0),
+            tick,
+        });
+    }
+    let target = EventTarget::Range(MeasRange { start: 1, end: 2 });
+    stretch(&mut song, &target, 2.0, None, true);
+    let events: Vec<_> = song.events.iter().map(|ev| (ev.tick, ev.payload)).collect();
+    assert_eq!(
+        events,
+        [
+            (0, EventPayload::On { duration: 5 }),
+            (10, EventPayload::On { duration: 10 }),
+            (14, EventPayload::Portament { duration: 8 }),
+            (20, EventPayload::Volume(50)),
+            (30, EventPayload::On { duration: 10 }),
+        ]
+    );
+}
+
+#[test]
+fn test_quantize() {
+    let mut song = Song::default();
+    song.master.timing.ticks_per_beat = 96;
+    for (payload, tick) in [
+        (EventPayload::Key(60 * 256), 5),
+        (EventPayload::On { duration: 20 }, 5),
+        (EventPayload::On { duration: 10 }, 30),
+        (EventPayload::On { duration: 10 }, 100),
+    ] {
+        song.events.push(ptcow::Event {
+            payload,
+            unit: ptcow::UnitIdx(0),
+            tick,
+        });
+    }
+    let target = EventTarget::Unit(ptcow::UnitIdx(0));
+    let q = Quantize {
+        length: true,
+        strength: 0.5,
+        ..Quantize::default()
+    };
+    assert_eq!(quantize(&mut song, &target, &q), 3);
+    let events: Vec<_> = song.events.iter().map(|ev| (ev.tick, ev.payload)).collect();
+    assert_eq!(
+        events,
+        [
+            (3, EventPayload::Key(60 * 256)),
+            (3, EventPayload::On { duration: 22 }),
+            (27, EventPayload::On { duration: 17 }),
+            (98, EventPayload::On { duration: 17 }),
+        ]
+    );
+}
+
+#[test]
+fn test_humanize() {
+    let mut results = Vec::new();
+    for _ in 0..2 {
+        let mut song = Song::default();
+        for tick in [0, 10, 20] {
+            song.events.push(ptcow::Event {
+                payload: EventPayload::On { duration: 5 },
+                unit: ptcow::UnitIdx(0),
+                tick,
+            });
+        }
+        let target = EventTarget::Indices(BTreeSet::from([0]));
+        let h = Humanize {
+            timing: 0,
+            seed: 42,
+            ..Humanize::default()
+        };
+        assert_eq!(humanize(&mut song, &target, &h), 1);
+        let events: Vec<_> = song.events.iter().map(|ev| (ev.tick, ev.payload)).collect();
+        results.push(events);
+    }
+    assert_eq!(results[0], results[1]);
+    let vels: Vec<_> = results[0]
+        .iter()
+        .filter(|(_, payload)| matches!(payload, EventPayload::Velocity(_)))
+        .collect();
+    // The second note gets its velocity restored, the third one doesn't need it anymore
+    assert_eq!(vels.len(), 2);
+    assert_eq!(*vels[1], (10, EventPayload::Velocity(DEFAULT_VELOCITY)));
+}
+
+#[test]
+fn test_humanize_moves_values_along() {
+    let mut song = Song::default();
+    let ev = |tick, payload| Event {
+        payload,
+        unit: UnitIdx(0),
+        tick,
+    };
+    for (i, tick) in [10, 20, 30, 40].into_iter().enumerate() {
+        song.events.extend([
+            ev(tick, EventPayload::Key(DEFAULT_KEY + i as i32 * 256)),
+            ev(tick, EventPayload::Velocity(90 + i as i16)),
+            ev(tick, EventPayload::On { duration: 5 }),
+        ]);
+    }
+    let h = Humanize {
+        timing: 8,
+        velocity: 0,
+        seed: 7,
+        ..Humanize::default()
+    };
+    assert_eq!(humanize(&mut song, &EventTarget::All, &h), 4);
+    let note_ticks: Vec<_> = song
+        .events
+        .iter()
+        .filter(|ev| matches!(ev.payload, EventPayload::On { .. }))
+        .map(|ev| ev.tick)
+        .collect();
+    assert_ne!(note_ticks, [10, 20, 30, 40]);
+    assert!(song.events.is_sorted_by_key(|ev| ev.tick));
+    // Every note keeps its key and velocity, and nothing is left at the old ticks
+    let keys = NoteValue::Key.of_notes(&song.events);
+    let vels = NoteValue::Velocity.of_notes(&song.events);
+    for (idx, ev) in song.events.iter().enumerate() {
+        let i = match ev.payload {
+            EventPayload::On { .. } => note_ticks.iter().position(|&t| t == ev.tick).unwrap(),
+            EventPayload::Key(_) | EventPayload::Velocity(_) => {
+                assert!(note_ticks.contains(&ev.tick));
+                continue;
+            }
+            _ => continue,
+        };
+        assert_eq!(keys[&idx], DEFAULT_KEY + i as i32 * 256);
+        assert_eq!(vels[&idx], 90 + i as i32);
+    }
+}
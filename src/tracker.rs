@@ -0,0 +1,1169 @@
+//! Tracker module (.mod, .s3m, .xm) import
+//!
+//! The formats are parsed into a common [`Module`] representation, which is then converted
+//! into PxTone events. Only the features that have a reasonable PxTone equivalent are converted:
+//! notes, volume, panning, portamento, and speed/tempo changes.
+
+use {
+    anyhow::{Context as _, bail},
+    ptcow::{
+        Event, EventPayload, Herd, MooInstructions, PcmData, Song, Unit, UnitIdx, Voice,
+        VoiceFlags, VoiceIdx, VoiceUnit,
+    },
+    rustc_hash::FxHashMap,
+    std::num::NonZeroU32,
+};
+
+/// PxTone ticks per tracker row
+const TICKS_PER_ROW: u32 = 120;
+const ROWS_PER_BEAT: u32 = 4;
+/// Offset between tracker notes (C-4 = 48) and PxTone keys (C4 = 87)
+const KEY_OFFSET: i32 = 39;
+/// Tracker note that plays samples at their own sample rate
+const BASE_NOTE: u8 = 48;
+/// Looping samples that don't loop from the start get their loop unrolled to at least this long
+const UNROLL_SECONDS: usize = 2;
+/// Sample rates outside of this are broken headers, not real samples
+const MIN_RATE: u32 = 1000;
+const MAX_RATE: u32 = 192_000;
+
+/// Tracker module, normalized from one of the supported formats
+pub struct Module {
+    title: String,
+    n_channels: usize,
+    /// Pattern index for each order. Out of range indices are skipped.
+    orders: Vec<usize>,
+    patterns: Vec<Pattern>,
+    /// Indexed by instrument number - 1
+    instruments: Vec<Instrument>,
+    speed: u8,
+    tempo: u8,
+    /// Initial panning of each channel (0..=255)
+    channel_pan: Vec<u8>,
+}
+
+struct Pattern {
+    n_rows: usize,
+    /// `n_rows * n_channels` cells, row by row
+    cells: Vec<Cell>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Cell {
+    note: Note,
+    /// 1-based instrument number, 0 if not set
+    ins: u8,
+    /// Volume (0..=64)
+    vol: Option<u8>,
+    /// Some formats can have more than one effect per cell (e.g. XM volume column effects)
+    fx: [Effect; 3],
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+enum Note {
+    #[default]
+    None,
+    /// Semitones from C-0
+    On(u8),
+    Off,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+enum Effect {
+    #[default]
+    None,
+    /// Slide pitch up by 1/16 semitones per tick. 0 means reuse last value.
+    PortaUp(u8),
+    /// Slide pitch down by 1/16 semitones per tick. 0 means reuse last value.
+    PortaDown(u8),
+    /// Slide pitch towards the note by 1/16 semitones per tick. 0 means reuse last value.
+    TonePorta(u8),
+    /// Volume change on every tick but the first. 0 means reuse last value.
+    VolSlide(i8),
+    /// Volume change on the first tick
+    FineVolSlide(i8),
+    /// 0..=255
+    Pan(u8),
+    Volume(u8),
+    PosJump(u8),
+    PatBreak(u8),
+    Speed(u8),
+    Tempo(u8),
+    /// Cut the note after this many ticks
+    NoteCut(u8),
+}
+
+struct Instrument {
+    /// Sample index for each note
+    keymap: [u8; 96],
+    samples: Vec<Sample>,
+}
+
+impl Instrument {
+    fn single(sample: Sample) -> Self {
+        Self {
+            keymap: [0; 96],
+            samples: vec![sample],
+        }
+    }
+}
+
+struct Sample {
+    name: String,
+    /// Playback rate at [`BASE_NOTE`], within [`MIN_RATE`]`..=`[`MAX_RATE`]
+    rate: f64,
+    /// Unsigned 8 bit, or signed 16 bit little endian data
+    data: Vec<u8>,
+    bits16: bool,
+    /// Start and end frame of the loop
+    loop_: Option<(usize, usize)>,
+    ping_pong: bool,
+    /// Default volume (0..=64)
+    volume: u8,
+}
+
+impl Sample {
+    fn frame_size(&self) -> usize {
+        if self.bits16 { 2 } else { 1 }
+    }
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+impl Module {
+    /// Parse a module, detecting the format from the data
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.starts_with(b"Extended Module: ") {
+            parse_xm(data)
+        } else if data.get(0x2C..0x30) == Some(b"SCRM".as_slice()) {
+            parse_s3m(data)
+        } else {
+            parse_mod(data)
+        }
+    }
+}
+
+fn get(data: &[u8], off: usize, len: usize) -> anyhow::Result<&[u8]> {
+    data.get(off..off + len)
+        .with_context(|| format!("Unexpected end of data at offset {off}"))
+}
+
+fn u8_at(data: &[u8], off: usize) -> anyhow::Result<u8> {
+    Ok(get(data, off, 1)?[0])
+}
+
+fn u16_le(data: &[u8], off: usize) -> anyhow::Result<u16> {
+    Ok(u16::from_le_bytes(get(data, off, 2)?.try_into()?))
+}
+
+fn u16_be(data: &[u8], off: usize) -> anyhow::Result<u16> {
+    Ok(u16::from_be_bytes(get(data, off, 2)?.try_into()?))
+}
+
+fn u32_le(data: &[u8], off: usize) -> anyhow::Result<u32> {
+    Ok(u32::from_le_bytes(get(data, off, 4)?.try_into()?))
+}
+
+fn string_at(data: &[u8], off: usize, len: usize) -> anyhow::Result<String> {
+    let bytes = get(data, off, len)?;
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(len);
+    Ok(String::from_utf8_lossy(&bytes[..end]).trim_end().to_owned())
+}
+
+/// Sample data that's possibly cut short, for sloppily written modules
+fn sample_data(data: &[u8], off: usize, len: usize) -> &[u8] {
+    let start = off.min(data.len());
+    let end = off.saturating_add(len).min(data.len());
+    &data[start..end]
+}
+
+/// Playback rate of a sample for a C-4 rate and a finetune in 1/128 semitones
+fn tuned_rate(c4_rate: f64, finetune: f64) -> f64 {
+    (c4_rate * 2f64.powf(finetune / (12. * 128.))).clamp(f64::from(MIN_RATE), f64::from(MAX_RATE))
+}
+
+/// Convert a volume slide parameter where the high nibble slides up and the low nibble down
+fn vol_slide(param: u8) -> i8 {
+    let (up, down) = (param >> 4, param & 0x0F);
+    if up != 0 { up as i8 } else { -(down as i8) }
+}
+
+/// Position jump and pattern break parameters are decimal in disguise
+fn bcd(param: u8) -> u8 {
+    (param >> 4) * 10 + (param & 0x0F)
+}
+
+/// Convert a Protracker style effect, also used by XM
+fn protracker_effect(cmd: u8, param: u8) -> [Effect; 2] {
+    let one = |fx| [fx, Effect::None];
+    match cmd {
+        0x1 => one(Effect::PortaUp(param)),
+        0x2 => one(Effect::PortaDown(param)),
+        0x3 => one(Effect::TonePorta(param)),
+        0x5 => [Effect::TonePorta(0), Effect::VolSlide(vol_slide(param))],
+        0x6 | 0xA => one(Effect::VolSlide(vol_slide(param))),
+        0x8 => one(Effect::Pan(param)),
+        0xB => one(Effect::PosJump(param)),
+        0xC => one(Effect::Volume(param.min(64))),
+        0xD => one(Effect::PatBreak(bcd(param))),
+        0xE => match param >> 4 {
+            0x8 => one(Effect::Pan((param & 0x0F) * 17)),
+            0xA => one(Effect::FineVolSlide((param & 0x0F) as i8)),
+            0xB => one(Effect::FineVolSlide(-((param & 0x0F) as i8))),
+            0xC => one(Effect::NoteCut(param & 0x0F)),
+            _ => one(Effect::None),
+        },
+        0xF if param >= 32 => one(Effect::Tempo(param)),
+        0xF if param != 0 => one(Effect::Speed(param)),
+        _ => one(Effect::None),
+    }
+}
+
+/// Amiga period of C-4 in Protracker terms (which calls it C-2)
+const MOD_BASE_PERIOD: f64 = 428.0;
+
+fn mod_period_to_note(period: u16) -> Note {
+    if period == 0 {
+        return Note::None;
+    }
+    let semis = 12. * (MOD_BASE_PERIOD / f64::from(period)).log2();
+    Note::On((f64::from(BASE_NOTE) + semis.round()).clamp(0., 119.) as u8)
+}
+
+fn parse_mod(data: &[u8]) -> anyhow::Result<Module> {
+    let tag = get(data, 1080, 4).context("Not a tracker module")?;
+    let n_channels = match tag {
+        b"M.K." | b"M!K!" | b"FLT4" | b"4CHN" => 4,
+        b"6CHN" => 6,
+        b"8CHN" | b"OCTA" | b"CD81" | b"FLT8" => 8,
+        [a, b, b'C', b'H' | b'N'] if a.is_ascii_digit() && b.is_ascii_digit() => {
+            usize::from(a - b'0') * 10 + usize::from(b - b'0')
+        }
+        [a, b'C', b'H', b'N'] if a.is_ascii_digit() => usize::from(a - b'0'),
+        _ => bail!("Unrecognized module format (tag {tag:?})"),
+    };
+    if n_channels == 0 {
+        bail!("Module has no channels");
+    }
+    let title = string_at(data, 0, 20)?;
+    let mut sample_headers = Vec::new();
+    for i in 0..31 {
+        let off = 20 + i * 30;
+        let len = usize::from(u16_be(data, off + 22)?) * 2;
+        let finetune = ((u8_at(data, off + 24)? & 0x0F) << 4) as i8 >> 4;
+        let volume = u8_at(data, off + 25)?.min(64);
+        let loop_start = usize::from(u16_be(data, off + 26)?) * 2;
+        let loop_len = usize::from(u16_be(data, off + 28)?) * 2;
+        sample_headers.push((
+            string_at(data, off, 22)?,
+            len,
+            finetune,
+            volume,
+            loop_start,
+            loop_len,
+        ));
+    }
+    let song_len = usize::from(u8_at(data, 950)?).min(128);
+    let order_table = get(data, 952, 128)?;
+    let orders = order_table[..song_len]
+        .iter()
+        .map(|pat| usize::from(*pat))
+        .collect();
+    let n_patterns = usize::from(order_table.iter().copied().max().unwrap_or(0)) + 1;
+    let mut off = 1084;
+    let mut patterns = Vec::new();
+    for _ in 0..n_patterns {
+        let mut cells = Vec::new();
+        for _ in 0..64 * n_channels {
+            let raw = get(data, off, 4)?;
+            off += 4;
+            let [eff1, eff2] = protracker_effect(raw[2] & 0x0F, raw[3]);
+            cells.push(Cell {
+                note: mod_period_to_note((u16::from(raw[0] & 0x0F) << 8) | u16::from(raw[1])),
+                ins: (raw[0] & 0xF0) | (raw[2] >> 4),
+                vol: None,
+                fx: [eff1, eff2, Effect::None],
+            });
+        }
+        patterns.push(Pattern { n_rows: 64, cells });
+    }
+    let mut instruments = Vec::new();
+    for (name, len, finetune, volume, loop_start, loop_len) in sample_headers {
+        let data: Vec<u8> = sample_data(data, off, len)
+            .iter()
+            .map(|b| b ^ 0x80)
+            .collect();
+        off += len;
+        let loop_end = (loop_start + loop_len).min(data.len());
+        instruments.push(Instrument::single(Sample {
+            name,
+            rate: tuned_rate(8363., f64::from(finetune) * 16.),
+            loop_: (loop_len > 2 && loop_start < loop_end).then_some((loop_start, loop_end)),
+            data,
+            bits16: false,
+            ping_pong: false,
+            volume,
+        }));
+    }
+    Ok(Module {
+        title,
+        n_channels,
+        orders,
+        patterns,
+        instruments,
+        speed: 6,
+        tempo: 125,
+        // Amiga style LRRL panning, but not fully hard panned
+        channel_pan: (0..n_channels)
+            .map(|ch| if matches!(ch % 4, 0 | 3) { 0x40 } else { 0xC0 })
+            .collect(),
+    })
+}
+
+fn parse_s3m(data: &[u8]) -> anyhow::Result<Module> {
+    let title = string_at(data, 0, 28)?;
+    let n_orders = usize::from(u16_le(data, 0x20)?);
+    let n_ins = usize::from(u16_le(data, 0x22)?);
+    let n_patterns = usize::from(u16_le(data, 0x24)?);
+    let signed_samples = u16_le(data, 0x2A)? == 1;
+    let speed = u8_at(data, 0x31)?;
+    let tempo = u8_at(data, 0x32)?;
+    let default_pan = u8_at(data, 0x35)? == 252;
+    let ch_settings = get(data, 0x40, 32)?;
+    let order_bytes = get(data, 0x60, n_orders)?;
+    let orders = order_bytes
+        .iter()
+        .take_while(|pat| **pat != 255)
+        .map(|pat| usize::from(*pat))
+        .collect();
+    let ins_ptrs_off = 0x60 + n_orders;
+    let pat_ptrs_off = ins_ptrs_off + n_ins * 2;
+    let pan_off = pat_ptrs_off + n_patterns * 2;
+    // Channels 0..=7 are on the left, 8..=15 on the right, everything else is disabled
+    let n_channels = ch_settings
+        .iter()
+        .rposition(|setting| *setting < 16)
+        .map_or(0, |pos| pos + 1);
+    let mut channel_pan = Vec::new();
+    for (ch, setting) in ch_settings[..n_channels].iter().enumerate() {
+        let mut pan = if *setting < 8 { 0x33 } else { 0xCC };
+        if default_pan {
+            let val = u8_at(data, pan_off + ch)?;
+            if val & 0x20 != 0 {
+                pan = (val & 0x0F) * 17;
+            }
+        }
+        channel_pan.push(pan);
+    }
+    let mut instruments = Vec::new();
+    for i in 0..n_ins {
+        let off = usize::from(u16_le(data, ins_ptrs_off + i * 2)?) * 16;
+        let name = string_at(data, off + 0x30, 28)?;
+        let kind = u8_at(data, off)?;
+        let flags = u8_at(data, off + 0x1F)?;
+        let bits16 = flags & 4 != 0;
+        let len = u32_le(data, off + 0x10)? as usize;
+        let data_off = ((usize::from(u8_at(data, off + 0x0D)?) << 16)
+            | usize::from(u16_le(data, off + 0x0E)?))
+            * 16;
+        let frame_size = if bits16 { 2 } else { 1 };
+        // Only sample instruments are supported, Adlib instruments come out as silence
+        let raw = if kind == 1 {
+            sample_data(data, data_off, len * frame_size)
+        } else {
+            &[]
+        };
+        let smp_data: Vec<u8> = if bits16 {
+            raw.chunks_exact(2)
+                .flat_map(|pair| {
+                    let val = u16::from_le_bytes([pair[0], pair[1]]);
+                    let val = if signed_samples { val } else { val ^ 0x8000 };
+                    val.to_le_bytes()
+                })
+                .collect()
+        } else if signed_samples {
+            raw.iter().map(|b| b ^ 0x80).collect()
+        } else {
+            raw.to_vec()
+        };
+        let n_frames = smp_data.len() / frame_size;
+        let loop_start = u32_le(data, off + 0x14)? as usize;
+        let loop_end = (u32_le(data, off + 0x18)? as usize).min(n_frames);
+        instruments.push(Instrument::single(Sample {
+            name,
+            rate: match u32_le(data, off + 0x20)? {
+                0 => 8363.,
+                c2spd => f64::from(c2spd.clamp(MIN_RATE, MAX_RATE)),
+            },
+            data: smp_data,
+            bits16,
+            loop_: (flags & 1 != 0 && loop_start < loop_end).then_some((loop_start, loop_end)),
+            ping_pong: false,
+            volume: u8_at(data, off + 0x1C)?.min(64),
+        }));
+    }
+    let mut patterns = Vec::new();
+    for i in 0..n_patterns {
+        let ptr = usize::from(u16_le(data, pat_ptrs_off + i * 2)?) * 16;
+        let mut cells = vec![Cell::default(); 64 * n_channels];
+        if ptr != 0 {
+            // Skip the packed length
+            let mut off = ptr + 2;
+            let mut row = 0;
+            while row < 64 {
+                let what = u8_at(data, off)?;
+                off += 1;
+                if what == 0 {
+                    row += 1;
+                    continue;
+                }
+                let mut cell = Cell::default();
+                if what & 32 != 0 {
+                    let note = u8_at(data, off)?;
+                    cell.note = match note {
+                        255 => Note::None,
+                        254 => Note::Off,
+                        _ => Note::On((note >> 4) * 12 + (note & 0x0F)),
+                    };
+                    cell.ins = u8_at(data, off + 1)?;
+                    off += 2;
+                }
+                if what & 64 != 0 {
+                    cell.vol = Some(u8_at(data, off)?.min(64));
+                    off += 1;
+                }
+                if what & 128 != 0 {
+                    cell.fx = s3m_effect(u8_at(data, off)?, u8_at(data, off + 1)?);
+                    off += 2;
+                }
+                let ch = usize::from(what & 31);
+                if ch < n_channels {
+                    cells[row * n_channels + ch] = cell;
+                }
+            }
+        }
+        patterns.push(Pattern { n_rows: 64, cells });
+    }
+    Ok(Module {
+        title,
+        n_channels,
+        orders,
+        patterns,
+        instruments,
+        speed,
+        tempo,
+        channel_pan,
+    })
+}
+
+fn s3m_effect(cmd: u8, param: u8) -> [Effect; 3] {
+    let (hi, lo) = (param >> 4, param & 0x0F);
+    let vol_slide = || match (hi, lo) {
+        (0x0F, lo) if lo != 0 => Effect::FineVolSlide(-(lo as i8)),
+        (hi, 0x0F) if hi != 0 => Effect::FineVolSlide(hi as i8),
+        (0, lo) => Effect::VolSlide(-(lo as i8)),
+        (hi, _) => Effect::VolSlide(hi as i8),
+    };
+    // Fine/extra fine slides (Ex/Fx) are approximated as regular slides
+    let porta = |param: u8| if param >= 0xE0 { param & 0x0F } else { param };
+    let fx = match cmd {
+        // A
+        1 => Effect::Speed(param),
+        // B
+        2 => Effect::PosJump(param),
+        // C
+        3 => Effect::PatBreak(bcd(param)),
+        // D, K
+        4 | 11 => vol_slide(),
+        // E
+        5 => Effect::PortaDown(porta(param)),
+        // F
+        6 => Effect::PortaUp(porta(param)),
+        // G
+        7 => Effect::TonePorta(param),
+        // L
+        12 => return [Effect::TonePorta(0), vol_slide(), Effect::None],
+        // S
+        19 => match hi {
+            0x8 => Effect::Pan(lo * 17),
+            0xC => Effect::NoteCut(lo),
+            _ => Effect::None,
+        },
+        // T
+        20 if param >= 0x20 => Effect::Tempo(param),
+        // X
+        24 if param <= 0x80 => Effect::Pan((u16::from(param) * 255 / 0x80) as u8),
+        _ => Effect::None,
+    };
+    [fx, Effect::None, Effect::None]
+}
+
+fn parse_xm(data: &[u8]) -> anyhow::Result<Module> {
+    let title = string_at(data, 17, 20)?;
+    let header_size = u32_le(data, 60)? as usize;
+    let song_len = usize::from(u16_le(data, 64)?).min(256);
+    let n_channels = usize::from(u16_le(data, 68)?);
+    let n_patterns = usize::from(u16_le(data, 70)?);
+    let n_ins = usize::from(u16_le(data, 72)?);
+    let linear_freq = u16_le(data, 74)? & 1 != 0;
+    if !linear_freq {
+        log::warn!("XM uses Amiga frequency table, portamento will be approximate");
+    }
+    let speed = u16_le(data, 76)?.min(31) as u8;
+    let tempo = u16_le(data, 78)?.min(255) as u8;
+    let orders = get(data, 80, song_len)?
+        .iter()
+        .map(|pat| usize::from(*pat))
+        .collect();
+    let mut off = 60 + header_size;
+    let mut patterns = Vec::new();
+    for _ in 0..n_patterns {
+        let pat_header_len = u32_le(data, off)? as usize;
+        let n_rows = usize::from(u16_le(data, off + 5)?);
+        let packed_size = usize::from(u16_le(data, off + 7)?);
+        off += pat_header_len;
+        let packed = get(data, off, packed_size)?;
+        off += packed_size;
+        let mut cells = vec![Cell::default(); n_rows * n_channels];
+        if packed_size != 0 {
+            let mut pos = 0;
+            for cell in &mut cells {
+                let flags = u8_at(packed, pos)?;
+                let mut fields = [0u8; 5];
+                if flags & 0x80 == 0 {
+                    fields.copy_from_slice(get(packed, pos, 5)?);
+                    pos += 5;
+                } else {
+                    pos += 1;
+                    for (bit, field) in fields.iter_mut().enumerate() {
+                        if flags & (1 << bit) != 0 {
+                            *field = u8_at(packed, pos)?;
+                            pos += 1;
+                        }
+                    }
+                }
+                let [note, ins, vol, cmd, param] = fields;
+                *cell = xm_cell(note, ins, vol, cmd, param);
+            }
+        }
+        patterns.push(Pattern { n_rows, cells });
+    }
+    let mut instruments = Vec::new();
+    for _ in 0..n_ins {
+        let ins_size = u32_le(data, off)? as usize;
+        let n_samples = usize::from(u16_le(data, off + 27)?);
+        let mut keymap = [0; 96];
+        let mut smp_header_size = 40;
+        if n_samples > 0 {
+            smp_header_size = u32_le(data, off + 29)? as usize;
+            keymap.copy_from_slice(get(data, off + 33, 96)?);
+        }
+        off += ins_size;
+        let mut headers = Vec::new();
+        for _ in 0..n_samples {
+            let flags = u8_at(data, off + 14)?;
+            headers.push((
+                u32_le(data, off)? as usize,
+                u32_le(data, off + 4)? as usize,
+                u32_le(data, off + 8)? as usize,
+                u8_at(data, off + 12)?.min(64),
+                u8_at(data, off + 13)? as i8,
+                flags,
+                u8_at(data, off + 16)? as i8,
+                string_at(data, off + 18, 22)?,
+            ));
+            off += smp_header_size;
+        }
+        let mut samples = Vec::new();
+        for (len, loop_start, loop_len, volume, finetune, flags, rel_note, name) in headers {
+            let raw = sample_data(data, off, len);
+            off += len;
+            let bits16 = flags & 0x10 != 0;
+            // Sample data is delta encoded
+            let smp_data: Vec<u8> = if bits16 {
+                let mut acc = 0i16;
+                raw.chunks_exact(2)
+                    .flat_map(|pair| {
+                        acc = acc.wrapping_add(i16::from_le_bytes([pair[0], pair[1]]));
+                        acc.to_le_bytes()
+                    })
+                    .collect()
+            } else {
+                let mut acc = 0u8;
+                raw.iter()
+                    .map(|b| {
+                        acc = acc.wrapping_add(*b);
+                        acc ^ 0x80
+                    })
+                    .collect()
+            };
+            let frame_size = if bits16 { 2 } else { 1 };
+            let (loop_start, loop_end) = (
+                loop_start / frame_size,
+                ((loop_start + loop_len) / frame_size).min(smp_data.len() / frame_size),
+            );
+            let loop_kind = flags & 3;
+            samples.push(Sample {
+                name,
+                rate: tuned_rate(8363., f64::from(rel_note) * 128. + f64::from(finetune)),
+                data: smp_data,
+                bits16,
+                loop_: (loop_kind != 0 && loop_start < loop_end).then_some((loop_start, loop_end)),
+                ping_pong: loop_kind == 2,
+                volume,
+            });
+        }
+        instruments.push(Instrument { keymap, samples });
+    }
+    Ok(Module {
+        title,
+        n_channels,
+        orders,
+        patterns,
+        instruments,
+        speed,
+        tempo,
+        channel_pan: vec![0x80; n_channels],
+    })
+}
+
+fn xm_cell(note: u8, ins: u8, vol: u8, cmd: u8, param: u8) -> Cell {
+    let [eff1, eff2] = protracker_effect(cmd, param);
+    let (vol_hi, vol_lo) = (vol >> 4, vol & 0x0F);
+    let (vol, vol_fx) = match vol_hi {
+        0x1..=0x5 if vol <= 0x50 => (Some(vol - 0x10), Effect::None),
+        0x6 => (None, Effect::VolSlide(-(vol_lo as i8))),
+        0x7 => (None, Effect::VolSlide(vol_lo as i8)),
+        0x8 => (None, Effect::FineVolSlide(-(vol_lo as i8))),
+        0x9 => (None, Effect::FineVolSlide(vol_lo as i8)),
+        0xC => (None, Effect::Pan(vol_lo * 17)),
+        0xF => (None, Effect::TonePorta(vol_lo * 16)),
+        _ => (None, Effect::None),
+    };
+    Cell {
+        note: match note {
+            1..=96 => Note::On(note - 1),
+            97 => Note::Off,
+            _ => Note::None,
+        },
+        ins,
+        vol,
+        fx: [eff1, eff2, vol_fx],
+    }
+}
+
+/// Per channel conversion state
+#[derive(Default)]
+struct ChState {
+    events: Vec<Event>,
+    ins: u8,
+    voice: Option<VoiceIdx>,
+    /// Current pitch in semitones from C-0 (including slides)
+    pitch: Option<f64>,
+    last_key: Option<i32>,
+    volume: u8,
+    last_volume: Option<u8>,
+    portament: u32,
+    /// Index of the `On` event of the currently playing note
+    open_on: Option<usize>,
+    porta_mem: u8,
+    tone_porta_mem: u8,
+    vol_slide_mem: i8,
+}
+
+impl ChState {
+    fn push(&mut self, tick: u32, payload: EventPayload) {
+        self.events.push(Event {
+            payload,
+            // Assigned once we know which channels are used
+            unit: UnitIdx(0),
+            tick,
+        });
+    }
+    fn key(&mut self, tick: u32, pitch: f64) {
+        let key = ((pitch + f64::from(KEY_OFFSET)) * 256.).round() as i32;
+        if self.last_key != Some(key) {
+            self.push(tick, EventPayload::Key(key));
+            self.last_key = Some(key);
+        }
+    }
+    fn set_portament(&mut self, tick: u32, duration: u32) {
+        if self.portament != duration {
+            self.push(tick, EventPayload::Portament { duration });
+            self.portament = duration;
+        }
+    }
+    fn flush_volume(&mut self, tick: u32) {
+        // Nothing to set the volume of before the first note
+        if self.voice.is_some() && self.last_volume != Some(self.volume) {
+            self.push(tick, EventPayload::Volume(i16::from(self.volume) * 2));
+            self.last_volume = Some(self.volume);
+        }
+    }
+    fn slide_volume(&mut self, delta: i8) {
+        self.volume = self.volume.saturating_add_signed(delta).min(64);
+    }
+    fn stop(&mut self, tick: u32) {
+        if let Some(idx) = self.open_on.take() {
+            let ev = &mut self.events[idx];
+            ev.payload = EventPayload::On {
+                duration: tick - ev.tick,
+            };
+        }
+    }
+}
+
+fn bpm(speed: u8, tempo: u8) -> f32 {
+    f32::from(tempo) * 24. / (f32::from(speed) * ROWS_PER_BEAT as f32)
+}
+
+/// Import a tracker module into a PxTone song
+pub fn import(
+    module: &Module,
+    herd: &mut Herd,
+    song: &mut Song,
+    ins: &mut MooInstructions,
+) -> anyhow::Result<()> {
+    let ticks_per_meas = TICKS_PER_ROW * ROWS_PER_BEAT * 4;
+    song.master.timing.ticks_per_beat = (TICKS_PER_ROW * ROWS_PER_BEAT) as u16;
+    song.master.timing.beats_per_meas = 4;
+    let mut speed = module.speed.max(1);
+    let mut tempo = module.tempo.max(32);
+    song.master.timing.bpm = bpm(speed, tempo);
+    let mut tempo_events = Vec::new();
+    let mut chs: Vec<ChState> = (0..module.n_channels).map(|_| ChState::default()).collect();
+    // (instrument, sample) -> voice
+    let mut voice_map: FxHashMap<(u8, u8), VoiceIdx> = FxHashMap::default();
+    let mut voices = Vec::new();
+    // Tick at which each (order, row) was first entered
+    let mut visited: FxHashMap<(usize, usize), u32> = FxHashMap::default();
+    let mut loop_tick = None;
+    let (mut order, mut start_row) = (0, 0);
+    let mut tick = 0;
+    while let Some(&pat_idx) = module.orders.get(order) {
+        let Some(pat) = module.patterns.get(pat_idx) else {
+            // S3M uses "marker" patterns that are skipped
+            order += 1;
+            start_row = 0;
+            continue;
+        };
+        if let Some(&first_tick) = visited.get(&(order, start_row)) {
+            loop_tick = Some(first_tick);
+            break;
+        }
+        visited.insert((order, start_row), tick);
+        let mut next = (order + 1, 0);
+        for row in start_row..pat.n_rows {
+            let cells = &pat.cells[row * module.n_channels..(row + 1) * module.n_channels];
+            let mut jump = None;
+            for fx in cells.iter().flat_map(|cell| cell.fx) {
+                match fx {
+                    Effect::Speed(new) if new != 0 => speed = new,
+                    Effect::Tempo(new) => tempo = new,
+                    Effect::PosJump(pos) => jump = Some((usize::from(pos), 0)),
+                    Effect::PatBreak(row) => {
+                        jump = Some((jump.map_or(order + 1, |(order, _)| order), usize::from(row)));
+                    }
+                    _ => {}
+                }
+            }
+            let new_bpm = bpm(speed, tempo);
+            if tick == 0 {
+                song.master.timing.bpm = new_bpm;
+            } else if tempo_events
+                .last()
+                .map_or(song.master.timing.bpm, |(_, bpm)| *bpm)
+                != new_bpm
+            {
+                tempo_events.push((tick, new_bpm));
+            }
+            for (cell, ch) in cells.iter().zip(&mut chs) {
+                convert_cell(module, cell, ch, tick, speed, &mut voice_map, &mut voices)?;
+            }
+            tick += TICKS_PER_ROW;
+            if let Some(jump) = jump {
+                next = jump;
+                break;
+            }
+        }
+        (order, start_row) = next;
+    }
+    let end_tick = tick;
+
+    song.events.clear();
+    herd.units.clear();
+    ins.voices.clear();
+    for (ch_idx, mut ch) in chs.into_iter().enumerate() {
+        ch.stop(end_tick);
+        if !ch
+            .events
+            .iter()
+            .any(|ev| matches!(ev.payload, EventPayload::On { .. }))
+        {
+            continue;
+        }
+        if herd.units.is_full() {
+            bail!("Too many channels (PxTone supports at most 50 units)");
+        }
+        let unit = UnitIdx(herd.units.len());
+        herd.units.push(Unit {
+            name: format!("ch{}", ch_idx + 1),
+            ..Default::default()
+        });
+        let pan = module.channel_pan.get(ch_idx).copied().unwrap_or(0x80);
+        song.events.push(Event {
+            payload: EventPayload::PanVol(pan_vol(pan)),
+            unit,
+            tick: 0,
+        });
+        for mut ev in ch.events {
+            // Drop notes that got cut right away
+            if matches!(ev.payload, EventPayload::On { duration: 0 }) {
+                continue;
+            }
+            ev.unit = unit;
+            song.events.push(ev);
+        }
+    }
+    for (tick, bpm) in tempo_events {
+        song.events.push(Event {
+            payload: EventPayload::BeatTempo(bpm),
+            unit: UnitIdx(0),
+            tick,
+        });
+    }
+    for voice in voices {
+        ins.voices.push(voice);
+    }
+    if !module.title.is_empty() {
+        song.text.name.clone_from(&module.title);
+    }
+    song.master.loop_points.repeat = loop_tick.map_or(0, |tick| tick / ticks_per_meas);
+    song.master.loop_points.last = NonZeroU32::new(end_tick.div_ceil(ticks_per_meas));
+    // PxTone events need to be in order of increasing tick
+    song.events.sort_by_key(|ev| ev.tick);
+    ptcow::rebuild_tones(ins, &mut herd.delays, &mut herd.overdrives, &song.master);
+    Ok(())
+}
+
+/// Tracker panning (0..=255) to PxTone panning (0..=128)
+fn pan_vol(pan: u8) -> u8 {
+    (u16::from(pan) * 128 / 255) as u8
+}
+
+fn convert_cell(
+    module: &Module,
+    cell: &Cell,
+    ch: &mut ChState,
+    tick: u32,
+    speed: u8,
+    voice_map: &mut FxHashMap<(u8, u8), VoiceIdx>,
+    voices: &mut Vec<Voice>,
+) -> anyhow::Result<()> {
+    let sub_tick = |t: u8| tick + u32::from(t) * TICKS_PER_ROW / u32::from(speed);
+    // Slides happen on every tick but the first one of a row
+    let slide_ticks = f64::from(speed - 1);
+    if cell.ins != 0 {
+        ch.ins = cell.ins;
+    }
+    let tone_porta = cell.fx.iter().find_map(|fx| match *fx {
+        Effect::TonePorta(param) => Some(param),
+        _ => None,
+    });
+    match cell.note {
+        Note::On(note) if tone_porta.is_some() && ch.open_on.is_some() => {
+            let param = match tone_porta {
+                Some(0) | None => ch.tone_porta_mem,
+                Some(param) => param,
+            };
+            ch.tone_porta_mem = param;
+            let from = ch.pitch.unwrap_or(f64::from(note));
+            let semis = (f64::from(note) - from).abs();
+            let tracker_ticks = if param == 0 {
+                0.
+            } else {
+                (semis * 16. / f64::from(param)).ceil()
+            };
+            let duration = (tracker_ticks * f64::from(TICKS_PER_ROW) / f64::from(speed)) as u32;
+            ch.set_portament(tick, duration);
+            ch.key(tick, f64::from(note));
+            ch.pitch = Some(f64::from(note));
+        }
+        Note::On(note) => {
+            let ins_idx = ch.ins;
+            let Some(ins) = module.instruments.get(usize::from(ins_idx).wrapping_sub(1)) else {
+                return Ok(());
+            };
+            let smp_idx = ins.keymap.get(usize::from(note)).copied().unwrap_or(0);
+            let Some(smp) = ins
+                .samples
+                .get(usize::from(smp_idx))
+                .filter(|smp| !smp.is_empty())
+            else {
+                return Ok(());
+            };
+            let voice_idx = if let Some(idx) = voice_map.get(&(ins_idx, smp_idx)) {
+                *idx
+            } else {
+                let Ok(idx) = u8::try_from(voices.len()) else {
+                    bail!("Too many samples (PxTone supports at most 256 voices)");
+                };
+                let idx = VoiceIdx(idx);
+                voices.push(sample_voice(smp));
+                voice_map.insert((ins_idx, smp_idx), idx);
+                idx
+            };
+            ch.stop(tick);
+            if ch.voice != Some(voice_idx) {
+                ch.push(tick, EventPayload::SetVoice(voice_idx));
+                ch.voice = Some(voice_idx);
+            }
+            ch.set_portament(tick, 0);
+            ch.key(tick, f64::from(note));
+            ch.pitch = Some(f64::from(note));
+            ch.volume = cell.vol.unwrap_or(smp.volume);
+            for fx in cell.fx {
+                if let Effect::Volume(vol) = fx {
+                    ch.volume = vol;
+                }
+            }
+            ch.flush_volume(tick);
+            ch.open_on = Some(ch.events.len());
+            ch.push(tick, EventPayload::On { duration: 0 });
+        }
+        Note::Off => ch.stop(tick),
+        Note::None => {}
+    }
+    if let Some(vol) = cell.vol {
+        ch.volume = vol;
+    }
+    for fx in cell.fx {
+        match fx {
+            Effect::Volume(vol) => ch.volume = vol,
+            Effect::FineVolSlide(delta) => ch.slide_volume(delta),
+            Effect::Pan(pan) => ch.push(tick, EventPayload::PanVol(pan_vol(pan))),
+            Effect::NoteCut(t) if t < speed => ch.stop(sub_tick(t)),
+            Effect::PortaUp(param) | Effect::PortaDown(param) => {
+                let param = if param == 0 { ch.porta_mem } else { param };
+                ch.porta_mem = param;
+                if let Some(pitch) = ch.pitch {
+                    let mut semis = f64::from(param) * slide_ticks / 16.;
+                    if matches!(fx, Effect::PortaDown(_)) {
+                        semis = -semis;
+                    }
+                    ch.set_portament(tick, TICKS_PER_ROW);
+                    ch.key(tick, pitch + semis);
+                    ch.pitch = Some(pitch + semis);
+                }
+            }
+            _ => {}
+        }
+    }
+    ch.flush_volume(tick);
+    for fx in cell.fx {
+        if let Effect::VolSlide(delta) = fx {
+            let delta = if delta == 0 { ch.vol_slide_mem } else { delta };
+            ch.vol_slide_mem = delta;
+            for t in 1..speed {
+                ch.slide_volume(delta);
+                ch.flush_volume(sub_tick(t));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Create a PCM voice from a sample, baking the loop into something PxTone can play
+fn sample_voice(smp: &Sample) -> Voice {
+    let frame_size = smp.frame_size();
+    let mut data = smp.data.clone();
+    let mut flags = VoiceFlags::SMOOTH;
+    if let Some((start, end)) = smp.loop_ {
+        let mut loop_data = data[start * frame_size..end * frame_size].to_vec();
+        if smp.ping_pong {
+            // Ping-pong loops become a forward loop of the region and its reverse
+            let reversed: Vec<u8> = loop_data
+                .chunks_exact(frame_size)
+                .rev()
+                .flatten()
+                .copied()
+                .collect();
+            loop_data.extend(reversed);
+        }
+        data.truncate(start * frame_size);
+        if start == 0 {
+            // PxTone can loop the whole sample
+            data = loop_data;
+            flags |= VoiceFlags::WAVE_LOOP;
+        } else {
+            // PxTone can't loop a region of a sample, so we unroll the loop for a while instead
+            let min_len = smp.rate as usize * UNROLL_SECONDS * frame_size;
+            while data.len() < min_len {
+                data.extend_from_slice(&loop_data);
+            }
+        }
+    }
+    let pcm = PcmData {
+        ch: ptcow::ChNum::Mono,
+        sps: smp.rate.round() as u32,
+        bps: if smp.bits16 {
+            ptcow::Bps::B16
+        } else {
+            ptcow::Bps::B8
+        },
+        num_samples: (data.len() / frame_size) as u32,
+        smp: data,
+    };
+    let unit = VoiceUnit {
+        basic_key: (i32::from(BASE_NOTE) + KEY_OFFSET) * 256,
+        flags,
+        ..VoiceUnit::default()
+    };
+    let mut voice = Voice::from_unit_and_data(unit, ptcow::VoiceData::Pcm(pcm));
+    voice.name.clone_from(&smp.name);
+    voice
+}
+
+#[test]
+fn test_mod_period_to_note() {
+    assert_eq!(mod_period_to_note(428), Note::On(BASE_NOTE));
+    assert_eq!(mod_period_to_note(856), Note::On(BASE_NOTE - 12));
+    assert_eq!(mod_period_to_note(113), Note::On(BASE_NOTE + 23));
+    assert_eq!(mod_period_to_note(0), Note::None);
+}
+
+#[test]
+fn test_import_mod() {
+    let mut data = vec![0; 1084];
+    data[..4].copy_from_slice(b"Test");
+    // Sample 1: 8 bytes, full volume, too short of a loop to count
+    data[20 + 23] = 4;
+    data[20 + 25] = 64;
+    data[20 + 29] = 1;
+    data[950] = 1;
+    data[1080..1084].copy_from_slice(b"M.K.");
+    let mut pattern = vec![0; 64 * 4 * 4];
+    // C-4 with sample 1, then a pattern break on row 8
+    pattern[..4].copy_from_slice(&[0x01, 0xAC, 0x10, 0x00]);
+    pattern[8 * 16..8 * 16 + 4].copy_from_slice(&[0x00, 0x00, 0x0D, 0x00]);
+    data.extend(pattern);
+    data.extend([0, 1, 2, 3, 0xFC, 0xFD, 0xFE, 0xFF]);
+    let module = Module::parse(&data).unwrap();
+    assert_eq!(module.title, "Test");
+    assert_eq!(module.n_channels, 4);
+    assert_eq!(module.orders, [0]);
+    let smp = &module.instruments[0].samples[0];
+    assert_eq!(smp.data, [0x80, 0x81, 0x82, 0x83, 0x7C, 0x7D, 0x7E, 0x7F]);
+    assert!(smp.loop_.is_none());
+    let (mut herd, mut song, mut ins) = Default::default();
+    import(&module, &mut herd, &mut song, &mut ins).unwrap();
+    assert_eq!(herd.units.len(), 1);
+    assert_eq!(ins.voices.len(), 1);
+    let payloads: Vec<_> = song.events.iter().map(|ev| (ev.tick, ev.payload)).collect();
+    assert!(payloads.contains(&(0, EventPayload::Key((48 + KEY_OFFSET) * 256))));
+    let duration = 9 * TICKS_PER_ROW;
+    assert!(payloads.contains(&(0, EventPayload::On { duration })));
+    assert_eq!(song.master.loop_points.last, NonZeroU32::new(1));
+}
+
+#[test]
+fn test_import_s3m() {
+    let mut data = vec![0; 0x118];
+    let put = |data: &mut Vec<u8>, off: usize, bytes: &[u8]| {
+        data[off..off + bytes.len()].copy_from_slice(bytes);
+    };
+    put(&mut data, 0, b"Test");
+    // 2 orders, 1 instrument, 1 pattern, unsigned samples
+    put(&mut data, 0x20, &[2, 0, 1, 0, 1, 0]);
+    put(&mut data, 0x2A, &[2, 0]);
+    put(&mut data, 0x2C, b"SCRM");
+    put(&mut data, 0x31, &[6, 125]);
+    // Only channel 1 is enabled
+    data[0x41..0x60].fill(255);
+    put(&mut data, 0x60, &[0, 255]);
+    put(&mut data, 0x62, &[0x07, 0]);
+    put(&mut data, 0x64, &[0x0C, 0]);
+    // The instrument, at 0x70: a looping sample at 0x110 with a rate way too high
+    put(&mut data, 0x70, &[1]);
+    put(&mut data, 0x70 + 0x0E, &[0x11, 0]);
+    put(&mut data, 0x70 + 0x10, &8u32.to_le_bytes());
+    put(&mut data, 0x70 + 0x14, &2u32.to_le_bytes());
+    put(&mut data, 0x70 + 0x18, &6u32.to_le_bytes());
+    put(&mut data, 0x70 + 0x1C, &[40]);
+    put(&mut data, 0x70 + 0x1F, &[1]);
+    put(&mut data, 0x70 + 0x20, &10_000_000u32.to_le_bytes());
+    // The pattern, at 0xC0: C-4 with instrument 1 and volume 32, then 63 empty rows
+    put(&mut data, 0xC2, &[0x60, 0x40, 1, 32, 0]);
+    put(&mut data, 0x110, &[128, 160, 192, 224, 255, 224, 192, 160]);
+    let module = Module::parse(&data).unwrap();
+    assert_eq!(module.n_channels, 1);
+    assert_eq!(module.orders, [0]);
+    let smp = &module.instruments[0].samples[0];
+    assert_eq!(smp.rate, f64::from(MAX_RATE));
+    assert_eq!(smp.loop_, Some((2, 6)));
+    // The loop gets unrolled, but not for longer than the rate allows
+    let voice = sample_voice(smp);
+    let ptcow::VoiceData::Pcm(pcm) = &voice.base.data else {
+        panic!("Sample voices should be PCM");
+    };
+    let min_len = MAX_RATE as usize * UNROLL_SECONDS;
+    assert!((min_len..min_len + 4).contains(&(pcm.num_samples as usize)));
+    let (mut herd, mut song, mut ins) = Default::default();
+    import(&module, &mut herd, &mut song, &mut ins).unwrap();
+    assert_eq!(herd.units.len(), 1);
+    let payloads: Vec<_> = song.events.iter().map(|ev| (ev.tick, ev.payload)).collect();
+    assert!(payloads.contains(&(0, EventPayload::Volume(64))));
+    let duration = 64 * TICKS_PER_ROW;
+    assert!(payloads.contains(&(0, EventPayload::On { duration })));
+    assert_eq!(song.master.loop_points.last, NonZeroU32::new(4));
+}
+
+#[test]
+fn test_import_xm() {
+    let mut data = b"Extended Module: Test".to_vec();
+    data.resize(60, 0);
+    data.extend(276u32.to_le_bytes());
+    // 1 order, 2 channels, 1 pattern, 1 instrument, linear frequencies, speed 6, tempo 125
+    for val in [1u16, 0, 2, 1, 1, 1, 6, 125] {
+        data.extend(val.to_le_bytes());
+    }
+    data.resize(60 + 276, 0);
+    // 2 rows: C-4 with instrument 1, then a note off
+    data.extend(9u32.to_le_bytes());
+    data.extend([0, 2, 0, 10, 0]);
+    data.extend([0x83, 49, 1, 0x80, 97, 0, 0, 0, 0, 0x80]);
+    let ins_off = data.len();
+    data.resize(ins_off + 263, 0);
+    data[ins_off..ins_off + 4].copy_from_slice(&263u32.to_le_bytes());
+    data[ins_off + 27] = 1;
+    data[ins_off + 29] = 40;
+    // A ping-pong looping sample, delta encoded
+    let smp_off = data.len();
+    data.resize(smp_off + 40, 0);
+    data[smp_off] = 4;
+    data[smp_off + 8] = 4;
+    data[smp_off + 12] = 64;
+    data[smp_off + 14] = 2;
+    data.extend([10, 10, 10, 10]);
+    let module = Module::parse(&data).unwrap();
+    assert_eq!(module.title, "Test");
+    assert_eq!(module.n_channels, 2);
+    let smp = &module.instruments[0].samples[0];
+    assert_eq!(smp.data, [138, 148, 158, 168]);
+    assert!(smp.ping_pong);
+    let voice = sample_voice(smp);
+    let ptcow::VoiceData::Pcm(pcm) = &voice.base.data else {
+        panic!("Sample voices should be PCM");
+    };
+    assert_eq!(pcm.smp, [138, 148, 158, 168, 168, 158, 148, 138]);
+    // Relative notes can go way out of range
+    assert_eq!(tuned_rate(8363., 127. * 128.), f64::from(MAX_RATE));
+    assert_eq!(tuned_rate(8363., -128. * 128.), f64::from(MIN_RATE));
+    let (mut herd, mut song, mut ins) = Default::default();
+    import(&module, &mut herd, &mut song, &mut ins).unwrap();
+    assert_eq!(herd.units.len(), 1);
+    let payloads: Vec<_> = song.events.iter().map(|ev| (ev.tick, ev.payload)).collect();
+    let duration = TICKS_PER_ROW;
+    assert!(payloads.contains(&(0, EventPayload::On { duration })));
+}
@@ -0,0 +1,704 @@
+//! Copying material between projects, with voice, unit and group indices remapped
+
+use {
+    crate::{audio_out::SongState, pxtone_misc::MeasRange},
+    anyhow::bail,
+    ptcow::{
+        Event, EventPayload, GroupIdx, Herd, MooInstructions, Song, Unit, UnitIdx, Voice,
+        VoiceData, VoiceIdx,
+    },
+    rustc_hash::FxHashMap,
+    std::num::NonZeroU32,
+};
+
+/// PxTone supports at most this many units
+const MAX_UNITS: usize = 50;
+
+/// Copy voice `idx` of `src` to the end of the voices of `dst`, returning its new index
+pub fn copy_voice(
+    src: &MooInstructions,
+    idx: VoiceIdx,
+    dst: &mut MooInstructions,
+) -> anyhow::Result<VoiceIdx> {
+    let Some(voice) = src.voices.get(idx, &[]) else {
+        bail!("No voice at index {}", idx.0);
+    };
+    dst.voices.push(voice.clone());
+    Ok(VoiceIdx(dst.voices.len() - 1))
+}
+
+/// Copy `units` of `src` with all their events to `dst`, along with the voices they use
+///
+/// Returns the indices of the new units in `dst`. Nothing is copied if any of it fails.
+pub fn copy_units(
+    src: &SongState,
+    units: &[UnitIdx],
+    dst: &mut SongState,
+) -> anyhow::Result<Vec<UnitIdx>> {
+    let room = MAX_UNITS.saturating_sub(usize::from(dst.herd.units.len()));
+    if units.len() > room {
+        bail!(
+            "Can't copy {} units, the target project only has room for {room} more",
+            units.len()
+        );
+    }
+    let mut new_units = Vec::new();
+    let mut unit_map = FxHashMap::default();
+    for &idx in units {
+        let Some(unit) = src.herd.units.get(idx) else {
+            bail!("No unit at index {}", idx.0);
+        };
+        unit_map.insert(idx, UnitIdx(dst.herd.units.len() + new_units.len() as u8));
+        new_units.push(unit);
+    }
+    let mut voices = VoiceCopies::new(&src.ins, &dst.ins);
+    let mut events = Vec::new();
+    for ev in src.song.events.iter() {
+        let Some(&unit) = unit_map.get(&ev.unit) else {
+            continue;
+        };
+        let mut ev = *ev;
+        ev.unit = unit;
+        if let EventPayload::SetVoice(voice) = &mut ev.payload {
+            *voice = voices.map(*voice)?;
+        }
+        events.push(ev);
+    }
+    for unit in new_units {
+        push_unit(&mut dst.herd, unit);
+    }
+    voices.commit(&mut dst.ins);
+    dst.song.events.extend(events);
+    dst.song.events.sort();
+    Ok(units.iter().map(|idx| unit_map[idx]).collect())
+}
+
+/// Copy the events at `indices` of `src` to `dst`, at the same ticks
+///
+/// Events go to the unit of `dst` with the same name, or to a new unit if it doesn't have one.
+/// The voices they set are copied along. Returns how many events were copied, nothing is copied
+/// if any of it fails.
+pub fn copy_events(
+    src: &SongState,
+    indices: &[usize],
+    dst: &mut SongState,
+) -> anyhow::Result<usize> {
+    let mut new_units = Vec::new();
+    let mut unit_map = FxHashMap::default();
+    let mut voices = VoiceCopies::new(&src.ins, &dst.ins);
+    let mut events = Vec::new();
+    for &idx in indices {
+        let Some(&ev) = src.song.events.get(idx) else {
+            bail!("No event at index {idx}");
+        };
+        let unit = match unit_map.get(&ev.unit) {
+            Some(&unit) => unit,
+            None => {
+                let Some(src_unit) = src.herd.units.get(ev.unit) else {
+                    bail!("Event {idx} is for unit {}, which doesn't exist", ev.unit.0);
+                };
+                let unit = match dst
+                    .herd
+                    .units
+                    .enumerated()
+                    .find(|(_, unit)| unit.name == src_unit.name)
+                {
+                    Some((unit, _)) => unit,
+                    None => {
+                        if usize::from(dst.herd.units.len()) + new_units.len() >= MAX_UNITS {
+                            bail!(
+                                "The target project has no room for unit \"{}\"",
+                                src_unit.name
+                            );
+                        }
+                        new_units.push(src_unit);
+                        UnitIdx(dst.herd.units.len() + new_units.len() as u8 - 1)
+                    }
+                };
+                unit_map.insert(ev.unit, unit);
+                unit
+            }
+        };
+        let mut ev = ev;
+        ev.unit = unit;
+        if let EventPayload::SetVoice(voice) = &mut ev.payload {
+            *voice = voices.map(*voice)?;
+        }
+        events.push(ev);
+    }
+    for unit in new_units {
+        push_unit(&mut dst.herd, unit);
+    }
+    voices.commit(&mut dst.ins);
+    let n = events.len();
+    dst.song.events.extend(events);
+    dst.song.events.sort();
+    Ok(n)
+}
+
+/// Voices of `src` to add to the end of the voices of another project, in the order they're first
+/// used
+struct VoiceCopies<'a> {
+    src: &'a MooInstructions,
+    /// Index the first copy will have in the other project
+    first_idx: usize,
+    map: FxHashMap<VoiceIdx, VoiceIdx>,
+    voices: Vec<Voice>,
+}
+
+impl<'a> VoiceCopies<'a> {
+    fn new(src: &'a MooInstructions, dst: &MooInstructions) -> Self {
+        Self {
+            src,
+            first_idx: usize::from(dst.voices.len()),
+            map: FxHashMap::default(),
+            voices: Vec::new(),
+        }
+    }
+    /// Index voice `idx` of `src` will have in the other project
+    fn map(&mut self, idx: VoiceIdx) -> anyhow::Result<VoiceIdx> {
+        if let Some(&mapped) = self.map.get(&idx) {
+            return Ok(mapped);
+        }
+        let Some(voice) = self.src.voices.get(idx, &[]) else {
+            bail!("No voice at index {}", idx.0);
+        };
+        let Ok(mapped) = u8::try_from(self.first_idx + self.voices.len()) else {
+            bail!("The target project has no room for more voices");
+        };
+        self.voices.push(voice.clone());
+        self.map.insert(idx, VoiceIdx(mapped));
+        Ok(VoiceIdx(mapped))
+    }
+    /// Add the voices to `dst`, which must be the project given to [`Self::new`]
+    fn commit(self, dst: &mut MooInstructions) {
+        for voice in self.voices {
+            dst.voices.push(voice);
+        }
+    }
+}
+
+fn push_unit(herd: &mut Herd, unit: &Unit) -> UnitIdx {
+    let idx = UnitIdx(herd.units.len());
+    herd.units.push(Unit {
+        name: unit.name.clone(),
+        ..Default::default()
+    });
+    idx
+}
+
+/// Bytes that identify the sound of a voice, for finding duplicates
+///
+/// Names are left out, the same sound under another name is still a duplicate.
+fn voice_fingerprint(voice: &Voice) -> Option<Vec<u8>> {
+    let unit = &voice.base.unit;
+    let mut out = Vec::new();
+    out.extend_from_slice(&unit.basic_key.to_le_bytes());
+    out.extend_from_slice(&unit.volume.to_le_bytes());
+    out.extend_from_slice(&unit.pan.to_le_bytes());
+    out.extend_from_slice(&unit.tuning.to_le_bytes());
+    match &voice.base.data {
+        VoiceData::Wave(_) => {
+            let mut nameless = voice.clone();
+            nameless.name.clear();
+            out.extend(nameless.to_ptvoice().ok()?);
+        }
+        VoiceData::Noise(noise) => out.extend(noise.to_ptnoise()),
+        VoiceData::Pcm(pcm) => {
+            out.extend_from_slice(&pcm.sps.to_le_bytes());
+            out.extend_from_slice(&pcm.num_samples.to_le_bytes());
+            out.push(u8::from(pcm.bps == ptcow::Bps::B16));
+            out.push(u8::from(pcm.ch == ptcow::ChNum::Stereo));
+            out.extend_from_slice(&pcm.smp);
+        }
+        VoiceData::OggV(oggv) => out.extend_from_slice(&oggv.raw_bytes),
+    }
+    Some(out)
+}
+
+/// The last measure with anything in it, or the end of the song if it's later
+pub fn song_end_meas(song: &Song) -> u32 {
+    song.master.end_meas().max(content_end_meas(song))
+}
+
+/// Measure at which the last event is over
+pub fn content_end_meas(song: &Song) -> u32 {
+    let last_tick = song
+        .events
+        .iter()
+        .map(|ev| match ev.payload {
+            EventPayload::On { duration } => ev.tick + duration,
+            _ => ev.tick,
+        })
+        .max()
+        .unwrap_or(0);
+    let ticks_per_meas = ptcow::timing::meas_to_tick(1, song.master.timing).max(1);
+    last_tick.div_ceil(ticks_per_meas)
+}
+
+/// What [`merge_project`] did, and what it had to leave out
+#[derive(Default)]
+pub struct MergeReport {
+    pub units_added: usize,
+    /// Names of the units that didn't fit
+    pub units_skipped: Vec<String>,
+    pub voices_added: usize,
+    pub voices_deduped: usize,
+    pub effects_added: usize,
+    pub effects_skipped: usize,
+    /// Groups that had to be shared with the same group of the current song
+    pub groups_shared: Vec<GroupIdx>,
+}
+
+impl MergeReport {
+    /// Human readable list of problems, empty if everything was merged cleanly
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.units_skipped.is_empty() {
+            problems.push(format!(
+                "{} unit(s) didn't fit in the {MAX_UNITS} unit limit, and were left out: {}",
+                self.units_skipped.len(),
+                self.units_skipped.join(", ")
+            ));
+        }
+        if self.effects_skipped != 0 {
+            problems.push(format!(
+                "{} effect(s) were left out, there is no room for more",
+                self.effects_skipped
+            ));
+        }
+        for group in &self.groups_shared {
+            problems.push(format!(
+                "Out of free groups, group {} is shared with the current song",
+                group.0
+            ));
+        }
+        problems
+    }
+}
+
+/// Merge another project into `dst`, starting at measure `at_meas`
+///
+/// Every unit of `src` that fits becomes a new unit. Voices that `dst` already has are reused,
+/// and groups are moved to ones `dst` doesn't use, as long as there are free ones.
+pub fn merge_project(
+    src_song: &Song,
+    src_herd: &Herd,
+    src_ins: &MooInstructions,
+    dst: &mut SongState,
+    at_meas: u32,
+) -> MergeReport {
+    let mut report = MergeReport::default();
+    let group_map = map_groups(src_song, src_herd, &dst.song, &dst.herd, &mut report);
+    let map_group = |group: GroupIdx| {
+        group_map
+            .get(usize::from(group.0))
+            .copied()
+            .unwrap_or(group)
+    };
+    // Units
+    let room = MAX_UNITS.saturating_sub(usize::from(dst.herd.units.len()));
+    let mut unit_map = FxHashMap::default();
+    for (idx, unit) in src_herd.units.enumerated() {
+        if unit_map.len() < room {
+            unit_map.insert(idx, push_unit(&mut dst.herd, unit));
+            report.units_added += 1;
+        } else {
+            report.units_skipped.push(unit.name.clone());
+        }
+    }
+    // Effects
+    for delay in src_herd.delays.iter() {
+        if dst.herd.delays.is_full() {
+            report.effects_skipped += 1;
+            continue;
+        }
+        let mut delay = delay.clone();
+        delay.group = map_group(delay.group);
+        dst.herd.delays.push(delay);
+        report.effects_added += 1;
+    }
+    for ovr in src_herd.overdrives.iter() {
+        if dst.herd.overdrives.is_full() {
+            report.effects_skipped += 1;
+            continue;
+        }
+        let mut ovr = ovr.clone();
+        ovr.group = map_group(ovr.group);
+        dst.herd.overdrives.push(ovr);
+        report.effects_added += 1;
+    }
+    // Events
+    let src_tpb = u32::from(src_song.master.timing.ticks_per_beat).max(1);
+    let dst_tpb = u32::from(dst.song.master.timing.ticks_per_beat).max(1);
+    let rescale = |tick: u32| (u64::from(tick) * u64::from(dst_tpb) / u64::from(src_tpb)) as u32;
+    let offset = ptcow::timing::meas_to_tick(at_meas, dst.song.master.timing);
+    // Units start out in group 0, so they need to be moved if group 0 was remapped.
+    // This comes before their own events, so it doesn't override them.
+    if group_map[0].0 != 0 {
+        for &unit in unit_map.values() {
+            dst.song.events.push(Event {
+                payload: EventPayload::SetGroup(group_map[0]),
+                unit,
+                tick: offset,
+            });
+        }
+    }
+    let mut dedupe = None;
+    let mut voice_map: FxHashMap<VoiceIdx, VoiceIdx> = FxHashMap::default();
+    for ev in src_song.events.iter() {
+        let Some(&unit) = unit_map.get(&ev.unit) else {
+            continue;
+        };
+        let mut ev = *ev;
+        ev.unit = unit;
+        ev.tick = offset + rescale(ev.tick);
+        match &mut ev.payload {
+            EventPayload::On { duration } | EventPayload::Portament { duration } => {
+                *duration = rescale(*duration);
+            }
+            EventPayload::SetGroup(group) => *group = map_group(*group),
+            EventPayload::SetVoice(voice) => {
+                let mapped = match voice_map.get(voice) {
+                    Some(&mapped) => Some(mapped),
+                    None => merge_voice(src_ins, *voice, &mut dst.ins, &mut dedupe, &mut report),
+                };
+                let Some(mapped) = mapped else {
+                    // Refers to a voice that doesn't exist, nothing sensible to map it to
+                    continue;
+                };
+                voice_map.insert(*voice, mapped);
+                *voice = mapped;
+            }
+            _ => {}
+        }
+        dst.song.events.push(ev);
+    }
+    dst.song.events.sort();
+    // Don't cut off the merged part
+    let end = song_end_meas(&dst.song);
+    if let Some(last) = &mut dst.song.master.loop_points.last
+        && last.get() < end
+    {
+        *last = NonZeroU32::new(end).unwrap_or(*last);
+    }
+    report
+}
+
+/// Copy `range` of `src` into a project of its own, starting at tick 0
+///
+/// Only the units with notes in the range, and the voices they use are kept. Each unit starts
+/// out in the state (voice, volume, pan, etc.) it was in at the start of the range.
+pub fn extract_range(src: &SongState, range: MeasRange) -> (Song, Herd, MooInstructions) {
+    let mut song = Song::default();
+    let mut herd = Herd::default();
+    let mut ins = MooInstructions::new(src.ins.out_sample_rate);
+    song.master.timing = src.song.master.timing;
+    song.master.loop_points.last = NonZeroU32::new(range.len());
+    song.text = src.song.text.clone();
+    for delay in src.herd.delays.iter() {
+        herd.delays.push(delay.clone());
+    }
+    for ovr in src.herd.overdrives.iter() {
+        herd.overdrives.push(ovr.clone());
+    }
+    let (start, end) = range.ticks(src.song.master.timing);
+    // Units with notes in the range
+    let mut unit_map = FxHashMap::default();
+    for (idx, unit) in src.herd.units.enumerated() {
+        let has_notes = src.song.events.iter().any(|ev| {
+            ev.unit == idx
+                && matches!(ev.payload, EventPayload::On { duration }
+                    if ev.tick < end && ev.tick + duration > start)
+        });
+        if has_notes {
+            unit_map.insert(idx, push_unit(&mut herd, unit));
+        }
+    }
+    // State of each unit at the start of the range, defaulting to the first voice
+    let mut initial: FxHashMap<UnitIdx, Vec<EventPayload>> = unit_map
+        .keys()
+        .map(|&idx| (idx, vec![EventPayload::SetVoice(VoiceIdx(0))]))
+        .collect();
+    for ev in src.song.events.iter().take_while(|ev| ev.tick < start) {
+        let Some(state) = initial.get_mut(&ev.unit) else {
+            continue;
+        };
+        if matches!(ev.payload, EventPayload::On { .. }) {
+            continue;
+        }
+        let discr = std::mem::discriminant(&ev.payload);
+        state.retain(|payload| std::mem::discriminant(payload) != discr);
+        state.push(ev.payload);
+    }
+    let mut events = Vec::new();
+    for (&unit, state) in &initial {
+        for &payload in state {
+            events.push(Event {
+                payload,
+                unit,
+                tick: start,
+            });
+        }
+    }
+    events.sort_by_key(|ev: &Event| ev.unit.0);
+    for ev in src.song.events.iter() {
+        if !unit_map.contains_key(&ev.unit) {
+            continue;
+        }
+        let mut ev = *ev;
+        if let EventPayload::On { duration } = &mut ev.payload {
+            // Cut notes at the range boundaries
+            let note_end = (ev.tick + *duration).min(end);
+            if ev.tick >= end || note_end <= start {
+                continue;
+            }
+            ev.tick = ev.tick.max(start);
+            *duration = note_end - ev.tick;
+        } else if ev.tick < start || ev.tick >= end {
+            continue;
+        }
+        events.push(ev);
+    }
+    let mut voice_map: FxHashMap<VoiceIdx, VoiceIdx> = FxHashMap::default();
+    for mut ev in events {
+        ev.unit = unit_map[&ev.unit];
+        ev.tick -= start;
+        if let EventPayload::SetVoice(voice) = &mut ev.payload {
+            if let Some(&mapped) = voice_map.get(voice) {
+                *voice = mapped;
+            } else {
+                let Ok(mapped) = copy_voice(&src.ins, *voice, &mut ins) else {
+                    // Refers to a voice that doesn't exist
+                    continue;
+                };
+                voice_map.insert(*voice, mapped);
+                *voice = mapped;
+            }
+        }
+        song.events.push(ev);
+    }
+    song.events.sort();
+    (song, herd, ins)
+}
+
+/// Voice indices of `dst` by fingerprint, made when first needed
+type Dedupe = Option<FxHashMap<Vec<u8>, VoiceIdx>>;
+
+/// Copy voice `idx` of `src` to `dst`, unless `dst` already has the same voice
+fn merge_voice(
+    src: &MooInstructions,
+    idx: VoiceIdx,
+    dst: &mut MooInstructions,
+    dedupe: &mut Dedupe,
+    report: &mut MergeReport,
+) -> Option<VoiceIdx> {
+    let fingerprints = dedupe.get_or_insert_with(|| {
+        dst.voices
+            .iter()
+            .enumerate()
+            .filter_map(|(i, voice)| Some((voice_fingerprint(voice)?, VoiceIdx(i as u8))))
+            .collect()
+    });
+    let fingerprint = voice_fingerprint(src.voices.get(idx, &[])?);
+    if let Some(&existing) = fingerprint.as_ref().and_then(|fp| fingerprints.get(fp)) {
+        report.voices_deduped += 1;
+        return Some(existing);
+    }
+    let mapped = copy_voice(src, idx, dst).ok()?;
+    if let Some(fp) = fingerprint {
+        fingerprints.insert(fp, mapped);
+    }
+    report.voices_added += 1;
+    Some(mapped)
+}
+
+/// Which group of `dst` each group of `src` should go to
+///
+/// Groups used by `src` are moved to groups that `dst` doesn't use. When there aren't enough free
+/// groups, the rest keep their number and share the group.
+fn map_groups(
+    src_song: &Song,
+    src_herd: &Herd,
+    dst_song: &Song,
+    dst_herd: &Herd,
+    report: &mut MergeReport,
+) -> Vec<GroupIdx> {
+    let n_groups = usize::from(GroupIdx::MAX.0) + 1;
+    let used = |song: &Song, herd: &Herd| {
+        let mut used = vec![false; n_groups];
+        // Units start out in group 0
+        used[0] = !herd.units.is_empty();
+        for ev in song.events.iter() {
+            if let EventPayload::SetGroup(group) = ev.payload {
+                used[usize::from(group.0).min(n_groups - 1)] = true;
+            }
+        }
+        for group in herd
+            .delays
+            .iter()
+            .map(|delay| delay.group)
+            .chain(herd.overdrives.iter().map(|ovr| ovr.group))
+        {
+            used[usize::from(group.0).min(n_groups - 1)] = true;
+        }
+        used
+    };
+    let src_used = used(src_song, src_herd);
+    let mut dst_used = used(dst_song, dst_herd);
+    let mut map: Vec<GroupIdx> = (0..n_groups).map(|i| GroupIdx(i as u8)).collect();
+    // Groups that are free in `dst` keep their number, the rest take what's left
+    let mut clashing = Vec::new();
+    for src in (0..n_groups).filter(|&i| src_used[i]) {
+        if dst_used[src] {
+            clashing.push(src);
+        } else {
+            dst_used[src] = true;
+        }
+    }
+    for src in clashing {
+        if let Some(free) = dst_used.iter().position(|used| !used) {
+            dst_used[free] = true;
+            map[src] = GroupIdx(free as u8);
+        } else {
+            report.groups_shared.push(GroupIdx(src as u8));
+        }
+    }
+    map
+}
+
+#[test]
+fn test_map_groups() {
+    let mut src_song = Song::default();
+    let mut src_herd = Herd::default();
+    src_herd.units.push(Unit::default());
+    src_song.events.push(Event {
+        payload: EventPayload::SetGroup(GroupIdx(1)),
+        unit: UnitIdx(0),
+        tick: 0,
+    });
+    let mut dst_song = Song::default();
+    let mut dst_herd = Herd::default();
+    dst_herd.units.push(Unit::default());
+    dst_song.events.push(Event {
+        payload: EventPayload::SetGroup(GroupIdx(2)),
+        unit: UnitIdx(0),
+        tick: 0,
+    });
+    let mut report = MergeReport::default();
+    let map = map_groups(&src_song, &src_herd, &dst_song, &dst_herd, &mut report);
+    // Group 1 is free, so it stays. Group 0 is taken, and goes to the first group that's left.
+    assert_eq!(map[0], GroupIdx(3));
+    assert_eq!(map[1], GroupIdx(1));
+    assert!(report.groups_shared.is_empty());
+}
+
+#[test]
+fn test_extract_range() {
+    let mut src = SongState::new(44_100);
+    let timing = src.song.master.timing;
+    let meas = ptcow::timing::meas_to_tick(1, timing);
+    src.herd.units.push(Unit::default());
+    src.herd.units.push(Unit::default());
+    for (payload, unit, tick) in [
+        (EventPayload::SetVoice(VoiceIdx(0)), 0, 0),
+        (EventPayload::Volume(50), 0, 0),
+        (EventPayload::On { duration: meas * 2 }, 0, 0),
+        (EventPayload::Volume(60), 0, meas / 2),
+        (EventPayload::On { duration: 10 }, 1, 0),
+    ] {
+        src.song.events.push(Event {
+            payload,
+            unit: UnitIdx(unit),
+            tick,
+        });
+    }
+    let (song, herd, ins) = extract_range(&src, MeasRange { start: 1, end: 2 });
+    // Unit 1 has no notes in the range
+    assert_eq!(herd.units.len(), 1);
+    assert_eq!(ins.voices.len(), 1);
+    let payloads: Vec<_> = song.events.iter().map(|ev| (ev.tick, ev.payload)).collect();
+    assert!(payloads.contains(&(0, EventPayload::SetVoice(VoiceIdx(0)))));
+    assert!(payloads.contains(&(0, EventPayload::Volume(60))));
+    assert!(!payloads.contains(&(0, EventPayload::Volume(50))));
+    // The note that started before the range is cut at its start
+    assert!(payloads.contains(&(0, EventPayload::On { duration: meas })));
+}
+
+#[test]
+fn test_copy_units() {
+    let mut src = SongState::new(44_100);
+    src.herd.units.push(Unit::default());
+    src.herd.units.push(Unit::default());
+    for (payload, unit) in [
+        (EventPayload::SetVoice(VoiceIdx(0)), 0),
+        (EventPayload::On { duration: 10 }, 0),
+        (EventPayload::SetVoice(VoiceIdx(5)), 1),
+    ] {
+        src.song.events.push(Event {
+            payload,
+            unit: UnitIdx(unit),
+            tick: 0,
+        });
+    }
+    let mut dst = SongState::new(44_100);
+    // Unit 1 uses a voice that doesn't exist, so nothing is copied
+    assert!(copy_units(&src, &[UnitIdx(0), UnitIdx(1)], &mut dst).is_err());
+    assert!(dst.herd.units.is_empty());
+    assert_eq!(dst.ins.voices.len(), 1);
+    assert!(dst.song.events.is_empty());
+    assert_eq!(
+        copy_units(&src, &[UnitIdx(0)], &mut dst).unwrap(),
+        [UnitIdx(0)]
+    );
+    assert_eq!(dst.ins.voices.len(), 2);
+    assert_eq!(dst.song.events.len(), 2);
+    assert!(
+        dst.song
+            .events
+            .iter()
+            .any(|ev| ev.payload == EventPayload::SetVoice(VoiceIdx(1)))
+    );
+}
+
+#[test]
+fn test_copy_events() {
+    let mut src = SongState::new(44_100);
+    for name in ["bass", "lead"] {
+        src.herd.units.push(Unit {
+            name: name.into(),
+            ..Default::default()
+        });
+    }
+    for (payload, unit, tick) in [
+        (EventPayload::SetVoice(VoiceIdx(0)), 1, 0),
+        (EventPayload::On { duration: 10 }, 0, 0),
+        (EventPayload::On { duration: 10 }, 1, 20),
+    ] {
+        src.song.events.push(Event {
+            payload,
+            unit: UnitIdx(unit),
+            tick,
+        });
+    }
+    let mut dst = SongState::new(44_100);
+    dst.herd.units.push(Unit {
+        name: "lead".into(),
+        ..Default::default()
+    });
+    assert_eq!(copy_events(&src, &[0, 1, 2], &mut dst).unwrap(), 3);
+    // "lead" goes to the unit of the same name, "bass" to a new one
+    assert_eq!(dst.herd.units.len(), 2);
+    assert_eq!(dst.herd.units.get(UnitIdx(1)).unwrap().name, "bass");
+    assert_eq!(dst.ins.voices.len(), 2);
+    let found: Vec<_> = dst
+        .song
+        .events
+        .iter()
+        .map(|ev| (ev.tick, ev.unit.0, ev.payload))
+        .collect();
+    assert!(found.contains(&(0, 0, EventPayload::SetVoice(VoiceIdx(1)))));
+    assert!(found.contains(&(0, 1, EventPayload::On { duration: 10 })));
+    assert!(found.contains(&(20, 0, EventPayload::On { duration: 10 })));
+    assert!(copy_events(&src, &[3], &mut dst).is_err());
+}
@@ -77,36 +77,64 @@ pub fn write_wav<W: Write + Seek>(
     Ok(())
 }
 
+/// Render of a song into .wav data, which can be advanced a few buffers at a time
+pub struct WavRender {
+    samples: Vec<i16>,
+}
+
+impl WavRender {
+    /// Prepare `song` for a non-looping render from the start
+    pub fn new(song: &mut SongState) -> Self {
+        prepare_song(song, false);
+        // Make sure we can moo
+        song.herd.moo_end = false;
+        Self {
+            samples: Vec::new(),
+        }
+    }
+    /// Render up to `n_bufs` more buffers. Returns `false` once the song has ended.
+    pub fn step(&mut self, song: &mut SongState, n_bufs: usize) -> bool {
+        let mut buf = [0; 8192];
+        for _ in 0..n_bufs {
+            if !song
+                .herd
+                .moo(&mut song.ins, &mut song.song, &mut buf, true, &mut [], &[])
+            {
+                return false;
+            }
+            self.samples.extend_from_slice(&buf);
+        }
+        true
+    }
+    /// Progress of the render, range 0..1
+    pub fn progress(song: &SongState) -> f32 {
+        song.herd.smp_count as f32 / song.herd.smp_end as f32
+    }
+    pub fn finish(self, song: &SongState) -> anyhow::Result<Vec<u8>> {
+        let mut wav_out = std::io::Cursor::new(Vec::new());
+        write_wav(
+            &mut wav_out,
+            ChNum::Stereo,
+            &self.samples,
+            song.ins.out_sample_rate.into(),
+        )?;
+        Ok(wav_out.into_inner())
+    }
+}
+
 pub fn export_wav(
     song: &mut SongState,
     progress: &AtomicU32,
     cancel: &AtomicBool,
 ) -> anyhow::Result<Vec<u8>> {
-    let mut samp_data = Vec::new();
-    let mut buf = [0; 8192];
-    let mut wav_out = std::io::Cursor::new(Vec::new());
-    // Prepare non-looping moo
-    prepare_song(song, false);
-    // Make sure we can moo
-    song.herd.moo_end = false;
-    while song
-        .herd
-        .moo(&mut song.ins, &mut song.song, &mut buf, true, &mut [], &[])
-    {
+    let mut render = WavRender::new(song);
+    while render.step(song, 1) {
         if cancel.load(Ordering::Relaxed) {
             anyhow::bail!("Cancelled");
         }
-        samp_data.extend_from_slice(&buf);
-        let progress_ratio = song.herd.smp_count as f32 / song.herd.smp_end as f32;
-        progress.store(progress_ratio.to_bits(), Ordering::Relaxed);
+        progress.store(WavRender::progress(song).to_bits(), Ordering::Relaxed);
     }
-    write_wav(
-        &mut wav_out,
-        ChNum::Stereo,
-        &samp_data,
-        song.ins.out_sample_rate.into(),
-    )?;
-    Ok(wav_out.into_inner())
+    render.finish(song)
 }
 
 pub trait HashSetExt<T> {
@@ -124,3 +152,15 @@ impl<T: Eq + core::hash::Hash + Clone, S: std::hash::BuildHasher> HashSetExt<T>
         }
     }
 }
+
+#[test]
+fn test_wav_render() {
+    let mut song = SongState::new(44_100);
+    song.song.master.loop_points.last = Some(std::num::NonZero::new(1).unwrap());
+    let mut render = WavRender::new(&mut song);
+    while render.step(&mut song, 16) {}
+    let data = render.finish(&song).unwrap();
+    assert_eq!(&data[..4], b"RIFF");
+    assert_eq!(&data[8..12], b"WAVE");
+    assert!(data.len() > 44);
+}
@@ -16,6 +16,15 @@ unsafe extern "C" {
     pub fn save_file(data: &[u8], filename: &str);
 }
 
+#[wasm_bindgen(module = "/web_glue.js")]
+extern "C" {
+    /// Returns false if local storage couldn't take the snapshot
+    pub fn recovery_store(slot: &str, data: &[u8], origin: &str) -> bool;
+    pub fn recovery_load(slot: &str) -> Option<Vec<u8>>;
+    pub fn recovery_origin(slot: &str) -> Option<String>;
+    pub fn recovery_clear(slot: &str);
+}
+
 pub fn request_fullscreen() {
     let window = web_sys::window().unwrap();
     let document = window.document().unwrap();
@@ -81,6 +90,12 @@ pub enum WebCmd {
     ImportOrganya {
         data: Vec<u8>,
     },
+    ImportTracker {
+        data: Vec<u8>,
+    },
+    ImportMml {
+        data: Vec<u8>,
+    },
     ImportPtVoice {
         data: Vec<u8>,
         name: String,
@@ -96,6 +111,9 @@ pub enum WebCmd {
     ImportAllPtcop {
         data: Vec<u8>,
     },
+    MergeProj {
+        data: Vec<u8>,
+    },
     ReplacePtVoiceSingle {
         data: Vec<u8>,
         name: String,
@@ -114,16 +132,19 @@ pub enum WebCmd {
 }
 
 impl WebCmd {
-    pub fn from_file_op(file_op: FileOp, data: Vec<u8>, name: String) -> Self {
-        match file_op {
+    /// Command to handle a file opened for `file_op`
+    ///
+    /// Save ops don't open files, so they have no command.
+    pub fn from_file_op(file_op: FileOp, data: Vec<u8>, name: String) -> Option<Self> {
+        let cmd = match file_op {
             FileOp::OpenProj => Self::OpenFile { data, name },
             FileOp::ImportAllPtcop => Self::ImportAllPtcop { data },
+            FileOp::MergeProj => Self::MergeProj { data },
             FileOp::ImportMidi => Self::ImportMidi { data },
-            FileOp::SaveProjAs => todo!(),
             FileOp::ImportPiyoPiyo => Self::ImportPiyo { data },
             FileOp::ImportOrganya => Self::ImportOrganya { data },
-            FileOp::ExportWav => todo!(),
-            FileOp::ExportWavData { .. } => todo!(),
+            FileOp::ImportTracker => Self::ImportTracker { data },
+            FileOp::ImportMml => Self::ImportMml { data },
             FileOp::ImportPtNoise => Self::ImportPtNoise { data, name },
             FileOp::ImportPtVoice => Self::ImportPtVoice { data, name },
             FileOp::ImportOggVorbis => Self::ImportOggVorbis { data, name },
@@ -142,8 +163,14 @@ impl WebCmd {
                 name,
                 voice_idx,
             },
-            FileOp::ExportPtvoice { voice } => todo!(),
-            FileOp::ExportPtnoise { voice } => todo!(),
-        }
+            FileOp::SaveProjAs
+            | FileOp::ExportMml
+            | FileOp::ExportMusicXml
+            | FileOp::ExportWav
+            | FileOp::ExportWavData { .. }
+            | FileOp::ExportPtvoice { .. }
+            | FileOp::ExportPtnoise { .. } => return None,
+        };
+        Some(cmd)
     }
 }
@@ -25,3 +25,40 @@ export function save_file(data, filename) {
 
     URL.revokeObjectURL(url);
 }
+
+// Crash recovery snapshots are kept in local storage, base64 encoded
+export function recovery_store(slot, data, origin) {
+    let bin = "";
+    for (let i = 0; i < data.length; i += 0x8000) {
+        bin += String.fromCharCode.apply(null, data.subarray(i, i + 0x8000));
+    }
+    try {
+        localStorage.setItem(`recovery-${slot}`, btoa(bin));
+        localStorage.setItem(`recovery-${slot}-origin`, origin);
+        return true;
+    } catch {
+        return false;
+    }
+}
+
+export function recovery_load(slot) {
+    const b64 = localStorage.getItem(`recovery-${slot}`);
+    if (b64 === null) {
+        return undefined;
+    }
+    const bin = atob(b64);
+    const data = new Uint8Array(bin.length);
+    for (let i = 0; i < bin.length; i++) {
+        data[i] = bin.charCodeAt(i);
+    }
+    return data;
+}
+
+export function recovery_origin(slot) {
+    return localStorage.getItem(`recovery-${slot}-origin`) ?? undefined;
+}
+
+export function recovery_clear(slot) {
+    localStorage.removeItem(`recovery-${slot}`);
+    localStorage.removeItem(`recovery-${slot}-origin`);
+}